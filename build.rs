@@ -0,0 +1,5 @@
+// SPDX-License-Identifier: Apache-2.0
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/cas.proto").expect("failed to compile proto/cas.proto");
+}
@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::{FsStorageError, IoContext}, fsstorage::Sharding};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::io::Read;
+
+/// Blocks stored as block blobs in an Azure Blob Storage container. Blob names use the same
+/// sharded layout as the filesystem stores, "<subfolder>/<Base32Z encoded Cid>", so a container
+/// can be filled from or copied to a FsBlocks store with azcopy. rm deletes the blob without
+/// downloading it first so it doesn't return the data of the block.
+pub struct AzureBlocks {
+    agent: ureq::Agent,
+    container: String,
+    sas: String,
+    sharding: Sharding,
+}
+
+impl AzureBlocks {
+    /// open the container from its URL with a shared access signature that allows reading,
+    /// writing, deleting and listing blobs, e.g.
+    /// "https://account.blob.core.windows.net/container?sv=...&sig=..."
+    pub fn new(container_url: &str) -> Result<Self, Error> {
+        let (container, sas) = container_url.split_once('?').unwrap_or((container_url, ""));
+        if container.is_empty() {
+            return Err(FsStorageError::InvalidConfig(container_url.to_string()).into());
+        }
+        debug!("azure: Using container {}", container);
+        Ok(AzureBlocks {
+            agent: ureq::Agent::new(),
+            container: container.trim_end_matches('/').to_string(),
+            sas: sas.to_string(),
+            sharding: Sharding::default(),
+        })
+    }
+
+    /// lay the blob names out with the sharding instead of the default
+    pub fn with_sharding(mut self, sharding: Sharding) -> Self {
+        self.sharding = sharding;
+        self
+    }
+
+    // the url of the blob holding the block
+    fn blob_url(&self, cid: &Cid) -> Result<String, Error> {
+        Ok(self.with_sas(format!("{}/{}", self.container, blob_name(&self.sharding, cid)?)))
+    }
+
+    fn with_sas(&self, url: String) -> String {
+        match (self.sas.is_empty(), url.contains('?')) {
+            (true, _) => url,
+            (false, true) => format!("{}&{}", url, self.sas),
+            (false, false) => format!("{}?{}", url, self.sas),
+        }
+    }
+}
+
+impl Blocks for AzureBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        match self.agent.head(&self.blob_url(cid)?).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(wrap(e)),
+        }
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let url = self.blob_url(cid)?;
+        let response = match self.agent.get(&url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Err(FsStorageError::NoSuchData(blob_name(&self.sharding, cid)?).into()),
+            Err(e) => return Err(wrap(e)),
+        };
+        let mut data = Vec::default();
+        response.into_reader().read_to_end(&mut data).io_context("read", &self.container)?;
+        Ok(data)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        let mut cids = Vec::default();
+        let mut marker = String::default();
+        loop {
+            let url = format!("{}?restype=container&comp=list", self.container);
+            let mut request = self.agent.get(&self.with_sas(url));
+            // markers hold "+", "/" and "=" so they have to be percent-encoded
+            if !marker.is_empty() {
+                request = request.query("marker", &marker);
+            }
+            let listing = request
+                .call()
+                .map_err(wrap)?
+                .into_string()
+                .io_context("read", &self.container)?;
+            let (names, next) = parse_listing(&listing);
+            for name in names {
+                let encoded = name.rsplit('/').next().unwrap_or_default();
+                if let Ok((_, bytes)) = multibase::decode(encoded) {
+                    if let Ok(cid) = Cid::try_from(bytes.as_slice()) {
+                        cids.push((encoded.to_string(), cid));
+                    }
+                }
+            }
+            match next {
+                Some(next) => marker = next,
+                None => break,
+            }
+        }
+        cids.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(cids.into_iter().map(|(_, cid)| cid).collect())
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let cid = get_cid(data)?;
+        pre_commit(&cid)?;
+
+        // a blob is replaced atomically so readers never see a partial block
+        self.agent
+            .put(&self.blob_url(&cid)?)
+            .set("x-ms-blob-type", "BlockBlob")
+            .send_bytes(data.as_ref())
+            .map_err(wrap)?;
+        debug!("azure: Stored block {}", blob_name(&self.sharding, &cid)?);
+        Ok(cid)
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        match self.agent.delete(&self.blob_url(cid)?).call() {
+            Ok(_) => Ok(Vec::default()),
+            Err(ureq::Error::Status(404, _)) => Err(FsStorageError::NoSuchData(blob_name(&self.sharding, cid)?).into()),
+            Err(e) => Err(wrap(e)),
+        }
+    }
+}
+
+/// the name of the blob holding the block
+fn blob_name(sharding: &Sharding, cid: &Cid) -> Result<String, Error> {
+    let data: Vec<u8> = cid.clone().into();
+    let encoded = multibase::encode(Base::Base32Z, data);
+    let mut name = sharding.folders(&encoded)?.join("/");
+    name.push('/');
+    name.push_str(&encoded);
+    Ok(name)
+}
+
+/// the blob names in a List Blobs response and the marker of the next page, if any
+fn parse_listing(xml: &str) -> (Vec<String>, Option<String>) {
+    let names = xml
+        .split("<Name>")
+        .skip(1)
+        .filter_map(|s| s.split_once("</Name>").map(|(name, _)| name.to_string()))
+        .collect();
+    let next = xml
+        .split_once("<NextMarker>")
+        .and_then(|(_, s)| s.split_once("</NextMarker>"))
+        .map(|(marker, _)| marker.to_string())
+        .filter(|marker| !marker.is_empty());
+    (names, next)
+}
+
+fn wrap(e: ureq::Error) -> Error {
+    Error::Wrapped(Box::new(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    #[test]
+    fn test_blob_names() {
+        let cid = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Raw)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Blake3, b"for great justice!").unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap();
+        let name = blob_name(&Sharding::Suffix { chars: 2, depth: 2 }, &cid).unwrap();
+        let (folders, encoded) = name.rsplit_once('/').unwrap();
+        assert_eq!(folders.len(), 5);
+        assert!(encoded.ends_with(&folders.replace('/', "")));
+
+        let blocks = AzureBlocks::new("https://account.blob.core.windows.net/blocks/?sv=1&sig=abc").unwrap();
+        assert!(blocks.blob_url(&cid).unwrap().starts_with("https://account.blob.core.windows.net/blocks/"));
+        assert!(blocks.blob_url(&cid).unwrap().ends_with("?sv=1&sig=abc"));
+
+        let xml = "<EnumerationResults><Blobs><Blob><Name>a/ybnd</Name></Blob><Blob><Name>b/ybnr</Name></Blob></Blobs><NextMarker>2!abc</NextMarker></EnumerationResults>";
+        assert_eq!(parse_listing(xml), (vec!["a/ybnd".to_string(), "b/ybnr".to_string()], Some("2!abc".to_string())));
+        assert_eq!(parse_listing("<Blobs /><NextMarker /></EnumerationResults>").1, None);
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: Apache-2.0
+//! cas: command line access to content addressable stores
+use clap::{Parser, Subcommand};
+use content_addressable::{error::IoContext, fsblocks, fsvlad_map, Blocks, CidMap, Error};
+use multicid::{Cid, Vlad};
+use multicodec::Codec;
+use multiutil::{BaseEncoded, DetectedEncoder, EncodingInfo};
+use std::{fs, io::{self, Write}, path::PathBuf, process};
+
+#[derive(Debug, Parser)]
+#[command(name = "cas", version, about = "Content addressable storage tool")]
+struct Cli {
+    /// The root directory of the block store
+    #[arg(long, default_value = "blocks")]
+    root: PathBuf,
+    /// The root directory of the Vlad to Cid map store
+    #[arg(long, default_value = "vlads")]
+    map_root: PathBuf,
+    #[command(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Store the contents of a file as a block and print its Cid
+    Put {
+        /// The file to store
+        file: PathBuf,
+    },
+    /// Write the contents of a block to stdout
+    Get {
+        /// The Cid of the block
+        cid: String,
+    },
+    /// List the Cids of all stored blocks
+    Ls,
+    /// Remove lazy deleted and expired blocks
+    Gc,
+    /// Manage Vlad to Cid mappings
+    #[command(subcommand)]
+    Map(MapCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum MapCommand {
+    /// Point the Vlad at the Cid
+    Put {
+        /// The Vlad to map from
+        vlad: String,
+        /// The Cid to map to
+        cid: String,
+    },
+    /// Print the Cid the Vlad points at
+    Get {
+        /// The Vlad to look up
+        vlad: String,
+    },
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("cas: {e}");
+        process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    match cli.cmd {
+        Command::Put { file } => {
+            let mut blocks = fsblocks::Builder::new(&cli.root).with_cid_config(Codec::Raw, Codec::Blake3).try_build()?;
+            let data = fs::read(&file).io_context("read", &file)?;
+            let cid = blocks.put_default(&data)?;
+            println!("{}", encode(blocks.encoding(), cid));
+        }
+        Command::Get { cid } => {
+            let blocks = fsblocks::Builder::new(&cli.root).try_build()?;
+            let data = blocks.get(&decode_cid(&cid)?)?;
+            io::stdout().write_all(&data).io_context("write", "-")?;
+        }
+        Command::Ls => {
+            let blocks = fsblocks::Builder::new(&cli.root).try_build()?;
+            for cid in blocks.ids()? {
+                println!("{}", encode(blocks.encoding(), cid));
+            }
+        }
+        Command::Gc => {
+            let mut blocks = fsblocks::Builder::new(&cli.root).try_build()?;
+            blocks.gc()?;
+        }
+        Command::Map(MapCommand::Put { vlad, cid }) => {
+            let mut map = fsvlad_map::Builder::new(&cli.map_root).try_build()?;
+            if let Some(prev) = map.put(&decode_vlad(&vlad)?, &decode_cid(&cid)?)? {
+                println!("{}", encode(map.encoding(), prev));
+            }
+        }
+        Command::Map(MapCommand::Get { vlad }) => {
+            let map = fsvlad_map::Builder::new(&cli.map_root).try_build()?;
+            let cid = map.get(&decode_vlad(&vlad)?)?;
+            println!("{}", encode(map.encoding(), cid));
+        }
+    }
+    Ok(())
+}
+
+fn encode(base: multibase::Base, cid: Cid) -> String {
+    BaseEncoded::<Cid, DetectedEncoder>::new(base, cid).to_string()
+}
+
+fn decode_bytes(s: &str) -> Result<Vec<u8>, Error> {
+    let (_, bytes) = multibase::decode(s).map_err(|e| Error::Custom(e.to_string()))?;
+    Ok(bytes)
+}
+
+fn decode_cid(s: &str) -> Result<Cid, Error> {
+    Ok(Cid::try_from(decode_bytes(s)?.as_slice())?)
+}
+
+fn decode_vlad(s: &str) -> Result<Vlad, Error> {
+    Ok(Vlad::try_from(decode_bytes(s)?.as_slice())?)
+}
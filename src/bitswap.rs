@@ -0,0 +1,377 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::no_such_data, traits::blocks::verify};
+use log::debug;
+use multicid::Cid;
+use multitrait::TryDecodeFrom;
+use prost::Message as _;
+use std::sync::{Mutex, MutexGuard};
+
+/// The protocol id of the bitswap version spoken by BitswapBlocks
+pub const PROTOCOL: &str = "/ipfs/bitswap/1.2.0";
+
+/// The protobuf messages of the bitswap protocol
+pub mod message {
+    /// A bitswap message, on the wire each one is prefixed with its length as an unsigned varint
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Message {
+        /// the blocks the sender wants, or no longer wants
+        #[prost(message, optional, tag = "1")]
+        pub wantlist: Option<Wantlist>,
+        /// the blocks sent by bitswap 1.0.0 peers
+        #[prost(bytes = "vec", repeated, tag = "2")]
+        pub blocks: Vec<Vec<u8>>,
+        /// the blocks sent in answer to want-block entries
+        #[prost(message, repeated, tag = "3")]
+        pub payload: Vec<Block>,
+        /// the answers to want-have entries and the dont-haves that were asked for
+        #[prost(message, repeated, tag = "4")]
+        pub block_presences: Vec<BlockPresence>,
+        /// the number of bytes the sender still has queued for the receiver
+        #[prost(int32, tag = "5")]
+        pub pending_bytes: i32,
+    }
+
+    /// The wants of the sender of a message
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Wantlist {
+        /// the wanted, or cancelled, blocks
+        #[prost(message, repeated, tag = "1")]
+        pub entries: Vec<Entry>,
+        /// whether this is the sender's full wantlist instead of an update to it
+        #[prost(bool, tag = "2")]
+        pub full: bool,
+    }
+
+    /// One entry in a wantlist
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Entry {
+        /// the binary Cid of the block
+        #[prost(bytes = "vec", tag = "1")]
+        pub block: Vec<u8>,
+        /// the priority of the want, higher goes first
+        #[prost(int32, tag = "2")]
+        pub priority: i32,
+        /// whether this cancels an earlier want of the block
+        #[prost(bool, tag = "3")]
+        pub cancel: bool,
+        /// whether the block or only its presence is wanted
+        #[prost(enumeration = "WantType", tag = "4")]
+        pub want_type: i32,
+        /// whether the receiver should say so when it doesn't have the block
+        #[prost(bool, tag = "5")]
+        pub send_dont_have: bool,
+    }
+
+    /// What a wantlist entry asks for
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum WantType {
+        /// the block itself
+        Block = 0,
+        /// only whether the receiver has the block
+        Have = 1,
+    }
+
+    /// A block sent to a peer
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Block {
+        /// the Cid of the block without its digest: version, codec, hash codec and digest length
+        #[prost(bytes = "vec", tag = "1")]
+        pub prefix: Vec<u8>,
+        /// the block data
+        #[prost(bytes = "vec", tag = "2")]
+        pub data: Vec<u8>,
+    }
+
+    /// Whether the sender has a block
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlockPresence {
+        /// the binary Cid of the block
+        #[prost(bytes = "vec", tag = "1")]
+        pub cid: Vec<u8>,
+        /// whether the sender has it
+        #[prost(enumeration = "BlockPresenceType", tag = "2")]
+        pub r#type: i32,
+    }
+
+    /// The answer to a want-have, or to a want with send_dont_have set
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum BlockPresenceType {
+        /// the sender has the block
+        Have = 0,
+        /// the sender doesn't have the block
+        DontHave = 1,
+    }
+}
+
+use message::{Block, BlockPresence, BlockPresenceType, Entry, Message, WantType, Wantlist};
+
+/// A stream to a peer speaking PROTOCOL. A libp2p node implements this by writing the frame to
+/// a bitswap stream to the peer and reading back the frame the peer answers with. Frames are
+/// messages prefixed with their length as an unsigned varint.
+pub trait Peer {
+    /// send the frame to the peer and return the frame it answers with
+    fn exchange(&self, frame: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Blocks that serves local blocks to peers over bitswap and fetches the blocks it is missing
+/// from them. Fetched blocks are verified against their Cids and stored locally before they are
+/// returned.
+pub struct BitswapBlocks<B, P> {
+    blocks: Mutex<B>,
+    peers: Vec<P>,
+}
+
+impl<B, P> BitswapBlocks<B, P>
+where
+    B: Blocks<Error = Error>,
+    P: Peer,
+{
+    /// wrap the local blocks and the peers asked for the blocks missing from them
+    pub fn new(blocks: B, peers: Vec<P>) -> Self {
+        BitswapBlocks {
+            blocks: Mutex::new(blocks),
+            peers,
+        }
+    }
+
+    /// unwrap the local blocks
+    pub fn into_inner(self) -> B {
+        self.blocks.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// answer the frame a peer sent with the frame to send back. want-blocks are answered with
+    /// the local blocks and want-haves with their presence, missing blocks only get a dont-have
+    /// when the peer asked for one. this never goes to the network.
+    pub fn serve(&self, frame: &[u8]) -> Result<Vec<u8>, Error> {
+        let request = decode(frame)?;
+        let blocks = self.local();
+        let mut response = Message::default();
+        for entry in request.wantlist.map(|wantlist| wantlist.entries).unwrap_or_default() {
+            if entry.cancel {
+                continue;
+            }
+            let Ok(cid) = Cid::try_from(entry.block.as_slice()) else {
+                continue;
+            };
+            let have = blocks.exists(&cid)?;
+            match (WantType::try_from(entry.want_type), have) {
+                (Ok(WantType::Block), true) => response.payload.push(Block {
+                    prefix: prefix(&cid)?,
+                    data: blocks.get(&cid)?,
+                }),
+                (Ok(WantType::Have), true) => response.block_presences.push(BlockPresence {
+                    cid: entry.block,
+                    r#type: BlockPresenceType::Have as i32,
+                }),
+                (_, false) if entry.send_dont_have => response.block_presences.push(BlockPresence {
+                    cid: entry.block,
+                    r#type: BlockPresenceType::DontHave as i32,
+                }),
+                _ => {}
+            }
+        }
+        Ok(response.encode_length_delimited_to_vec())
+    }
+
+    /// ask the peers in order for the block, None if none of them sent one that verifies
+    fn fetch(&self, cid: &Cid) -> Result<Option<Vec<u8>>, Error> {
+        let want = Message {
+            wantlist: Some(Wantlist {
+                entries: vec![Entry {
+                    block: cid.clone().into(),
+                    priority: 1,
+                    cancel: false,
+                    want_type: WantType::Block as i32,
+                    send_dont_have: true,
+                }],
+                full: false,
+            }),
+            ..Default::default()
+        };
+        let frame = want.encode_length_delimited_to_vec();
+        let prefix = prefix(cid)?;
+        for peer in &self.peers {
+            let reply = match peer.exchange(&frame).and_then(|frame| decode(&frame)) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    debug!("bitswap: Failed to exchange with a peer: {}", e);
+                    continue;
+                }
+            };
+            // never trust the data without checking it against the Cid
+            if let Some(block) = reply.payload.into_iter().find(|block| block.prefix == prefix && verify(cid, &block.data).is_ok()) {
+                debug!("bitswap: Fetched {} bytes from a peer", block.data.len());
+                return Ok(Some(block.data));
+            }
+        }
+        Ok(None)
+    }
+
+    fn local(&self) -> MutexGuard<'_, B> {
+        self.blocks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<B, P> Blocks for BitswapBlocks<B, P>
+where
+    B: Blocks<Error = Error>,
+    P: Peer,
+{
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        self.local().exists(cid)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        if self.local().exists(cid)? {
+            return self.local().get(cid);
+        }
+        let data = self.fetch(cid)?.ok_or_else(|| no_such_data(cid))?;
+        self.local().put_with_cid(cid, &data, false)?;
+        Ok(data)
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        self.local().put(data, get_cid, pre_commit)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        self.local().cids()
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        self.local().rm(cid)
+    }
+}
+
+fn decode(frame: &[u8]) -> Result<Message, Error> {
+    Message::decode_length_delimited(frame).map_err(|e| Error::Wrapped(Box::new(e)))
+}
+
+/// the Cid without the digest that ends it, the digest is the varint length prefixed bytes that
+/// end its multihash
+fn prefix(cid: &Cid) -> Result<Vec<u8>, Error> {
+    let mut bytes: Vec<u8> = cid.clone().into();
+    let mh: Vec<u8> = cid.hash().clone().into();
+    let mut rest = mh.as_slice();
+    while !rest.is_empty() {
+        let (len, digest) = u64::try_decode_from(rest)?;
+        if usize::try_from(len).is_ok_and(|len| len == digest.len()) {
+            bytes.truncate(bytes.len().saturating_sub(digest.len()));
+            return Ok(bytes);
+        }
+        rest = digest;
+    }
+    Err(Error::Custom("the multihash has no digest".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::FsStorageError, fsblocks::{Builder, FsBlocks}};
+    use multicodec::Codec;
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    // a peer that answers from its own store, standing in for a bitswap stream
+    struct Loopback(Arc<BitswapBlocks<FsBlocks, Loopback>>);
+
+    impl Peer for Loopback {
+        fn exchange(&self, frame: &[u8]) -> Result<Vec<u8>, Error> {
+            self.0.serve(frame)
+        }
+    }
+
+    // a peer that answers every want with the wrong data
+    struct Liar;
+
+    impl Peer for Liar {
+        fn exchange(&self, frame: &[u8]) -> Result<Vec<u8>, Error> {
+            let want = decode(frame)?;
+            let cid = Cid::try_from(want.wantlist.unwrap().entries[0].block.as_slice())?;
+            let reply = Message {
+                payload: vec![Block { prefix: prefix(&cid)?, data: b"all your base".to_vec() }],
+                ..Default::default()
+            };
+            Ok(reply.encode_length_delimited_to_vec())
+        }
+    }
+
+    fn want(cid: &Cid, want_type: WantType) -> Vec<u8> {
+        let entry = Entry { block: cid.clone().into(), priority: 1, cancel: false, want_type: want_type as i32, send_dont_have: true };
+        Message { wantlist: Some(Wantlist { entries: vec![entry], full: false }), ..Default::default() }.encode_length_delimited_to_vec()
+    }
+
+    #[test]
+    fn test_fetch_on_miss() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".bitswap1");
+
+        let mut blocks = Builder::new(pb.join("remote")).with_cid_config(Codec::Raw, Codec::Blake3).try_build().unwrap();
+        let cid = blocks.put_default(b"for great justice!").unwrap();
+        let remote = Arc::new(BitswapBlocks::new(blocks, Vec::default()));
+
+        let mut blocks = Builder::new(pb.join("local")).with_cid_config(Codec::Raw, Codec::Blake3).try_build().unwrap();
+        let missing = blocks.put_default(b"move every zig!").unwrap();
+        blocks.rm(&missing).unwrap();
+        let local = BitswapBlocks::new(blocks, vec![Loopback(remote.clone())]);
+
+        // the block is fetched from the peer and kept
+        assert!(!local.exists(&cid).unwrap());
+        assert_eq!(local.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert!(local.into_inner().get_verified(&cid).is_ok());
+
+        // blocks no peer has are missing
+        let local = BitswapBlocks::new(Builder::new(pb.join("local")).try_build().unwrap(), vec![Loopback(remote)]);
+        assert!(matches!(local.get(&missing), Err(Error::FsStorage(FsStorageError::NoSuchData(_)))));
+
+        // data that doesn't hash to the Cid is refused
+        let liar = BitswapBlocks::new(Builder::new(pb.join("liar")).try_build().unwrap(), vec![Liar]);
+        assert!(matches!(liar.get(&cid), Err(Error::FsStorage(FsStorageError::NoSuchData(_)))));
+        assert!(!liar.exists(&cid).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".bitswap2");
+
+        let mut blocks = Builder::new(&pb).with_cid_config(Codec::Raw, Codec::Blake3).try_build().unwrap();
+        let cid = blocks.put_default(b"for great justice!").unwrap();
+        let missing = blocks.put_default(b"move every zig!").unwrap();
+        blocks.rm(&missing).unwrap();
+        let bitswap = BitswapBlocks::<FsBlocks, Loopback>::new(blocks, Vec::default());
+
+        // a want-block gets the block with its prefix
+        let reply = decode(&bitswap.serve(&want(&cid, WantType::Block)).unwrap()).unwrap();
+        assert_eq!(reply.payload.len(), 1);
+        assert_eq!(reply.payload[0].data, b"for great justice!".to_vec());
+        let bytes: Vec<u8> = cid.clone().into();
+        assert!(bytes.starts_with(&reply.payload[0].prefix));
+        assert!(reply.payload[0].prefix.len() < bytes.len());
+
+        // a want-have only gets the presence
+        let reply = decode(&bitswap.serve(&want(&cid, WantType::Have)).unwrap()).unwrap();
+        assert!(reply.payload.is_empty());
+        assert_eq!(reply.block_presences, vec![BlockPresence { cid: bytes, r#type: BlockPresenceType::Have as i32 }]);
+
+        // missing blocks get a dont-have
+        let reply = decode(&bitswap.serve(&want(&missing, WantType::Block)).unwrap()).unwrap();
+        assert!(reply.payload.is_empty());
+        assert_eq!(reply.block_presences[0].r#type, BlockPresenceType::DontHave as i32);
+
+        // garbage is refused
+        assert!(bitswap.serve(b"\x05garbage").is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,339 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::ChunkerError};
+use fastcdc::v2020::{FastCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MINIMUM_MIN};
+use log::debug;
+use multicid::Cid;
+use multitrait::{EncodeInto, TryDecodeFrom};
+use std::{collections::BTreeSet, fmt::Display, io::{self, Read}, vec::IntoIter};
+
+/// The magic bytes at the start of every manifest block
+pub const MANIFEST_MAGIC: &[u8] = b"cdcm";
+
+/// The FastCDC chunking parameters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Params {
+    /// The minimum chunk size
+    pub min_size: u32,
+    /// The average chunk size
+    pub avg_size: u32,
+    /// The maximum chunk size
+    pub max_size: u32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            min_size: 16_384,
+            avg_size: 65_536,
+            max_size: 262_144,
+        }
+    }
+}
+
+impl Params {
+    /// check that the parameters are within the limits of the FastCDC algorithm
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.min_size < MINIMUM_MIN
+            || self.avg_size < AVERAGE_MIN
+            || self.avg_size > AVERAGE_MAX
+            || self.max_size > MAXIMUM_MAX
+            || self.min_size > self.avg_size
+            || self.avg_size > self.max_size
+        {
+            return Err(ChunkerError::InvalidParams(self.min_size, self.avg_size, self.max_size).into());
+        }
+        Ok(())
+    }
+}
+
+/// A manifest lists the chunks making up a larger piece of content, in order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// The total size of the reassembled content
+    pub size: u64,
+    /// The Cids of the chunks
+    pub chunks: Vec<Cid>,
+}
+
+impl From<Manifest> for Vec<u8> {
+    fn from(manifest: Manifest) -> Self {
+        let mut v = MANIFEST_MAGIC.to_vec();
+        v.append(&mut manifest.size.encode_into());
+        v.append(&mut (manifest.chunks.len() as u64).encode_into());
+        for cid in manifest.chunks {
+            let mut c: Vec<u8> = cid.into();
+            v.append(&mut c);
+        }
+        v
+    }
+}
+
+impl TryFrom<&[u8]> for Manifest {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let ptr = bytes
+            .strip_prefix(MANIFEST_MAGIC)
+            .ok_or(ChunkerError::InvalidManifest("missing magic".to_string()))?;
+        let (size, ptr) = u64::try_decode_from(ptr)?;
+        let (count, mut ptr) = u64::try_decode_from(ptr)?;
+        let mut chunks = Vec::default();
+        for _ in 0..count {
+            let (cid, p) = Cid::try_decode_from(ptr)?;
+            chunks.push(cid);
+            ptr = p;
+        }
+        if !ptr.is_empty() {
+            return Err(ChunkerError::InvalidManifest("trailing bytes".to_string()).into());
+        }
+        Ok(Manifest { size, chunks })
+    }
+}
+
+/// Split the data into content defined chunks, store each chunk and a manifest block listing
+/// the chunks in the block store. The get_cid closure is called to calculate the Cid of each
+/// chunk and of the manifest. Returns the Cid of the manifest block.
+pub fn put_chunked<B, F>(blocks: &mut B, data: &[u8], params: &Params, get_cid: F) -> Result<Cid, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+    F: Fn(&[u8]) -> Result<Cid, B::Error>,
+{
+    params.validate()?;
+
+    let mut manifest = Manifest {
+        size: data.len() as u64,
+        chunks: Vec::default(),
+    };
+
+    for chunk in FastCDC::new(data, params.min_size, params.avg_size, params.max_size) {
+        let bytes = &data[chunk.offset..chunk.offset + chunk.length];
+        let cid = blocks.put(&bytes, |d| get_cid(d), |_| Ok(()))?;
+        manifest.chunks.push(cid);
+    }
+    debug!("chunker: Stored {} chunks", manifest.chunks.len());
+
+    let manifest: Vec<u8> = manifest.into();
+    blocks.put(&manifest, |d| get_cid(d), |_| Ok(()))
+}
+
+/// Get the manifest stored in the block with the given Cid
+pub fn get_manifest<B>(blocks: &B, manifest_cid: &Cid) -> Result<Manifest, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let data = blocks.get(manifest_cid)?;
+    Ok(Manifest::try_from(data.as_slice())?)
+}
+
+/// Get a reader that streams the reassembled content described by the manifest block
+pub fn get_chunked<'a, B>(blocks: &'a B, manifest_cid: &Cid) -> Result<ChunkReader<'a, B>, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let manifest = get_manifest(blocks, manifest_cid)?;
+    Ok(ChunkReader {
+        blocks,
+        chunks: manifest.chunks.into_iter(),
+        buf: Vec::default(),
+        pos: 0,
+    })
+}
+
+/// How much the chunks of a set of chunked content were deduplicated, to evaluate the chunker
+/// parameters on the data
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DedupReport {
+    /// The number of pieces of chunked content
+    pub manifests: usize,
+    /// The number of chunks the manifests list
+    pub chunks: usize,
+    /// The number of distinct chunks
+    pub unique_chunks: usize,
+    /// The number of chunks that repeat a chunk listed before them
+    pub duplicate_chunks: usize,
+    /// The total size of the content
+    pub content_bytes: u64,
+    /// The total size of the distinct chunks, what the content takes up in the store
+    pub stored_bytes: u64,
+}
+
+impl DedupReport {
+    /// the number of bytes deduplication saved
+    pub fn bytes_saved(&self) -> u64 {
+        self.content_bytes.saturating_sub(self.stored_bytes)
+    }
+
+    /// the size of the content over the size of its distinct chunks, 1.0 when nothing was
+    /// deduplicated
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.content_bytes as f64 / self.stored_bytes as f64
+    }
+
+    // count the chunks of the manifests, the sizes of the distinct ones are read from the store
+    pub(crate) fn from_manifests<B, I>(blocks: &B, manifests: I) -> Result<Self, B::Error>
+    where
+        B: Blocks,
+        I: IntoIterator<Item = Manifest>,
+    {
+        let mut report = DedupReport::default();
+        let mut seen = BTreeSet::default();
+        for manifest in manifests {
+            report.manifests += 1;
+            report.content_bytes += manifest.size;
+            for chunk in manifest.chunks {
+                report.chunks += 1;
+                let key: Vec<u8> = chunk.clone().into();
+                if !seen.insert(key) {
+                    report.duplicate_chunks += 1;
+                    continue;
+                }
+                report.unique_chunks += 1;
+                report.stored_bytes += blocks.stat(&chunk)?.size;
+            }
+        }
+        debug!("chunker: {} of {} chunks are duplicates", report.duplicate_chunks, report.chunks);
+        Ok(report)
+    }
+}
+
+/// Report how much the chunks of the content behind the manifest blocks were deduplicated
+pub fn dedup_report<B>(blocks: &B, manifest_cids: &[Cid]) -> Result<DedupReport, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let manifests = manifest_cids.iter().map(|cid| get_manifest(blocks, cid)).collect::<Result<Vec<_>, _>>()?;
+    DedupReport::from_manifests(blocks, manifests)
+}
+
+/// Reader over chunked content that fetches chunks from the block store as needed
+pub struct ChunkReader<'a, B>
+where
+    B: Blocks,
+{
+    blocks: &'a B,
+    chunks: IntoIter<Cid>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<B> Read for ChunkReader<'_, B>
+where
+    B: Blocks,
+    B::Error: Display,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // fetch the next chunk once the current one is exhausted
+        while self.pos == self.buf.len() {
+            match self.chunks.next() {
+                Some(cid) => {
+                    self.buf = self.blocks.get(&cid).map_err(|e| io::Error::other(e.to_string()))?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::{Builder, FsBlocks};
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use rand::RngCore;
+    use std::{fs, path::PathBuf};
+
+    fn get_cid(data: &[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?
+            .try_build()?;
+        let cid = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Raw)
+            .with_hash(&mh)
+            .try_build()?;
+        Ok(cid)
+    }
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut v = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut v);
+        v
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let manifest = Manifest {
+            size: 42,
+            chunks: vec![get_cid(b"for great justice!").unwrap(), get_cid(b"move every zig!").unwrap()],
+        };
+        let v: Vec<u8> = manifest.clone().into();
+        assert_eq!(manifest, Manifest::try_from(v.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_put_get_chunked() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".chunker1");
+
+        let mut blocks: FsBlocks = Builder::new(&pb).try_build().unwrap();
+
+        let v1 = random_data(1_000_000);
+        let manifest_cid = put_chunked(&mut blocks, &v1, &Params::default(), get_cid).unwrap();
+
+        let manifest = get_manifest(&blocks, &manifest_cid).unwrap();
+        assert_eq!(manifest.size, v1.len() as u64);
+        assert!(manifest.chunks.len() > 1);
+
+        let mut v2 = Vec::default();
+        get_chunked(&blocks, &manifest_cid).unwrap().read_to_end(&mut v2).unwrap();
+        assert_eq!(v1, v2);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_chunk_dedup() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".chunker2");
+
+        let mut blocks: FsBlocks = Builder::new(&pb).try_build().unwrap();
+
+        // two inputs that share a large common prefix
+        let v1 = random_data(1_000_000);
+        let mut v2 = v1.clone();
+        v2.extend_from_slice(&random_data(100_000));
+
+        let cid1 = put_chunked(&mut blocks, &v1, &Params::default(), get_cid).unwrap();
+        let cid2 = put_chunked(&mut blocks, &v2, &Params::default(), get_cid).unwrap();
+        let m1 = get_manifest(&blocks, &cid1).unwrap();
+        let m2 = get_manifest(&blocks, &cid2).unwrap();
+
+        let shared = m2.chunks.iter().filter(|c| m1.chunks.contains(c)).count();
+        assert!(shared > 0);
+
+        // the shared chunks are only stored once
+        let report = dedup_report(&blocks, &[cid1, cid2]).unwrap();
+        assert_eq!(report.manifests, 2);
+        assert_eq!(report.chunks, m1.chunks.len() + m2.chunks.len());
+        assert!(report.duplicate_chunks >= shared);
+        assert_eq!(report.unique_chunks + report.duplicate_chunks, report.chunks);
+        assert_eq!(report.content_bytes, 2_100_000);
+        assert!(report.bytes_saved() > 500_000);
+        assert!(report.dedup_ratio() > 1.0);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
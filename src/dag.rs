@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::DagError};
+use multicid::Cid;
+use multicodec::Codec;
+use serde_cbor::Value;
+
+/// The CBOR tag used for Cid links in dag-cbor
+pub const CID_TAG: u64 = 42;
+
+/// Decode a dag-cbor encoded block into a generic CBOR value
+pub fn decode(data: &[u8]) -> Result<Value, Error> {
+    Ok(serde_cbor::from_slice(data)?)
+}
+
+/// Recursively extract all of the Cid links from a decoded dag-cbor value
+pub fn extract_links(value: &Value) -> Result<Vec<Cid>, Error> {
+    let mut links = Vec::default();
+    walk(value, &mut links)?;
+    Ok(links)
+}
+
+fn walk(value: &Value, links: &mut Vec<Cid>) -> Result<(), Error> {
+    match value {
+        Value::Tag(CID_TAG, inner) => links.push(decode_link(inner)?),
+        Value::Tag(_, inner) => walk(inner, links)?,
+        Value::Array(values) => {
+            for v in values {
+                walk(v, links)?;
+            }
+        }
+        Value::Map(map) => {
+            for (k, v) in map {
+                walk(k, links)?;
+                walk(v, links)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn decode_link(value: &Value) -> Result<Cid, Error> {
+    match value {
+        // dag-cbor links are binary Cids prefixed with the 0x00 identity multibase prefix
+        Value::Bytes(b) if b.first() == Some(&0) => Ok(Cid::try_from(&b[1..])?),
+        _ => Err(DagError::InvalidLink.into()),
+    }
+}
+
+/// Get the Cids of all of the blocks linked to from the block with the given Cid. Raw and
+/// identity blocks have no links.
+pub fn links<B>(blocks: &B, cid: &Cid) -> Result<Vec<Cid>, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    match cid.target_codec() {
+        Codec::DagCbor => {
+            let data = blocks.get(cid)?;
+            Ok(extract_links(&decode(&data)?)?)
+        }
+        Codec::Raw | Codec::Identity => Ok(Vec::default()),
+        codec => Err(Error::from(DagError::UnsupportedCodec(codec)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::Builder;
+    use multicid::cid;
+    use multihash::mh;
+    use std::{collections::BTreeMap, fs, path::PathBuf};
+
+    fn get_cid(codec: Codec, data: &[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Sha3512, data)?
+            .try_build()?;
+        let cid = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(codec)
+            .with_hash(&mh)
+            .try_build()?;
+        Ok(cid)
+    }
+
+    fn link(cid: &Cid) -> Value {
+        let mut b = vec![0u8];
+        let mut c: Vec<u8> = cid.clone().into();
+        b.append(&mut c);
+        Value::Tag(CID_TAG, Box::new(Value::Bytes(b)))
+    }
+
+    #[test]
+    fn test_links() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".dag1");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+
+        // store two raw leaf blocks
+        let leaf1 = blocks.put(&b"for great justice!", |d| get_cid(Codec::Raw, *d), |_| Ok(())).unwrap();
+        let leaf2 = blocks.put(&b"move every zig!", |d| get_cid(Codec::Raw, *d), |_| Ok(())).unwrap();
+
+        // store a dag-cbor node linking to both, one of them nested
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("first".to_string()), link(&leaf1));
+        map.insert(Value::Text("rest".to_string()), Value::Array(vec![link(&leaf2)]));
+        let node = serde_cbor::to_vec(&Value::Map(map)).unwrap();
+        let root = blocks.put(&node, |d| get_cid(Codec::DagCbor, d), |_| Ok(())).unwrap();
+
+        let l = links(&blocks, &root).unwrap();
+        assert_eq!(l.len(), 2);
+        assert!(l.contains(&leaf1));
+        assert!(l.contains(&leaf2));
+
+        // leaves have no links
+        assert!(links(&blocks, &leaf1).unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
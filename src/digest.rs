@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::Error;
+use multicodec::Codec;
+use multihash::Multihash;
+use multitrait::EncodeInto;
+use sha2::Digest as _;
+
+/// An incremental hash over data that is fed to it in pieces so that hashing can share a single
+/// pass over the data with writing it somewhere else
+#[derive(Clone, Debug)]
+pub enum Digest {
+    /// Blake3 with a 32 byte digest
+    Blake3(Box<blake3::Hasher>, u64),
+    /// Sha2-256
+    Sha2256(sha2::Sha256, u64),
+    /// Sha2-512
+    Sha2512(sha2::Sha512, u64),
+}
+
+impl Digest {
+    /// start a new digest for the hash codec
+    pub fn new(codec: Codec) -> Result<Self, Error> {
+        match codec {
+            Codec::Blake3 => Ok(Digest::Blake3(Box::default(), 0)),
+            Codec::Sha2256 => Ok(Digest::Sha2256(sha2::Sha256::new(), 0)),
+            Codec::Sha2512 => Ok(Digest::Sha2512(sha2::Sha512::new(), 0)),
+            _ => Err(Error::Unsupported(format!("incremental {:?} hashing", codec))),
+        }
+    }
+
+    /// hash the next piece of the data
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Digest::Blake3(h, len) => {
+                h.update(data);
+                *len += data.len() as u64;
+            }
+            Digest::Sha2256(h, len) => {
+                h.update(data);
+                *len += data.len() as u64;
+            }
+            Digest::Sha2512(h, len) => {
+                h.update(data);
+                *len += data.len() as u64;
+            }
+        }
+    }
+
+    /// the hash codec
+    pub fn codec(&self) -> Codec {
+        match self {
+            Digest::Blake3(..) => Codec::Blake3,
+            Digest::Sha2256(..) => Codec::Sha2256,
+            Digest::Sha2512(..) => Codec::Sha2512,
+        }
+    }
+
+    /// the number of bytes hashed so far
+    pub fn len(&self) -> u64 {
+        match self {
+            Digest::Blake3(_, len) | Digest::Sha2256(_, len) | Digest::Sha2512(_, len) => *len,
+        }
+    }
+
+    /// true if nothing has been hashed yet
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the digest of the data hashed so far, this doesn't consume the context
+    pub fn finalize(&self) -> Vec<u8> {
+        match self {
+            Digest::Blake3(h, _) => h.finalize().as_bytes().to_vec(),
+            Digest::Sha2256(h, _) => h.clone().finalize().to_vec(),
+            Digest::Sha2512(h, _) => h.clone().finalize().to_vec(),
+        }
+    }
+
+    /// the multihash of the data hashed so far
+    pub fn multihash(&self) -> Result<Multihash, Error> {
+        let digest = self.finalize();
+        let mut v = self.codec().encode_into();
+        v.append(&mut (digest.len() as u64).encode_into());
+        v.extend_from_slice(&digest);
+        Ok(Multihash::try_from(v.as_slice())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multihash::mh;
+
+    #[test]
+    fn test_matches_one_shot() {
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        for codec in [Codec::Blake3, Codec::Sha2256, Codec::Sha2512] {
+            let mut digest = Digest::new(codec).unwrap();
+            for piece in data.chunks(777) {
+                digest.update(piece);
+            }
+            assert_eq!(digest.len(), data.len() as u64);
+            let expected = mh::Builder::new_from_bytes(codec, &data).unwrap().try_build().unwrap();
+            assert_eq!(digest.multihash().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert!(Digest::new(Codec::Identity).is_err());
+    }
+}
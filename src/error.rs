@@ -1,4 +1,5 @@
 // SPDX-License-Idnetifier: Apache-2.0
+use alloc::{boxed::Box, string::String};
 
 /// Errors created by this library
 #[derive(Debug, thiserror::Error)]
@@ -6,11 +7,22 @@
 pub enum Error {
     /// formatting error
     #[error(transparent)]
-    Fmt(#[from] std::fmt::Error),
-    /// I/O error
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Fmt(#[from] core::fmt::Error),
+    /// I/O error with the operation and the encoded ID or path it failed on
+    #[cfg(feature = "std")]
+    #[error("{op} {}: {source}", io_target(.id, .path))]
+    Io {
+        /// the operation that failed
+        op: &'static str,
+        /// the encoded ID of the entry the operation was for, if any
+        id: Option<String>,
+        /// the path the operation failed on
+        path: std::path::PathBuf,
+        /// the underlying I/O error
+        source: std::io::Error,
+    },
     /// Persist error
+    #[cfg(feature = "std")]
     #[error(transparent)]
     Persist(#[from] tempfile::PersistError),
 
@@ -26,6 +38,9 @@ pub enum Error {
     /// A multikey error
     #[error(transparent)]
     Multikey(#[from] multikey::Error),
+    /// A multisig error
+    #[error(transparent)]
+    Multisig(#[from] multisig::Error),
     /// A multitrait error
     #[error(transparent)]
     Multitrait(#[from] multitrait::Error),
@@ -35,15 +50,93 @@ pub enum Error {
     /// An FsStorage
     #[error(transparent)]
     FsStorage(#[from] FsStorageError),
+    /// A Chunker error
+    #[error(transparent)]
+    Chunker(#[from] ChunkerError),
+    /// A Dag error
+    #[error(transparent)]
+    Dag(#[from] DagError),
+    /// A CBOR error
+    #[cfg(feature = "dag_cbor")]
+    #[error(transparent)]
+    Cbor(#[from] serde_cbor::Error),
+    /// A platform keychain error
+    #[cfg(feature = "keyring")]
+    #[error(transparent)]
+    Keyring(#[from] keyring::Error),
+    /// A provenance log error
+    #[cfg(feature = "provenance")]
+    #[error(transparent)]
+    ProvenanceLog(#[from] provenance_log::Error),
+
+    /// Storing the data would exceed the storage quota
+    #[error("Quota exceeded: {used} bytes used, storing {needed} more bytes would exceed the {limit} byte limit")]
+    QuotaExceeded {
+        /// the number of bytes already stored
+        used: u64,
+        /// the maximum number of bytes that may be stored
+        limit: u64,
+        /// the number of bytes the operation needed
+        needed: u64,
+    },
+
+    /// Storing the data would eat into the free space reserved on the filesystem
+    #[error("Store full: {free} bytes free, storing {needed} more bytes would drop below the {reserved} byte reserve")]
+    StoreFull {
+        /// the number of bytes free on the filesystem
+        free: u64,
+        /// the number of bytes that have to stay free
+        reserved: u64,
+        /// the number of bytes the operation needed
+        needed: u64,
+    },
+
+    /// The block is bigger than the largest block the store accepts
+    #[error("Block too large: {size} bytes is over the {limit} byte limit, split it into smaller blocks with the chunker")]
+    BlockTooLarge {
+        /// the size of the block in bytes
+        size: u64,
+        /// the largest block size the store accepts
+        limit: u64,
+    },
+
+    /// The data breaks the store's acceptance policy
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
+
+    /// A compare and swap found a different value than expected
+    #[error("Compare and swap failed: expected {0:?}, found {1:?}")]
+    CasMismatch(Option<multicid::Cid>, Option<multicid::Cid>),
+
+    /// Too few of the replicated stores carried out the operation
+    #[error("Too few replicas: {op} needed {needed} successes but only {succeeded} succeeded")]
+    TooFewReplicas {
+        /// the operation that was carried out
+        op: &'static str,
+        /// the number of stores that had to succeed
+        needed: usize,
+        /// the number of stores that did succeed
+        succeeded: usize,
+    },
+
+    /// The operation isn't supported by the implementation or its configuration
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
 
     /// A custom error for callback functions
     #[error("Custom error: {0}")]
     Custom(String),
     /// A wraps any error
     #[error(transparent)]
-    Wrapped(#[from] Box<dyn std::error::Error>)
+    Wrapped(#[from] Box<dyn core::error::Error + Send + Sync>)
 }
 
+// errors are handed back from worker threads and blocking tasks so they must stay Send + Sync
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Error>();
+};
+
 /// Error from FsStorage
 #[derive(Clone, Debug, thiserror::Error)]
 #[non_exhaustive]
@@ -52,6 +145,7 @@ pub enum FsStorageError {
     #[error("Unsupported base encoding {0:?}")]
     UnsupportedBaseEncoding(multibase::Base),
     /// the path exists but it isn't a dir
+    #[cfg(feature = "std")]
     #[error("Path is not a directory {0}")]
     NotDir(std::path::PathBuf),
     /// the id for the data is invalid
@@ -60,4 +154,151 @@ pub enum FsStorageError {
     /// the id doesn't refer to data
     #[error("No such data {0}")]
     NoSuchData(String),
+    /// the stored value for the id can't be decoded
+    #[error("Invalid value for {0}")]
+    InvalidValue(String),
+    /// the configuration persisted in the store can't be read or written
+    #[error("Invalid store config: {0}")]
+    InvalidConfig(String),
+    /// the store was created with a different configuration than it is being opened with
+    #[error("Store config mismatch: {0}")]
+    ConfigMismatch(String),
+    /// the store was written by a newer version of this library
+    #[error("Unsupported store format version {0}")]
+    UnsupportedVersion(u64),
+    /// the store was written by an older version of this library and must be migrated
+    #[error("Store format version {0} must be migrated")]
+    NeedsMigration(u64),
+    /// the sharding must have at least one character and one level, prefix sharding no more
+    /// characters than its hash has and a store that isn't lazy few enough subfolders to create
+    #[error("Invalid sharding chars: {0}, depth: {1}")]
+    InvalidSharding(usize, usize),
+    /// compressing or decompressing data failed
+    #[error("Compression failed: {0}")]
+    CompressionFailed(String),
+    /// unsupported compression codec
+    #[error("Unsupported compression codec {0:?}")]
+    UnsupportedCompression(multicodec::Codec),
+    /// the encryption key is not a valid symmetric key
+    #[error("Invalid encryption key")]
+    InvalidEncryptionKey,
+    /// encrypting data failed
+    #[error("Encryption failed")]
+    EncryptionFailed,
+    /// decrypting data failed, either the key is wrong or the data is corrupt
+    #[error("Decryption failed")]
+    DecryptionFailed,
+    /// the data doesn't hash to the multihash in its Cid
+    #[error("Corrupt block {0}")]
+    CorruptBlock(String),
+    /// the entry doesn't have that many previous versions
+    #[error("No version {1} of {0}")]
+    NoSuchVersion(String, usize),
+    /// timed out waiting for another writer to release the lock on an entry
+    #[error("Timed out waiting for lock on {0}")]
+    LockTimeout(String),
+    /// the time to live puts the expiry beyond the latest time the system can represent
+    #[error("Invalid ttl of {0}s")]
+    InvalidTtl(u64),
+    /// the snapshot archive can't be imported
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    /// there is no mapping from the ID
+    #[error("No mapping for {0}")]
+    NoMapping(String),
+    /// the mapping from the ID points at a block that isn't stored
+    #[error("Mapping for {0} points at missing block {1}")]
+    DanglingMapping(String, String),
+    /// the two-phase commit transaction name is invalid
+    #[error("Invalid transaction: {0}")]
+    InvalidTransaction(String),
+}
+
+/// Error from the chunker
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ChunkerError {
+    /// the chunking parameters are out of range
+    #[error("Invalid chunking parameters min: {0}, avg: {1}, max: {2}")]
+    InvalidParams(u32, u32, u32),
+    /// the manifest block could not be decoded
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+}
+
+/// Error from the dag module
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum DagError {
+    /// the block codec isn't one we can extract links from
+    #[error("Unsupported dag codec {0:?}")]
+    UnsupportedCodec(multicodec::Codec),
+    /// a tagged link doesn't contain a valid Cid
+    #[error("Invalid link")]
+    InvalidLink,
+    /// a dag-pb or UnixFS node can't be decoded or isn't the kind of node expected
+    #[error("Invalid node: {0}")]
+    InvalidNode(String),
+}
+
+/// the NoSuchData error for a missing block, naming it by its Base32Z encoded Cid
+#[cfg(feature = "std")]
+pub(crate) fn no_such_data(cid: &multicid::Cid) -> Error {
+    let data: alloc::vec::Vec<u8> = cid.clone().into();
+    FsStorageError::NoSuchData(multibase::encode(multibase::Base::Base32Z, data)).into()
+}
+
+/// Attach the operation and path to I/O errors so they say what failed where
+#[cfg(feature = "std")]
+pub trait IoContext<T> {
+    /// convert the I/O error into an Error::Io for the operation on the path
+    fn io_context<P: AsRef<std::path::Path>>(self, op: &'static str, path: P) -> Result<T, Error>;
+
+    /// convert the I/O error into an Error::Io for the operation on the path of the entry with
+    /// the encoded ID
+    fn io_context_id<P: AsRef<std::path::Path>>(self, op: &'static str, eid: &str, path: P) -> Result<T, Error>;
+}
+
+#[cfg(feature = "std")]
+impl<T> IoContext<T> for Result<T, std::io::Error> {
+    fn io_context<P: AsRef<std::path::Path>>(self, op: &'static str, path: P) -> Result<T, Error> {
+        self.map_err(|source| Error::Io {
+            op,
+            id: None,
+            path: path.as_ref().to_path_buf(),
+            source: without_path(source),
+        })
+    }
+
+    fn io_context_id<P: AsRef<std::path::Path>>(self, op: &'static str, eid: &str, path: P) -> Result<T, Error> {
+        self.map_err(|source| Error::Io {
+            op,
+            id: Some(eid.into()),
+            path: path.as_ref().to_path_buf(),
+            source: without_path(source),
+        })
+    }
+}
+
+/// name an entry by its encoded ID, the path is only shown for everything else
+#[cfg(feature = "std")]
+fn io_target(id: &Option<String>, path: &std::path::Path) -> String {
+    match id {
+        Some(eid) => eid.clone(),
+        None => path.display().to_string(),
+    }
+}
+
+/// tempfile wraps the OS errors it returns to add the path, which the context already has
+#[cfg(feature = "std")]
+fn without_path(source: std::io::Error) -> std::io::Error {
+    let code = source
+        .get_ref()
+        .and_then(|e| e.source())
+        .and_then(|e| e.downcast_ref::<std::io::Error>())
+        .and_then(|e| e.raw_os_error());
+    match code {
+        Some(code) => std::io::Error::from_raw_os_error(code),
+        None => source,
+    }
 }
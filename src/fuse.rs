@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::IoContext};
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::{collections::BTreeMap, ffi::OsStr, path::Path, time::{Duration, UNIX_EPOCH}};
+
+/// How long the kernel may cache names and attributes. Blocks never change so only the listing
+/// of the root directory goes stale.
+const TTL: Duration = Duration::from_secs(1);
+
+/// The inode of the root directory
+const ROOT_INO: u64 = 1;
+
+/// Mount the blocks read-only at the mountpoint and serve them until the filesystem is
+/// unmounted. The root directory holds one file per block named by its Base32Z encoded Cid.
+/// Lookups accept a Cid in any multibase encoding so a block can be opened by any name for it.
+pub fn mount<B, P>(blocks: B, mountpoint: P) -> Result<(), Error>
+where
+    B: Blocks<Error = Error> + Send + 'static,
+    P: AsRef<Path>,
+{
+    debug!("fuse: Mounting blocks at {}", mountpoint.as_ref().display());
+    fuser::mount2(BlockFs::new(blocks), mountpoint.as_ref(), &options()).io_context("mount", mountpoint.as_ref())
+}
+
+/// Mount the blocks read-only at the mountpoint on a background thread. The filesystem is
+/// unmounted when the returned session is dropped.
+pub fn spawn_mount<B, P>(blocks: B, mountpoint: P) -> Result<fuser::BackgroundSession, Error>
+where
+    B: Blocks<Error = Error> + Send + 'static,
+    P: AsRef<Path>,
+{
+    debug!("fuse: Mounting blocks at {}", mountpoint.as_ref().display());
+    fuser::spawn_mount2(BlockFs::new(blocks), mountpoint.as_ref(), &options()).io_context("mount", mountpoint.as_ref())
+}
+
+fn options() -> Vec<MountOption> {
+    vec![MountOption::RO, MountOption::FSName("content-addressable".to_string())]
+}
+
+/// The read-only filesystem over a block store. Inodes are handed out as blocks are looked up
+/// and dropped once the kernel forgets them. Each open file handle holds its block so reads are
+/// served without fetching it again.
+struct BlockFs<B> {
+    blocks: B,
+    inodes: BTreeMap<Vec<u8>, u64>,
+    // the Cid of each inode and the number of lookups of it the kernel hasn't forgotten
+    cids: BTreeMap<u64, (Cid, u64)>,
+    handles: BTreeMap<u64, Vec<u8>>,
+    next_ino: u64,
+    next_fh: u64,
+}
+
+impl<B> BlockFs<B>
+where
+    B: Blocks<Error = Error>,
+{
+    fn new(blocks: B) -> Self {
+        BlockFs {
+            blocks,
+            inodes: BTreeMap::default(),
+            cids: BTreeMap::default(),
+            handles: BTreeMap::default(),
+            next_ino: ROOT_INO + 1,
+            next_fh: 1,
+        }
+    }
+
+    // get the inode for the Cid, assigning the next one if it is new
+    fn inode(&mut self, cid: &Cid) -> u64 {
+        let key: Vec<u8> = cid.clone().into();
+        if let Some(ino) = self.inodes.get(&key) {
+            return *ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(key, ino);
+        self.cids.insert(ino, (cid.clone(), 0));
+        ino
+    }
+
+    // drop the lookups of the inode the kernel forgot and the inode itself once it has none
+    fn forget_inode(&mut self, ino: u64, nlookup: u64) {
+        let Some((cid, lookups)) = self.cids.get_mut(&ino) else {
+            return;
+        };
+        *lookups = lookups.saturating_sub(nlookup);
+        if *lookups == 0 {
+            let key: Vec<u8> = cid.clone().into();
+            self.inodes.remove(&key);
+            self.cids.remove(&ino);
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        if ino == ROOT_INO {
+            return Some(file_attr(ino, FileType::Directory, 0));
+        }
+        let (cid, _) = self.cids.get(&ino)?;
+        let size = match self.blocks.stat(cid) {
+            Ok(stat) if !stat.lazy_deleted => stat.size,
+            Ok(_) => return None,
+            // backends without stat have to read the block to know its size
+            Err(_) => self.blocks.get(cid).ok()?.len() as u64,
+        };
+        Some(file_attr(ino, FileType::RegularFile, size))
+    }
+}
+
+impl<B> Filesystem for BlockFs<B>
+where
+    B: Blocks<Error = Error>,
+{
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent != ROOT_INO {
+            return reply.error(libc::ENOENT);
+        }
+        let Some(cid) = decode_name(name) else {
+            return reply.error(libc::ENOENT);
+        };
+        if !self.blocks.exists(&cid).unwrap_or(false) {
+            return reply.error(libc::ENOENT);
+        }
+        let ino = self.inode(&cid);
+        match self.attr(ino) {
+            Some(attr) => {
+                // every entry replied counts as a lookup until the kernel forgets it
+                if let Some((_, lookups)) = self.cids.get_mut(&ino) {
+                    *lookups += 1;
+                }
+                reply.entry(&TTL, &attr, 0);
+            }
+            None => {
+                self.forget_inode(ino, 0);
+                reply.error(libc::ENOENT);
+            }
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.forget_inode(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let Some((cid, _)) = self.cids.get(&ino) else {
+            return reply.error(libc::ENOENT);
+        };
+        // the block is fetched once per open file, not once per read
+        match self.blocks.get(cid) {
+            Ok(data) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.handles.insert(fh, data);
+                reply.opened(fh, 0);
+            }
+            Err(e) => {
+                debug!("fuse: Failed to read block: {}", e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(data) = self.handles.get(&fh) else {
+            return reply.error(libc::EBADF);
+        };
+        let start = (offset.max(0) as usize).min(data.len());
+        let end = start.saturating_add(size as usize).min(data.len());
+        reply.data(&data[start..end]);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != ROOT_INO {
+            return reply.error(libc::ENOTDIR);
+        }
+        let cids = match self.blocks.cids() {
+            Ok(cids) => cids,
+            Err(e) => {
+                debug!("fuse: Failed to list blocks: {}", e);
+                return reply.error(libc::EIO);
+            }
+        };
+
+        // listing doesn't count as a lookup so blocks that haven't been looked up get a number
+        // without an inode that would never be forgotten
+        let mut entries = vec![(ROOT_INO, FileType::Directory, ".".to_string()), (ROOT_INO, FileType::Directory, "..".to_string())];
+        for cid in &cids {
+            let key: Vec<u8> = cid.clone().into();
+            let ino = match self.inodes.get(&key) {
+                Some(ino) => *ino,
+                None => {
+                    self.next_ino += 1;
+                    self.next_ino - 1
+                }
+            };
+            entries.push((ino, FileType::RegularFile, encode_name(cid)));
+        }
+
+        // the offset is the number of entries the kernel has already been given
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset.max(0) as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// the file name of a block
+fn encode_name(cid: &Cid) -> String {
+    let data: Vec<u8> = cid.clone().into();
+    multibase::encode(Base::Base32Z, data)
+}
+
+/// the Cid a file name refers to, None if it isn't a multibase encoded Cid
+fn decode_name(name: &OsStr) -> Option<Cid> {
+    let (_, data) = multibase::decode(name.to_str()?).ok()?;
+    Cid::try_from(data.as_slice()).ok()
+}
+
+fn file_attr(ino: u64, kind: FileType, size: u64) -> FileAttr {
+    FileAttr {
+        ino,
+        size,
+        blocks: size.div_ceil(512),
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind,
+        perm: if kind == FileType::Directory { 0o555 } else { 0o444 },
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 512,
+        flags: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    #[test]
+    fn test_names() {
+        let cid = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Identity)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Blake3, b"for great justice!").unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap();
+
+        let name = encode_name(&cid);
+        assert_eq!(decode_name(OsStr::new(&name)), Some(cid.clone()));
+
+        // any multibase encoding of the Cid finds the same block
+        let data: Vec<u8> = cid.clone().into();
+        let other = multibase::encode(Base::Base58Btc, data);
+        assert_eq!(decode_name(OsStr::new(&other)), Some(cid));
+        assert_eq!(decode_name(OsStr::new("not a cid")), None);
+    }
+}
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::{FsStorageError, IoContext}};
+use log::debug;
+use multicid::Cid;
+use std::{net::SocketAddr, sync::{Arc, Mutex}};
+use tonic::{transport::{Channel, Server}, Code, Request, Response, Status};
+
+/// The protocol types and service stubs generated from proto/cas.proto
+pub mod proto {
+    tonic::include_proto!("cas");
+}
+
+use proto::{cas_client::CasClient, cas_server::{Cas, CasServer}};
+
+/// The gRPC service that gives remote clients access to a store
+pub struct CasService<B> {
+    blocks: Arc<Mutex<B>>,
+}
+
+impl<B> CasService<B>
+where
+    B: Blocks<Error = Error> + Send + 'static,
+{
+    /// wrap the store, this process becomes the only one that touches it
+    pub fn new(blocks: B) -> Self {
+        CasService {
+            blocks: Arc::new(Mutex::new(blocks)),
+        }
+    }
+
+    // run the closure against the store off of the async workers since it does blocking I/O
+    async fn run<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut B) -> Result<T, Error> + Send + 'static,
+    {
+        let blocks = self.blocks.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut blocks = blocks.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut blocks)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(to_status)
+    }
+}
+
+#[tonic::async_trait]
+impl<B> Cas for CasService<B>
+where
+    B: Blocks<Error = Error> + Send + 'static,
+{
+    async fn put(&self, request: Request<proto::Block>) -> Result<Response<proto::Empty>, Status> {
+        let block = request.into_inner();
+        let cid = decode_cid(&block.cid)?;
+        self.run(move |blocks| blocks.put_with_cid(&cid, &block.data, true)).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn get(&self, request: Request<proto::CidRef>) -> Result<Response<proto::Block>, Status> {
+        let cid_bytes = request.into_inner().cid;
+        let cid = decode_cid(&cid_bytes)?;
+        let data = self.run(move |blocks| blocks.get(&cid)).await?;
+        Ok(Response::new(proto::Block { cid: cid_bytes, data }))
+    }
+
+    async fn exists(&self, request: Request<proto::CidRef>) -> Result<Response<proto::ExistsResponse>, Status> {
+        let cid = decode_cid(&request.into_inner().cid)?;
+        let exists = self.run(move |blocks| blocks.exists(&cid)).await?;
+        Ok(Response::new(proto::ExistsResponse { exists }))
+    }
+
+    async fn rm(&self, request: Request<proto::CidRef>) -> Result<Response<proto::Block>, Status> {
+        let cid_bytes = request.into_inner().cid;
+        let cid = decode_cid(&cid_bytes)?;
+        let data = self.run(move |blocks| blocks.rm(&cid)).await?;
+        Ok(Response::new(proto::Block { cid: cid_bytes, data }))
+    }
+
+    async fn list(&self, _request: Request<proto::Empty>) -> Result<Response<proto::CidList>, Status> {
+        let cids = self.run(|blocks| blocks.cids()).await?;
+        Ok(Response::new(proto::CidList {
+            cids: cids.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+/// Serve the store over gRPC on the address until the server fails
+pub async fn serve<B>(blocks: B, addr: SocketAddr) -> Result<(), Error>
+where
+    B: Blocks<Error = Error> + Send + 'static,
+{
+    debug!("grpc: Serving blocks on {}", addr);
+    Server::builder()
+        .add_service(CasServer::new(CasService::new(blocks)))
+        .serve(addr)
+        .await
+        .map_err(|e| Error::Wrapped(Box::new(e)))
+}
+
+/// Blocks backed by a store in another process served with serve(). The calls block on a private
+/// runtime so this must not be used from inside an async task.
+pub struct GrpcBlocks {
+    client: CasClient<Channel>,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl GrpcBlocks {
+    /// connect to the server at the address (e.g. "http://127.0.0.1:4001")
+    pub fn connect(addr: &str) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .io_context("start runtime for", addr)?;
+        let client = runtime
+            .block_on(CasClient::connect(addr.to_string()))
+            .map_err(|e| Error::Wrapped(Box::new(e)))?;
+        debug!("grpc: Connected to {}", addr);
+        Ok(GrpcBlocks { client, runtime })
+    }
+
+    fn cid_ref(cid: &Cid) -> proto::CidRef {
+        proto::CidRef { cid: cid.clone().into() }
+    }
+}
+
+impl Blocks for GrpcBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        let mut client = self.client.clone();
+        let response = self.runtime.block_on(client.exists(Self::cid_ref(cid))).map_err(from_status)?;
+        Ok(response.into_inner().exists)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let mut client = self.client.clone();
+        let response = self.runtime.block_on(client.get(Self::cid_ref(cid))).map_err(from_status)?;
+        Ok(response.into_inner().data)
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let cid = get_cid(data)?;
+        pre_commit(&cid)?;
+        let block = proto::Block {
+            cid: cid.clone().into(),
+            data: data.as_ref().to_vec(),
+        };
+        self.runtime.block_on(self.client.put(block)).map_err(from_status)?;
+        Ok(cid)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        let mut client = self.client.clone();
+        let response = self.runtime.block_on(client.list(proto::Empty {})).map_err(from_status)?;
+        response
+            .into_inner()
+            .cids
+            .iter()
+            .map(|cid| Ok(Cid::try_from(cid.as_slice())?))
+            .collect()
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let mut client = self.client.clone();
+        let response = self.runtime.block_on(client.rm(Self::cid_ref(cid))).map_err(from_status)?;
+        Ok(response.into_inner().data)
+    }
+}
+
+fn decode_cid(bytes: &[u8]) -> Result<Cid, Status> {
+    Cid::try_from(bytes).map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+// map the errors clients need to tell apart to their own status codes
+fn to_status(e: Error) -> Status {
+    match &e {
+        Error::FsStorage(FsStorageError::NoSuchData(id)) => Status::not_found(id.clone()),
+        Error::FsStorage(FsStorageError::CorruptBlock(id)) => Status::data_loss(id.clone()),
+        Error::QuotaExceeded { .. } | Error::StoreFull { .. } => Status::resource_exhausted(e.to_string()),
+        Error::TooFewReplicas { .. } => Status::unavailable(e.to_string()),
+        Error::BlockTooLarge { .. } | Error::PolicyViolation(..) => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+fn from_status(status: Status) -> Error {
+    match status.code() {
+        Code::NotFound => FsStorageError::NoSuchData(status.message().to_string()).into(),
+        Code::DataLoss => FsStorageError::CorruptBlock(status.message().to_string()).into(),
+        _ => Error::Wrapped(Box::new(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::Builder;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::{fs, net::TcpListener, path::PathBuf, thread, time::Duration};
+
+    fn get_cid(data: &[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Raw).with_hash(&mh).try_build()?)
+    }
+
+    #[test]
+    fn test_remote_store() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".grpc1");
+        let blocks = Builder::new(&pb).try_build().unwrap();
+
+        // pick a free port and run the server on its own runtime
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(serve(blocks, addr)).unwrap();
+        });
+
+        let mut client = None;
+        for _ in 0..100 {
+            if let Ok(c) = GrpcBlocks::connect(&format!("http://{}", addr)) {
+                client = Some(c);
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut client = client.unwrap();
+
+        let cid = client.put(&b"for great justice!", |d| get_cid(*d), |_| Ok(())).unwrap();
+        assert!(client.exists(&cid).unwrap());
+        assert_eq!(client.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(client.cids().unwrap(), vec![cid.clone()]);
+        assert_eq!(client.rm(&cid).unwrap(), b"for great justice!".to_vec());
+        assert!(!client.exists(&cid).unwrap());
+        assert!(matches!(client.get(&cid), Err(Error::FsStorage(FsStorageError::NoSuchData(_)))));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
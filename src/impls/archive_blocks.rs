@@ -0,0 +1,278 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, traits::blocks::BlockStat, error::{FsStorageError, IoContext}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::{collections::BTreeMap, fs::File, io::Read, path::{Path, PathBuf}, sync::Mutex};
+
+/// The magic bytes at the start of a zip archive
+#[cfg(feature = "zip")]
+const ZIP_MAGIC: &[u8; 2] = b"PK";
+
+/// Where a block is in the archive
+#[derive(Clone, Copy, Debug)]
+enum Entry {
+    /// the offset and size of the file data in a tar archive
+    #[cfg(feature = "tar")]
+    Tar(u64, u64),
+    /// the index and size of the file in a zip archive
+    #[cfg(feature = "zip")]
+    Zip(usize, u64),
+}
+
+/// The open archive
+enum Archive {
+    #[cfg(feature = "tar")]
+    Tar(File),
+    #[cfg(feature = "zip")]
+    Zip(Box<zip::ZipArchive<File>>),
+}
+
+/// Read-only blocks served straight out of a zip or tar archive, so published datasets can be
+/// used without extracting them. Every file in the archive named by a multibase encoded Cid is a
+/// block, whatever folder it is in, so a snapshot of an uncompressed, unencrypted FsBlocks
+/// store works too. Files and folders with names starting with a '.' are skipped.
+pub struct ArchiveBlocks {
+    path: PathBuf,
+    entries: BTreeMap<Vec<u8>, Entry>,
+    archive: Mutex<Archive>,
+}
+
+impl ArchiveBlocks {
+    /// open the archive and index the blocks in it, zip archives are detected by their magic
+    /// bytes and anything else is read as a tar archive
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut magic = [0u8; 2];
+        let n = File::open(&path).and_then(|mut f| f.read(&mut magic)).io_context("read", &path)?;
+
+        #[cfg(feature = "zip")]
+        if &magic[..n] == ZIP_MAGIC {
+            return Self::open_zip(path);
+        }
+        Self::open_tar(path)
+    }
+
+    /// the number of blocks in the archive
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// whether the archive has no blocks
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[cfg(feature = "zip")]
+    fn open_zip(path: PathBuf) -> Result<Self, Error> {
+        let file = File::open(&path).io_context("open", &path)?;
+        let mut zip = zip::ZipArchive::new(file).map_err(|e| Error::Wrapped(Box::new(e)))?;
+        let mut entries = BTreeMap::default();
+        for i in 0..zip.len() {
+            let file = zip.by_index_raw(i).map_err(|e| Error::Wrapped(Box::new(e)))?;
+            if !file.is_file() {
+                continue;
+            }
+            if let Some(key) = block_key(file.name()) {
+                entries.insert(key, Entry::Zip(i, file.size()));
+            }
+        }
+        debug!("archive_blocks: Opened zip {} with {} blocks", path.display(), entries.len());
+        Ok(ArchiveBlocks {
+            path,
+            entries,
+            archive: Mutex::new(Archive::Zip(Box::new(zip))),
+        })
+    }
+
+    #[cfg(feature = "tar")]
+    fn open_tar(path: PathBuf) -> Result<Self, Error> {
+        let mut tar = tar::Archive::new(File::open(&path).io_context("open", &path)?);
+        let mut entries = BTreeMap::default();
+        for entry in tar.entries().io_context("read", &path)? {
+            let entry = entry.io_context("read", &path)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path().io_context("read", &path)?.to_string_lossy().to_string();
+            if let Some(key) = block_key(&name) {
+                entries.insert(key, Entry::Tar(entry.raw_file_position(), entry.size()));
+            }
+        }
+        debug!("archive_blocks: Opened tar {} with {} blocks", path.display(), entries.len());
+        let file = File::open(&path).io_context("open", &path)?;
+        Ok(ArchiveBlocks {
+            path,
+            entries,
+            archive: Mutex::new(Archive::Tar(file)),
+        })
+    }
+
+    #[cfg(not(feature = "tar"))]
+    fn open_tar(path: PathBuf) -> Result<Self, Error> {
+        Err(FsStorageError::InvalidValue(format!("{} is not a zip archive", path.display())).into())
+    }
+
+    fn entry(&self, cid: &Cid) -> Result<Entry, Error> {
+        let key: Vec<u8> = cid.clone().into();
+        self.entries.get(&key).copied().ok_or_else(|| FsStorageError::NoSuchData(encode_key(&key)).into())
+    }
+}
+
+impl Blocks for ArchiveBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        Ok(self.entries.contains_key(&key))
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let entry = self.entry(cid)?;
+        let mut archive = self.archive.lock().unwrap_or_else(|e| e.into_inner());
+        let mut data = Vec::default();
+        match (entry, &mut *archive) {
+            #[cfg(feature = "tar")]
+            (Entry::Tar(offset, size), Archive::Tar(file)) => {
+                use std::io::{Seek, SeekFrom};
+                file.seek(SeekFrom::Start(offset)).io_context("seek", &self.path)?;
+                data.resize(size as usize, 0);
+                file.read_exact(&mut data).io_context("read", &self.path)?;
+            }
+            #[cfg(feature = "zip")]
+            (Entry::Zip(i, size), Archive::Zip(zip)) => {
+                let mut file = zip.by_index(i).map_err(|e| Error::Wrapped(Box::new(e)))?;
+                data.reserve(size as usize);
+                file.read_to_end(&mut data).io_context("read", &self.path)?;
+            }
+            #[allow(unreachable_patterns)]
+            _ => return Err(FsStorageError::InvalidValue(format!("bad entry in {}", self.path.display())).into()),
+        }
+        Ok(data)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        let mut cids: Vec<(String, &Vec<u8>)> = self.entries.keys().map(|key| (encode_key(key), key)).collect();
+        cids.sort();
+        cids.iter().map(|(_, key)| Ok(Cid::try_from(key.as_slice())?)).collect()
+    }
+
+    fn put<D, F1, F2>(&mut self, _data: &D, _get_cid: F1, _pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        Err(Error::Unsupported("put on a read-only archive".to_string()))
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        let size = match self.entry(cid)? {
+            #[cfg(feature = "tar")]
+            Entry::Tar(_, size) => size,
+            #[cfg(feature = "zip")]
+            Entry::Zip(_, size) => size,
+        };
+        Ok(BlockStat {
+            size,
+            created: None,
+            lazy_deleted: false,
+        })
+    }
+
+    fn rm(&self, _cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        Err(Error::Unsupported("rm on a read-only archive".to_string()))
+    }
+}
+
+fn encode_key(key: &[u8]) -> String {
+    multibase::encode(Base::Base32Z, key)
+}
+
+/// the Cid bytes of the block a file in the archive holds, None if it isn't a block
+fn block_key(name: &str) -> Option<Vec<u8>> {
+    // archives made with "tar -C root ." name everything under "./"
+    if name.split('/').any(|part| part.starts_with('.') && part != ".") {
+        return None;
+    }
+    let (_, bytes) = multibase::decode(name.rsplit('/').next()?).ok()?;
+    Cid::try_from(bytes.as_slice()).ok()?;
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::fs;
+
+    fn cid_of(data: &[u8]) -> Cid {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data).unwrap().try_build().unwrap();
+        cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Raw).with_hash(&mh).try_build().unwrap()
+    }
+
+    fn name_of(cid: &Cid) -> String {
+        let data: Vec<u8> = cid.clone().into();
+        multibase::encode(Base::Base32Z, data)
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_tar_archive() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".archiveblocks1");
+        fs::create_dir_all(&pb).unwrap();
+        let path = pb.join("dataset.tar");
+
+        let a = cid_of(b"for great justice!");
+        let b = cid_of(b"move every zig!");
+        let mut tar = tar::Builder::new(File::create(&path).unwrap());
+        for (name, data) in [
+            (format!("blocks/{}", name_of(&a)), &b"for great justice!"[..]),
+            (name_of(&b), &b"move every zig!"[..]),
+            (format!(".meta/{}", name_of(&a)), &b"skipped"[..]),
+            ("README".to_string(), &b"not a block"[..]),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data).unwrap();
+        }
+        tar.into_inner().unwrap();
+
+        let mut blocks = ArchiveBlocks::open(&path).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks.get(&a).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.get_verified(&b).unwrap(), b"move every zig!".to_vec());
+        assert_eq!(blocks.stat(&b).unwrap().size, 15);
+        assert!(!blocks.exists(&cid_of(b"all your base")).unwrap());
+        assert!(matches!(blocks.rm(&a), Err(Error::Unsupported(_))));
+        assert!(matches!(blocks.put(&b"all your base", |d| Ok(cid_of(*d)), |_| Ok(())), Err(Error::Unsupported(_))));
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn test_zip_archive() {
+        use std::io::Write;
+
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".archiveblocks2");
+        fs::create_dir_all(&pb).unwrap();
+        let path = pb.join("dataset.zip");
+
+        let a = cid_of(b"for great justice!");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        zip.start_file(format!("ab/{}", name_of(&a)), zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"for great justice!").unwrap();
+        zip.finish().unwrap();
+
+        let blocks = ArchiveBlocks::open(&path).unwrap();
+        assert_eq!(blocks.cids().unwrap(), vec![a.clone()]);
+        assert_eq!(blocks.get(&a).unwrap(), b"for great justice!".to_vec());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
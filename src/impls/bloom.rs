@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Error, error::{FsStorageError, IoContext}};
+use log::debug;
+use std::{fmt, fs::OpenOptions, io::{Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::RwLock};
+
+/// The length of the header at the start of the bloom filter file that holds the number of hashes
+const HEADER_LEN: u64 = 4;
+
+/// A bloom filter over the IDs in a store that is persisted to a file. Setting bits writes them
+/// through to the file so the filter never has to be saved as a whole after it is created. Bits are
+/// never cleared, removed IDs just become false positives.
+pub struct BloomFilter {
+    path: PathBuf,
+    hashes: u32,
+    bits: RwLock<Vec<u8>>,
+}
+
+impl BloomFilter {
+    /// create an empty filter sized to hold the expected number of items with the given false
+    /// positive rate. nothing is written to the path until save() is called.
+    pub fn new<P: AsRef<Path>>(path: P, expected_items: u64, false_positive_rate: f64) -> Result<Self, Error> {
+        if expected_items == 0 || !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(FsStorageError::InvalidConfig(
+                format!("bloom filter for {} items at a false positive rate of {}", expected_items, false_positive_rate)
+            ).into());
+        }
+
+        // the optimal number of bits and hashes for the expected items and false positive rate
+        let ln2 = core::f64::consts::LN_2;
+        let bits = (-(expected_items as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(8.0);
+        let hashes = ((bits / expected_items as f64) * ln2).round().max(1.0);
+        Ok(BloomFilter {
+            path: path.as_ref().to_path_buf(),
+            hashes: hashes as u32,
+            bits: RwLock::new(vec![0; (bits as usize).div_ceil(8)]),
+        })
+    }
+
+    /// load the filter from the path if the file exists and was created with the same size,
+    /// otherwise return the empty filter so that the caller can rebuild it
+    pub fn open<P: AsRef<Path>>(path: P, expected_items: u64, false_positive_rate: f64) -> Result<(Self, bool), Error> {
+        let filter = Self::new(path, expected_items, false_positive_rate)?;
+        let path = filter.path.clone();
+        if !path.try_exists().io_context("stat", &path)? {
+            return Ok((filter, false));
+        }
+
+        let data = std::fs::read(&path).io_context("read", &path)?;
+        let (header, bits) = data.split_at(data.len().min(HEADER_LEN as usize));
+        let hashes = header.try_into().map(u32::from_le_bytes).unwrap_or_default();
+        if hashes != filter.hashes || bits.len() != filter.len() {
+            debug!("bloom: Ignoring bloom filter at {} created with a different size", path.display());
+            return Ok((filter, false));
+        }
+        *filter.bits.write().unwrap_or_else(|e| e.into_inner()) = bits.to_vec();
+        Ok((filter, true))
+    }
+
+    /// check if the ID may be in the filter. false means the ID is definitely not in the filter.
+    pub fn contains(&self, id: &[u8]) -> bool {
+        let bits = self.bits.read().unwrap_or_else(|e| e.into_inner());
+        self.indexes(id).all(|i| bits[i / 8] & (1 << (i % 8)) != 0)
+    }
+
+    /// add the ID to the filter in memory only, call save() to persist the whole filter
+    pub fn add(&self, id: &[u8]) {
+        let mut bits = self.bits.write().unwrap_or_else(|e| e.into_inner());
+        for i in self.indexes(id) {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    /// add the ID to the filter and write the bytes that changed through to the file
+    pub fn insert(&self, id: &[u8]) -> Result<(), Error> {
+        let mut bits = self.bits.write().unwrap_or_else(|e| e.into_inner());
+        let mut f = None;
+        for i in self.indexes(id) {
+            let byte = bits[i / 8] | 1 << (i % 8);
+            if byte == bits[i / 8] {
+                continue;
+            }
+            bits[i / 8] = byte;
+
+            if f.is_none() {
+                f = Some(OpenOptions::new().write(true).open(&self.path).io_context("open", &self.path)?);
+            }
+            if let Some(f) = f.as_mut() {
+                f.seek(SeekFrom::Start(HEADER_LEN + (i / 8) as u64)).io_context("seek", &self.path)?;
+                f.write_all(&[byte]).io_context("write", &self.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write the whole filter to its file
+    pub fn save(&self) -> Result<(), Error> {
+        let bits = self.bits.read().unwrap_or_else(|e| e.into_inner());
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut temp = tempfile::Builder::new().tempfile_in(dir).io_context("create temp file in", dir)?;
+        temp.write_all(&self.hashes.to_le_bytes()).io_context("write", temp.path())?;
+        temp.write_all(&bits).io_context("write", temp.path())?;
+        temp.persist(&self.path)?;
+        debug!("bloom: Saved bloom filter to {}", self.path.display());
+        Ok(())
+    }
+
+    // the number of bytes of bits in the filter
+    fn len(&self) -> usize {
+        self.bits.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    // the bit indexes for the ID using double hashing of two FNV-1a hashes
+    fn indexes(&self, id: &[u8]) -> impl Iterator<Item = usize> {
+        let m = (self.len() * 8) as u64;
+        let h1 = fnv1a(id, 0xcbf29ce484222325);
+        let h2 = fnv1a(id, 0x84222325cbf29ce4) | 1;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BloomFilter({}, {} bytes, {} hashes)", self.path.display(), self.len(), self.hashes)
+    }
+}
+
+impl PartialEq for BloomFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && self.hashes == other.hashes && self.len() == other.len()
+    }
+}
+
+// the 64-bit FNV-1a hash with the given offset basis
+fn fnv1a(data: &[u8], basis: u64) -> u64 {
+    data.iter().fold(basis, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
@@ -0,0 +1,426 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, traits::blocks::BlockStat};
+use log::debug;
+use multicid::Cid;
+use std::{collections::{BTreeMap, HashMap, hash_map::DefaultHasher}, hash::{Hash, Hasher}, sync::Mutex};
+
+/// The share of the capacity W-TinyLFU gives to the window of newly cached blocks, in percent
+const WINDOW_PERCENT: usize = 1;
+
+/// The number of counters in each row of the frequency sketch
+const SKETCH_WIDTH: usize = 4096;
+
+/// The number of rows in the frequency sketch
+const SKETCH_DEPTH: usize = 4;
+
+/// How a full cache picks the blocks to drop
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Eviction {
+    /// drop the least recently used blocks, good for the bursts of reads of a DAG traversal
+    #[default]
+    Lru,
+    /// drop the least frequently used blocks, ties go to the least recently used
+    Lfu,
+    /// W-TinyLFU: new blocks go into a small LRU window and only move into the main LRU cache if
+    /// they have been read more often than the blocks they would push out, weighed by size. this
+    /// keeps popular blocks cached under the random reads of web serving.
+    TinyLfu,
+}
+
+/// A cached block
+#[derive(Clone, Debug)]
+struct Entry {
+    data: Vec<u8>,
+    hits: u64,
+    tick: u64,
+    in_window: bool,
+}
+
+/// The cached blocks and their eviction order
+#[derive(Debug)]
+struct CacheState {
+    eviction: Eviction,
+    capacity: usize,
+    entries: HashMap<Vec<u8>, Entry>,
+    // the window of W-TinyLFU ordered by last use
+    window: BTreeMap<u64, Vec<u8>>,
+    window_bytes: usize,
+    // the main cache ordered by (hits, last use) for LFU or (0, last use) otherwise
+    main: BTreeMap<(u64, u64), Vec<u8>>,
+    main_bytes: usize,
+    tick: u64,
+    sketch: Sketch,
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheState {
+    fn new(eviction: Eviction, capacity: usize) -> Self {
+        CacheState {
+            eviction,
+            capacity,
+            entries: HashMap::default(),
+            window: BTreeMap::default(),
+            window_bytes: 0,
+            main: BTreeMap::default(),
+            main_bytes: 0,
+            tick: 0,
+            sketch: Sketch::default(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn window_capacity(&self) -> usize {
+        match self.eviction {
+            Eviction::TinyLfu => (self.capacity * WINDOW_PERCENT / 100).max(1),
+            _ => 0,
+        }
+    }
+
+    fn rank(&self, entry: &Entry) -> (u64, u64) {
+        match self.eviction {
+            Eviction::Lfu => (entry.hits, entry.tick),
+            _ => (0, entry.tick),
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.tick += 1;
+        self.sketch.increment(key);
+        let Some(mut entry) = self.unlink(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        entry.hits += 1;
+        entry.tick = self.tick;
+        let data = entry.data.clone();
+        self.link(key.to_vec(), entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, data: Vec<u8>) {
+        if data.len() > self.capacity || self.entries.contains_key(&key) {
+            return;
+        }
+        self.tick += 1;
+        let entry = Entry { data, hits: 0, tick: self.tick, in_window: false };
+        match self.eviction {
+            Eviction::Lru | Eviction::Lfu => self.admit(key, entry, false),
+            Eviction::TinyLfu => {
+                self.link(key, Entry { in_window: true, ..entry });
+                // blocks pushed out of the window are candidates for the main cache
+                while self.window_bytes > self.window_capacity() {
+                    let Some((_, key)) = self.window.pop_first() else { break };
+                    if let Some(mut entry) = self.entries.remove(&key) {
+                        self.window_bytes -= entry.data.len();
+                        entry.in_window = false;
+                        self.admit(key, entry, true);
+                    }
+                }
+            }
+        }
+    }
+
+    // make room in the main cache for the entry, with the admission filter the entry is dropped
+    // instead if it is read less often than the blocks it would push out
+    fn admit(&mut self, key: Vec<u8>, entry: Entry, filter: bool) {
+        let main_capacity = self.capacity - self.window_capacity();
+        if entry.data.len() > main_capacity {
+            return;
+        }
+        let mut victims = Vec::default();
+        let mut freed = 0;
+        for victim in self.main.values() {
+            if self.main_bytes - freed + entry.data.len() <= main_capacity {
+                break;
+            }
+            freed += self.entries.get(victim).map(|e| e.data.len()).unwrap_or(0);
+            victims.push(victim.clone());
+        }
+        if filter && !victims.is_empty() {
+            // weigh the frequencies by size so one big block can't push out many small ones
+            let candidate = self.sketch.estimate(&key) as usize * freed;
+            let displaced: usize = victims
+                .iter()
+                .map(|v| self.sketch.estimate(v) as usize * self.entries.get(v).map(|e| e.data.len()).unwrap_or(0))
+                .sum();
+            if candidate <= displaced {
+                return;
+            }
+        }
+        for victim in victims {
+            self.unlink(&victim);
+        }
+        self.link(key, entry);
+    }
+
+    fn link(&mut self, key: Vec<u8>, entry: Entry) {
+        if entry.in_window {
+            self.window_bytes += entry.data.len();
+            self.window.insert(entry.tick, key.clone());
+        } else {
+            self.main_bytes += entry.data.len();
+            self.main.insert(self.rank(&entry), key.clone());
+        }
+        self.entries.insert(key, entry);
+    }
+
+    fn unlink(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if entry.in_window {
+            self.window_bytes -= entry.data.len();
+            self.window.remove(&entry.tick);
+        } else {
+            self.main_bytes -= entry.data.len();
+            self.main.remove(&self.rank(&entry));
+        }
+        Some(entry)
+    }
+}
+
+/// Count-min sketch of how often blocks were read, halved periodically so it follows changes
+/// in popularity
+#[derive(Debug)]
+struct Sketch {
+    counters: Vec<u8>,
+    additions: usize,
+}
+
+impl Default for Sketch {
+    fn default() -> Self {
+        Sketch {
+            counters: vec![0; SKETCH_WIDTH * SKETCH_DEPTH],
+            additions: 0,
+        }
+    }
+}
+
+impl Sketch {
+    fn slots(key: &[u8]) -> [usize; SKETCH_DEPTH] {
+        let mut slots = [0; SKETCH_DEPTH];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+            key.hash(&mut hasher);
+            *slot = row * SKETCH_WIDTH + (hasher.finish() as usize % SKETCH_WIDTH);
+        }
+        slots
+    }
+
+    fn increment(&mut self, key: &[u8]) {
+        for slot in Self::slots(key) {
+            self.counters[slot] = self.counters[slot].saturating_add(1);
+        }
+        self.additions += 1;
+        if self.additions >= SKETCH_WIDTH * 10 {
+            self.counters.iter_mut().for_each(|c| *c /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, key: &[u8]) -> u8 {
+        Self::slots(key).iter().map(|slot| self.counters[*slot]).min().unwrap_or(0)
+    }
+}
+
+/// Blocks read through an in-memory cache of a given number of bytes. Blocks are cached when
+/// they are read and dropped from the cache when they are removed, puts go straight to the
+/// wrapped store. The eviction policy picks which blocks are dropped when the cache is full.
+#[derive(Debug)]
+pub struct CachedBlocks<B> {
+    inner: B,
+    state: Mutex<CacheState>,
+}
+
+impl<B> CachedBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    /// cache up to capacity bytes of the blocks read from the store, evicting the least recently
+    /// used blocks first
+    pub fn new(inner: B, capacity: usize) -> Self {
+        CachedBlocks {
+            inner,
+            state: Mutex::new(CacheState::new(Eviction::default(), capacity)),
+        }
+    }
+
+    /// use the eviction policy instead of LRU, this empties the cache
+    pub fn with_eviction(self, eviction: Eviction) -> Self {
+        let capacity = self.lock().capacity;
+        debug!("cached_blocks: Using {:?} eviction for {} bytes", eviction, capacity);
+        CachedBlocks {
+            inner: self.inner,
+            state: Mutex::new(CacheState::new(eviction, capacity)),
+        }
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// the number of reads served from the cache and from the store
+    pub fn stats(&self) -> (u64, u64) {
+        let state = self.lock();
+        (state.hits, state.misses)
+    }
+
+    /// the number of bytes of blocks in the cache
+    pub fn cached_bytes(&self) -> usize {
+        let state = self.lock();
+        state.window_bytes + state.main_bytes
+    }
+
+    /// drop every block from the cache
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        *state = CacheState::new(state.eviction, state.capacity);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<B> Blocks for CachedBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    type Error = B::Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        if self.lock().entries.contains_key(&key) {
+            return Ok(true);
+        }
+        self.inner.exists(cid)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        if let Some(data) = self.lock().get(&key) {
+            return Ok(data);
+        }
+        let data = self.inner.get(cid)?;
+        self.lock().insert(key, data.clone());
+        Ok(data)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        self.inner.cids()
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        self.inner.put(data, get_cid, pre_commit)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        self.inner.stat(cid)
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        self.lock().unlink(&key);
+        self.inner.rm(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::Builder;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::{fs, path::PathBuf};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    fn cached(state: &CacheState) -> Vec<u8> {
+        let mut keys: Vec<u8> = state.entries.keys().map(|k| k[0]).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        // LRU drops the block read longest ago
+        let mut lru = CacheState::new(Eviction::Lru, 30);
+        for k in [1, 2, 3] {
+            lru.insert(vec![k], vec![0; 10]);
+        }
+        lru.get(&[1]);
+        lru.get(&[1]);
+        lru.get(&[2]);
+        lru.get(&[3]);
+        lru.insert(vec![4], vec![0; 10]);
+        assert_eq!(cached(&lru), vec![2, 3, 4]);
+
+        // LFU drops the block read least often
+        let mut lfu = CacheState::new(Eviction::Lfu, 30);
+        for k in [1, 2, 3] {
+            lfu.insert(vec![k], vec![0; 10]);
+        }
+        lfu.get(&[1]);
+        lfu.get(&[1]);
+        lfu.get(&[2]);
+        lfu.get(&[3]);
+        lfu.get(&[3]);
+        lfu.insert(vec![4], vec![0; 10]);
+        assert_eq!(cached(&lfu), vec![1, 3, 4]);
+        assert_eq!(lfu.main_bytes, 30);
+
+        // W-TinyLFU keeps popular blocks when a scan of blocks read once goes by
+        let mut tiny = CacheState::new(Eviction::TinyLfu, 3000);
+        for k in [1, 2] {
+            for _ in 0..5 {
+                tiny.get(&[k]);
+            }
+            tiny.insert(vec![k], vec![0; 1000]);
+        }
+        for k in 10..40 {
+            tiny.get(&[k]);
+            tiny.insert(vec![k], vec![0; 1000]);
+        }
+        assert!(tiny.entries.contains_key([1u8].as_slice()) && tiny.entries.contains_key([2u8].as_slice()));
+        assert!(tiny.window_bytes + tiny.main_bytes <= 3000);
+    }
+
+    #[test]
+    fn test_cached_blocks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".cachedblocks1");
+
+        let inner = Builder::new(&pb).not_lazy().try_build().unwrap();
+        let mut blocks = CachedBlocks::new(inner, 1024).with_eviction(Eviction::Lfu);
+        let cid = blocks.put(&&b"for great justice!"[..], get_cid, |_| Ok(())).unwrap();
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.stats(), (1, 1));
+        assert_eq!(blocks.cached_bytes(), 18);
+
+        // prefetching fills the cache ahead of the reads
+        blocks.clear();
+        assert_eq!(blocks.prefetch(&[cid.clone()], 2), 1);
+        assert_eq!(blocks.cached_bytes(), 18);
+
+        // removing the block drops it from the cache too
+        blocks.rm(&cid).unwrap();
+        assert_eq!(blocks.cached_bytes(), 0);
+        assert!(blocks.get(&cid).is_err());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,409 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, traits::blocks::BlockStat, error::{FsStorageError, IoContext}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::{collections::BTreeMap, fs::{File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+/// The magic bytes at the start of every CasFile
+pub const MAGIC: &[u8; 8] = b"CASFILE1";
+
+/// The magic bytes at the end of a CasFile with an up to date index
+pub const TRAILER: &[u8; 8] = b"CASINDEX";
+
+/// The record tag for a stored block
+const RECORD_PUT: u8 = 1;
+
+/// The record tag for a removed block
+const RECORD_RM: u8 = 0;
+
+/// The length of the footer after the index: the index offset, the entry count and the trailer
+const FOOTER_LEN: u64 = 24;
+
+/// The open file and the in memory index
+struct CasState {
+    file: File,
+    entries: BTreeMap<Vec<u8>, (u64, u64)>,
+    // the end of the last record, the index starts here
+    end: u64,
+    // whether the index at the end of the file is up to date
+    sealed: bool,
+    // whether the file was opened for writing
+    writable: bool,
+}
+
+/// A complete block store in a single file, for shipping a content addressed dataset as one
+/// artifact. The file is the magic bytes, a record per put or rm and then an index of the live
+/// blocks. Puts and rms go over the index and a new one is written by seal(), or when the
+/// CasFile is dropped. A file whose index is missing or stale because a writer crashed is
+/// recovered by scanning the records. Opened files are read-only unless opened with
+/// open_writable() so shipped artifacts can be read from read-only media.
+pub struct CasFile {
+    path: PathBuf,
+    state: Mutex<CasState>,
+}
+
+impl CasFile {
+    /// create a new empty CasFile, replacing any file at the path
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).io_context("create", &path)?;
+        file.write_all(MAGIC).io_context("write", &path)?;
+        debug!("cas_file: Created {}", path.display());
+        Ok(CasFile {
+            path,
+            state: Mutex::new(CasState {
+                file,
+                entries: BTreeMap::default(),
+                end: MAGIC.len() as u64,
+                sealed: false,
+                writable: true,
+            }),
+        })
+    }
+
+    /// open an existing CasFile for reading. only the index at the end of the file is read, the
+    /// records are only scanned if the index is missing or stale.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_with(path, false)
+    }
+
+    /// open an existing CasFile for reading and writing
+    pub fn open_writable<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_with(path, true)
+    }
+
+    fn open_with<P: AsRef<Path>>(path: P, writable: bool) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(writable).open(&path).io_context("open", &path)?;
+        let mut magic = [0; MAGIC.len()];
+        if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(FsStorageError::InvalidValue(format!("{} is not a CasFile", path.display())).into());
+        }
+
+        let (entries, end, sealed) = match load_index(&mut file, &path)? {
+            Some((entries, end)) => (entries, end, true),
+            None => {
+                let mut data = Vec::default();
+                file.seek(SeekFrom::Start(0)).io_context("seek", &path)?;
+                file.read_to_end(&mut data).io_context("read", &path)?;
+                let (entries, end) = scan_records(&data);
+                debug!("cas_file: Recovered the index of {} from its records", path.display());
+                (entries, end, false)
+            }
+        };
+        debug!("cas_file: Opened {} with {} blocks", path.display(), entries.len());
+        Ok(CasFile {
+            path,
+            state: Mutex::new(CasState { file, entries, end, sealed, writable }),
+        })
+    }
+
+    /// the number of blocks in the file
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// whether the file has no blocks
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// write the index to the end of the file and sync it so it can be shipped
+    pub fn seal(&self) -> Result<(), Error> {
+        let mut state = self.lock();
+        if state.sealed {
+            return Ok(());
+        }
+        if !state.writable {
+            return Err(Error::Unsupported(format!("sealing {} opened read-only", self.path.display())));
+        }
+        let mut index = Vec::default();
+        for (key, (offset, len)) in &state.entries {
+            index.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            index.extend_from_slice(key);
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&len.to_le_bytes());
+        }
+        index.extend_from_slice(&state.end.to_le_bytes());
+        index.extend_from_slice(&(state.entries.len() as u64).to_le_bytes());
+        index.extend_from_slice(TRAILER);
+
+        let end = state.end;
+        state.file.seek(SeekFrom::Start(end)).io_context("seek", &self.path)?;
+        state.file.write_all(&index).io_context("write", &self.path)?;
+        state.file.set_len(end + index.len() as u64).io_context("truncate", &self.path)?;
+        state.file.sync_all().io_context("sync", &self.path)?;
+        state.sealed = true;
+        debug!("cas_file: Sealed {} with {} blocks", self.path.display(), state.entries.len());
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CasState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // append a record over the index, returning the offset of its data
+    fn append(&self, state: &mut CasState, tag: u8, key: &[u8], data: &[u8]) -> Result<u64, Error> {
+        if !state.writable {
+            return Err(Error::Unsupported(format!("writing {} opened read-only", self.path.display())));
+        }
+        let mut record = Vec::with_capacity(13 + key.len() + data.len());
+        record.push(tag);
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        record.extend_from_slice(data);
+
+        let end = state.end;
+        state.file.seek(SeekFrom::Start(end)).io_context("seek", &self.path)?;
+        if state.sealed {
+            // the old index is stale from here on
+            state.file.set_len(end).io_context("truncate", &self.path)?;
+            state.sealed = false;
+        }
+        state.file.write_all(&record).io_context("write", &self.path)?;
+        state.end += record.len() as u64;
+        Ok(end + (record.len() - data.len()) as u64)
+    }
+
+    fn read(&self, state: &mut CasState, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        state.file.seek(SeekFrom::Start(offset)).io_context("seek", &self.path)?;
+        let mut data = vec![0; len as usize];
+        state.file.read_exact(&mut data).io_context("read", &self.path)?;
+        Ok(data)
+    }
+}
+
+impl Drop for CasFile {
+    fn drop(&mut self) {
+        // a read-only file with a stale index is recovered again the next time it is opened
+        if !self.lock().writable {
+            return;
+        }
+        if let Err(e) = self.seal() {
+            debug!("cas_file: Failed to seal {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Blocks for CasFile {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        Ok(self.lock().entries.contains_key(&key))
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        let mut state = self.lock();
+        let (offset, len) = *state.entries.get(&key).ok_or_else(|| FsStorageError::NoSuchData(encode_key(&key)))?;
+        self.read(&mut state, offset, len)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        let mut cids: Vec<(String, Vec<u8>)> = self.lock().entries.keys().map(|key| (encode_key(key), key.clone())).collect();
+        cids.sort();
+        cids.iter().map(|(_, key)| Ok(Cid::try_from(key.as_slice())?)).collect()
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let cid = get_cid(data)?;
+        let key: Vec<u8> = cid.clone().into();
+        pre_commit(&cid)?;
+
+        let mut state = self.lock();
+        if state.entries.contains_key(&key) {
+            return Ok(cid);
+        }
+        let offset = self.append(&mut state, RECORD_PUT, &key, data.as_ref())?;
+        state.entries.insert(key, (offset, data.as_ref().len() as u64));
+        Ok(cid)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        let (_, len) = *self.lock().entries.get(&key).ok_or_else(|| FsStorageError::NoSuchData(encode_key(&key)))?;
+        Ok(BlockStat {
+            size: len,
+            created: None,
+            lazy_deleted: false,
+        })
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        let mut state = self.lock();
+        let (offset, len) = *state.entries.get(&key).ok_or_else(|| FsStorageError::NoSuchData(encode_key(&key)))?;
+        let data = self.read(&mut state, offset, len)?;
+        self.append(&mut state, RECORD_RM, &key, &[])?;
+        state.entries.remove(&key);
+        Ok(data)
+    }
+}
+
+fn encode_key(key: &[u8]) -> String {
+    multibase::encode(Base::Base32Z, key)
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// read the footer at the end of the file and then the index before it, None if there isn't a
+/// valid index. the records are never read.
+fn load_index(file: &mut File, path: &Path) -> Result<Option<(BTreeMap<Vec<u8>, (u64, u64)>, u64)>, Error> {
+    let len = file.metadata().io_context("stat", path)?.len();
+    let Some(footer) = len.checked_sub(FOOTER_LEN).filter(|footer| *footer >= MAGIC.len() as u64) else {
+        return Ok(None);
+    };
+    let mut buf = [0; FOOTER_LEN as usize];
+    file.seek(SeekFrom::Start(footer)).io_context("seek", path)?;
+    file.read_exact(&mut buf).io_context("read", path)?;
+    let end = match read_u64(&buf, 0) {
+        Some(end) if &buf[16..] == TRAILER && end >= MAGIC.len() as u64 && end <= footer => end,
+        _ => return Ok(None),
+    };
+    let mut tail = vec![0; (len - end) as usize];
+    file.seek(SeekFrom::Start(end)).io_context("seek", path)?;
+    file.read_exact(&mut tail).io_context("read", path)?;
+    Ok(read_index(&tail, end).map(|entries| (entries, end)))
+}
+
+/// the entries in the index given the tail of the file from the end of the records, None if
+/// there isn't a valid index
+fn read_index(tail: &[u8], end: u64) -> Option<BTreeMap<Vec<u8>, (u64, u64)>> {
+    let footer = tail.len().checked_sub(FOOTER_LEN as usize)?;
+    if &tail[footer + 16..] != TRAILER || read_u64(tail, footer)? != end {
+        return None;
+    }
+    let count = read_u64(tail, footer + 8)?;
+    let mut at = 0;
+    let mut entries = BTreeMap::default();
+    for _ in 0..count {
+        let key_len = read_u32(tail, at)? as usize;
+        let key = tail.get(at + 4..at + 4 + key_len)?.to_vec();
+        at += 4 + key_len;
+        let offset = read_u64(tail, at)?;
+        let len = read_u64(tail, at + 8)?;
+        if offset.checked_add(len)? > end {
+            return None;
+        }
+        entries.insert(key, (offset, len));
+        at += 16;
+    }
+    (at == footer).then_some(entries)
+}
+
+/// replay the records to rebuild the index, stopping at a record torn by a crash
+fn scan_records(data: &[u8]) -> (BTreeMap<Vec<u8>, (u64, u64)>, u64) {
+    let mut entries = BTreeMap::default();
+    let mut at = MAGIC.len();
+    while let Some((next, tag, key, offset, len)) = next_record(data, at) {
+        match tag {
+            RECORD_PUT => {
+                entries.insert(key, (offset as u64, len as u64));
+            }
+            RECORD_RM => {
+                entries.remove(&key);
+            }
+            _ => break,
+        }
+        at = next;
+    }
+    (entries, at as u64)
+}
+
+fn next_record(data: &[u8], at: usize) -> Option<(usize, u8, Vec<u8>, usize, usize)> {
+    let tag = *data.get(at)?;
+    let key_len = read_u32(data, at + 1)? as usize;
+    let key = data.get(at + 5..at + 5 + key_len)?.to_vec();
+    let len = usize::try_from(read_u64(data, at + 5 + key_len)?).ok()?;
+    let offset = at + 13 + key_len;
+    data.get(offset..offset.checked_add(len)?)?;
+    Some((offset + len, tag, key, offset, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::fs;
+
+    fn put(blocks: &mut CasFile, data: &[u8]) -> Cid {
+        blocks
+            .put(
+                &data,
+                |data| {
+                    let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+                    Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+                },
+                |_| Ok(()),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cas_file() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".casfile1");
+        fs::create_dir_all(&pb).unwrap();
+        let path = pb.join("dataset.cas");
+
+        let mut blocks = CasFile::create(&path).unwrap();
+        let a = put(&mut blocks, b"for great justice!");
+        let b = put(&mut blocks, b"move every zig!");
+        assert_eq!(put(&mut blocks, b"move every zig!"), b);
+        assert_eq!(blocks.rm(&a).unwrap(), b"for great justice!".to_vec());
+        let c = put(&mut blocks, b"all your base");
+        drop(blocks);
+
+        // the dropped file was sealed with an index
+        let sealed = fs::read(&path).unwrap();
+        assert!(sealed.ends_with(TRAILER));
+        let mut blocks = CasFile::open(&path).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert!(!blocks.exists(&a).unwrap());
+        assert_eq!(blocks.get(&b).unwrap(), b"move every zig!".to_vec());
+        assert_eq!(blocks.stat(&c).unwrap().size, 13);
+
+        // a file opened read-only can't be written
+        assert!(blocks.put(&b"are belong to us", |_| Ok(a.clone()), |_| Ok(())).is_err());
+        drop(blocks);
+
+        // writing after opening for writing replaces the index
+        let mut blocks = CasFile::open_writable(&path).unwrap();
+        let d = put(&mut blocks, b"are belong to us");
+        blocks.seal().unwrap();
+        drop(blocks);
+        let blocks = CasFile::open(&path).unwrap();
+        assert_eq!(blocks.get(&d).unwrap(), b"are belong to us".to_vec());
+        assert_eq!(blocks.cids().unwrap().len(), 3);
+        drop(blocks);
+
+        // a file that lost its index to a crash is recovered from the records
+        let data = fs::read(&path).unwrap();
+        let end = read_u64(&data, data.len() - FOOTER_LEN as usize).unwrap();
+        fs::write(&path, &data[..end as usize + 5]).unwrap();
+        let blocks = CasFile::open(&path).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks.get(&c).unwrap(), b"all your base".to_vec());
+        drop(blocks);
+
+        fs::write(&path, b"not a cas file").unwrap();
+        assert!(CasFile::open(&path).is_err());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
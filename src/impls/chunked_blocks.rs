@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{
+    Blocks, CidMap, Error,
+    chunker::{get_manifest, DedupReport, Manifest, Params},
+    traits::blocks::{verify, BlockStat},
+};
+use fastcdc::v2020::FastCDC;
+use log::debug;
+use multicid::{cid, Cid};
+use multicodec::Codec;
+use multihash::mh;
+use std::collections::BTreeSet;
+
+/// Blocks that are transparently split up with the chunker when they are too big. A put of
+/// data bigger than the threshold stores the content defined chunks and the manifest listing
+/// them as Raw blocks, hashed with the same hash codec as the Cid of the whole data, so every
+/// block in the inner store still hashes to its Cid. The index maps the Cid of the whole data to
+/// the Cid of its manifest and a get of that Cid reassembles the data so callers don't have to
+/// care whether it fit in one block. Removing chunked data removes its manifest and the chunks
+/// no other manifest lists.
+#[derive(Clone, Debug)]
+pub struct ChunkedBlocks<B, M> {
+    inner: B,
+    index: M,
+    threshold: usize,
+    params: Params,
+}
+
+impl<B, M> ChunkedBlocks<B, M>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+    M: CidMap<Cid, Error = B::Error>,
+{
+    /// chunk the data put into the inner store when it is bigger than the threshold in bytes,
+    /// keeping the mapping from the Cid of the data to the Cid of its manifest in the index
+    pub fn new(inner: B, index: M, threshold: usize) -> Self {
+        ChunkedBlocks {
+            inner,
+            index,
+            threshold,
+            params: Params::default(),
+        }
+    }
+
+    /// set the FastCDC parameters used to chunk the data, the largest chunk should fit in the
+    /// inner store's largest block
+    pub fn with_params(mut self, params: Params) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// the inner store
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// the index from the Cids of chunked data to the Cids of their manifests
+    pub fn index(&self) -> &M {
+        &self.index
+    }
+
+    /// report how much the chunks of the data stored under the Cids were deduplicated, data
+    /// that was stored whole is left out
+    pub fn dedup_report(&self, cids: &[Cid]) -> Result<DedupReport, B::Error> {
+        let mut manifests = Vec::default();
+        for cid in cids {
+            if let Some((_, manifest)) = self.manifest(cid)? {
+                manifests.push(manifest);
+            }
+        }
+        DedupReport::from_manifests(&self.inner, manifests)
+    }
+
+    // the Cid of the manifest of the data and the manifest, if the data was chunked
+    fn manifest(&self, cid: &Cid) -> Result<Option<(Cid, Manifest)>, B::Error> {
+        if !self.index.exists(cid)? {
+            return Ok(None);
+        }
+        let manifest_cid = self.index.get(cid)?;
+        let manifest = get_manifest(&self.inner, &manifest_cid)?;
+        Ok(Some((manifest_cid, manifest)))
+    }
+
+    // reassemble the chunks listed in the manifest
+    fn reassemble(&self, cid: &Cid, manifest: Manifest) -> Result<Vec<u8>, B::Error> {
+        let mut data = Vec::with_capacity(manifest.size as usize);
+        for chunk in &manifest.chunks {
+            data.extend_from_slice(&self.inner.get(chunk)?);
+        }
+        verify(cid, &data)?;
+        debug!("chunked_blocks: Reassembled {} chunks", manifest.chunks.len());
+        Ok(data)
+    }
+
+    // the keys of the manifests and chunks of all of the chunked data but the cid, the same
+    // data under a Cid with another target codec shares its manifest too
+    fn blocks_in_use(&self, except: &Cid) -> Result<BTreeSet<Vec<u8>>, B::Error> {
+        let mut in_use = BTreeSet::default();
+        for cid in self.index.ids()? {
+            if &cid == except {
+                continue;
+            }
+            if let Some((manifest_cid, manifest)) = self.manifest(&cid)? {
+                in_use.insert(manifest_cid.into());
+                in_use.extend(manifest.chunks.into_iter().map(Vec::<u8>::from));
+            }
+        }
+        Ok(in_use)
+    }
+}
+
+impl<B, M> Blocks for ChunkedBlocks<B, M>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+    M: CidMap<Cid, Error = B::Error>,
+{
+    type Error = B::Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        if self.index.exists(cid)? {
+            return self.inner.exists(&self.index.get(cid)?);
+        }
+        self.inner.exists(cid)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        match self.manifest(cid)? {
+            Some((_, manifest)) => self.reassemble(cid, manifest),
+            None => self.inner.get(cid),
+        }
+    }
+
+    // the blocks in the inner store and the chunked data, without the manifests
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        let chunked = self.index.ids()?;
+        let mut manifests = BTreeSet::default();
+        for cid in &chunked {
+            manifests.insert(Vec::<u8>::from(self.index.get(cid)?));
+        }
+        let mut cids: Vec<Cid> = self.inner.cids()?.into_iter().filter(|cid| !manifests.contains(&Vec::<u8>::from(cid.clone()))).collect();
+        cids.extend(chunked);
+        Ok(cids)
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let bytes = data.as_ref();
+        if bytes.len() <= self.threshold {
+            return self.inner.put(data, get_cid, pre_commit);
+        }
+        self.params.validate()?;
+
+        let cid = get_cid(data)?;
+        pre_commit(&cid)?;
+
+        // the chunks and the manifest are hashed the same way as the whole data
+        let hash = cid.hash().codec();
+        let mut manifest = Manifest {
+            size: bytes.len() as u64,
+            chunks: Vec::default(),
+        };
+        for chunk in FastCDC::new(bytes, self.params.min_size, self.params.avg_size, self.params.max_size) {
+            let chunk = &bytes[chunk.offset..chunk.offset + chunk.length];
+            let chunk_cid = self.inner.put(&chunk, |c| Ok(raw_cid(hash, c)?), |_| Ok(()))?;
+            manifest.chunks.push(chunk_cid);
+        }
+        debug!("chunked_blocks: Stored {} chunks", manifest.chunks.len());
+
+        let manifest: Vec<u8> = manifest.into();
+        let manifest_cid = self.inner.put(&manifest, |m| Ok(raw_cid(hash, m)?), |_| Ok(()))?;
+        self.index.put(&cid, &manifest_cid)?;
+        Ok(cid)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        if !self.index.exists(cid)? {
+            return self.inner.stat(cid);
+        }
+
+        // only the manifest is read, it knows the size of the whole data
+        let manifest_cid = self.index.get(cid)?;
+        let mut stat = self.inner.stat(&manifest_cid)?;
+        stat.size = get_manifest(&self.inner, &manifest_cid)?.size;
+        Ok(stat)
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let (manifest_cid, manifest) = match self.manifest(cid)? {
+            Some(found) => found,
+            None => return self.inner.rm(cid),
+        };
+        let data = self.reassemble(cid, manifest.clone())?;
+
+        // the blocks other chunked data uses stay behind
+        let in_use = self.blocks_in_use(cid)?;
+        self.index.rm(cid)?;
+        if !in_use.contains(&Vec::<u8>::from(manifest_cid.clone())) {
+            self.inner.rm(&manifest_cid)?;
+        }
+        let mut removed = BTreeSet::default();
+        for chunk in manifest.chunks {
+            let key: Vec<u8> = chunk.clone().into();
+            if !in_use.contains(&key) && removed.insert(key) {
+                self.inner.rm(&chunk)?;
+            }
+        }
+        debug!("chunked_blocks: Removed {} chunks", removed.len());
+        Ok(data)
+    }
+}
+
+// the Cid of a Raw chunk or manifest
+fn raw_cid(hash: Codec, chunk: &[u8]) -> Result<Cid, Error> {
+    let mh = mh::Builder::new_from_bytes(hash, chunk)?.try_build()?;
+    Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Raw).with_hash(&mh).try_build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunker::MANIFEST_MAGIC, fscid_map, fsblocks::Builder};
+    use rand::RngCore;
+    use std::{fs, path::PathBuf};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    #[test]
+    fn test_chunked_blocks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".chunkedblocks1");
+
+        let inner = Builder::new(pb.join("blocks")).with_max_block_size(262_144).try_build().unwrap();
+        let index = fscid_map::Builder::new(pb.join("index")).try_build().unwrap();
+        let mut blocks = ChunkedBlocks::new(inner, index, 262_144);
+
+        // small blocks are stored as is
+        let small = blocks.put(&&b"for great justice!"[..], get_cid, |_| Ok(())).unwrap();
+        assert_eq!(blocks.inner().get(&small).unwrap(), b"for great justice!".to_vec());
+
+        // big ones are split up and put back together
+        let mut data = vec![0u8; 1_000_000];
+        rand::thread_rng().fill_bytes(&mut data);
+        let big = blocks.put(&data.as_slice(), get_cid, |_| Ok(())).unwrap();
+        assert!(!blocks.inner().exists(&big).unwrap());
+        assert!(blocks.inner().cids().unwrap().len() > 2);
+        assert_eq!(blocks.get(&big).unwrap(), data);
+        assert_eq!(blocks.get_verified(&big).unwrap(), data);
+        assert_eq!(blocks.stat(&big).unwrap().size, 1_000_000);
+        assert!(blocks.cids().unwrap().contains(&big));
+
+        // the manifest is a block of its own that hashes to its Cid
+        let manifest_cid = blocks.index().get(&big).unwrap();
+        let manifest = blocks.inner().get_verified(&manifest_cid).unwrap();
+        assert!(manifest.starts_with(MANIFEST_MAGIC));
+        assert!(!blocks.cids().unwrap().contains(&manifest_cid));
+
+        // data sharing most of its chunks takes up little more room
+        let mut more = data.clone();
+        more.extend_from_slice(b"for great justice!");
+        let bigger = blocks.put(&more.as_slice(), get_cid, |_| Ok(())).unwrap();
+        let report = blocks.dedup_report(&[small.clone(), big.clone(), bigger.clone()]).unwrap();
+        assert_eq!(report.manifests, 2);
+        assert!(report.duplicate_chunks > 0);
+        assert!(report.bytes_saved() > 500_000);
+
+        // a small block that looks like a manifest is just a block
+        let cid = blocks.put(&manifest.as_slice(), get_cid, |_| Ok(())).unwrap();
+        assert_eq!(blocks.get(&cid).unwrap(), manifest);
+
+        // removing the data leaves the chunks the other data shares behind
+        let before = blocks.inner().cids().unwrap().len();
+        assert_eq!(blocks.rm(&big).unwrap(), data);
+        assert!(!blocks.exists(&big).unwrap());
+        assert!(!blocks.inner().exists(&manifest_cid).unwrap());
+        assert!(blocks.inner().cids().unwrap().len() < before);
+        assert_eq!(blocks.get(&bigger).unwrap(), more);
+
+        // and once nothing shares them they go too
+        assert_eq!(blocks.rm(&bigger).unwrap(), more);
+        assert_eq!(blocks.inner().cids().unwrap().len(), 2);
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -1,19 +1,120 @@
 // SPDX-License-Identifier: Apache-2.0
-use crate::{Blocks, Error, error::FsStorageError, fsstorage::{self, FsStorage}};
+use crate::{Blocks, Error, traits::blocks::{verify, BlockStat}, error::{FsStorageError, IoContext}, Event, fsstorage::{self, Durability, EncryptionKey, FsStorage, GcPolicy, Intent, Passphrase, Sharding}};
 use log::debug;
 use multibase::Base;
-use multicid::Cid;
-use std::{fs::{self, File}, io::{Read, Write}, path::{Path, PathBuf}};
+use multicid::{cid, Cid};
+use multicodec::Codec;
+use multihash::mh;
+use multikey::Multikey;
+use multitrait::EncodeInto;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs::{self, File}, io::{ErrorKind, Read, Write}, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+use tempfile::TempPath;
 
 /// The FsBlocks type uses CID's
 pub type FsBlocks = FsStorage<Cid>;
 
+/// The size of the buffer used when streaming a block into the store
+pub const STREAM_BUF_SIZE: usize = 65_536;
+
+/// The number of bytes put_reader streams to disk between checks of the free space reserve
+pub const HEADROOM_CHECK_BYTES: u64 = 16 * STREAM_BUF_SIZE as u64;
+
+/// The name of the folder in the root that caches the Bao outboard trees of Blake3 blocks
+#[cfg(feature = "bao")]
+pub const BAO_DIR: &str = ".bao";
+
+/// The name of the file in the root that counts the puts that were skipped because the block
+/// was already stored
+pub const DEDUP_FILE: &str = ".dedup";
+
+/// The folder of the codec index that lists blocks by hash codec
+const CODEC_INDEX_HASH: &str = "hash";
+
+/// The folder of the codec index that lists blocks by target codec
+const CODEC_INDEX_TARGET: &str = "target";
+
+/// What a put did with the block
+#[derive(Clone, Debug, PartialEq)]
+pub enum PutOutcome {
+    /// the block was written to the store
+    Stored(Cid),
+    /// the block was already in the store so nothing was written
+    AlreadyExists(Cid),
+}
+
+impl PutOutcome {
+    /// get the Cid of the block
+    pub fn cid(&self) -> &Cid {
+        match self {
+            PutOutcome::Stored(cid) | PutOutcome::AlreadyExists(cid) => cid,
+        }
+    }
+}
+
+/// Metadata kept in a small CBOR sidecar next to a block, for the things about a block that
+/// don't fit in its Cid
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BlockMeta {
+    /// when the block was first stored with metadata
+    pub created: SystemTime,
+    /// the MIME type of the block data, if known
+    pub content_type: Option<String>,
+    /// free form labels
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Default for BlockMeta {
+    fn default() -> Self {
+        BlockMeta {
+            created: SystemTime::now(),
+            content_type: None,
+            labels: BTreeMap::default(),
+        }
+    }
+}
+
+impl BlockMeta {
+    /// set the MIME type of the block data
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// add a label
+    pub fn with_label(mut self, key: &str, value: &str) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
 /// Builder for a FsBlock instance
 #[derive(Clone, Debug, Default)]
 pub struct Builder {
     root: PathBuf,
     lazy: bool,
     base_encoding: Option<Base>,
+    sharding: Sharding,
+    compression: Option<Codec>,
+    encryption_key: Option<EncryptionKey>,
+    passphrase: Option<Passphrase>,
+    #[cfg(feature = "keyring")]
+    keyring: Option<(String, String)>,
+    max_bytes: Option<u64>,
+    reserved_bytes: Option<u64>,
+    max_block_size: Option<u64>,
+    allowed_hashes: Option<Vec<Codec>>,
+    cid_config: Option<(Codec, Codec)>,
+    gc_policy: GcPolicy,
+    #[cfg(feature = "parallel")]
+    gc_threads: Option<usize>,
+    bloom: Option<(u64, f64)>,
+    durability: Durability,
+    journal: bool,
+    codec_index: bool,
+    staging_dir: Option<PathBuf>,
+    cleanup_on_open: Option<Duration>,
+    recover_on_open: bool,
 }
 
 impl Builder {
@@ -24,6 +125,27 @@ impl Builder {
             root: root.as_ref().to_path_buf(),
             lazy: true,
             base_encoding: None,
+            sharding: Sharding::default(),
+            compression: None,
+            encryption_key: None,
+            passphrase: None,
+            #[cfg(feature = "keyring")]
+            keyring: None,
+            max_bytes: None,
+            reserved_bytes: None,
+            max_block_size: None,
+            allowed_hashes: None,
+            cid_config: None,
+            gc_policy: GcPolicy::default(),
+            #[cfg(feature = "parallel")]
+            gc_threads: None,
+            bloom: None,
+            durability: Durability::default(),
+            journal: false,
+            codec_index: false,
+            staging_dir: None,
+            cleanup_on_open: None,
+            recover_on_open: false,
         }
     }
 
@@ -39,176 +161,1194 @@ impl Builder {
         self
     }
 
+    /// set how blocks are spread across subfolders, large stores want more than one level
+    pub fn with_sharding(mut self, sharding: Sharding) -> Self {
+        self.sharding = sharding;
+        self
+    }
+
+    /// set the codec used to compress blocks at rest (e.g. Codec::Zstd)
+    pub fn with_compression(mut self, codec: Codec) -> Self {
+        self.compression = Some(codec);
+        self
+    }
+
+    /// set the symmetric key used to encrypt blocks at rest
+    pub fn with_encryption_key(mut self, key: &Multikey) -> Self {
+        self.encryption_key = Some(EncryptionKey(key.clone()));
+        self
+    }
+
+    /// encrypt blocks at rest with a key derived from the passphrase, for stores on laptops and
+    /// removable media. the key derivation parameters are kept in the store's config.
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(Passphrase(passphrase.to_string()));
+        self
+    }
+
+    /// keep the encryption key in the platform keychain entry for the service and user, see
+    /// fsstorage::Builder::with_keyring()
+    #[cfg(feature = "keyring")]
+    pub fn with_keyring(mut self, service: &str, user: &str) -> Self {
+        self.keyring = Some((service.to_string(), user.to_string()));
+        self
+    }
+
+    /// set the maximum number of bytes the store may hold
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// keep at least this many bytes free on the filesystem holding the store, puts that would
+    /// eat into it fail with an Error::StoreFull before anything is written
+    pub fn with_reserved_bytes(mut self, reserved_bytes: u64) -> Self {
+        self.reserved_bytes = Some(reserved_bytes);
+        self
+    }
+
+    /// refuse blocks bigger than this many bytes, puts of them fail with an Error::BlockTooLarge
+    /// and the data should be split up with the chunker instead
+    pub fn with_max_block_size(mut self, max_block_size: u64) -> Self {
+        self.max_block_size = Some(max_block_size);
+        self
+    }
+
+    /// only accept blocks whose Cids use one of these hash codecs, e.g. Blake3 and Sha3-512, puts
+    /// of any other blocks fail with an Error::PolicyViolation
+    pub fn with_allowed_hashes(mut self, hashes: &[Codec]) -> Self {
+        self.allowed_hashes = Some(hashes.to_vec());
+        self
+    }
+
+    /// set the target and hash codecs of the Cids put_default() builds so callers don't have to
+    /// pass a closure to calculate them
+    pub fn with_cid_config(mut self, target_codec: Codec, hash_codec: Codec) -> Self {
+        self.cid_config = Some((target_codec, hash_codec));
+        self
+    }
+
+    /// set which lazy deleted blocks gc() removes, e.g. keep them for a day so they can be restored
+    pub fn with_gc_policy(mut self, policy: GcPolicy) -> Self {
+        self.gc_policy = policy;
+        self
+    }
+
+    /// limit the number of threads gc() uses
+    #[cfg(feature = "parallel")]
+    pub fn with_gc_threads(mut self, threads: usize) -> Self {
+        self.gc_threads = Some(threads);
+        self
+    }
+
+    /// keep a bloom filter so that exists() can answer for missing blocks without touching the
+    /// filesystem. size it for the expected number of blocks and the acceptable false positive
+    /// rate (e.g. 0.01). only use it when this is the only process writing to the store.
+    pub fn with_bloom_filter(mut self, expected_items: u64, false_positive_rate: f64) -> Self {
+        self.bloom = Some((expected_items, false_positive_rate));
+        self
+    }
+
+    /// set how hard puts try to make sure stored blocks survive a crash. the default leaves
+    /// write back to the OS, Durability::SyncData is what most databases use.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// record each put and rm in a journal so that the ones a crash interrupts can be finished or
+    /// rolled back by recover()
+    pub fn with_journal(mut self) -> Self {
+        self.journal = true;
+        self
+    }
+
+    /// maintain an index of the blocks by hash and target codec so cids_by_hash() and
+    /// cids_by_target_codec() work
+    pub fn with_codec_index(mut self) -> Self {
+        self.codec_index = true;
+        self
+    }
+
+    /// write blocks to temp files in the directory before moving them into the store, the
+    /// directory may be on another filesystem
+    pub fn with_staging_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.staging_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// remove the temp and lock files older than min_age that crashed writers left behind when
+    /// the store is opened
+    pub fn with_cleanup_on_open(mut self, min_age: Duration) -> Self {
+        self.cleanup_on_open = Some(min_age);
+        self
+    }
+
+    /// finish or roll back the puts and rms a crash interrupted when the store is opened, see
+    /// fsstorage::Builder::with_recover_on_open()
+    pub fn with_recover_on_open(mut self) -> Self {
+        self.recover_on_open = true;
+        self
+    }
+
     /// build the instance
     pub fn try_build(&self) -> Result<FsBlocks, Error> {
         let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
 
-        let mut builder = fsstorage::Builder::<Cid>::new(&self.root).with_base_encoding(base_encoding);
+        let mut builder = fsstorage::Builder::<Cid>::new(&self.root)
+            .with_base_encoding(base_encoding)
+            .with_sharding(self.sharding)
+            .with_gc_policy(self.gc_policy)
+            .with_durability(self.durability);
         if !self.lazy {
             builder = builder.not_lazy();
         }
+        if let Some(codec) = self.compression {
+            builder = builder.with_compression(codec);
+        }
+        if let Some(key) = &self.encryption_key {
+            builder = builder.with_encryption_key(&key.0);
+        }
+        if let Some(passphrase) = &self.passphrase {
+            builder = builder.with_passphrase(&passphrase.0);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some((service, user)) = &self.keyring {
+            builder = builder.with_keyring(service, user);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            builder = builder.with_max_bytes(max_bytes);
+        }
+        if let Some(reserved_bytes) = self.reserved_bytes {
+            builder = builder.with_reserved_bytes(reserved_bytes);
+        }
+        if let Some(max_block_size) = self.max_block_size {
+            builder = builder.with_max_block_size(max_block_size);
+        }
+        if let Some(hashes) = &self.allowed_hashes {
+            builder = builder.with_allowed_hashes(hashes);
+        }
+        if let Some((target_codec, hash_codec)) = self.cid_config {
+            builder = builder.with_cid_config(target_codec, hash_codec);
+        }
+        #[cfg(feature = "parallel")]
+        if let Some(threads) = self.gc_threads {
+            builder = builder.with_gc_threads(threads);
+        }
+        if let Some((expected_items, false_positive_rate)) = self.bloom {
+            builder = builder.with_bloom_filter(expected_items, false_positive_rate);
+        }
+        if self.journal {
+            builder = builder.with_journal();
+        }
+        if self.codec_index {
+            builder = builder.with_codec_index();
+        }
+        if let Some(dir) = &self.staging_dir {
+            builder = builder.with_staging_dir(dir);
+        }
+        if let Some(min_age) = self.cleanup_on_open {
+            builder = builder.with_cleanup_on_open(min_age);
+        }
+        if self.recover_on_open {
+            builder = builder.with_recover_on_open();
+        }
 
         builder.try_build()
     }
 }
 
-impl Blocks for FsBlocks {
-    type Error = Error;
+impl FsBlocks {
+    /// get the number of blocks stored, lazy deleted blocks aren't counted. this reads a persisted
+    /// counter that is maintained by put and rm and rebuilt by walking the store if it is missing.
+    pub fn len(&self) -> Result<u64, Error> {
+        let _counters = self.lock_counters()?;
+        Ok(self.usage()?.1)
+    }
 
-    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
-        // get the paths
-        let (_, _, file, _) = self.get_paths(cid)?;
-        Ok(file.try_exists()?)
+    /// check if there are no blocks stored
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
     }
 
-    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
-        // get the paths
+    /// add a block by hard linking its file from another store on the same filesystem instead of
+    /// copying it. both stores must store blocks the same way, with the same compression and
+    /// encryption key, since the linked file is shared as is.
+    pub fn link_from(&mut self, other: &FsBlocks, cid: &Cid) -> Result<(), Error> {
+        if self.compression != other.compression || self.encryption_key != other.encryption_key {
+            return Err(FsStorageError::ConfigMismatch(
+                "linked stores must use the same compression and encryption".to_string()
+            ).into());
+        }
+
+        self.check_hash(cid)?;
         let (ecid, subfolder, file, _) = self.get_paths(cid)?;
+        let (_, _, src, _) = other.get_paths(cid)?;
+        if !src.try_exists().io_context_id("stat", &ecid, &src)? {
+            return Err(FsStorageError::NoSuchData(ecid.to_string()).into());
+        }
+        if file.try_exists().io_context_id("stat", &ecid, &file)? {
+            return Ok(());
+        }
 
-        // check if it exists and is a dir...otherwise create the dir
-        if subfolder.try_exists()? {
-            if !subfolder.is_dir() {
-                return Err(FsStorageError::NotDir(subfolder).into());
+        // enforce the quota, if any. the file only holds the block as is when it isn't packed
+        let size = fs::metadata(&src).io_context_id("stat", &ecid, &src)?.len();
+        if self.compression.is_none() && self.encryption_key.is_none() {
+            self.check_block_size(size)?;
+        }
+        {
+            let _counters = self.lock_counters()?;
+            let (used, count) = self.usage()?;
+            if let Some(max) = self.max_bytes {
+                if used + size > max {
+                    return Err(Error::QuotaExceeded { used, limit: max, needed: size });
+                }
             }
-        } else {
+
+            fs::create_dir_all(&subfolder).io_context_id("create dir", &ecid, &subfolder)?;
+            self.record_name(cid)?;
+            self.bloom_insert(cid)?;
+            match fs::hard_link(&src, &file) {
+                Ok(()) => {}
+                // another writer linked or put it first
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(()),
+                Err(e) => return Err(e).io_context_id("link", &ecid, &file),
+            }
+            debug!("fsblocks: Linked block from: {} to {}", src.display(), file.display());
+            self.write_usage(used + size, count + 1)?;
+        }
+
+        self.committed(cid)
+    }
+
+    /// watch the store for blocks added or removed by any writer, including other processes and
+    /// tools like rsync that drop files into the subfolders. a BlockPut event is sent when a
+    /// block file is created or renamed into place and a BlockRemoved event when one is removed
+    /// or lazy deleted. blocks should be renamed into place so they are complete when the event
+    /// arrives. the events stop when the watch is dropped.
+    #[cfg(feature = "watch")]
+    pub fn watch(&self) -> Result<crate::Watch<Event<Cid>>, Error> {
+        use notify::{event::ModifyKind, EventKind, RecursiveMode, Watcher};
+
+        // the watcher is owned by the returned watch, dropping it closes the events channel and
+        // ends the forwarding thread even if no change ever arrives
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(events_tx).map_err(|e| Error::Wrapped(Box::new(e)))?;
+        watcher.watch(&self.root, RecursiveMode::Recursive).map_err(|e| Error::Wrapped(Box::new(e)))?;
+        debug!("fsblocks: Watching {}", self.root.display());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let blocks = self.clone();
+        std::thread::spawn(move || {
+            for event in events_rx {
+                let Ok(event) = event else {
+                    continue;
+                };
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)) | EventKind::Remove(_)) {
+                    continue;
+                }
+                for path in &event.paths {
+                    // only block files in the subfolders, dot files are temp files, tombstones
+                    // and the bookkeeping folders in the root
+                    let Ok(rel) = path.strip_prefix(&blocks.root) else {
+                        continue;
+                    };
+                    if rel.components().count() < 2 || rel.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.')) {
+                        continue;
+                    }
+                    let Some(cid) = path.file_name().and_then(|name| blocks.decode_id(&name.to_string_lossy())) else {
+                        continue;
+                    };
+                    let event = if path.is_file() { Event::BlockPut(cid) } else { Event::BlockRemoved(cid) };
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(crate::Watch::new(rx, watcher))
+    }
+
+    /// store the file or directory at the path as a UnixFS DAG the same way `ipfs add` does and
+    /// return the root Cid
+    #[cfg(feature = "unixfs")]
+    pub fn add_path<P: AsRef<Path>>(&mut self, path: P) -> Result<Cid, Error> {
+        crate::unixfs::add_path(self, path)
+    }
+
+    /// write the UnixFS file or directory with the root Cid out to the destination path
+    #[cfg(feature = "unixfs")]
+    pub fn export_to_path<P: AsRef<Path>>(&self, root: &Cid, dest: P) -> Result<(), Error> {
+        crate::unixfs::export_to_path(self, root, dest)
+    }
+
+    /// get a reader over the contents of the UnixFS file with the root Cid
+    #[cfg(feature = "unixfs")]
+    pub fn cat(&self, root: &Cid) -> crate::unixfs::Cat<'_, FsBlocks> {
+        crate::unixfs::cat(self, root)
+    }
+
+    /// get a reader over a Blake3 block that verifies each chunk group against the Cid as it is
+    /// read, failing with an InvalidData error at the first corrupted chunk instead of only after
+    /// the whole block has been read. the Bao outboard tree this needs is built with one full
+    /// verified pass the first time a block is streamed and cached under .bao after that. the
+    /// stream is always keyed with the digest in the Cid so a bad cached tree can't verify.
+    #[cfg(feature = "bao")]
+    pub fn get_verified_stream(&self, cid: &Cid) -> Result<impl Read, Error> {
+        if cid.hash().codec() != Codec::Blake3 {
+            return Err(Error::Unsupported(format!("verified streams of {:?} hashed blocks", cid.hash().codec())));
+        }
+        if self.compression.is_some() || self.encryption_key.is_some() {
+            return Err(Error::Unsupported("verified streams from a compressed or encrypted store".to_string()));
+        }
+
+        let (ecid, _, file, _) = self.get_paths(cid)?;
+        if !file.try_exists().io_context_id("stat", &ecid, &file)? {
             return Err(FsStorageError::NoSuchData(ecid.to_string()).into());
         }
 
-        // store the block in the filesystem
-        debug!("fsblocks: Getting block from: {}", file.display());
-        let mut f = File::open(&file)?;
-        let mut data = Vec::default();
-        f.read_to_end(&mut data)?;
-        Ok(data)
+        // the Blake3 digest is what follows the codec and length in the encoded multihash
+        let mh: Vec<u8> = cid.hash().clone().into();
+        let mut prefix = Codec::Blake3.encode_into();
+        prefix.append(&mut 32u64.encode_into());
+        let hash = match <[u8; 32]>::try_from(&mh[prefix.len().min(mh.len())..]) {
+            Ok(hash) if mh.starts_with(&prefix) => bao::Hash::from(hash),
+            _ => return Err(Error::Unsupported("verified streams of Blake3 hashes that aren't 32 bytes".to_string())),
+        };
+
+        // the cache holds just the outboard tree, one of the wrong size is left from an older
+        // version or a torn write and is rebuilt
+        let dir = self.root.join(BAO_DIR);
+        let cache = dir.join(ecid.to_string());
+        let len = fs::metadata(&file).io_context_id("stat", &ecid, &file)?.len();
+        let cached = match fs::metadata(&cache) {
+            Ok(m) => m.len() as u128 == bao::encode::outboard_size(len),
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(e).io_context_id("stat", &ecid, &cache),
+        };
+        if !cached {
+            // build the tree in one streaming pass over the block and check its root
+            fs::create_dir_all(&dir).io_context_id("create dir", &ecid, &dir)?;
+            let temp = tempfile::Builder::new().tempfile_in(&dir).io_context_id("create temp file in", &ecid, &dir)?;
+            let root = {
+                let mut encoder = bao::encode::Encoder::new_outboard(temp.as_file());
+                let mut f = File::open(&file).io_context_id("open", &ecid, &file)?;
+                std::io::copy(&mut f, &mut encoder).io_context_id("read", &ecid, &file)?;
+                encoder.finalize().io_context_id("write", &ecid, temp.path())?
+            };
+            if root != hash {
+                return Err(FsStorageError::CorruptBlock(ecid.to_string()).into());
+            }
+            temp.persist(&cache)?;
+            debug!("fsblocks: Cached Bao outboard at: {}", cache.display());
+        }
+
+        let outboard = std::io::BufReader::new(File::open(&cache).io_context_id("open", &ecid, &cache)?);
+        let f = File::open(&file).io_context_id("open", &ecid, &file)?;
+        Ok(bao::decode::Decoder::new_outboard(f, outboard, &hash))
     }
 
-    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    /// map a block into memory instead of reading it into a Vec. blocks stored compressed or
+    /// encrypted can't be mapped since the bytes on disk aren't the block.
+    #[cfg(feature = "mmap")]
+    pub fn get_mmap(&self, cid: &Cid) -> Result<memmap2::Mmap, Error> {
+        if self.compression.is_some() || self.encryption_key.is_some() {
+            return Err(Error::Unsupported("get_mmap on a compressed or encrypted store".to_string()));
+        }
+
+        let (ecid, _, file, _) = self.get_paths(cid)?;
+        if !file.try_exists().io_context_id("stat", &ecid, &file)? {
+            return Err(FsStorageError::NoSuchData(ecid.to_string()).into());
+        }
+        debug!("fsblocks: Mapping block from: {}", file.display());
+        let f = File::open(&file).io_context_id("open", &ecid, &file)?;
+
+        // SAFETY: blocks are immutable once stored, put only ever replaces the file by renaming a
+        // new one over it so the mapped file is never modified in place
+        let mmap = unsafe { memmap2::Mmap::map(&f) }.io_context_id("map", &ecid, &file)?;
+        Ok(mmap)
+    }
+
+    /// get the total number of bytes the stored blocks take up on disk, after any compression and
+    /// encryption. this reads the same persisted counter as len().
+    pub fn total_bytes(&self) -> Result<u64, Error> {
+        self.stored_bytes()
+    }
+
+    /// Try to put a block into storage that expires after the given ttl. Expired blocks are
+    /// removed by the next call to gc(). Putting the same block again without a ttl clears the
+    /// expiry.
+    pub fn put_with_ttl<D, F1, F2>(&mut self, data: &D, ttl: Duration, get_cid: F1, pre_commit: F2) -> Result<Cid, Error>
     where
         D: AsRef<[u8]>,
-        F1: Fn(&D) -> Result<Cid, Self::Error>,
-        F2: Fn(&Cid) -> Result<(), Self::Error>,
+        F1: Fn(&D) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+    {
+        let expires = SystemTime::now()
+            .checked_add(ttl)
+            .ok_or_else(|| Error::from(FsStorageError::InvalidTtl(ttl.as_secs())))?;
+        let cid = self.put(data, get_cid, pre_commit)?;
+        self.set_expiry(&cid, expires)?;
+        debug!("fsblocks: Block expires in {}s", ttl.as_secs());
+        Ok(cid)
+    }
+
+    /// Try to put a block into storage, building its Cid from the target and hash codecs set
+    /// with Builder::with_cid_config()
+    pub fn put_default<D: AsRef<[u8]>>(&mut self, data: &D) -> Result<Cid, Error> {
+        let (Some(target_codec), Some(hash_codec)) = (self.target_codec, self.hash_codec) else {
+            return Err(FsStorageError::InvalidConfig("put_default needs a cid config".to_string()).into());
+        };
+        self.put(data, |data| {
+            let mh = mh::Builder::new_from_bytes(hash_codec, data.as_ref())?.try_build()?;
+            Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(target_codec).with_hash(&mh).try_build()?)
+        }, |_| Ok(()))
+    }
+
+    /// Try to put a block into storage like put does and report whether it was written. A block
+    /// that is already stored isn't rewritten, the skipped puts are counted by dedup_stats().
+    pub fn put_outcome<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<PutOutcome, Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+    {
+        self.put_committed(data, get_cid, pre_commit, |_, _| Ok(()))
+    }
+
+    /// Try to put a block into storage like put does and then call the post_commit closure with
+    /// the Cid and the path of the block file once the block is durable, so callers can update
+    /// their indexes only after the data is safely stored. It is called for blocks that were
+    /// already stored too. If it fails the block stays stored and its error is returned.
+    pub fn put_with_post_commit<D, F1, F2, F3>(&mut self, data: &D, get_cid: F1, pre_commit: F2, post_commit: F3) -> Result<Cid, Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+        F3: Fn(&Cid, &Path) -> Result<(), Error>,
+    {
+        self.put_committed(data, get_cid, pre_commit, post_commit).map(|outcome| outcome.cid().clone())
+    }
+
+    /// the put behind every put method, it only takes &self so shared handles can call it
+    pub(crate) fn put_committed<D, F1, F2, F3>(&self, data: &D, get_cid: F1, pre_commit: F2, post_commit: F3) -> Result<PutOutcome, Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+        F3: Fn(&Cid, &Path) -> Result<(), Error>,
     {
+        // refuse oversized blocks before spending time hashing them
+        self.check_block_size(data.as_ref().len() as u64)?;
+
         // call the callback for calculating the CID
         let cid = get_cid(data)?;
+        self.check_hash(&cid)?;
 
         // get the paths
         let (ecid, subfolder, file, _) = self.get_paths(&cid)?;
+        record!("cid" = &ecid, "path" = file.display(), "len" = data.as_ref().len());
 
-        // check if it exists and is a dir...otherwise create the dir
-        if subfolder.try_exists()? {
-            if !subfolder.is_dir() {
-                return Err(FsStorageError::NotDir(subfolder).into());
-            }
-        } else {
-            fs::create_dir_all(&subfolder)?;
-            debug!("fsblocks: Created subfolder at: {}", subfolder.display());
+        // identical content is never written twice, the pre_commit closure is still called so
+        // callers see the same side effects either way
+        if file.try_exists().io_context_id("stat", &ecid, &file)? {
+            pre_commit(&cid)?;
+            self.clear_expiry(&cid)?;
+            self.count_dedup(data.as_ref().len() as u64)?;
+            debug!("fsblocks: Block already stored at: {}", file.display());
+            post_commit(&cid, &file)?;
+            return Ok(PutOutcome::AlreadyExists(cid));
         }
 
+        // check if it exists and is a dir...otherwise create the dir
+        self.create_subfolder(&subfolder)?;
+
         // store the block in the filesystem
         debug!("fsblocks: Storing block at: {}", file.display());
 
+        // compress and encrypt the contents if configured to
+        let packed = self.pack(&self.aad(&cid)?, data.as_ref())?;
+        self.check_headroom(packed.len() as u64)?;
+
         // securely create a temporary file. its name begins with "." so that if something goes
         // wrong, the temporary file will be cleaned up by a future GC pass
+        let staging = self.staging(&subfolder);
         let mut temp = tempfile::Builder::new()
             .suffix(&format!(".{}", ecid))
-            .tempfile_in(&subfolder)?;
+            .tempfile_in(staging).io_context_id("create temp file in", &ecid, staging)?;
 
         // write the contents to the file
-        temp.write_all(data.as_ref())?;
+        temp.write_all(&packed).io_context_id("write", &ecid, temp.path())?;
 
         // call the pre_commit closure to give the caller a chance to do other side effects
         pre_commit(&cid)?;
 
-        // atomically rename/move it to the correct location
-        temp.persist(&file)?;
+        // atomically rename/move it to the correct location and update the usage counters
+        self.sync_file(temp.as_file(), temp.path())?;
+        self.commit_block(&cid, temp.into_temp_path(), &file)?;
 
-        Ok(cid)
+        // the block is durable so the caller can point at it
+        post_commit(&cid, &file)?;
+
+        Ok(PutOutcome::Stored(cid))
     }
 
-    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
-        // first try to get the value
-        let v = self.get(cid)?;
+    /// Try to put many blocks at once on the global rayon pool, with at most threads puts at a
+    /// time or one per thread of the pool if threads is 0, and return their Cids in the order of
+    /// the items. Each block goes through the same put as put() so the hashing, compression,
+    /// encryption and writing run in parallel while the usage counters are updated under the
+    /// counters lock. The pre_commit closures aren't supported, if a put fails the blocks already
+    /// committed are kept and its error is returned.
+    #[cfg(feature = "parallel")]
+    pub fn put_many_par<D, F>(&mut self, items: &[D], threads: usize, get_cid: F) -> Result<Vec<Cid>, Error>
+    where
+        D: AsRef<[u8]> + Sync,
+        F: Fn(&D) -> Result<Cid, Error> + Sync,
+    {
+        use rayon::prelude::*;
 
-        // get the paths
-        let (_, subfolder, file, lazy_deleted_file) = self.get_paths(cid)?;
-
-        // remove the file if it exists
-        if file.try_exists()? && file.is_file() {
-            if self.lazy {
-                // rename the file instead of remove it
-                fs::rename(&file, &lazy_deleted_file)?;
-                debug!("fsblocks: Lazy deleted block at: {} to {}", file.display(), lazy_deleted_file.display());
-            } else {
-                // not lazy so delete it
-                fs::remove_file(&file)?;
-                debug!("fsblocks: Removed block at: {}", file.display());
-            }
-        }
+        // one chunk of the items per thread, each chunk is put in order
+        let threads = if threads == 0 { rayon::current_num_threads() } else { threads };
+        let blocks: &FsBlocks = self;
+        let chunks: Vec<Vec<Cid>> = items
+            .par_chunks(items.len().div_ceil(threads).max(1))
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .map(|item| blocks.put_committed(item, &get_cid, |_| Ok(()), |_, _| Ok(())).map(|outcome| outcome.cid().clone()))
+                    .collect::<Result<Vec<Cid>, Error>>()
+            })
+            .collect::<Result<_, Error>>()?;
+        debug!("fsblocks: Put {} blocks in parallel", items.len());
+        Ok(chunks.into_iter().flatten().collect())
+    }
+
+    /// stage the block as part of the named transaction, the first phase of a two-phase commit
+    /// across stores. the block is checked like a put: its size, the hash codec of its Cid, that
+    /// the data hashes to the Cid, the pre_commit closure, the quota and the free space reserve.
+    /// nothing is visible until commit() moves it into place, abort() drops it instead.
+    pub fn prepare_block<D, F1, F2>(&self, txn: &str, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+    {
+        self.check_block_size(data.as_ref().len() as u64)?;
+        let cid = get_cid(data)?;
+        self.check_hash(&cid)?;
+        verify(&cid, data.as_ref())?;
 
-        // remove the subfolder if it is emtpy and we're not lazy
-        if subfolder.try_exists()? && subfolder.is_dir() && fs::read_dir(&subfolder)?.count() == 0 && !self.lazy {
-            fs::remove_dir(&subfolder)?;
-            debug!("fsblocks: Removed subdir at: {}", subfolder.display());
+        // the quota is checked now so that committing the transaction doesn't fail on it
+        let (_, _, file, _) = self.get_paths(&cid)?;
+        let packed = self.pack(&self.aad(&cid)?, data.as_ref())?;
+        {
+            let _counters = self.lock_counters()?;
+            self.reserve(&file, packed.len() as u64)?;
         }
 
-        Ok(v)
+        pre_commit(&cid)?;
+        self.stage(txn, &cid, &packed)?;
+        Ok(cid)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use multicid::cid;
-    use multicodec::Codec;
-    use multihash::mh;
+    /// move the blocks staged by prepare_block() for the transaction into place and return how
+    /// many there were, the second phase of a two-phase commit. like a put each block is made
+    /// permanent, indexed and the observers are notified.
+    pub fn commit(&self, txn: &str) -> Result<usize, Error> {
+        self.commit_staged(txn, |cid| self.committed(cid))
+    }
 
-    #[test]
-    fn test_builder_lazy() {
-        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        pb.push(".fsblocks1");
+    // fail with an Error::PolicyViolation if the Cid uses a hash codec the store doesn't allow
+    fn check_hash(&self, cid: &Cid) -> Result<(), Error> {
+        match &self.allowed_hashes {
+            Some(allowed) if !allowed.contains(&cid.hash().codec()) => {
+                Err(Error::PolicyViolation(format!("{:?} hashes aren't allowed", cid.hash().codec())))
+            }
+            _ => Ok(()),
+        }
+    }
 
-        let blocks = Builder::new(&pb).try_build().unwrap();
-        assert_eq!(blocks.root, pb);
-        assert_eq!(blocks.lazy, true);
-        assert_eq!(blocks.base_encoding, Base::Base32Z);
-        assert!(pb.try_exists().is_ok());
-        assert!(pb.is_dir());
+    /// get the number of bytes and the number of puts that were skipped because the block was
+    /// already stored
+    pub fn dedup_stats(&self) -> Result<(u64, u64), Error> {
+        let _counters = self.lock_counters()?;
+        self.read_dedup()
+    }
 
-        assert!(fs::remove_dir_all(&pb).is_ok());
+    // read the dedup counters, the caller must hold the counters lock. the skipped puts leave
+    // nothing behind to count them again so counters that don't parse start over from zero.
+    fn read_dedup(&self) -> Result<(u64, u64), Error> {
+        let file = self.root.join(DEDUP_FILE);
+        match fsstorage::read_counters(&file)? {
+            Some(counters) => Ok(counters),
+            None => {
+                if file.try_exists().io_context("stat", &file)? {
+                    debug!("fsblocks: Resetting the unreadable dedup counters at: {}", file.display());
+                    self.write_counters(&file, 0, 0)?;
+                }
+                Ok((0, 0))
+            }
+        }
     }
 
-    #[test]
-    fn test_builder_not_lazy() {
-        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        pb.push(".fsblocks2");
+    // add a skipped put to the dedup counters
+    fn count_dedup(&self, bytes: u64) -> Result<(), Error> {
+        let _counters = self.lock_counters()?;
+        let (total, count) = self.read_dedup()?;
+        self.write_counters(&self.root.join(DEDUP_FILE), total + bytes, count + 1)
+    }
 
-        let blocks = Builder::new(&pb).not_lazy().try_build().unwrap();
-        assert_eq!(blocks.root, pb);
-        assert_eq!(blocks.lazy, false);
-        assert_eq!(blocks.base_encoding, Base::Base32Z);
-        assert!(pb.try_exists().is_ok());
-        assert!(pb.is_dir());
+    /// Try to put a block into storage along with its metadata. The metadata of a block that is
+    /// already stored is replaced.
+    pub fn put_with_meta<D, F1, F2>(&mut self, data: &D, meta: &BlockMeta, get_cid: F1, pre_commit: F2) -> Result<Cid, Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+    {
+        let cid = self.put(data, get_cid, pre_commit)?;
+        self.set_meta(&cid, meta)?;
+        Ok(cid)
+    }
 
-        for d in fs::read_dir(&pb).unwrap() {
-            assert!(d.is_ok());
-            let dir = d.unwrap();
-            assert!(dir.file_type().unwrap().is_dir());
+    /// set the metadata of a stored block
+    pub fn set_meta(&self, cid: &Cid, meta: &BlockMeta) -> Result<(), Error> {
+        if !self.exists(cid)? {
+            let (ecid, _, _, _) = self.get_paths(cid)?;
+            return Err(FsStorageError::NoSuchData(ecid.to_string()).into());
         }
+        let file = self.meta_file(cid)?;
+        let dir = file.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir).io_context("create dir", dir)?;
 
-        assert!(fs::remove_dir_all(&pb).is_ok());
+        // the sidecar is packed like the block so an encrypted store doesn't leak it
+        let data = self.pack(&self.aad(cid)?, &serde_cbor::to_vec(meta).map_err(|e| Error::Wrapped(Box::new(e)))?)?;
+        let mut temp = tempfile::Builder::new().tempfile_in(dir).io_context("create temp file in", dir)?;
+        temp.write_all(&data).io_context("write", temp.path())?;
+        temp.persist(&file)?;
+        debug!("fsblocks: Stored metadata for block");
+        Ok(())
     }
 
-    fn put(blocks: &mut FsBlocks, v: impl AsRef<[u8]>) -> Cid {
-        let cid = blocks.put(&v, |data| -> Result<Cid, Error> {
-            let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?
-                .try_build()?;
-            let cid = cid::Builder::new(Codec::Cidv1)
-                .with_target_codec(Codec::Identity)
-                .with_hash(&mh)
-                .try_build()?;
-            Ok(cid)
-        }, |_| Ok(())).unwrap();
-        cid
+    /// get the metadata of a block, None if it was stored without any
+    pub fn meta(&self, cid: &Cid) -> Result<Option<BlockMeta>, Error> {
+        let file = self.meta_file(cid)?;
+        let data = match fs::read(&file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).io_context("read", &file),
+        };
+        Ok(Some(serde_cbor::from_slice(&self.unpack(&self.aad(cid)?, data)?).map_err(|e| Error::Wrapped(Box::new(e)))?))
+    }
+
+    fn meta_file(&self, cid: &Cid) -> Result<PathBuf, Error> {
+        let (ecid, subfolder, _, _) = self.get_paths(cid)?;
+        Ok(fsstorage::meta_file(&self.root, &subfolder, &ecid.to_string()))
+    }
+
+    /// put an existing file as a block without reading it into memory. the file is copied first
+    /// and the get_cid closure is given the path of the copy so that it can hash it however it
+    /// likes, the Cid is always that of the bytes stored even if the file changes meanwhile. with
+    /// the reflink feature the file is cloned copy-on-write on filesystems that support it (btrfs,
+    /// XFS, APFS), otherwise it is copied with fs::copy which uses copy_file_range where it can.
+    /// compressed or encrypted stores have to transform the data so they fall back to reading the
+    /// copy and calling put.
+    pub fn put_path<P, F>(&mut self, path: P, get_cid: F) -> Result<Cid, Error>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Path) -> Result<Cid, Error>,
+    {
+        let path = path.as_ref();
+        let size = fs::metadata(path).io_context("stat", path)?.len();
+        self.check_block_size(size)?;
+        self.check_headroom(size)?;
+
+        // the Cid isn't known until the copy is hashed so it starts out in the root, or the
+        // staging dir, under a temporary name that gc() cleans up
+        let staging = self.staging(&self.root);
+        let temp = tempfile::Builder::new()
+            .tempfile_in(staging).io_context("create temp file in", staging)?
+            .into_temp_path();
+        // the copy keeps the permissions of the file, a read-only one must not make the block so
+        let permissions = fs::metadata(&temp).io_context("stat", &temp)?.permissions();
+        clone_file(path, &temp)?;
+        fs::set_permissions(&temp, permissions).io_context("set permissions", &temp)?;
+        self.check_block_size(fs::metadata(&temp).io_context("stat", &temp)?.len())?;
+
+        let cid = get_cid(&temp)?;
+        self.check_hash(&cid)?;
+        if self.compression.is_some() || self.encryption_key.is_some() {
+            let data = fs::read(&temp).io_context("read", &temp)?;
+            return self.put(&data, |_| Ok(cid.clone()), |_| Ok(()));
+        }
+
+        let (ecid, subfolder, file, _) = self.get_paths(&cid)?;
+        self.create_subfolder(&subfolder)?;
+        self.sync_file(&File::open(&temp).io_context_id("open", &ecid, &temp)?, &temp)?;
+        debug!("fsblocks: Storing block from: {} at: {}", path.display(), file.display());
+        self.commit_block(&cid, temp, &file)?;
+        Ok(cid)
+    }
+
+    /// put the data from the reader, hashing it while it is written to a temporary file so that
+    /// large payloads are read once and never held in memory. the get_cid closure is given the
+    /// digest context with the hash codec once all of the data has been read and builds the Cid
+    /// from it. compressed or encrypted stores transform the whole block so they still buffer the
+    /// data, but it is only hashed once. the Cid has to carry the multihash of the digest.
+    #[cfg(feature = "digest")]
+    pub fn put_reader<R, F1, F2>(&mut self, mut reader: R, hash: Codec, get_cid: F1, pre_commit: F2) -> Result<Cid, Error>
+    where
+        R: Read,
+        F1: Fn(&crate::digest::Digest) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+    {
+        let mut digest = crate::digest::Digest::new(hash)?;
+        let packed = self.compression.is_some() || self.encryption_key.is_some();
+
+        // the size isn't known up front so the free space reserve is checked before anything is
+        // written and again every HEADROOM_CHECK_BYTES while the data streams in
+        self.check_headroom(0)?;
+        let mut next_check = HEADROOM_CHECK_BYTES;
+
+        // the Cid isn't known until the data has been read so the temporary file starts out in
+        // the root, or the staging dir, and is moved into its subfolder once the block is committed
+        let staging = self.staging(&self.root);
+        let mut temp = tempfile::Builder::new()
+            .tempfile_in(staging).io_context("create temp file in", staging)?;
+        let mut data = Vec::default();
+        let mut buf = vec![0; STREAM_BUF_SIZE];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).io_context("read", temp.path()),
+            };
+            digest.update(&buf[..n]);
+            self.check_block_size(digest.len())?;
+            if packed {
+                data.extend_from_slice(&buf[..n]);
+            } else {
+                if digest.len() >= next_check {
+                    self.check_headroom(n as u64)?;
+                    next_check = digest.len() + HEADROOM_CHECK_BYTES;
+                }
+                temp.write_all(&buf[..n]).io_context("write", temp.path())?;
+            }
+        }
+
+        let cid = get_cid(&digest)?;
+        self.check_hash(&cid)?;
+        let (ecid, subfolder, file, _) = self.get_paths(&cid)?;
+        // a Cid that doesn't address the streamed data would store the block under the wrong name
+        if *cid.hash() != digest.multihash()? {
+            return Err(FsStorageError::CorruptBlock(ecid.to_string()).into());
+        }
+        // an encrypted block is bound to its Cid so it can only be packed once that is known
+        if packed {
+            let packed = self.pack(&self.aad(&cid)?, &data)?;
+            self.check_headroom(packed.len() as u64)?;
+            temp.write_all(&packed).io_context_id("write", &ecid, temp.path())?;
+        }
+        self.create_subfolder(&subfolder)?;
+
+        pre_commit(&cid)?;
+        debug!("fsblocks: Storing {} streamed bytes at: {}", digest.len(), file.display());
+        self.sync_file(temp.as_file(), temp.path())?;
+        self.commit_block(&cid, temp.into_temp_path(), &file)?;
+        Ok(cid)
+    }
+
+    // make sure the subfolder exists and is a dir
+    fn create_subfolder(&self, subfolder: &Path) -> Result<(), Error> {
+        if subfolder.try_exists().io_context("stat", subfolder)? {
+            if !subfolder.is_dir() {
+                return Err(FsStorageError::NotDir(subfolder.to_path_buf()).into());
+            }
+        } else {
+            fs::create_dir_all(subfolder).io_context("create dir", subfolder)?;
+            self.sync_dir(subfolder)?;
+            debug!("fsblocks: Created subfolder at: {}", subfolder.display());
+        }
+        Ok(())
+    }
+
+    // move the staged block into place and do the bookkeeping of a put. the lock on the block is
+    // held across the journal, rename and fsyncs, the store-wide counters lock only while the
+    // usage is reserved so puts of other blocks don't wait on the disk. the reservation is given
+    // back if the block can't be moved into place. neither lock is held while the observers are
+    // notified because they may put blocks themselves.
+    fn commit_block(&self, cid: &Cid, temp: TempPath, file: &Path) -> Result<(), Error> {
+        let needed = fs::metadata(&temp).io_context("stat", &temp)?.len();
+        let intent = {
+            let _lock = self.lock(cid)?;
+            let (before, after) = {
+                let _counters = self.lock_counters()?;
+                let before = self.usage()?;
+                let after = self.reserve(file, needed)?;
+                self.write_usage(after.0, after.1)?;
+                (before, after)
+            };
+            let moved = self.begin(Intent::Put, cid).and_then(|intent| {
+                self.record_name(cid)?;
+                // the filter has to cover the block before it is visible or a racing exists() misses it
+                self.bloom_insert(cid)?;
+                self.rename_into_place(temp, file)?;
+                self.sync_dir(file)?;
+                Ok(intent)
+            });
+            match moved {
+                Ok(intent) => intent,
+                Err(e) => {
+                    self.update_usage(|bytes, count| {
+                        ((bytes + before.0).saturating_sub(after.0), (count + before.1).saturating_sub(after.1))
+                    })?;
+                    return Err(e);
+                }
+            }
+        };
+        self.committed(cid)?;
+        intent.commit()
+    }
+
+    // enforce the quota and the free space reserve, if any, on storing the needed bytes in the
+    // file and return the usage counters to write once the block is committed. the caller must
+    // hold the counters lock.
+    fn reserve(&self, file: &Path, needed: u64) -> Result<(u64, u64), Error> {
+        self.check_headroom(needed)?;
+        let prev = if file.try_exists().io_context("stat", file)? { Some(fs::metadata(file).io_context("stat", file)?.len()) } else { None };
+        let (used, count) = self.usage()?;
+        let used = used.saturating_sub(prev.unwrap_or_default());
+        if let Some(max) = self.max_bytes {
+            if used + needed > max {
+                return Err(Error::QuotaExceeded { used, limit: max, needed });
+            }
+        }
+        let count = if prev.is_some() { count } else { count + 1 };
+        Ok((used + needed, count))
+    }
+
+    /// get the Cids of the blocks hashed with the hash codec, e.g. to find the blocks that still
+    /// use a deprecated hash function. this needs the codec index.
+    pub fn cids_by_hash(&self, codec: Codec) -> Result<Vec<Cid>, Error> {
+        self.read_codec_index(CODEC_INDEX_HASH, codec)
+    }
+
+    /// get the Cids of the blocks with the target codec, e.g. all of the dag-cbor blocks. this
+    /// needs the codec index.
+    pub fn cids_by_target_codec(&self, codec: Codec) -> Result<Vec<Cid>, Error> {
+        self.read_codec_index(CODEC_INDEX_TARGET, codec)
+    }
+
+    /// rebuild the codec index from the blocks in the store, for stores that were written to
+    /// before the index was enabled or by other tools. this returns the number of blocks indexed.
+    pub fn rebuild_codec_index(&self) -> Result<usize, Error> {
+        if !self.codec_index {
+            return Err(Error::Unsupported("rebuilding the codec index without a codec index".to_string()));
+        }
+        let dir = self.root.join(fsstorage::CODECS_DIR);
+        if dir.try_exists().io_context("stat", &dir)? {
+            fs::remove_dir_all(&dir).io_context("remove dir", &dir)?;
+        }
+        let cids = self.ids()?;
+        for cid in &cids {
+            self.index_codecs(cid, true)?;
+        }
+        debug!("fsblocks: Rebuilt the codec index of {} blocks", cids.len());
+        Ok(cids.len())
+    }
+
+    // the folder of the codec index that lists the blocks with the codec in the given part of
+    // their Cid. the folder is named after the varuint of the codec so every codec has a name.
+    fn codec_index_dir(&self, kind: &str, codec: Codec) -> PathBuf {
+        let mut pb = self.root.clone();
+        pb.push(fsstorage::CODECS_DIR);
+        pb.push(kind);
+        pb.push(multibase::encode(Base::Base16Lower, codec.encode_into()));
+        pb
+    }
+
+    // add the block to or remove it from the codec index, if the index is enabled
+    fn index_codecs(&self, cid: &Cid, add: bool) -> Result<(), Error> {
+        if !self.codec_index {
+            return Ok(());
+        }
+        let ecid = self.get_paths(cid)?.0.to_string();
+        for (kind, codec) in [(CODEC_INDEX_HASH, cid.hash().codec()), (CODEC_INDEX_TARGET, cid.target_codec())] {
+            let dir = self.codec_index_dir(kind, codec);
+            let file = dir.join(&ecid);
+            if add {
+                fs::create_dir_all(&dir).io_context_id("create dir", &ecid, &dir)?;
+                fs::File::create(&file).io_context_id("create", &ecid, &file)?;
+                continue;
+            }
+            match fs::remove_file(&file) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e).io_context_id("remove", &ecid, &file),
+                _ => {}
+            }
+            // drop the folder once no block has the codec
+            if dir.is_dir() && fs::read_dir(&dir).io_context_id("read dir", &ecid, &dir)?.count() == 0 {
+                fs::remove_dir(&dir).io_context_id("remove dir", &ecid, &dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_codec_index(&self, kind: &str, codec: Codec) -> Result<Vec<Cid>, Error> {
+        if !self.codec_index {
+            return Err(Error::Unsupported("listing blocks by codec without a codec index".to_string()));
+        }
+        let dir = self.codec_index_dir(kind, codec);
+        if !dir.try_exists().io_context("stat", &dir)? {
+            return Ok(Vec::default());
+        }
+        self.read_ids(&[dir], |name| Some(name))
+    }
+
+    // update the indexes after a block was moved into place and tell the observers. a plain put
+    // makes the block permanent.
+    fn committed(&self, cid: &Cid) -> Result<(), Error> {
+        self.clear_expiry(cid)?;
+        self.index_codecs(cid, true)?;
+        self.notify(Event::BlockPut(cid.clone()));
+        Ok(())
+    }
+}
+
+// clone the file copy-on-write if the filesystem supports it, otherwise copy it
+#[cfg(feature = "reflink")]
+fn clone_file(from: &Path, to: &Path) -> Result<(), Error> {
+    // reflinks can only be made to new files
+    fs::remove_file(to).io_context("remove", to)?;
+    reflink_copy::reflink_or_copy(from, to).io_context("clone", from)?;
+    Ok(())
+}
+
+// copy the file, fs::copy uses copy_file_range on Linux and clonefile on macOS where it can
+#[cfg(not(feature = "reflink"))]
+fn clone_file(from: &Path, to: &Path) -> Result<(), Error> {
+    fs::copy(from, to).io_context("copy", from)?;
+    Ok(())
+}
+
+impl fsstorage::Expire for Cid {
+    fn expire(store: &FsBlocks, id: &Cid, _file: &Path) -> Result<Option<Event<Cid>>, Error> {
+        store.index_codecs(id, false)?;
+        Ok(Some(Event::BlockRemoved(id.clone())))
+    }
+}
+
+impl fsstorage::Restore for Cid {
+    fn restore(store: &FsBlocks, id: &Cid, _file: &Path) -> Result<(), Error> {
+        store.index_codecs(id, true)
+    }
+}
+
+impl Blocks for FsBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        // the bloom filter rules out missing blocks without a stat
+        if !self.may_contain(cid) {
+            return Ok(false);
+        }
+
+        // get the paths
+        let (ecid, _, file, _) = self.get_paths(cid)?;
+        Ok(file.try_exists().io_context_id("stat", &ecid, &file)?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(cid = tracing::field::Empty, path = tracing::field::Empty, len = tracing::field::Empty)
+    ))]
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        // get the paths
+        let (ecid, subfolder, file, _) = self.get_paths(cid)?;
+        record!("cid" = &ecid, "path" = file.display());
+
+        // check if it exists and is a dir...otherwise create the dir
+        if subfolder.try_exists().io_context_id("stat", &ecid, &subfolder)? {
+            if !subfolder.is_dir() {
+                return Err(FsStorageError::NotDir(subfolder).into());
+            }
+        } else {
+            return Err(FsStorageError::NoSuchData(ecid.to_string()).into());
+        }
+
+        // store the block in the filesystem
+        debug!("fsblocks: Getting block from: {}", file.display());
+        let mut f = File::open(&file).io_context_id("open", &ecid, &file)?;
+        let mut data = Vec::default();
+        f.read_to_end(&mut data).io_context_id("read", &ecid, &file)?;
+        let data = self.unpack(&self.aad(cid)?, data)?;
+        record!("len" = data.len());
+        Ok(data)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        self.ids()
+    }
+
+    #[cfg(all(feature = "bytes", feature = "mmap"))]
+    fn get_bytes(&self, cid: &Cid) -> Result<bytes::Bytes, Self::Error> {
+        // hand out the mapped file when the bytes on disk are the block
+        if self.compression.is_none() && self.encryption_key.is_none() {
+            return Ok(bytes::Bytes::from_owner(self.get_mmap(cid)?));
+        }
+        Ok(bytes::Bytes::from(self.get(cid)?))
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        let (ecid, _, file, lazy_deleted_file) = self.get_paths(cid)?;
+        let (file, lazy_deleted) = if file.try_exists().io_context_id("stat", &ecid, &file)? {
+            (file, false)
+        } else if lazy_deleted_file.try_exists().io_context_id("stat", &ecid, &lazy_deleted_file)? {
+            (lazy_deleted_file, true)
+        } else {
+            return Err(FsStorageError::NoSuchData(ecid.to_string()).into());
+        };
+
+        let metadata = fs::metadata(&file).io_context_id("stat", &ecid, &file)?;
+        let size = if self.compression.is_some() || self.encryption_key.is_some() {
+            // the size on disk isn't the size of the block, the pack header has that
+            self.packed_len(&file)?
+        } else {
+            metadata.len()
+        };
+        Ok(BlockStat {
+            size,
+            created: metadata.created().ok(),
+            lazy_deleted,
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(cid = tracing::field::Empty, path = tracing::field::Empty, len = tracing::field::Empty)
+    ))]
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        self.put_outcome(data, get_cid, pre_commit).map(|outcome| outcome.cid().clone())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(cid = tracing::field::Empty, path = tracing::field::Empty, len = tracing::field::Empty)
+    ))]
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        // first try to get the value
+        let v = self.get(cid)?;
+
+        // get the paths
+        let (ecid, _, file, _) = self.get_paths(cid)?;
+        record!("cid" = &ecid, "path" = file.display(), "len" = v.len());
+        let intent = self.begin(Intent::Rm, cid)?;
+
+        // drop the cached outboard tree, it is rebuilt if the block is put back
+        #[cfg(feature = "bao")]
+        {
+            let cache = self.root.join(BAO_DIR).join(ecid.to_string());
+            if cache.try_exists().io_context_id("stat", &ecid, &cache)? {
+                fs::remove_file(&cache).io_context_id("remove", &ecid, &cache)?;
+            }
+        }
+
+        if let Some(size) = self.remove_entry(cid)? {
+            // not lazy so the metadata goes too
+            if !self.lazy {
+                let meta = self.meta_file(cid)?;
+                if meta.try_exists().io_context_id("stat", &ecid, &meta)? {
+                    fs::remove_file(&meta).io_context_id("remove", &ecid, &meta)?;
+                }
+            }
+
+            // update the usage counters
+            self.update_usage(|used, count| (used.saturating_sub(size), count.saturating_sub(1)))?;
+        }
+
+        self.index_codecs(cid, false)?;
+        intent.commit()?;
+        self.notify(Event::BlockRemoved(cid.clone()));
+
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockStore, fsstorage::{Issue, Repair, QUARANTINE_DIR}, traits::blocks::verify};
+    use multicid::cid;
+    use multihash::mh;
+    use multikey::mk;
+    use std::sync::{Arc, Mutex};
+
+    // returns a random symmetric encryption key
+    fn get_key() -> Multikey {
+        let mut rng = rand::rngs::OsRng::default();
+        mk::Builder::new_from_random_bytes(Codec::Chacha20Poly1305, &mut rng)
+            .unwrap()
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_builder_lazy() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks1");
+
+        let blocks = Builder::new(&pb).try_build().unwrap();
+        assert_eq!(blocks.root, pb);
+        assert_eq!(blocks.lazy, true);
+        assert_eq!(blocks.base_encoding, Base::Base32Z);
+        assert!(pb.try_exists().is_ok());
+        assert!(pb.is_dir());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_builder_not_lazy() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks2");
+
+        let blocks = Builder::new(&pb).not_lazy().try_build().unwrap();
+        assert_eq!(blocks.root, pb);
+        assert_eq!(blocks.lazy, false);
+        assert_eq!(blocks.base_encoding, Base::Base32Z);
+        assert!(pb.try_exists().is_ok());
+        assert!(pb.is_dir());
+
+        for d in fs::read_dir(&pb).unwrap() {
+            assert!(d.is_ok());
+            let dir = d.unwrap();
+            if dir.file_name() == fsstorage::CONFIG_FILE {
+                continue;
+            }
+            assert!(dir.file_type().unwrap().is_dir());
+        }
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    // calculates a CIDv1 over raw data using Blake3
+    fn get_cid<D: AsRef<[u8]> + ?Sized>(data: &D) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data.as_ref())?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    fn put(blocks: &mut FsBlocks, v: impl AsRef<[u8]>) -> Cid {
+        let cid = blocks.put(&v, get_cid, |_| Ok(())).unwrap();
+        cid
     }
 
     #[test]
@@ -335,4 +1475,1686 @@ mod tests {
 
         assert!(fs::remove_dir_all(&pb).is_ok());
     }
+
+    #[test]
+    fn test_put_compressed() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks8");
+
+        let mut blocks = Builder::new(&pb).with_compression(Codec::Zstd).try_build().unwrap();
+        assert_eq!(blocks.compression, Some(Codec::Zstd));
+
+        let v1 = b"for great justice! for great justice! for great justice!".to_vec();
+        let cid = put(&mut blocks, &v1);
+
+        // the file on disk is compressed and different from the original data
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        let raw = fs::read(&file).unwrap();
+        assert_ne!(v1, raw);
+
+        // but get returns the uncompressed data
+        let v2 = blocks.get(&cid).unwrap();
+        assert_eq!(v1, v2);
+
+        // the codec is read from the header so reopening with another one still reads it
+        let blocks = Builder::new(&pb).with_compression(Codec::Identity).try_build().unwrap();
+        assert_eq!(blocks.get(&cid).unwrap(), v1);
+
+        // but reopening without compression is refused rather than misreading every block
+        assert!(Builder::new(&pb).try_build().is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_encrypted() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks9");
+
+        let key = get_key();
+        let mut blocks = Builder::new(&pb).with_encryption_key(&key).try_build().unwrap();
+
+        let v1 = b"for great justice!".to_vec();
+        let cid = put(&mut blocks, &v1);
+
+        // the file on disk is encrypted
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        let raw = fs::read(&file).unwrap();
+        assert_ne!(v1, raw);
+
+        // get returns the plaintext
+        let v2 = blocks.get(&cid).unwrap();
+        assert_eq!(v1, v2);
+
+        // a block moved to the file of another Cid fails to decrypt there
+        let other_cid = put(&mut blocks, b"move every zig!");
+        let (_, _, other_file, _) = blocks.get_paths(&other_cid).unwrap();
+        fs::copy(&file, &other_file).unwrap();
+        assert!(blocks.get(&other_cid).is_err());
+
+        // the key never shows up in Debug output
+        assert!(!format!("{:?}", blocks).contains(&format!("{:?}", key)));
+
+        // a store opened with a different key is refused
+        let other = Builder::new(&pb).with_encryption_key(&get_key()).try_build();
+        assert!(matches!(other, Err(Error::FsStorage(FsStorageError::InvalidEncryptionKey))));
+
+        // opening it without a key keeps the key check for the next handle with the key
+        let _ = Builder::new(&pb).try_build().unwrap();
+        assert!(Builder::new(&pb).with_encryption_key(&get_key()).try_build().is_err());
+        let blocks = Builder::new(&pb).with_encryption_key(&key).try_build().unwrap();
+        assert_eq!(blocks.get(&cid).unwrap(), v1);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_passphrase() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks59");
+
+        let mut blocks = Builder::new(&pb).with_passphrase("correct horse battery staple").try_build().unwrap();
+        let v1 = b"for great justice!".to_vec();
+        let cid = put(&mut blocks, &v1);
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        assert_ne!(fs::read(&file).unwrap(), v1);
+        assert!(blocks.read_config().unwrap().unwrap().kdf.is_some());
+
+        // the same passphrase opens the store again and a wrong one or none at all is refused
+        let reopened = Builder::new(&pb).with_passphrase("correct horse battery staple").try_build().unwrap();
+        assert_eq!(reopened.get(&cid).unwrap(), v1);
+        assert!(Builder::new(&pb).with_passphrase("incorrect horse").try_build().is_err());
+        assert!(Builder::new(&pb).try_build().is_err());
+        assert!(Builder::new(&pb).with_passphrase("correct horse battery staple").with_encryption_key(&get_key()).try_build().is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_get_verified() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks10");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+
+        let v1 = b"for great justice!".to_vec();
+        let cid = put(&mut blocks, &v1);
+        assert_eq!(v1, blocks.get_verified(&cid).unwrap());
+
+        // simulate bit-rot by overwriting the block file
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        fs::write(&file, b"for great justice?").unwrap();
+
+        // plain get doesn't notice but verified get does
+        assert!(blocks.get(&cid).is_ok());
+        assert!(matches!(
+            blocks.get_verified(&cid),
+            Err(Error::FsStorage(FsStorageError::CorruptBlock(_)))
+        ));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks11");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+        let validate = |cid: &Cid, data: &[u8]| verify(cid, data).is_ok();
+
+        // a fresh store is clean
+        let report = blocks.check(Repair::None, validate).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.checked, 2);
+
+        // corrupt one block, leave a stray temp file and a garbage file
+        let (_, subfolder, file1, _) = blocks.get_paths(&cid1).unwrap();
+        fs::write(&file1, b"for great justice?").unwrap();
+        let mut temp = subfolder.clone();
+        temp.push(".tmpabc123.garbage");
+        fs::write(&temp, b"partial").unwrap();
+        let mut garbage = subfolder.clone();
+        garbage.push("not-an-id!");
+        fs::write(&garbage, b"garbage").unwrap();
+
+        let report = blocks.check(Repair::None, validate).unwrap();
+        assert_eq!(report.issues.len(), 3);
+        assert!(report.issues.contains(&Issue::CorruptData(file1.clone())));
+        assert!(report.issues.contains(&Issue::StrayTempFile(temp.clone())));
+        assert!(report.issues.contains(&Issue::UndecodableName(garbage.clone())));
+        assert!(report.repaired.is_empty());
+
+        // quarantine the bad files
+        let report = blocks.check(Repair::Quarantine, validate).unwrap();
+        assert_eq!(report.repaired.len(), 3);
+        assert!(!file1.try_exists().unwrap());
+
+        // the store is clean again and the good block is untouched
+        assert!(blocks.check(Repair::None, validate).unwrap().is_clean());
+        assert!(blocks.get_verified(&cid2).is_ok());
+
+        // a second bad copy of the same block doesn't replace the first in quarantine
+        assert_eq!(put(&mut blocks, b"for great justice!"), cid1);
+        fs::write(&file1, b"for great justice?!").unwrap();
+        assert_eq!(blocks.check(Repair::Quarantine, validate).unwrap().repaired, vec![file1.clone()]);
+        let mut quarantined = pb.clone();
+        quarantined.push(QUARANTINE_DIR);
+        quarantined.push(file1.file_name().unwrap());
+        assert_eq!(fs::read(&quarantined).unwrap(), b"for great justice?");
+        let name = format!("{}.1", file1.file_name().unwrap().to_string_lossy());
+        assert_eq!(fs::read(quarantined.with_file_name(name)).unwrap(), b"for great justice?!");
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks12");
+
+        let mut blocks = Builder::new(&pb).with_max_bytes(30).try_build().unwrap();
+        assert_eq!(blocks.stored_bytes().unwrap(), 0);
+
+        let cid = put(&mut blocks, b"for great justice!");
+        assert_eq!(blocks.stored_bytes().unwrap(), 18);
+
+        // storing another 15 bytes would exceed the quota
+        let result = blocks.put(&b"move every zig!", get_cid, |_| Ok(()));
+        assert!(matches!(result, Err(Error::QuotaExceeded { used: 18, limit: 30, needed: 15 })));
+
+        // removing the first block frees up the space
+        blocks.rm(&cid).unwrap();
+        assert_eq!(blocks.stored_bytes().unwrap(), 0);
+        let _ = put(&mut blocks, b"move every zig!");
+
+        // the counter is rebuilt if it goes missing
+        let mut usage = pb.clone();
+        usage.push(fsstorage::USAGE_FILE);
+        fs::remove_file(&usage).unwrap();
+        assert_eq!(blocks.stored_bytes().unwrap(), 15);
+
+        // and if it is torn or wrong in a way that doesn't parse
+        fs::write(&usage, b"15").unwrap();
+        assert_eq!(blocks.len().unwrap(), 1);
+        fs::write(&usage, b"15 1 7").unwrap();
+        assert_eq!(blocks.stored_bytes().unwrap(), 15);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_concurrent_usage() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks60");
+
+        // every thread has its own handle like separate processes would
+        let _ = Builder::new(&pb).try_build().unwrap();
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let pb = pb.clone();
+                std::thread::spawn(move || {
+                    let mut blocks = Builder::new(&pb).try_build().unwrap();
+                    for j in 0..16u8 {
+                        let _ = put(&mut blocks, [i, j]);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // no update of the counters was lost
+        let blocks = Builder::new(&pb).try_build().unwrap();
+        assert_eq!(blocks.len().unwrap(), 128);
+        assert_eq!(blocks.stored_bytes().unwrap(), 256);
+        assert_eq!(blocks.rebuild_usage().unwrap(), 256);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    fn put_ttl(blocks: &mut FsBlocks, v: impl AsRef<[u8]>, ttl: Duration) -> Cid {
+        blocks.put_with_ttl(&v, ttl, get_cid, |_| Ok(())).unwrap()
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks13");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+
+        let expired = put_ttl(&mut blocks, b"for great justice!", Duration::ZERO);
+        let alive = put_ttl(&mut blocks, b"move every zig!", Duration::from_secs(3600));
+        assert!(blocks.expiry(&expired).unwrap().is_some());
+        assert!(blocks.expiry(&alive).unwrap().is_some());
+
+        // putting the block again without a ttl makes it permanent
+        let permanent = put_ttl(&mut blocks, b"someday", Duration::ZERO);
+        let _ = put(&mut blocks, b"someday");
+        assert!(blocks.expiry(&permanent).unwrap().is_none());
+
+        blocks.gc().unwrap();
+
+        assert!(!blocks.exists(&expired).unwrap());
+        assert!(blocks.expiry(&expired).unwrap().is_none());
+        assert!(blocks.exists(&alive).unwrap());
+        assert!(blocks.exists(&permanent).unwrap());
+
+        // a ttl past the end of time is refused before anything is stored
+        let len = blocks.len().unwrap();
+        assert!(blocks.put_with_ttl(b"all your base", Duration::MAX, |_| unreachable!(), |_| Ok(())).is_err());
+        assert_eq!(blocks.len().unwrap(), len);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks14");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::default()));
+        let e = events.clone();
+        blocks.subscribe(move |event: &Event<Cid>| e.lock().unwrap().push(event.clone()));
+
+        let cid = put(&mut blocks, b"for great justice!");
+        let _ = blocks.rm(&cid).unwrap();
+
+        assert_eq!(*events.lock().unwrap(), vec![Event::BlockPut(cid.clone()), Event::BlockRemoved(cid)]);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_ids() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks15");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+        let cid3 = put(&mut blocks, b"someday");
+        let _ = blocks.rm(&cid3).unwrap();
+
+        // lazy deleted blocks are not listed
+        let ids = blocks.ids().unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&cid1));
+        assert!(ids.contains(&cid2));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_sharding() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks16");
+
+        let mut blocks = Builder::new(&pb)
+            .with_sharding(Sharding::Suffix { chars: 2, depth: 2 })
+            .try_build()
+            .unwrap();
+
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+
+        // the block is two levels down, named after the end of the encoded Cid
+        let (ecid, subfolder, file, _) = blocks.get_paths(&cid1).unwrap();
+        let ecid = ecid.to_string();
+        let inner = &ecid[ecid.len() - 2..];
+        let outer = &ecid[ecid.len() - 4..ecid.len() - 2];
+        assert_eq!(subfolder, pb.join(outer).join(inner));
+        assert!(file.is_file());
+        assert_eq!(blocks.get(&cid1).unwrap(), b"for great justice!".to_vec());
+
+        let ids = blocks.ids().unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&cid2));
+
+        // gc removes the empty subfolders at every level
+        let _ = blocks.rm(&cid1).unwrap();
+        let _ = blocks.rm(&cid2).unwrap();
+        blocks.gc().unwrap();
+        assert_eq!(blocks.shards().unwrap().len(), 0);
+
+        // zero sized shards are rejected
+        assert!(Builder::new(&pb).with_sharding(Sharding::Prefix { chars: 0, depth: 1 }).try_build().is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_prefix_sharding() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks63");
+
+        // the encoded Cids all start the same but their hashes spread them out
+        let mut blocks = Builder::new(pb.join("lazy"))
+            .with_sharding(Sharding::Prefix { chars: 1, depth: 2 })
+            .try_build()
+            .unwrap();
+        let mut outer = std::collections::HashSet::new();
+        for i in 0..20u8 {
+            let cid = put(&mut blocks, &[i]);
+            let (_, subfolder, _, _) = blocks.get_paths(&cid).unwrap();
+            outer.insert(subfolder.parent().unwrap().to_path_buf());
+        }
+        assert!(outer.len() > 1);
+
+        // a remove that empties the subfolder takes its empty parents with it
+        let root = pb.join("eager");
+        let mut blocks = Builder::new(&root)
+            .with_sharding(Sharding::Prefix { chars: 1, depth: 2 })
+            .not_lazy()
+            .try_build()
+            .unwrap();
+        for shard in fs::read_dir(&root).unwrap() {
+            let shard = shard.unwrap();
+            if !shard.file_name().to_string_lossy().starts_with('.') {
+                fs::remove_dir_all(shard.path()).unwrap();
+            }
+        }
+        let cid = put(&mut blocks, b"for great justice!");
+        let (_, subfolder, _, _) = blocks.get_paths(&cid).unwrap();
+        let _ = blocks.rm(&cid).unwrap();
+        assert!(!subfolder.parent().unwrap().exists());
+        assert!(root.exists());
+
+        // the hash only has so many characters and eager stores only create so many subfolders
+        assert!(Builder::new(pb.join("long")).with_sharding(Sharding::Prefix { chars: 27, depth: 2 }).try_build().is_err());
+        assert!(Builder::new(pb.join("many")).with_sharding(Sharding::Prefix { chars: 2, depth: 4 }).not_lazy().try_build().is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_migrate_encoding() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks17");
+
+        let mut blocks = Builder::new(&pb).with_codec_index().try_build().unwrap();
+
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+        let cid3 = put(&mut blocks, b"someday");
+        let _ = blocks.rm(&cid3).unwrap();
+        let meta = BlockMeta::default().with_content_type("text/plain");
+        blocks.set_meta(&cid1, &meta).unwrap();
+
+        blocks.migrate_encoding(Base::Base16Lower).unwrap();
+        assert_eq!(blocks.base_encoding, Base::Base16Lower);
+        assert!(!pb.join(fsstorage::MIGRATE_DIR).exists());
+
+        // the entries are found under their new names
+        let (_, _, file, lazy_deleted_file) = blocks.get_paths(&cid1).unwrap();
+        assert!(file.is_file());
+        assert_eq!(blocks.get(&cid1).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.get(&cid2).unwrap(), b"move every zig!".to_vec());
+        let (_, _, _, lazy_deleted_file3) = blocks.get_paths(&cid3).unwrap();
+        assert!(!lazy_deleted_file.exists());
+        assert!(lazy_deleted_file3.is_file());
+        assert_eq!(blocks.ids().unwrap().len(), 2);
+
+        // so are their metadata sidecars and codec index records
+        assert_eq!(blocks.meta(&cid1).unwrap(), Some(meta));
+        let by_hash = blocks.cids_by_hash(cid1.hash().codec()).unwrap();
+        assert_eq!(by_hash.len(), 2);
+        assert!(by_hash.contains(&cid1) && by_hash.contains(&cid2));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_migrate_encoding_interrupted() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks61");
+        let staged = pb.with_extension("staged");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        let base = blocks.base_encoding;
+
+        // a held entry lock refuses the migration and leaves the store as it was
+        let lock = blocks.lock(&cid).unwrap();
+        assert!(blocks.migrate_encoding(Base::Base16Lower).is_err());
+        drop(lock);
+        assert!(!pb.join(fsstorage::MIGRATE_DIR).exists());
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+
+        // a staged layout that was never marked ready is rolled back
+        fs::create_dir_all(pb.join(fsstorage::MIGRATE_DIR).join("f")).unwrap();
+        let blocks = FsBlocks::open(&pb).unwrap();
+        assert!(!pb.join(fsstorage::MIGRATE_DIR).exists());
+        assert_eq!(blocks.base_encoding, base);
+
+        // one that was is finished
+        let mut target = Builder::new(&staged).with_base_encoding(Base::Base16Lower).try_build().unwrap();
+        put(&mut target, b"for great justice!");
+        drop(target);
+        fs::rename(&staged, pb.join(fsstorage::MIGRATE_DIR)).unwrap();
+        fs::File::create(pb.join(fsstorage::MIGRATE_DIR).join(".ready")).unwrap();
+        let blocks = FsBlocks::open(&pb).unwrap();
+        assert!(!pb.join(fsstorage::MIGRATE_DIR).exists());
+        assert_eq!(blocks.base_encoding, Base::Base16Lower);
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.ids().unwrap().len(), 1);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_config() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks18");
+
+        let blocks = Builder::new(&pb)
+            .with_sharding(Sharding::Suffix { chars: 2, depth: 1 })
+            .try_build()
+            .unwrap();
+        assert_eq!(blocks.read_config().unwrap(), Some(blocks.config()));
+
+        // reopening with the same layout works, a different one fails
+        assert!(Builder::new(&pb).with_sharding(Sharding::Suffix { chars: 2, depth: 1 }).try_build().is_ok());
+        assert!(Builder::new(&pb).try_build().is_err());
+        assert!(Builder::new(&pb)
+            .with_sharding(Sharding::Suffix { chars: 2, depth: 1 })
+            .with_base_encoding(Base::Base16Lower)
+            .try_build()
+            .is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_open_and_migrate() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks19");
+
+        let mut blocks = Builder::new(&pb)
+            .with_sharding(Sharding::Prefix { chars: 1, depth: 2 })
+            .try_build()
+            .unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+
+        // open uses the persisted layout
+        let opened = FsBlocks::open(&pb).unwrap();
+        assert_eq!(opened.sharding, Sharding::Prefix { chars: 1, depth: 2 });
+        assert_eq!(opened.get(&cid).unwrap(), b"for great justice!".to_vec());
+
+        // a current store has nothing to migrate
+        assert_eq!(FsBlocks::migrate(&pb).unwrap(), fsstorage::FORMAT_VERSION);
+
+        // stores from the future are refused
+        let mut config = blocks.config();
+        config.version = fsstorage::FORMAT_VERSION + 1;
+        fs::write(pb.join(fsstorage::CONFIG_FILE), serde_json::to_vec(&config).unwrap()).unwrap();
+        assert!(FsBlocks::open(&pb).is_err());
+        assert!(FsBlocks::migrate(&pb).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_block_store() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks20");
+
+        let mut store: Box<dyn BlockStore> = Box::new(Builder::new(&pb).try_build().unwrap());
+
+        let cid = store.put_with(b"for great justice!", &get_cid::<[u8]>, &|_| Ok(())).unwrap();
+        assert!(store.block_exists(&cid).unwrap());
+        assert_eq!(store.get_block(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(store.rm_block(&cid).unwrap(), b"for great justice!".to_vec());
+        assert!(!store.block_exists(&cid).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_gc_parallel() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks21");
+
+        let mut blocks = Builder::new(&pb)
+            .with_sharding(Sharding::Suffix { chars: 1, depth: 2 })
+            .with_gc_threads(4)
+            .try_build()
+            .unwrap();
+
+        let cids: Vec<Cid> = (0..64).map(|i| put(&mut blocks, format!("block {}", i))).collect();
+        for cid in &cids {
+            let _ = blocks.rm(cid).unwrap();
+        }
+
+        blocks.gc().unwrap();
+        assert!(blocks.shards().unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_put_many_par() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks53");
+
+        let mut blocks = Builder::new(&pb).with_compression(Codec::Zstd).try_build().unwrap();
+        let items: Vec<String> = (0..64).map(|i| format!("block {}", i % 48)).collect();
+        let cids = blocks.put_many_par(&items, 4, get_cid).unwrap();
+
+        // the results are in order and the repeated blocks are only stored once
+        assert_eq!(cids.len(), 64);
+        for (item, cid) in items.iter().zip(&cids) {
+            assert_eq!(blocks.get(cid).unwrap(), item.as_bytes().to_vec());
+            assert_eq!(put(&mut blocks, item), *cid);
+        }
+        assert_eq!(blocks.len().unwrap(), 48);
+
+        // a failing hash fails the whole call
+        let failed = blocks.put_many_par(&items, 0, |_| Err(Error::Custom("no hash".to_string())));
+        assert!(matches!(failed, Err(Error::Custom(_))));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks54");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let mut cids: Vec<Cid> = (0..16).map(|i| put(&mut blocks, format!("block {}", i))).collect();
+        let _ = blocks.rm(&cids[3]).unwrap();
+        cids.push(cids[0].clone());
+
+        let results = blocks.get_many(&cids, 4);
+        assert_eq!(results.len(), 16);
+        for (i, cid) in cids.iter().enumerate().take(16) {
+            match i {
+                3 => assert!(results[cid].is_err()),
+                _ => assert_eq!(results[cid].as_ref().unwrap(), &format!("block {}", i).into_bytes()),
+            }
+        }
+        assert_eq!(blocks.prefetch(&cids[..8], 2), 7);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_gc_policy() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks22");
+
+        let mut blocks = Builder::new(&pb)
+            .with_gc_policy(GcPolicy { retain_deleted: Duration::from_secs(3600) })
+            .try_build()
+            .unwrap();
+
+        let cid = put(&mut blocks, b"for great justice!");
+        let _ = blocks.rm(&cid).unwrap();
+        let (_, _, _, lazy_deleted_file) = blocks.get_paths(&cid).unwrap();
+
+        // the recently deleted block is retained
+        blocks.gc().unwrap();
+        assert!(lazy_deleted_file.try_exists().unwrap());
+
+        // without retention it is removed
+        blocks.gc_policy = GcPolicy::default();
+        blocks.gc().unwrap();
+        assert!(!lazy_deleted_file.try_exists().unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks23");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+        let _ = blocks.rm(&cid1).unwrap();
+        let _ = blocks.rm(&cid2).unwrap();
+
+        let deleted = blocks.list_deleted().unwrap();
+        assert_eq!(deleted.len(), 2);
+        assert!(deleted.contains(&cid1));
+
+        blocks.restore(&cid1).unwrap();
+        assert_eq!(blocks.get(&cid1).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.list_deleted().unwrap(), vec![cid2.clone()]);
+
+        // restoring twice fails
+        assert!(blocks.restore(&cid1).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_with_cid() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks24");
+
+        let mut blocks = Builder::new(&pb).not_lazy().try_build().unwrap();
+
+        // ingest a block under its already known Cid
+        let data = b"for great justice!".to_vec();
+        let cid = put(&mut blocks, &data);
+        let _ = blocks.rm(&cid).unwrap();
+        assert_eq!(blocks.put_with_cid(&cid, &data, true).unwrap(), cid);
+        assert_eq!(blocks.get(&cid).unwrap(), data);
+
+        // data that doesn't match the Cid is rejected when verifying
+        let bad = put(&mut blocks, b"move every zig!");
+        let _ = blocks.rm(&bad).unwrap();
+        assert!(blocks.put_with_cid(&bad, &data, true).is_err());
+        assert!(!blocks.exists(&bad).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_stat() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks25");
+
+        let mut blocks = Builder::new(&pb)
+            .with_compression(Codec::Zstd)
+            .try_build()
+            .unwrap();
+
+        let cid = put(&mut blocks, b"for great justice!");
+        let stat = blocks.stat(&cid).unwrap();
+        assert_eq!(stat.size, 18);
+        assert!(!stat.lazy_deleted);
+
+        let _ = blocks.rm(&cid).unwrap();
+        let stat = blocks.stat(&cid).unwrap();
+        assert_eq!(stat.size, 18);
+        assert!(stat.lazy_deleted);
+
+        blocks.gc().unwrap();
+        assert!(blocks.stat(&cid).is_err());
+
+        // encrypted stores read the size from the pack header too, which can't be changed
+        let mut blocks = Builder::new(pb.join("encrypted")).with_encryption_key(&get_key()).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        assert_eq!(blocks.stat(&cid).unwrap().size, 18);
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        let mut data = fs::read(&file).unwrap();
+        data[0] = 17;
+        fs::write(&file, data).unwrap();
+        assert!(blocks.get(&cid).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_len_and_total_bytes() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks26");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        assert!(blocks.is_empty().unwrap());
+
+        let cid = put(&mut blocks, b"for great justice!");
+        let _ = put(&mut blocks, b"move every zig!");
+        let _ = put(&mut blocks, b"move every zig!");
+        assert_eq!(blocks.len().unwrap(), 2);
+        assert_eq!(blocks.total_bytes().unwrap(), 33);
+
+        let _ = blocks.rm(&cid).unwrap();
+        assert_eq!(blocks.len().unwrap(), 1);
+        assert_eq!(blocks.total_bytes().unwrap(), 15);
+
+        // restoring puts it back in the counters
+        blocks.restore(&cid).unwrap();
+        assert_eq!(blocks.len().unwrap(), 2);
+
+        // the counters are rebuilt if they go missing
+        let mut usage = pb.clone();
+        usage.push(fsstorage::USAGE_FILE);
+        fs::remove_file(&usage).unwrap();
+        assert_eq!(blocks.len().unwrap(), 2);
+        assert_eq!(blocks.total_bytes().unwrap(), 33);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks27");
+
+        // blocks put before the filter is enabled are added when it is built
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let cid1 = put(&mut blocks, b"for great justice!");
+
+        let mut blocks = Builder::new(&pb).with_bloom_filter(1000, 0.01).try_build().unwrap();
+        assert!(pb.join(fsstorage::BLOOM_FILE).is_file());
+        assert!(blocks.exists(&cid1).unwrap());
+
+        let cid2 = put(&mut blocks, b"move every zig!");
+        assert!(blocks.exists(&cid2).unwrap());
+        let _ = blocks.rm(&cid2).unwrap();
+        assert!(!blocks.exists(&cid2).unwrap());
+
+        // the filter is loaded from disk when the store is reopened
+        let blocks = Builder::new(&pb).with_bloom_filter(1000, 0.01).try_build().unwrap();
+        assert!(blocks.exists(&cid1).unwrap());
+        assert!(blocks.may_contain(&cid2));
+
+        // opening without the filter removes it since it would go stale
+        let _ = Builder::new(&pb).try_build().unwrap();
+        assert!(!pb.join(fsstorage::BLOOM_FILE).exists());
+
+        // the false positive rate must be a probability
+        assert!(Builder::new(&pb).with_bloom_filter(1000, 1.5).try_build().is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_get_mmap() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks28");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        let mmap = blocks.get_mmap(&cid).unwrap();
+        assert_eq!(&mmap[..], b"for great justice!");
+        assert!(verify(&cid, &mmap).is_ok());
+        drop(mmap);
+        assert!(fs::remove_dir_all(&pb).is_ok());
+
+        // compressed blocks can't be mapped
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks29");
+        let mut blocks = Builder::new(&pb).with_compression(Codec::Zstd).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        assert!(blocks.get_mmap(&cid).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_get_bytes() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks30");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        let data = blocks.get_bytes(&cid).unwrap();
+        let copy = data.clone();
+        assert_eq!(&data[..], b"for great justice!");
+        assert_eq!(data.as_ptr(), copy.as_ptr());
+        drop(data);
+        drop(copy);
+
+        assert_eq!(&blocks.rm_bytes(&cid).unwrap()[..], b"for great justice!");
+        assert!(!blocks.exists(&cid).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_link_from() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks31");
+        let mut pool = Builder::new(pb.join("pool")).try_build().unwrap();
+        let mut project = Builder::new(pb.join("project")).try_build().unwrap();
+
+        let cid = put(&mut pool, b"for great justice!");
+        project.link_from(&pool, &cid).unwrap();
+        assert_eq!(project.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(project.len().unwrap(), 1);
+
+        // linking again is a no-op and removing from one store leaves the other alone
+        project.link_from(&pool, &cid).unwrap();
+        let _ = project.rm(&cid).unwrap();
+        assert!(pool.exists(&cid).unwrap());
+
+        // the stores must store blocks the same way
+        let mut zstd = Builder::new(pb.join("zstd")).with_compression(Codec::Zstd).try_build().unwrap();
+        assert!(zstd.link_from(&pool, &cid).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_path() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks32");
+
+        let mut blocks = Builder::new(pb.join("blocks")).try_build().unwrap();
+        let src = pb.join("src.txt");
+        fs::write(&src, b"for great justice!").unwrap();
+
+        let get_path_cid = |path: &Path| get_cid(&fs::read(path).unwrap());
+        let cid = blocks.put_path(&src, get_path_cid).unwrap();
+        assert_eq!(blocks.get_verified(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.total_bytes().unwrap(), 18);
+
+        // the source is left alone
+        assert!(src.is_file());
+
+        // a read-only source doesn't make a read-only block
+        let writable = fs::metadata(&src).unwrap().permissions();
+        let mut readonly = writable.clone();
+        readonly.set_readonly(true);
+        fs::set_permissions(&src, readonly).unwrap();
+        let _ = blocks.rm(&cid).unwrap();
+        blocks.gc().unwrap();
+        assert_eq!(blocks.put_path(&src, get_path_cid).unwrap(), cid);
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        assert!(!fs::metadata(&file).unwrap().permissions().readonly());
+        fs::set_permissions(&src, writable).unwrap();
+
+        // compressed stores fall back to put
+        let mut zstd = Builder::new(pb.join("zstd")).with_compression(Codec::Zstd).try_build().unwrap();
+        assert_eq!(zstd.put_path(&src, get_path_cid).unwrap(), cid);
+        assert_eq!(zstd.get(&cid).unwrap(), b"for great justice!".to_vec());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_snapshot_export_import() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks33");
+
+        let mut blocks = Builder::new(pb.join("a")).try_build().unwrap();
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+        let _ = blocks.rm(&cid2).unwrap();
+
+        let mut archive = Vec::default();
+        blocks.export_snapshot(&mut archive).unwrap();
+
+        let restored = Builder::new(pb.join("b")).try_build().unwrap();
+        restored.import_snapshot(archive.as_slice()).unwrap();
+        assert_eq!(restored.get(&cid1).unwrap(), b"for great justice!".to_vec());
+        assert!(!restored.exists(&cid2).unwrap());
+        assert!(restored.list_deleted().unwrap().is_empty());
+        assert_eq!(restored.len().unwrap(), 1);
+
+        // the layouts must match
+        let other = Builder::new(pb.join("c")).with_base_encoding(Base::Base58Btc).try_build().unwrap();
+        assert!(other.import_snapshot(archive.as_slice()).is_err());
+
+        // and an archive without the config first is refused before anything is written
+        let (_, _, file, _) = blocks.get_paths(&cid1).unwrap();
+        let mut tar = tar::Builder::new(Vec::default());
+        tar.append_path_with_name(&file, file.strip_prefix(pb.join("a")).unwrap()).unwrap();
+        let unchecked = tar.into_inner().unwrap();
+        let other = Builder::new(pb.join("d")).try_build().unwrap();
+        assert!(other.import_snapshot(unchecked.as_slice()).is_err());
+        assert!(!other.exists(&cid1).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_snapshot_export_import_encrypted() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks65");
+
+        let key = get_key();
+        let mut blocks = Builder::new(pb.join("a")).with_encryption_key(&key).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+
+        let mut archive = Vec::default();
+        blocks.export_snapshot(&mut archive).unwrap();
+
+        // the key check of each store has its own nonce so only the key has to match
+        let restored = Builder::new(pb.join("b")).with_encryption_key(&key).try_build().unwrap();
+        restored.import_snapshot(archive.as_slice()).unwrap();
+        assert_eq!(restored.get(&cid).unwrap(), b"for great justice!".to_vec());
+
+        // a different key or no key can't read the entries
+        let other = Builder::new(pb.join("c")).with_encryption_key(&get_key()).try_build().unwrap();
+        assert!(other.import_snapshot(archive.as_slice()).is_err());
+        let other = Builder::new(pb.join("d")).try_build().unwrap();
+        assert!(other.import_snapshot(archive.as_slice()).is_err());
+
+        // files outside the shards and record folders are refused
+        let mut tar = tar::Builder::new(Vec::default());
+        tar.append_path_with_name(pb.join("a").join(fsstorage::CONFIG_FILE), fsstorage::CONFIG_FILE).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, fsstorage::USAGE_FILE, b"0".as_slice()).unwrap();
+        let usage = tar.into_inner().unwrap();
+        assert!(restored.import_snapshot(usage.as_slice()).is_err());
+        assert!(!pb.join("b").join(fsstorage::USAGE_FILE).exists());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks34");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+
+        let snapshot = blocks.snapshot("before").unwrap();
+        assert!(blocks.snapshot("before").is_err());
+        assert!(blocks.snapshot("../escape").is_err());
+
+        // changes to the store don't show up in the snapshot
+        let _ = blocks.rm(&cid1).unwrap();
+        blocks.gc().unwrap();
+        let cid3 = put(&mut blocks, b"all your base are belong to us");
+        assert_eq!(snapshot.get(&cid1).unwrap(), b"for great justice!".to_vec());
+        assert!(snapshot.exists(&cid2).unwrap());
+        assert!(!snapshot.exists(&cid3).unwrap());
+
+        // the snapshot isn't part of the store
+        assert_eq!(blocks.len().unwrap(), 2);
+        assert!(!blocks.ids().unwrap().contains(&cid1));
+
+        assert_eq!(blocks.snapshots().unwrap(), vec!["before".to_string()]);
+        blocks.remove_snapshot("before").unwrap();
+        assert!(blocks.snapshots().unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "bao")]
+    #[test]
+    fn test_get_verified_stream() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks35");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let cid = put(&mut blocks, &data);
+
+        let mut out = Vec::default();
+        blocks.get_verified_stream(&cid).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+
+        // corrupt the end of the block, the stream returns the good chunks before failing
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[99_999] ^= 0xff;
+        fs::write(&file, &corrupt).unwrap();
+        let mut stream = blocks.get_verified_stream(&cid).unwrap();
+        let mut good = vec![0; 65_536];
+        stream.read_exact(&mut good).unwrap();
+        assert_eq!(good, data[..65_536].to_vec());
+        let err = stream.read_to_end(&mut Vec::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // a cached tree from another block of the same size doesn't verify this one
+        let other: Vec<u8> = (0..100_000).map(|i| (i + 1) as u8).collect();
+        let other = put(&mut blocks, &other);
+        blocks.get_verified_stream(&other).unwrap().read_to_end(&mut Vec::default()).unwrap();
+        fs::write(&file, &data).unwrap();
+        let (eother, _, _, _) = blocks.get_paths(&other).unwrap();
+        let (ecid, _, _, _) = blocks.get_paths(&cid).unwrap();
+        let bao = pb.join(BAO_DIR);
+        fs::copy(bao.join(eother.to_string()), bao.join(ecid.to_string())).unwrap();
+        let err = blocks.get_verified_stream(&cid).unwrap().read_to_end(&mut Vec::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "digest")]
+    #[test]
+    fn test_put_reader() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks36");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let data: Vec<u8> = (0..STREAM_BUF_SIZE * 3 + 17).map(|i| i as u8).collect();
+        let cid = blocks.put_reader(data.as_slice(), Codec::Blake3, |digest| {
+            assert_eq!(digest.len(), data.len() as u64);
+            Ok(cid::Builder::new(Codec::Cidv1)
+                .with_target_codec(Codec::Identity)
+                .with_hash(&digest.multihash()?)
+                .try_build()?)
+        }, |_| Ok(())).unwrap();
+
+        // the Cid is the same as hashing the whole slice
+        assert_eq!(cid, put(&mut blocks, &data));
+        assert_eq!(blocks.get(&cid).unwrap(), data);
+        assert_eq!(blocks.len().unwrap(), 1);
+        assert_eq!(blocks.total_bytes().unwrap(), data.len() as u64);
+
+        // a Cid that doesn't address the data is refused
+        let other = put(&mut blocks, b"for great justice!");
+        let result = blocks.put_reader(&b"move every zig!"[..], Codec::Blake3, |_| Ok(other.clone()), |_| Ok(()));
+        assert!(matches!(result, Err(Error::FsStorage(FsStorageError::CorruptBlock(_)))));
+        assert_eq!(blocks.get(&other).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.len().unwrap(), 2);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_durability() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks37");
+
+        for durability in [Durability::None, Durability::SyncData, Durability::SyncAll] {
+            let mut blocks = Builder::new(&pb).with_durability(durability).try_build().unwrap();
+            assert_eq!(blocks.durability, durability);
+            let v = format!("durable {:?}", durability).into_bytes();
+            let cid = put(&mut blocks, &v);
+            assert_eq!(blocks.get(&cid).unwrap(), v);
+        }
+
+        // the durability isn't part of the layout so a store can be reopened with any of them
+        let blocks = Builder::new(&pb).try_build().unwrap();
+        assert_eq!(blocks.len().unwrap(), 4);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_journal_recovery() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks38");
+
+        let mut blocks = Builder::new(&pb).not_lazy().with_journal().try_build().unwrap();
+        let cid1 = put(&mut blocks, b"for great justice!");
+        let cid2 = put(&mut blocks, b"move every zig!");
+        assert_eq!(blocks.len().unwrap(), 2);
+
+        // completed operations leave nothing in the journal
+        let journal = pb.join(fsstorage::JOURNAL_DIR);
+        assert_eq!(fs::read_dir(&journal).unwrap().count(), 0);
+
+        // crash in the middle of an rm, after the delete but before the subfolder cleanup
+        let (_, subfolder, file, _) = blocks.get_paths(&cid1).unwrap();
+        let _intent = blocks.begin(Intent::Rm, &cid1).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(blocks.recover().unwrap(), 1);
+        assert_eq!(blocks.len().unwrap(), 1);
+        assert!(!subfolder.try_exists().unwrap() || fs::read_dir(&subfolder).unwrap().count() > 0);
+
+        // crash in the middle of a put, before the rename
+        let (ecid, subfolder, file, _) = blocks.get_paths(&cid2).unwrap();
+        let _intent = blocks.begin(Intent::Put, &cid2).unwrap();
+        fs::remove_file(&file).unwrap();
+        let temp = subfolder.join(format!(".tmpXYZ.{}", ecid));
+        fs::write(&temp, b"move every").unwrap();
+        assert_eq!(fs::read_dir(&journal).unwrap().count(), 1);
+
+        // reopening leaves the journal alone unless asked to recover
+        let _ = Builder::new(&pb).not_lazy().with_journal().try_build().unwrap();
+        assert_eq!(fs::read_dir(&journal).unwrap().count(), 1);
+        assert!(temp.try_exists().unwrap());
+
+        // recovering on open rolls back the put and fixes the counters, the temp file is only
+        // removed by the cleanup once it is old enough
+        let _ = Builder::new(&pb)
+            .not_lazy()
+            .with_journal()
+            .with_recover_on_open()
+            .with_cleanup_on_open(Duration::from_secs(3600))
+            .try_build()
+            .unwrap();
+        assert_eq!(fs::read_dir(&journal).unwrap().count(), 0);
+        assert!(temp.try_exists().unwrap());
+        let blocks = Builder::new(&pb).not_lazy().with_journal().with_cleanup_on_open(Duration::ZERO).try_build().unwrap();
+        assert!(!temp.try_exists().unwrap());
+        assert_eq!(blocks.len().unwrap(), 0);
+        assert_eq!(blocks.recover().unwrap(), 0);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks39");
+
+        for sharding in [Sharding::Middle, Sharding::Prefix { chars: 2, depth: 2 }] {
+            let root = pb.join(format!("{:?}", sharding).chars().filter(|c| c.is_alphanumeric()).collect::<String>());
+            let mut blocks = Builder::new(&root).with_sharding(sharding).try_build().unwrap();
+            let cid1 = put(&mut blocks, b"for great justice!");
+            let cid2 = put(&mut blocks, b"move every zig!");
+            let (ecid1, _, _, _) = blocks.get_paths(&cid1).unwrap();
+            let ecid1 = ecid1.to_string();
+
+            // a short prefix matches both, a long one only the block it was cut from
+            let all = blocks.scan_prefix(&ecid1[..1]).unwrap();
+            assert_eq!(all.len(), 2);
+            assert!(all.contains(&cid1) && all.contains(&cid2));
+            assert_eq!(blocks.scan_prefix(&ecid1[..12]).unwrap(), vec![cid1.clone()]);
+            assert!(blocks.scan_prefix("nope").unwrap().is_empty());
+        }
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_ordered_ids() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks40");
+
+        // the same blocks put in different orders into differently sharded stores
+        let data: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 10]).collect();
+        let mut b1 = Builder::new(pb.join("a")).try_build().unwrap();
+        let mut b2 = Builder::new(pb.join("b")).with_sharding(Sharding::Suffix { chars: 1, depth: 2 }).try_build().unwrap();
+        for d in &data {
+            put(&mut b1, d);
+        }
+        for d in data.iter().rev() {
+            put(&mut b2, d);
+        }
+
+        let ids = b1.cids().unwrap();
+        assert_eq!(ids, b2.cids().unwrap());
+        let names: Vec<String> = ids.iter().map(|cid| b1.get_paths(cid).unwrap().0.to_string()).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn test_watch() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks41");
+
+        let mut blocks = Builder::new(&pb).not_lazy().try_build().unwrap();
+        let events = blocks.watch().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // a block dropped into its subfolder by another writer
+        let mut other_pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        other_pb.push(".fsblocks42");
+        let mut other = Builder::new(&other_pb).try_build().unwrap();
+        let cid = put(&mut other, b"for great justice!");
+        let (_, subfolder, file, _) = blocks.get_paths(&cid).unwrap();
+        let (_, _, src, _) = other.get_paths(&cid).unwrap();
+        fs::create_dir_all(&subfolder).unwrap();
+        fs::copy(&src, subfolder.join(".incoming")).unwrap();
+        fs::rename(subfolder.join(".incoming"), &file).unwrap();
+        assert!(fs::remove_dir_all(&other_pb).is_ok());
+        assert_eq!(events.recv_timeout(timeout).unwrap(), Event::BlockPut(cid.clone()));
+
+        blocks.rm(&cid).unwrap();
+        assert_eq!(events.recv_timeout(timeout).unwrap(), Event::BlockRemoved(cid));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_codec_index() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks43");
+
+        let mut blocks = Builder::new(&pb).with_codec_index().try_build().unwrap();
+        let blake3 = put(&mut blocks, b"for great justice!");
+        let sha3 = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::DagCbor)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b"move every zig!").unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap();
+        blocks.put_with_cid(&sha3, b"move every zig!", false).unwrap();
+
+        assert_eq!(blocks.cids_by_hash(Codec::Blake3).unwrap(), vec![blake3.clone()]);
+        assert_eq!(blocks.cids_by_hash(Codec::Sha3512).unwrap(), vec![sha3.clone()]);
+        assert_eq!(blocks.cids_by_target_codec(Codec::DagCbor).unwrap(), vec![sha3.clone()]);
+        assert!(blocks.cids_by_hash(Codec::Sha2256).unwrap().is_empty());
+
+        // removed blocks leave the index and a rebuild finds the rest again
+        blocks.rm(&blake3).unwrap();
+        assert!(blocks.cids_by_hash(Codec::Blake3).unwrap().is_empty());
+        assert_eq!(blocks.rebuild_codec_index().unwrap(), 1);
+        assert_eq!(blocks.cids_by_target_codec(Codec::DagCbor).unwrap(), vec![sha3]);
+
+        // a restored block is indexed again and an expired one leaves the index
+        blocks.restore(&blake3).unwrap();
+        assert_eq!(blocks.cids_by_hash(Codec::Blake3).unwrap(), vec![blake3.clone()]);
+        let expired = put_ttl(&mut blocks, b"someday", Duration::ZERO);
+        assert!(blocks.cids_by_hash(Codec::Blake3).unwrap().contains(&expired));
+        blocks.gc().unwrap();
+        assert_eq!(blocks.cids_by_hash(Codec::Blake3).unwrap(), vec![blake3]);
+
+        // the index has to be enabled
+        let plain = Builder::new(pb.join("plain")).try_build().unwrap();
+        assert!(plain.cids_by_hash(Codec::Blake3).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks44");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let small = put(&mut blocks, b"zig");
+        let large = put(&mut blocks, vec![0u8; 4096]);
+        let gone = put(&mut blocks, b"for great justice!");
+        blocks.rm(&gone).unwrap();
+
+        // a temp file left behind by a crashed writer
+        let (ecid, subfolder, _, _) = blocks.get_paths(&small).unwrap();
+        fs::write(subfolder.join(format!(".tmpabc.{}", ecid)), b"partial").unwrap();
+
+        let report = blocks.report().unwrap();
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.bytes, 3 + 4096);
+        assert_eq!(report.tombstones, 1);
+        assert_eq!(report.temp_files, 1);
+        assert_eq!(report.temp_bytes, 7);
+        assert_eq!(report.subfolders.iter().map(|s| s.entries).sum::<u64>(), 2);
+        let (elarge, _, _, _) = blocks.get_paths(&large).unwrap();
+        assert_eq!(report.largest[0], (elarge.to_string(), 4096));
+
+        // the report is meant to be handed to operators as JSON
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<fsstorage::UsageReport>(&json).unwrap(), report);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_pending_gc() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks45");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let kept = put(&mut blocks, b"zig");
+        let gone = put(&mut blocks, b"for great justice!");
+        blocks.rm(&gone).unwrap();
+        let (ecid, subfolder, _, _) = blocks.get_paths(&kept).unwrap();
+        let temp = subfolder.join(format!(".tmpabc.{}", ecid));
+        fs::write(&temp, b"partial").unwrap();
+
+        let mut pending = blocks.pending_gc().unwrap();
+        pending.sort_by_key(|p| p.path.clone());
+        let (_, _, _, tombstone) = blocks.get_paths(&gone).unwrap();
+        let mut expected = vec![(tombstone, fsstorage::PendingKind::Deleted), (temp, fsstorage::PendingKind::Temp)];
+        expected.sort_by_key(|e| e.0.clone());
+        assert_eq!(pending.iter().map(|p| (p.path.clone(), p.kind)).collect::<Vec<_>>(), expected);
+
+        // listing doesn't remove anything and gc() removes exactly what was listed
+        assert_eq!(blocks.pending_gc().unwrap().len(), 2);
+        blocks.gc().unwrap();
+        assert!(blocks.pending_gc().unwrap().is_empty());
+        assert!(blocks.exists(&kept).unwrap());
+
+        // recently deleted blocks kept by the policy aren't pending
+        let blocks = Builder::new(&pb)
+            .with_gc_policy(GcPolicy { retain_deleted: Duration::from_secs(3600) })
+            .try_build()
+            .unwrap();
+        blocks.rm(&kept).unwrap();
+        assert!(blocks.pending_gc().unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_cleanup_on_open() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks46");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        let gone = put(&mut blocks, b"move every zig!");
+        blocks.rm(&gone).unwrap();
+        let (ecid, subfolder, _, tombstone) = blocks.get_paths(&cid).unwrap();
+        let temp = subfolder.join(format!(".tmpabc.{}", ecid));
+        fs::write(&temp, b"partial").unwrap();
+        let staged = pb.join(".tmpxyz");
+        fs::write(&staged, b"partial").unwrap();
+
+        // files younger than the bound survive the open
+        let _ = Builder::new(&pb).with_cleanup_on_open(Duration::from_secs(3600)).try_build().unwrap();
+        assert!(temp.exists() && staged.exists());
+
+        // older ones are removed but the blocks and lazy deleted blocks are left alone
+        let blocks = Builder::new(&pb).with_cleanup_on_open(Duration::ZERO).try_build().unwrap();
+        assert!(!temp.exists() && !staged.exists());
+        assert!(blocks.exists(&cid).unwrap());
+        let (_, _, _, tombstone_gone) = blocks.get_paths(&gone).unwrap();
+        assert!(tombstone_gone.exists());
+        assert!(!tombstone.exists());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_meta() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks47");
+
+        let mut blocks = Builder::new(&pb).not_lazy().try_build().unwrap();
+        let meta = BlockMeta::default()
+            .with_content_type("text/plain")
+            .with_label("owner", "cats");
+        let cid = blocks.put_with_meta(&b"for great justice!", &meta, get_cid, |_| Ok(())).unwrap();
+        assert_eq!(blocks.meta(&cid).unwrap(), Some(meta));
+        assert_eq!(blocks.meta(&cid).unwrap().unwrap().content_type.as_deref(), Some("text/plain"));
+
+        // blocks stored without metadata don't have any
+        let plain = put(&mut blocks, b"move every zig!");
+        assert!(blocks.meta(&plain).unwrap().is_none());
+
+        // the sidecar goes with the block
+        blocks.rm(&cid).unwrap();
+        assert!(blocks.meta(&cid).unwrap().is_none());
+        assert!(blocks.set_meta(&cid, &BlockMeta::default()).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_dedup() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks48");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let data: &[u8] = b"for great justice!";
+
+        let cid = match blocks.put_outcome(&data, get_cid, |_| Ok(())).unwrap() {
+            PutOutcome::Stored(cid) => cid,
+            outcome => panic!("unexpected {:?}", outcome),
+        };
+        let (_, _, file, _) = blocks.get_paths(&cid).unwrap();
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        assert_eq!(blocks.dedup_stats().unwrap(), (0, 0));
+
+        // the second put leaves the stored file alone
+        assert_eq!(blocks.put_outcome(&data, get_cid, |_| Ok(())).unwrap(), PutOutcome::AlreadyExists(cid.clone()));
+        assert_eq!(blocks.put(&data, get_cid, |_| Ok(())).unwrap(), cid);
+        assert_eq!(fs::metadata(&file).unwrap().modified().unwrap(), modified);
+        assert_eq!(blocks.dedup_stats().unwrap(), (2 * data.len() as u64, 2));
+        assert_eq!(blocks.len().unwrap(), 1);
+
+        // torn counters start over instead of being trusted
+        fs::write(pb.join(DEDUP_FILE), b"12").unwrap();
+        assert_eq!(blocks.dedup_stats().unwrap(), (0, 0));
+        assert_eq!(blocks.put(&data, get_cid, |_| Ok(())).unwrap(), cid);
+        assert_eq!(blocks.dedup_stats().unwrap(), (data.len() as u64, 1));
+
+        // a lazy deleted block is stored again
+        blocks.rm(&cid).unwrap();
+        assert_eq!(blocks.put_outcome(&data, get_cid, |_| Ok(())).unwrap(), PutOutcome::Stored(cid));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_gc_step() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks49");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let budget = fsstorage::GcBudget { max_entries: Some(1), max_time: None };
+
+        // expired blocks count against the budget, one per step
+        let expired: Vec<Cid> = (10..13u8).map(|i| put_ttl(&mut blocks, [i], Duration::ZERO)).collect();
+        let mut cursor = fsstorage::GcCursor::default();
+        for left in (0..expired.len()).rev() {
+            cursor = blocks.gc_step(cursor, budget).unwrap().unwrap();
+            assert_eq!(expired.iter().filter(|cid| blocks.exists(cid).unwrap()).count(), left);
+        }
+        while let Some(next) = blocks.gc_step(cursor, budget).unwrap() {
+            cursor = next;
+        }
+
+        let mut subfolders = Vec::default();
+        for i in 0..10u8 {
+            let cid = put(&mut blocks, [i]);
+            blocks.rm(&cid).unwrap();
+            subfolders.push(blocks.get_paths(&cid).unwrap().1);
+        }
+        subfolders.sort();
+        subfolders.dedup();
+
+        // each step collects one subfolder of lazy deleted files and the last one ends the pass
+        let mut cursor = fsstorage::GcCursor::default();
+        let mut steps = 0;
+        while let Some(next) = blocks.gc_step(cursor, budget).unwrap() {
+            cursor = next;
+            steps += 1;
+            assert!(steps <= 20);
+        }
+        assert_eq!(steps, subfolders.len() - 1);
+        assert!(blocks.pending_gc().unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_reserved_bytes() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks50");
+
+        // no filesystem has this much room so every put eats into the reserve
+        let mut blocks = Builder::new(&pb).with_reserved_bytes(u64::MAX / 2).try_build().unwrap();
+        let result = blocks.put(&b"for great justice!", get_cid, |_| Ok(()));
+        assert!(matches!(result, Err(Error::StoreFull { needed: 18, .. })));
+
+        // streamed puts are refused before anything is written
+        #[cfg(feature = "digest")]
+        let result = blocks.put_reader(&b"for great justice!"[..], Codec::Blake3, |digest| {
+            Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&digest.multihash()?).try_build()?)
+        }, |_| Ok(()));
+        #[cfg(feature = "digest")]
+        assert!(matches!(result, Err(Error::StoreFull { needed: 0, .. })));
+
+        // the failed put left nothing behind
+        assert_eq!(blocks.len().unwrap(), 0);
+        assert!(blocks.pending_gc().unwrap().is_empty());
+
+        // a small reserve leaves room for the block
+        let mut blocks = Builder::new(&pb).with_reserved_bytes(1).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        assert!(blocks.exists(&cid).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_max_block_size() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks55");
+
+        let mut blocks = Builder::new(&pb).with_max_block_size(16).try_build().unwrap();
+        let result = blocks.put(&b"for great justice!", get_cid, |_| Ok(()));
+        assert!(matches!(result, Err(Error::BlockTooLarge { size: 18, limit: 16 })));
+        assert_eq!(blocks.len().unwrap(), 0);
+
+        // streamed blocks are refused once they grow past the limit
+        #[cfg(feature = "digest")]
+        let result = blocks.put_reader(&b"for great justice!"[..], Codec::Blake3, |digest| {
+            Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&digest.multihash()?).try_build()?)
+        }, |_| Ok(()));
+        #[cfg(feature = "digest")]
+        assert!(matches!(result, Err(Error::BlockTooLarge { size: 18, limit: 16 })));
+
+        // blocks at the limit are fine
+        let cid = put(&mut blocks, b"move every zig!!");
+        assert!(blocks.exists(&cid).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_allowed_hashes() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks56");
+
+        let mut blocks = Builder::new(&pb).with_allowed_hashes(&[Codec::Sha3512]).try_build().unwrap();
+        let result = blocks.put(&b"for great justice!", get_cid, |_| Ok(()));
+        assert!(matches!(result, Err(Error::PolicyViolation(_))));
+        assert_eq!(blocks.len().unwrap(), 0);
+
+        // the allowed hashes are accepted
+        let mut blocks = Builder::new(&pb).with_allowed_hashes(&[Codec::Blake3, Codec::Sha3512]).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        assert!(blocks.exists(&cid).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_default() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks57");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        assert!(blocks.put_default(b"for great justice!").is_err());
+
+        let mut blocks = Builder::new(&pb).with_cid_config(Codec::Identity, Codec::Blake3).try_build().unwrap();
+        let cid = blocks.put_default(b"for great justice!").unwrap();
+        assert_eq!(cid, put(&mut blocks, b"for great justice!"));
+        assert_eq!(blocks.get_verified(&cid).unwrap(), b"for great justice!".to_vec());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_post_commit() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks58");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let committed = std::cell::RefCell::new(Vec::default());
+        let cid = blocks.put_with_post_commit(&&b"for great justice!"[..], get_cid, |_| Ok(()), |cid, path| {
+            // the block is already in place when the hook runs
+            assert!(path.is_file());
+            committed.borrow_mut().push(cid.clone());
+            Ok(())
+        }).unwrap();
+        assert_eq!(*committed.borrow(), vec![cid.clone()]);
+
+        // a failing pre_commit never reaches the hook
+        let result = blocks.put_with_post_commit(&&b"move every zig!"[..], get_cid, |_| Err(Error::Custom("no".to_string())), |_, _| {
+            panic!("post_commit called");
+        });
+        assert!(result.is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        assert!(fsstorage::is_reserved_name("con"));
+        assert!(fsstorage::is_reserved_name("NUL"));
+        assert!(fsstorage::is_reserved_name("com1.txt"));
+        assert!(!fsstorage::is_reserved_name("com0"));
+        assert!(!fsstorage::is_reserved_name("cons"));
+        assert!(!fsstorage::is_reserved_name("ybnd"));
+    }
+
+    #[test]
+    fn test_long_names() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks62");
+
+        // a Cid in base 2 is too long to be a file name so it is stored under its hash
+        let mut blocks = Builder::new(&pb).with_base_encoding(Base::Base2).try_build().unwrap();
+
+        // only a put records the encoded Cid behind the hashed name, reads don't write
+        let cid = get_cid(b"for great justice!").unwrap();
+        assert!(!blocks.exists(&cid).unwrap());
+        assert!(blocks.get(&cid).is_err());
+        assert!(!pb.join(fsstorage::LONG_NAMES_DIR).exists());
+
+        let cid = put(&mut blocks, b"for great justice!");
+        let (name, _, file, _) = blocks.get_paths(&cid).unwrap();
+        assert!(name.starts_with('_') && file.is_file());
+        assert!(pb.join(fsstorage::LONG_NAMES_DIR).join(&name).is_file());
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(blocks.cids().unwrap(), vec![cid.clone()]);
+        assert!(blocks.check(Repair::None, |_, _| true).unwrap().is_clean());
+
+        // the hashed name goes through lazy deletes and migrations
+        let _ = blocks.rm(&cid).unwrap();
+        blocks.restore(&cid).unwrap();
+        blocks.migrate_encoding(Base::Base32Z).unwrap();
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+        blocks.migrate_encoding(Base::Base2).unwrap();
+        assert_eq!(blocks.cids().unwrap(), vec![cid]);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_staging_dir() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks51");
+        let mut staging = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        staging.push(".fsblocks52");
+
+        let mut blocks = Builder::new(&pb).with_staging_dir(&staging).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        #[cfg(feature = "digest")]
+        {
+            let data = b"move every zig!".to_vec();
+            let streamed = blocks.put_reader(data.as_slice(), Codec::Blake3, |digest| {
+                Ok(cid::Builder::new(Codec::Cidv1)
+                    .with_target_codec(Codec::Identity)
+                    .with_hash(&digest.multihash()?)
+                    .try_build()?)
+            }, |_| Ok(())).unwrap();
+            assert_eq!(blocks.get(&streamed).unwrap(), data);
+        }
+
+        // the blocks end up in the store and nothing is left in the staging dir
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+
+        // staged files left by a crashed writer are cleaned up
+        fs::write(staging.join(".tmpabc"), b"partial").unwrap();
+        assert_eq!(blocks.cleanup_temp_files(Duration::ZERO).unwrap(), 1);
+
+        // the staging dir is kept in the config for the next open
+        let reopened = Builder::new(&pb).try_build().unwrap();
+        assert_eq!(reopened.staging_dir, Some(staging.clone()));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+        assert!(fs::remove_dir_all(&staging).is_ok());
+    }
+
+    #[test]
+    fn test_io_error_context() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsblocks64");
+
+        let mut blocks = Builder::new(&pb).not_lazy().try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        let (ecid, _, file, _) = blocks.get_paths(&cid).unwrap();
+        fs::remove_file(&file).unwrap();
+
+        // the error names the block by its encoded Cid and keeps the path for callers
+        let err = blocks.get(&cid).unwrap_err();
+        assert!(matches!(&err, Error::Io { op: "open", id: Some(id), path, .. } if *id == ecid && *path == file));
+        assert!(err.to_string().starts_with(&format!("open {}: ", ecid)));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
 }
@@ -0,0 +1,349 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Error, fsstorage::{self, EntryKey, FsStorage, MapKey}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use multiutil::EncodingInfo;
+use std::path::{Path, PathBuf};
+
+/// A Cid used as the key of a FsCidMap. The wrapper keeps FsCidMap a distinct type from
+/// FsBlocks so the CidMap and Blocks methods never collide.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CidKey(pub Cid);
+
+impl EncodingInfo for CidKey {
+    fn preferred_encoding() -> Base {
+        Cid::preferred_encoding()
+    }
+
+    fn encoding(&self) -> Base {
+        self.0.encoding()
+    }
+}
+
+impl From<CidKey> for Vec<u8> {
+    fn from(key: CidKey) -> Self {
+        key.0.into()
+    }
+}
+
+impl TryFrom<&[u8]> for CidKey {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(CidKey(Cid::try_from(bytes)?))
+    }
+}
+
+/// The FsCidMap type maps Cids to Cids
+pub type FsCidMap = FsStorage<CidKey>;
+
+/// Builder for a FsCidMap instance
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+    root: PathBuf,
+    lazy: bool,
+    base_encoding: Option<Base>,
+    history: bool,
+    reverse_index: bool,
+}
+
+impl Builder {
+    /// create a new builder from the root path, this defaults to lazy
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("fscid_map::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            lazy: true,
+            base_encoding: None,
+            history: false,
+            reverse_index: false,
+        }
+    }
+
+    /// set lazy to false
+    pub fn not_lazy(mut self) -> Self {
+        self.lazy = false;
+        self
+    }
+
+    /// set the encoding codec to use for Cids, it needs at least 32 symbols
+    pub fn with_base_encoding(mut self, base: Base) -> Self {
+        self.base_encoding = Some(base);
+        self
+    }
+
+    /// keep the values replaced by each put in a per-mapping history
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    /// maintain a reverse index from Cids to the IDs that point at them so referrers() works
+    pub fn with_reverse_index(mut self) -> Self {
+        self.reverse_index = true;
+        self
+    }
+
+    /// build the instance
+    pub fn try_build(&self) -> Result<FsCidMap, Error> {
+        let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
+        FsCidMap::check_map_encoding(&base_encoding)?;
+
+        let mut builder = fsstorage::Builder::<CidKey>::new(&self.root).with_base_encoding(base_encoding);
+        if !self.lazy {
+            builder = builder.not_lazy();
+        }
+        if self.history {
+            builder = builder.with_history();
+        }
+        if self.reverse_index {
+            builder = builder.with_reverse_index();
+        }
+
+        builder.try_build()
+    }
+}
+
+impl EntryKey<CidKey> for Cid {
+    fn entry_key(&self) -> Result<CidKey, Error> {
+        Ok(CidKey(self.clone()))
+    }
+
+    fn from_entry_key(key: CidKey) -> Option<Self> {
+        Some(key.0)
+    }
+}
+
+impl MapKey for CidKey {
+    type Id = Cid;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fsblocks, traits::cid_map::{self, Format}, Blocks, CidMap};
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::fs;
+
+    // returns a Cid for the passed in data
+    fn get_cid(b: &[u8]) -> Cid {
+        cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Identity)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b).unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_builder_lazy() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap1");
+
+        let cm = Builder::new(&pb).try_build().unwrap();
+        assert_eq!(cm.root, pb);
+        assert_eq!(cm.lazy, true);
+        assert_eq!(cm.base_encoding, Base::Base32Z);
+        assert!(pb.try_exists().is_ok());
+        assert!(pb.is_dir());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_builder_small_base() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap8");
+
+        assert!(Builder::new(&pb).with_base_encoding(Base::Base16Lower).try_build().is_err());
+        assert!(Builder::new(&pb).with_base_encoding(Base::Base2).try_build().is_err());
+        assert!(!pb.try_exists().unwrap());
+    }
+
+    #[test]
+    fn test_put_lazy() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap2");
+
+        let mut cm = Builder::new(&pb).try_build().unwrap();
+
+        let doc = get_cid(b"for great justice!");
+        let v1 = get_cid(b"move every zig!");
+        let v2 = get_cid(b"someday");
+        assert!(cm.put(&doc, &v1).unwrap().is_none());
+        assert_eq!(cm.get(&doc).unwrap(), v1);
+
+        // updating the pointer returns the previous value
+        assert_eq!(cm.put(&doc, &v2).unwrap(), Some(v1));
+        assert_eq!(cm.get(&doc).unwrap(), v2);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_rm_not_lazy() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap3");
+
+        let mut cm = Builder::new(&pb).not_lazy().try_build().unwrap();
+
+        let doc = get_cid(b"for great justice!");
+        let cid1 = get_cid(b"move every zig!");
+        let _ = cm.put(&doc, &cid1).unwrap();
+
+        // get the paths to the subfolder and file created from the put
+        let (_, subfolder, file, lazy_deleted_file) = cm.get_paths(&CidKey(doc.clone())).unwrap();
+
+        let cid2 = cm.rm(&doc).unwrap();
+        assert_eq!(cid1, cid2);
+
+        assert!(!lazy_deleted_file.try_exists().unwrap());
+        assert!(!file.try_exists().unwrap());
+        assert!(!subfolder.try_exists().unwrap());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_alongside_blocks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap4");
+        let mut blocks_root = pb.clone();
+        blocks_root.push("blocks");
+        let mut map_root = pb.clone();
+        map_root.push("latest");
+
+        let mut blocks = fsblocks::Builder::new(&blocks_root).try_build().unwrap();
+        let mut cm = Builder::new(&map_root).try_build().unwrap();
+
+        // store two versions of a document and point the first at the second
+        let v1 = blocks.put(&b"version 1", |d| Ok(get_cid(*d)), |_| Ok(())).unwrap();
+        let v2 = blocks.put(&b"version 2", |d| Ok(get_cid(*d)), |_| Ok(())).unwrap();
+        let _ = cm.put(&v1, &v2).unwrap();
+
+        let latest = cm.get(&v1).unwrap();
+        assert_eq!(blocks.get(&latest).unwrap(), b"version 2".to_vec());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_batch() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap5");
+
+        let mut cm = fsstorage::Builder::<CidKey>::new(&pb).with_history().try_build().unwrap();
+        let docs: Vec<Cid> = (0..50u8).map(|i| get_cid(&[i])).collect();
+        let entries: Vec<(Cid, Cid)> = docs.iter().map(|doc| (doc.clone(), get_cid(b"move every zig!"))).collect();
+        assert!(cm.put_batch(&entries).into_iter().all(|r| r.unwrap().is_none()));
+        for doc in &docs {
+            assert_eq!(cm.get(doc).unwrap(), get_cid(b"move every zig!"));
+        }
+
+        // a second batch returns the previous values and records them in the history
+        let entries: Vec<(Cid, Cid)> = docs.iter().map(|doc| (doc.clone(), get_cid(b"someday"))).collect();
+        for result in cm.put_batch(&entries) {
+            assert_eq!(result.unwrap(), Some(get_cid(b"move every zig!")));
+        }
+        assert_eq!(cm.history(&docs[0]).unwrap().len(), 1);
+
+        // removing in a batch reports each entry
+        let results = cm.rm_batch(&docs[..10]);
+        assert!(results.iter().all(|r| r.as_ref().unwrap() == &get_cid(b"someday")));
+        assert!(cm.rm_batch(&docs[..1])[0].is_err());
+
+        // the batches keep the usage counters in step with what is on disk
+        let stored = cm.stored_bytes().unwrap();
+        assert_eq!(cm.rebuild_usage().unwrap(), stored);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_reserved_bytes() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap9");
+
+        // a put that would eat into the reserve fails and leaves the map as it was
+        let mut cm = fsstorage::Builder::<CidKey>::new(&pb).with_reserved_bytes(u64::MAX / 2).try_build().unwrap();
+        let doc = get_cid(b"for great justice!");
+        assert!(matches!(cm.put(&doc, &get_cid(b"move every zig!")), Err(Error::StoreFull { .. })));
+        assert!(!cm.exists(&doc).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_staging_dir() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap10");
+        let mut staging = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        staging.push(".fscidmap11");
+
+        // map puts are staged too and nothing is left behind
+        let mut cm = fsstorage::Builder::<CidKey>::new(&pb).with_staging_dir(&staging).try_build().unwrap();
+        let doc = get_cid(b"for great justice!");
+        assert!(cm.put(&doc, &get_cid(b"move every zig!")).unwrap().is_none());
+        assert_eq!(cm.get(&doc).unwrap(), get_cid(b"move every zig!"));
+        assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+        assert!(fs::remove_dir_all(&staging).is_ok());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap6");
+
+        let mut cm = Builder::new(pb.join("src")).try_build().unwrap();
+        for i in 0..10u8 {
+            cm.put(&get_cid(&[i]), &get_cid(&[i, i])).unwrap();
+        }
+
+        for (format, name) in [(Format::Json, "json"), (Format::Cbor, "cbor")] {
+            let mut dump = Vec::default();
+            assert_eq!(cm.export(&mut dump, format).unwrap(), 10);
+
+            // the same mappings always produce the same dump
+            let mut again = Vec::default();
+            cm.export(&mut again, format).unwrap();
+            assert_eq!(dump, again);
+
+            let mut restored = Builder::new(pb.join(name)).try_build().unwrap();
+            assert_eq!(restored.import(dump.as_slice()).unwrap(), 10);
+            for i in 0..10u8 {
+                assert_eq!(restored.get(&get_cid(&[i])).unwrap(), get_cid(&[i, i]));
+            }
+        }
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_merge_from() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmap7");
+
+        let mut ours = Builder::new(pb.join("ours")).try_build().unwrap();
+        let mut theirs = Builder::new(pb.join("theirs")).try_build().unwrap();
+        ours.put(&get_cid(b"a"), &get_cid(b"ours")).unwrap();
+        ours.put(&get_cid(b"b"), &get_cid(b"same")).unwrap();
+        theirs.put(&get_cid(b"a"), &get_cid(b"theirs")).unwrap();
+        theirs.put(&get_cid(b"b"), &get_cid(b"same")).unwrap();
+        theirs.put(&get_cid(b"c"), &get_cid(b"new")).unwrap();
+
+        // keeping the existing mappings only adds the new one
+        assert_eq!(ours.merge_from(&theirs, cid_map::keep_existing).unwrap(), 1);
+        assert_eq!(ours.get(&get_cid(b"a")).unwrap(), get_cid(b"ours"));
+        assert_eq!(ours.get(&get_cid(b"c")).unwrap(), get_cid(b"new"));
+
+        // last writer wins takes the conflicting mapping too
+        assert_eq!(ours.merge_from(&theirs, cid_map::last_writer_wins).unwrap(), 1);
+        assert_eq!(ours.get(&get_cid(b"a")).unwrap(), get_cid(b"theirs"));
+        assert_eq!(ours.get(&get_cid(b"b")).unwrap(), get_cid(b"same"));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
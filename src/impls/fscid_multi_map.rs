@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{CidMultiMap, Error, error::{FsStorageError, IoContext}, Event, fsstorage::{self, FsStorage, Intent}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use multitrait::TryDecodeFrom;
+use multiutil::EncodingInfo;
+use std::{fs, io::ErrorKind, marker::PhantomData, path::{Path, PathBuf}};
+
+/// Filesystem backed mapping from an ID to a set of Cids. Each ID has one file holding its Cids
+/// one after the other in the order they were added.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsCidMultiMap<ID>
+where
+    ID: EncodingInfo
+{
+    /// The underlying storage
+    pub storage: FsStorage<ID>,
+}
+
+/// Builder for a FsCidMultiMap instance
+#[derive(Clone, Debug)]
+pub struct Builder<ID> {
+    root: PathBuf,
+    lazy: bool,
+    base_encoding: Option<Base>,
+    _t: PhantomData<ID>,
+}
+
+impl<ID> Builder<ID>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>>
+{
+    /// create a new builder from the root path, this defaults to lazy
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("fscid_multi_map::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            lazy: true,
+            base_encoding: None,
+            _t: PhantomData,
+        }
+    }
+
+    /// set lazy to false
+    pub fn not_lazy(mut self) -> Self {
+        self.lazy = false;
+        self
+    }
+
+    /// set the encoding codec to use for IDs
+    pub fn with_base_encoding(mut self, base: Base) -> Self {
+        self.base_encoding = Some(base);
+        self
+    }
+
+    /// build the instance
+    pub fn try_build(&self) -> Result<FsCidMultiMap<ID>, Error> {
+        let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
+
+        let mut builder = fsstorage::Builder::<ID>::new(&self.root).with_base_encoding(base_encoding);
+        if !self.lazy {
+            builder = builder.not_lazy();
+        }
+
+        Ok(FsCidMultiMap {
+            storage: builder.try_build()?,
+        })
+    }
+}
+
+impl<ID> FsCidMultiMap<ID>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>>,
+{
+    // replace the set of Cids the ID maps to, an empty set removes the mapping
+    fn write(&self, id: &ID, cids: &[Cid]) -> Result<(), Error> {
+        let (eid, subfolder, file, _) = self.storage.get_paths(id)?;
+        let intent = self.storage.begin(if cids.is_empty() { Intent::Rm } else { Intent::Put }, id)?;
+
+        if cids.is_empty() {
+            self.storage.remove_entry(id)?;
+            return intent.commit();
+        }
+
+        // check if it exists and is a dir...otherwise create the dir
+        if subfolder.try_exists().io_context("stat", &subfolder)? {
+            if !subfolder.is_dir() {
+                return Err(FsStorageError::NotDir(subfolder).into());
+            }
+        } else {
+            fs::create_dir_all(&subfolder).io_context("create dir", &subfolder)?;
+            debug!("fscid_multi_map: Created subfolder at: {}", subfolder.display());
+        }
+
+        let data: Vec<u8> = cids.iter().flat_map(|cid| Vec::<u8>::from(cid.clone())).collect();
+        let temp = self.storage.stage_entry(&eid, &subfolder, &data)?;
+        self.storage.record_name(id)?;
+
+        // atomically rename/move it to the correct location
+        self.storage.persist(temp, &file)?;
+        intent.commit()
+    }
+}
+
+impl<ID> CidMultiMap<ID> for FsCidMultiMap<ID>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>>,
+{
+    type Error = Error;
+
+    fn add(&mut self, id: &ID, cid: &Cid) -> Result<bool, Self::Error> {
+        // hold the entry lock so concurrent adds and removes don't lose each other's updates
+        let _lock = self.storage.lock(id)?;
+        let mut cids = self.get_all(id)?;
+        if cids.contains(cid) {
+            return Ok(false);
+        }
+        cids.push(cid.clone());
+        self.write(id, &cids)?;
+        self.storage.notify(Event::MapUpdated(id.clone(), cid.clone()));
+        Ok(true)
+    }
+
+    fn remove(&mut self, id: &ID, cid: &Cid) -> Result<bool, Self::Error> {
+        let _lock = self.storage.lock(id)?;
+        let mut cids = self.get_all(id)?;
+        let len = cids.len();
+        cids.retain(|c| c != cid);
+        if cids.len() == len {
+            return Ok(false);
+        }
+        self.write(id, &cids)?;
+        self.storage.notify(Event::MapRemoved(id.clone(), cid.clone()));
+        Ok(true)
+    }
+
+    fn get_all(&self, id: &ID) -> Result<Vec<Cid>, Self::Error> {
+        let (eid, _, file, _) = self.storage.get_paths(id)?;
+        let data = match fs::read(&file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::default()),
+            Err(e) => return Err(e).io_context("read", &file),
+        };
+
+        let mut cids = Vec::default();
+        let mut ptr = data.as_slice();
+        while !ptr.is_empty() {
+            let (cid, p) = Cid::try_decode_from(ptr).map_err(|_| FsStorageError::InvalidValue(eid.to_string()))?;
+            cids.push(cid);
+            ptr = p;
+        }
+        Ok(cids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fscid_map::CidKey;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    // returns a Cid for the passed in data
+    fn get_cid(b: &[u8]) -> Cid {
+        cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::DagCbor)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b).unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fscidmultimap1");
+
+        let mut map = Builder::<CidKey>::new(&pb).not_lazy().try_build().unwrap();
+        let id = CidKey(get_cid(b"for great justice!"));
+        let doc1 = get_cid(b"move every zig!");
+        let doc2 = get_cid(b"take off every zig!");
+
+        assert!(map.get_all(&id).unwrap().is_empty());
+        assert!(map.add(&id, &doc1).unwrap());
+        assert!(map.add(&id, &doc2).unwrap());
+        assert!(!map.add(&id, &doc1).unwrap());
+        assert_eq!(map.get_all(&id).unwrap(), vec![doc1.clone(), doc2.clone()]);
+        assert!(map.contains(&id, &doc2).unwrap());
+
+        assert!(map.remove(&id, &doc1).unwrap());
+        assert!(!map.remove(&id, &doc1).unwrap());
+        assert_eq!(map.get_all(&id).unwrap(), vec![doc2.clone()]);
+
+        // removing the last Cid removes the mapping
+        assert!(map.remove(&id, &doc2).unwrap());
+        let (_, _, file, _) = map.storage.get_paths(&id).unwrap();
+        assert!(!file.try_exists().unwrap());
+        assert!(map.get_all(&id).unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
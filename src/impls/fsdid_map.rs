@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Error, error::FsStorageError, fsstorage::{self, FsStorage, MapKey, FILE_NAME_OVERHEAD, MAX_FILE_NAME_LEN}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use multiutil::EncodingInfo;
+use std::{fmt, path::{Path, PathBuf}, str::FromStr};
+
+/// DIDs longer than this many bytes are rejected so that the encoded file name stays within
+/// filesystem limits. the maps encode with at least 32 symbols, so 5 bits per character, and
+/// the multibase prefix takes one more.
+pub const MAX_DID_LEN: usize = (MAX_FILE_NAME_LEN - FILE_NAME_OVERHEAD - 1) * 5 / 8;
+
+/// A decentralized identifier of the form "did:<method>:<method specific id>". Only the shape of
+/// the DID is checked, resolving it is up to the DID method.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Did(String);
+
+impl Did {
+    /// the DID method, e.g. "key" for "did:key:z6Mk..."
+    pub fn method(&self) -> &str {
+        self.0.split(':').nth(1).unwrap_or_default()
+    }
+
+    /// the method specific id, everything after the method
+    pub fn method_id(&self) -> &str {
+        self.0.splitn(3, ':').nth(2).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Did {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for Did {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Did {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || -> Error { FsStorageError::InvalidId(s.to_string()).into() };
+        if s.len() > MAX_DID_LEN {
+            return Err(invalid());
+        }
+        let mut parts = s.splitn(3, ':');
+        let (Some("did"), Some(method), Some(id)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        if id.is_empty() || id.ends_with(':') {
+            return Err(invalid());
+        }
+        Ok(Did(s.to_string()))
+    }
+}
+
+impl EncodingInfo for Did {
+    fn preferred_encoding() -> Base {
+        Base::Base32Z
+    }
+
+    fn encoding(&self) -> Base {
+        Self::preferred_encoding()
+    }
+}
+
+impl From<Did> for Vec<u8> {
+    fn from(did: Did) -> Self {
+        did.0.into_bytes()
+    }
+}
+
+impl TryFrom<&[u8]> for Did {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        std::str::from_utf8(bytes)
+            .map_err(|_| FsStorageError::InvalidId(String::from_utf8_lossy(bytes).to_string()))?
+            .parse()
+    }
+}
+
+/// The FsDidMap type maps DIDs to the Cid of their current document
+pub type FsDidMap = FsStorage<Did>;
+
+/// Builder for a FsDidMap instance
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+    root: PathBuf,
+    lazy: bool,
+    base_encoding: Option<Base>,
+    history: bool,
+}
+
+impl Builder {
+    /// create a new builder from the root path, this defaults to lazy
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("fsdid_map::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            lazy: true,
+            base_encoding: None,
+            history: false,
+        }
+    }
+
+    /// set lazy to false
+    pub fn not_lazy(mut self) -> Self {
+        self.lazy = false;
+        self
+    }
+
+    /// set the encoding codec to use for DIDs, it needs at least 32 symbols
+    pub fn with_base_encoding(mut self, base: Base) -> Self {
+        self.base_encoding = Some(base);
+        self
+    }
+
+    /// keep the values replaced by each put in a per-mapping history
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    /// build the instance
+    pub fn try_build(&self) -> Result<FsDidMap, Error> {
+        let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
+        FsDidMap::check_map_encoding(&base_encoding)?;
+
+        let mut builder = fsstorage::Builder::<Did>::new(&self.root).with_base_encoding(base_encoding);
+        if !self.lazy {
+            builder = builder.not_lazy();
+        }
+        if self.history {
+            builder = builder.with_history();
+        }
+
+        builder.try_build()
+    }
+}
+
+impl MapKey for Did {
+    type Id = Did;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CidMap;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::fs;
+
+    // returns a Cid for the passed in data
+    fn get_cid(b: &[u8]) -> Cid {
+        cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::DagCbor)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b).unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let did: Did = "did:web:example.com:user:alice".parse().unwrap();
+        assert_eq!(did.method(), "web");
+        assert_eq!(did.method_id(), "example.com:user:alice");
+        assert_eq!(did.to_string(), "did:web:example.com:user:alice");
+
+        for bad in ["", "did:", "did:key", "did::abc", "did:Key:abc", "did:key:", "urn:key:abc"] {
+            assert!(bad.parse::<Did>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_put_get_rm() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsdidmap1");
+
+        let mut dm = Builder::new(&pb).not_lazy().try_build().unwrap();
+
+        let did: Did = "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK".parse().unwrap();
+        let doc1 = get_cid(b"move every zig!");
+        let doc2 = get_cid(b"take off every zig!");
+        assert!(dm.put(&did, &doc1).unwrap().is_none());
+        assert_eq!(dm.put(&did, &doc2).unwrap(), Some(doc1));
+        assert_eq!(dm.get(&did).unwrap(), doc2);
+        assert_eq!(CidMap::ids(&dm).unwrap(), vec![did.clone()]);
+
+        assert_eq!(dm.rm(&did).unwrap(), doc2);
+        assert!(!dm.exists(&did).unwrap());
+
+        // the longest DID still fits in a file name
+        let id = "a".repeat(MAX_DID_LEN - "did:web:".len());
+        let did: Did = format!("did:web:{}", id).parse().unwrap();
+        assert!(dm.put(&did, &doc1).unwrap().is_none());
+        assert_eq!(dm.get(&did).unwrap(), doc1);
+        assert!(format!("did:web:{}a", id).parse::<Did>().is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Error, error::{FsStorageError, IoContext}, fsstorage::{self, FsStorage, Intent}, KvMap};
+use log::debug;
+use multibase::Base;
+use multiutil::EncodingInfo;
+use std::{fs::{self, File}, io::{ErrorKind, Read}, marker::PhantomData, path::{Path, PathBuf}};
+
+/// Filesystem backed mapping from an ID to any value that can be converted to and from bytes
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsKvMap<ID, V>
+where
+    ID: EncodingInfo
+{
+    /// The underlying storage
+    pub storage: FsStorage<ID>,
+
+    // phantoms
+    _v: PhantomData<V>,
+}
+
+/// Builder for a FsKvMap instance
+#[derive(Clone, Debug)]
+pub struct Builder<ID, V> {
+    root: PathBuf,
+    lazy: bool,
+    base_encoding: Option<Base>,
+    _t: PhantomData<(ID, V)>,
+}
+
+impl<ID, V> Builder<ID, V>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>>
+{
+    /// create a new builder from the root path, this defaults to lazy
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("fskv_map::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            lazy: true,
+            base_encoding: None,
+            _t: PhantomData,
+        }
+    }
+
+    /// set lazy to false
+    pub fn not_lazy(mut self) -> Self {
+        self.lazy = false;
+        self
+    }
+
+    /// set the encoding codec to use for IDs
+    pub fn with_base_encoding(mut self, base: Base) -> Self {
+        self.base_encoding = Some(base);
+        self
+    }
+
+    /// build the instance
+    pub fn try_build(&self) -> Result<FsKvMap<ID, V>, Error> {
+        let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
+
+        let mut builder = fsstorage::Builder::<ID>::new(&self.root).with_base_encoding(base_encoding);
+        if !self.lazy {
+            builder = builder.not_lazy();
+        }
+
+        Ok(FsKvMap {
+            storage: builder.try_build()?,
+            _v: PhantomData,
+        })
+    }
+}
+
+impl<ID, V> KvMap<ID, V> for FsKvMap<ID, V>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>>,
+    V: Clone + Into<Vec<u8>> + for<'a> TryFrom<&'a [u8]>,
+{
+    type Error = Error;
+
+    fn exists(&self, id: &ID) -> Result<bool, Self::Error> {
+        // get the paths
+        let (_, _, file, _) = self.storage.get_paths(id)?;
+        Ok(file.try_exists().io_context("stat", &file)?)
+    }
+
+    fn get(&self, id: &ID) -> Result<V, Self::Error> {
+        // get the paths
+        let (eid, subfolder, file, _) = self.storage.get_paths(id)?;
+
+        // check if it exists and is a dir
+        if subfolder.try_exists().io_context("stat", &subfolder)? {
+            if !subfolder.is_dir() {
+                return Err(FsStorageError::NotDir(subfolder).into());
+            }
+        } else {
+            return Err(FsStorageError::NoSuchData(eid.to_string()).into());
+        }
+
+        // read the value from the filesystem
+        debug!("fskv_map: Getting value from: {}", file.display());
+        let mut f = File::open(&file).io_context("open", &file)?;
+        let mut data = Vec::default();
+        f.read_to_end(&mut data).io_context("read", &file)?;
+
+        // reconstruct the value from the data
+        V::try_from(data.as_slice()).map_err(|_| FsStorageError::InvalidValue(eid.to_string()).into())
+    }
+
+    fn put(&mut self, id: &ID, value: &V) -> Result<Option<V>, Self::Error> {
+        // get the paths
+        let (eid, subfolder, file, _) = self.storage.get_paths(id)?;
+
+        // hold the entry lock so a racing put can't land between reading the old value and
+        // replacing it
+        let _lock = self.storage.lock(id)?;
+
+        // check if it exists and is a dir...otherwise create the dir
+        if subfolder.try_exists().io_context("stat", &subfolder)? {
+            if !subfolder.is_dir() {
+                return Err(FsStorageError::NotDir(subfolder).into());
+            }
+        } else {
+            fs::create_dir_all(&subfolder).io_context("create dir", &subfolder)?;
+            debug!("fskv_map: Created subfolder at: {}", subfolder.display());
+        }
+
+        // store the value in the filesystem
+        debug!("fskv_map: Storing value at: {}", file.display());
+
+        // get the existing value, a missing one is None but any other failure fails the put
+        let prev = match fs::read(&file) {
+            Ok(data) => Some(V::try_from(data.as_slice()).map_err(|_| Error::from(FsStorageError::InvalidValue(eid.to_string())))?),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e).io_context_id("read", &eid, &file),
+        };
+
+        let intent = self.storage.begin(Intent::Put, id)?;
+
+        // write the contents to a temp file
+        let data: Vec<u8> = value.clone().into();
+        let temp = self.storage.stage_entry(&eid, &subfolder, &data)?;
+        self.storage.record_name(id)?;
+
+        // atomically rename/move it to the correct location
+        self.storage.persist(temp, &file)?;
+        intent.commit()?;
+
+        Ok(prev)
+    }
+
+    fn rm(&self, id: &ID) -> Result<V, Self::Error> {
+        // first try to get the value
+        let v = self.get(id)?;
+
+        let intent = self.storage.begin(Intent::Rm, id)?;
+        self.storage.remove_entry(id)?;
+        intent.commit()?;
+
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand;
+    use super::*;
+    use multicid::{cid, vlad, Cid, Vlad};
+    use multicodec::Codec;
+    use multihash::mh;
+    use multikey::{mk, Multikey, Views};
+
+    // returns a random Ed25519 secret key as a Multikey
+    fn get_mk() -> Multikey {
+        let mut rng = rand::rngs::OsRng::default();
+        mk::Builder::new_from_random_bytes(Codec::Ed25519Priv, &mut rng)
+            .unwrap()
+            .try_build()
+            .unwrap()
+    }
+
+    // returns a signed vlad
+    fn get_vlad(b: &[u8]) -> Vlad {
+        let cid: Cid = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Identity)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b).unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap();
+
+        vlad::Builder::default()
+            .with_signing_key(&get_mk())
+            .with_cid(&cid)
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_vlad_to_multikey() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fskvmap1");
+
+        let mut map = Builder::<Vlad, Multikey>::new(&pb).try_build().unwrap();
+
+        let vlad = get_vlad(b"for great justice!");
+        let mk1 = get_mk().conv_view().unwrap().to_public_key().unwrap();
+        let mk2 = get_mk().conv_view().unwrap().to_public_key().unwrap();
+
+        assert!(map.put(&vlad, &mk1).unwrap().is_none());
+        assert!(map.exists(&vlad).unwrap());
+        assert_eq!(map.get(&vlad).unwrap(), mk1);
+
+        // updating returns the previous value
+        assert_eq!(map.put(&vlad, &mk2).unwrap(), Some(mk1));
+        assert_eq!(map.rm(&vlad).unwrap(), mk2);
+        assert!(!map.exists(&vlad).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{CidMap, Error, error::{FsStorageError, IoContext}, Event, fsstorage::{self, FsStorage, Intent}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use multitrait::{EncodeInto, TryDecodeFrom};
+use multiutil::EncodingInfo;
+use std::{fs, io::ErrorKind, marker::PhantomData, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+/// The time and the replica of a write, stamps order concurrent writes to the same mapping so
+/// that every replica settles on the same winner
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp {
+    /// nanoseconds since the unix epoch
+    pub time: u64,
+    /// the replica that made the write
+    pub actor: u64,
+}
+
+/// A last-writer-wins register holding the stamp of the last write to a mapping and the Cid it
+/// wrote, or None if the last write removed the mapping
+#[derive(Clone, Debug, PartialEq)]
+pub struct Register {
+    /// the stamp of the last write
+    pub stamp: Stamp,
+    /// the Cid of the last write
+    pub cid: Option<Cid>,
+}
+
+impl Register {
+    // true if this register wins over the other. the stamps decide and the Cid bytes break the
+    // tie between replicas that share an actor so the choice is still deterministic
+    fn wins_over(&self, other: &Register) -> bool {
+        let key = |r: &Register| (r.stamp, r.cid.clone().map(Vec::<u8>::from));
+        key(self) > key(other)
+    }
+}
+
+impl From<Register> for Vec<u8> {
+    fn from(r: Register) -> Self {
+        let mut v = r.stamp.time.encode_into();
+        v.append(&mut r.stamp.actor.encode_into());
+        if let Some(cid) = r.cid {
+            v.append(&mut cid.into());
+        }
+        v
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for Register {
+    type Error = Error;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Self::Error> {
+        let (time, ptr) = u64::try_decode_from(bytes)?;
+        let (actor, ptr) = u64::try_decode_from(ptr)?;
+        let cid = if ptr.is_empty() { None } else { Some(Cid::try_from(ptr)?) };
+        Ok(Register { stamp: Stamp { time, actor }, cid })
+    }
+}
+
+/// Filesystem backed mapping from an ID to a Cid where every mapping is a last-writer-wins
+/// register. Replicas that each accept puts and removes converge on the same mappings no matter
+/// the order they sync in. Removes leave a register behind so they replicate like puts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsLwwMap<ID>
+where
+    ID: EncodingInfo
+{
+    /// The underlying storage
+    pub storage: FsStorage<ID>,
+
+    /// The replica writing to this copy of the map
+    pub actor: u64,
+}
+
+/// Builder for a FsLwwMap instance
+#[derive(Clone, Debug)]
+pub struct Builder<ID> {
+    root: PathBuf,
+    actor: u64,
+    base_encoding: Option<Base>,
+    _t: PhantomData<ID>,
+}
+
+impl<ID> Builder<ID>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>>
+{
+    /// create a new builder from the root path, this defaults to actor 0
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("fslww_map::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            actor: 0,
+            base_encoding: None,
+            _t: PhantomData,
+        }
+    }
+
+    /// set the replica id stamped on writes, every replica should have its own
+    pub fn with_actor(mut self, actor: u64) -> Self {
+        self.actor = actor;
+        self
+    }
+
+    /// set the encoding codec to use for IDs
+    pub fn with_base_encoding(mut self, base: Base) -> Self {
+        self.base_encoding = Some(base);
+        self
+    }
+
+    /// build the instance. removes are kept as registers so the storage is never lazy.
+    pub fn try_build(&self) -> Result<FsLwwMap<ID>, Error> {
+        let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
+
+        let storage = fsstorage::Builder::<ID>::new(&self.root)
+            .with_base_encoding(base_encoding)
+            .not_lazy()
+            .try_build()?;
+
+        Ok(FsLwwMap {
+            storage,
+            actor: self.actor,
+        })
+    }
+}
+
+impl<ID> FsLwwMap<ID>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>>,
+{
+    /// get the register for the ID, None if nothing was ever written to it
+    pub fn register(&self, id: &ID) -> Result<Option<Register>, Error> {
+        let (eid, _, file, _) = self.storage.get_paths(id)?;
+        let data = match fs::read(&file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).io_context("read", &file),
+        };
+        Register::try_from(data.as_slice())
+            .map(Some)
+            .map_err(|_| FsStorageError::InvalidValue(eid.to_string()).into())
+    }
+
+    /// merge a register from another replica, it replaces the local one only if it wins. this
+    /// returns true if the mapping changed. a register stamped with the last possible time is
+    /// refused because no local write could ever win over it.
+    pub fn merge(&mut self, id: &ID, register: &Register) -> Result<bool, Error> {
+        if register.stamp.time == u64::MAX {
+            return Err(FsStorageError::InvalidValue(self.storage.get_paths(id)?.0.to_string()).into());
+        }
+        let _lock = self.storage.lock(id)?;
+        let current = self.register(id)?;
+        if current.as_ref().is_some_and(|c| !register.wins_over(c)) {
+            return Ok(false);
+        }
+        self.write(id, register)?;
+        match (&register.cid, current.and_then(|c| c.cid)) {
+            (Some(cid), _) => self.storage.notify(Event::MapUpdated(id.clone(), cid.clone())),
+            (None, Some(prev)) => self.storage.notify(Event::MapRemoved(id.clone(), prev)),
+            (None, None) => {}
+        }
+        Ok(true)
+    }
+
+    /// merge every register in the other replica into this one, this returns the number of
+    /// mappings that changed
+    pub fn sync_from(&mut self, other: &FsLwwMap<ID>) -> Result<usize, Error>
+    where
+        ID: for<'a> TryFrom<&'a [u8]>,
+    {
+        let mut changed = 0;
+        for id in other.storage.ids()? {
+            if let Some(register) = other.register(&id)? {
+                if self.merge(&id, &register)? {
+                    changed += 1;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    // the stamp for a local write, it is always later than the register it replaces so a write
+    // wins over everything this replica has seen even if the clocks of the replicas disagree
+    fn next_stamp(&self, id: &ID, current: Option<&Register>) -> Result<Stamp, Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let time = match current {
+            Some(r) => match r.stamp.time.checked_add(1) {
+                Some(next) => now.max(next),
+                None => return Err(FsStorageError::InvalidValue(self.storage.get_paths(id)?.0.to_string()).into()),
+            },
+            None => now,
+        };
+        Ok(Stamp { time, actor: self.actor })
+    }
+
+    // replace the register for the ID
+    fn write(&self, id: &ID, register: &Register) -> Result<(), Error> {
+        let (eid, subfolder, file, _) = self.storage.get_paths(id)?;
+
+        // check if it exists and is a dir...otherwise create the dir
+        if subfolder.try_exists().io_context("stat", &subfolder)? {
+            if !subfolder.is_dir() {
+                return Err(FsStorageError::NotDir(subfolder).into());
+            }
+        } else {
+            fs::create_dir_all(&subfolder).io_context("create dir", &subfolder)?;
+            debug!("fslww_map: Created subfolder at: {}", subfolder.display());
+        }
+
+        let intent = self.storage.begin(Intent::Put, id)?;
+
+        let data: Vec<u8> = register.clone().into();
+        let temp = self.storage.stage_entry(&eid, &subfolder, &data)?;
+        self.storage.record_name(id)?;
+
+        // atomically rename/move it to the correct location
+        self.storage.persist(temp, &file)?;
+        debug!("fslww_map: Stored register at: {}", file.display());
+        intent.commit()
+    }
+}
+
+impl<ID> CidMap<ID> for FsLwwMap<ID>
+where
+    ID: Clone + EncodingInfo + Into<Vec<u8>> + for<'a> TryFrom<&'a [u8]>,
+{
+    type Error = Error;
+
+    fn exists(&self, id: &ID) -> Result<bool, Self::Error> {
+        Ok(self.register(id)?.is_some_and(|r| r.cid.is_some()))
+    }
+
+    fn get(&self, id: &ID) -> Result<Cid, Self::Error> {
+        match self.register(id)?.and_then(|r| r.cid) {
+            Some(cid) => Ok(cid),
+            None => Err(FsStorageError::NoSuchData(self.storage.get_paths(id)?.0.to_string()).into()),
+        }
+    }
+
+    fn put(&mut self, id: &ID, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
+        let _lock = self.storage.lock(id)?;
+        let current = self.register(id)?;
+        let register = Register {
+            stamp: self.next_stamp(id, current.as_ref())?,
+            cid: Some(cid.clone()),
+        };
+        self.write(id, &register)?;
+        self.storage.notify(Event::MapUpdated(id.clone(), cid.clone()));
+        Ok(current.and_then(|r| r.cid))
+    }
+
+    fn rm(&self, id: &ID) -> Result<Cid, Self::Error> {
+        let _lock = self.storage.lock(id)?;
+        let current = self.register(id)?;
+        let Some(cid) = current.as_ref().and_then(|r| r.cid.clone()) else {
+            return Err(FsStorageError::NoSuchData(self.storage.get_paths(id)?.0.to_string()).into());
+        };
+
+        // leave a register behind so the remove replicates
+        let register = Register {
+            stamp: self.next_stamp(id, current.as_ref())?,
+            cid: None,
+        };
+        self.write(id, &register)?;
+        self.storage.notify(Event::MapRemoved(id.clone(), cid.clone()));
+        Ok(cid)
+    }
+
+    fn ids(&self) -> Result<Vec<ID>, Self::Error> {
+        let mut ids = Vec::default();
+        for id in self.storage.ids()? {
+            if self.exists(&id)? {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fscid_map::CidKey;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    // returns a Cid for the passed in data
+    fn get_cid(b: &[u8]) -> Cid {
+        cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::DagCbor)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b).unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_replicas_converge() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fslwwmap1");
+
+        let mut a = Builder::<CidKey>::new(pb.join("a")).with_actor(1).try_build().unwrap();
+        let mut b = Builder::<CidKey>::new(pb.join("b")).with_actor(2).try_build().unwrap();
+        let id = CidKey(get_cid(b"for great justice!"));
+        let other = CidKey(get_cid(b"all your base"));
+
+        // concurrent puts on both replicas, the later one wins everywhere
+        a.put(&id, &get_cid(b"move every zig!")).unwrap();
+        a.put(&other, &get_cid(b"are belong to us")).unwrap();
+        b.put(&id, &get_cid(b"take off every zig!")).unwrap();
+        assert_eq!(a.sync_from(&b).unwrap(), 1);
+        assert_eq!(b.sync_from(&a).unwrap(), 1);
+        assert_eq!(a.get(&id).unwrap(), get_cid(b"take off every zig!"));
+        assert_eq!(b.get(&id).unwrap(), get_cid(b"take off every zig!"));
+        assert_eq!(a.register(&id).unwrap(), b.register(&id).unwrap());
+
+        // syncing again changes nothing
+        assert_eq!(a.sync_from(&b).unwrap(), 0);
+
+        // a remove replicates like a put
+        assert_eq!(a.rm(&other).unwrap(), get_cid(b"are belong to us"));
+        assert_eq!(b.sync_from(&a).unwrap(), 1);
+        assert!(!b.exists(&other).unwrap());
+        assert_eq!(a.ids().unwrap(), vec![id.clone()]);
+        assert_eq!(b.ids().unwrap(), vec![id.clone()]);
+
+        // a register no write could ever win over is refused
+        let last = Register { stamp: Stamp { time: u64::MAX, actor: 2 }, cid: None };
+        assert!(a.merge(&id, &last).is_err());
+        assert_eq!(a.get(&id).unwrap(), get_cid(b"take off every zig!"));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
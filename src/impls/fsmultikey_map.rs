@@ -1,20 +1,34 @@
 // SPDX-License-Identifier: Apache-2.0
-use crate::{CidMap, Error, error::FsStorageError, fsstorage::{self, FsStorage}};
+use crate::{CidMap, Error, error::{FsStorageError, IoContext}, fsstorage::{self, EncryptionKey, FsStorage, MapKey, Passphrase, FORWARD_DIR}};
 use log::debug;
 use multibase::Base;
 use multicid::Cid;
-use multikey::Multikey;
-use std::{fs::{self, File}, io::{Read, Write}, path::{Path, PathBuf}};
+use multicodec::Codec;
+use multihash::Multihash;
+use multikey::{Multikey, Views};
+use multisig::Multisig;
+use multitrait::{EncodeInto, TryDecodeFrom};
+use std::{fs, io::Write, path::{Path, PathBuf}};
 
 /// The FsMultikeyMap type uses CID's
 pub type FsMultikeyMap = FsStorage<Multikey>;
 
+/// The domain separation prefix of the message a key rotation is signed over
+pub const ROTATE_DOMAIN: &[u8] = b"cas-rotate";
+
 /// Builder for a FsMultikeyMap instance
 #[derive(Clone, Debug, Default)]
 pub struct Builder {
     root: PathBuf,
     lazy: bool,
     base_encoding: Option<Base>,
+    history: bool,
+    reverse_index: bool,
+    fingerprint: Option<Codec>,
+    encryption_key: Option<EncryptionKey>,
+    passphrase: Option<Passphrase>,
+    #[cfg(feature = "keyring")]
+    keyring: Option<(String, String)>,
 }
 
 impl Builder {
@@ -25,6 +39,13 @@ impl Builder {
             root: root.as_ref().to_path_buf(),
             lazy: true,
             base_encoding: None,
+            history: false,
+            reverse_index: false,
+            fingerprint: None,
+            encryption_key: None,
+            passphrase: None,
+            #[cfg(feature = "keyring")]
+            keyring: None,
         }
     }
 
@@ -40,6 +61,49 @@ impl Builder {
         self
     }
 
+    /// keep the values replaced by each put in a per-mapping history
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    /// maintain a reverse index from Cids to the IDs that point at them so referrers() works
+    pub fn with_reverse_index(mut self) -> Self {
+        self.reverse_index = true;
+        self
+    }
+
+    /// name the entries after the fingerprints of the keys hashed with the codec (e.g.
+    /// Codec::Blake3) instead of the encoded keys. this bounds the length of the file names and
+    /// lets entries be looked up with get_by_fingerprint() when only the fingerprint of a key is
+    /// known. the codec is persisted in the config, ids() and referrers() aren't supported.
+    pub fn with_fingerprints(mut self, codec: Codec) -> Self {
+        self.fingerprint = Some(codec);
+        self
+    }
+
+    /// encrypt the stored Cids with the symmetric key (e.g. a Codec::Chacha20Poly1305 key) so
+    /// the mapping from each ID to its Cid can't be read from the disk without it
+    pub fn with_encryption_key(mut self, key: &Multikey) -> Self {
+        self.encryption_key = Some(EncryptionKey(key.clone()));
+        self
+    }
+
+    /// encrypt the stored Cids with a key derived from the passphrase, see
+    /// fsstorage::Builder::with_passphrase()
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(Passphrase(passphrase.to_string()));
+        self
+    }
+
+    /// keep the encryption key in the platform keychain entry for the service and user, see
+    /// fsstorage::Builder::with_keyring()
+    #[cfg(feature = "keyring")]
+    pub fn with_keyring(mut self, service: &str, user: &str) -> Self {
+        self.keyring = Some((service.to_string(), user.to_string()));
+        self
+    }
+
     /// build the instance
     pub fn try_build(&self) -> Result<FsMultikeyMap, Error> {
         let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
@@ -48,110 +112,146 @@ impl Builder {
         if !self.lazy {
             builder = builder.not_lazy();
         }
+        if self.history {
+            builder = builder.with_history();
+        }
+        if self.reverse_index {
+            builder = builder.with_reverse_index();
+        }
+        if let Some(codec) = self.fingerprint {
+            builder = builder.with_fingerprints(codec, fingerprint);
+        }
+        if let Some(key) = &self.encryption_key {
+            builder = builder.with_encryption_key(&key.0);
+        }
+        if let Some(passphrase) = &self.passphrase {
+            builder = builder.with_passphrase(&passphrase.0);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some((service, user)) = &self.keyring {
+            builder = builder.with_keyring(service, user);
+        }
 
         builder.try_build()
     }
 }
 
-impl CidMap<Multikey> for FsMultikeyMap {
-    type Error = Error;
-
-    fn exists(&self, id: &Multikey) -> Result<bool, Self::Error> {
-        // get the paths
-        let (_, _, file, _) = self.get_paths(id)?;
-        Ok(file.try_exists()?)
-    }
-
-    fn get(&self, id: &Multikey) -> Result<Cid, Self::Error> {
-        // get the paths
-        let (eid, subfolder, file, _) = self.get_paths(id)?;
+// the fingerprint the entry for the key is named after in a map built with_fingerprints()
+fn fingerprint(key: &Multikey, codec: Codec) -> Result<Vec<u8>, Error> {
+    Ok(key.fingerprint_view()?.fingerprint(codec)?.into())
+}
 
-        // check if it exists and is a dir...otherwise create the dir
-        if subfolder.try_exists()? {
-            if !subfolder.is_dir() {
-                return Err(FsStorageError::NotDir(subfolder).into());
-            }
-        } else {
-            return Err(FsStorageError::NoSuchData(eid.to_string()).into());
+impl FsMultikeyMap {
+    /// get the Cid the key with the fingerprint maps to, the map must have been built
+    /// with_fingerprints() using the hash codec of the fingerprint
+    pub fn get_by_fingerprint(&self, fingerprint: &Multihash) -> Result<Cid, Error> {
+        if self.fingerprint != Some(fingerprint.codec()) {
+            return Err(Error::Unsupported(format!("{:?} fingerprints in this map", fingerprint.codec())));
         }
-
-        // store the mapping in the filesystem
-        debug!("fsmultikey_map: Getting Cid from: {}", file.display());
-        let mut f = File::open(&file)?;
-        let mut data = Vec::default();
-        f.read_to_end(&mut data)?;
-
-        // reconstruct the Cid from the data
-        let cid = Cid::try_from(data.as_slice())?;
-        Ok(cid)
+        let bytes: Vec<u8> = fingerprint.clone().into();
+        self.get_cid_at(multibase::encode(self.base_encoding, bytes))
     }
 
-    fn put(&mut self, id: &Multikey, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
-        // get the paths
-        let (eid, subfolder, file, _) = self.get_paths(id)?;
+    /// move the mapping of the old key to the new key when an identity rotates its key. the
+    /// entry locks of both keys are held and the new key is mapped before the old one is removed
+    /// so the value is never missing from the map. the new key must not be mapped already unless
+    /// to the same Cid, so rotating again finishes a rotation a crash interrupted. when the secret
+    /// half of the old key is passed in, a forwarding record from the old key to the new one is
+    /// signed with it and written before the mapping moves so forwarded() can find the new key
+    /// from the old one.
+    pub fn rotate(&mut self, old: &Multikey, new: &Multikey, signing_key: Option<&Multikey>) -> Result<Cid, Error> {
+        let (eid, _, _, _) = self.get_paths(old)?;
+        if old == new {
+            return Err(FsStorageError::InvalidId(format!("{} is rotated to itself", eid)).into());
+        }
 
-        // check if it exists and is a dir...otherwise create the dir
-        if subfolder.try_exists()? {
-            if !subfolder.is_dir() {
-                return Err(FsStorageError::NotDir(subfolder).into());
+        // sign the record first so a bad signing key leaves the map as it was
+        let record = match signing_key {
+            Some(key) => {
+                if key.conv_view()?.to_public_key()? != *old {
+                    return Err(FsStorageError::InvalidId(format!("{} isn't the public key of the signing key", eid)).into());
+                }
+                let sig: Vec<u8> = key.sign_view()?.sign(&rotation_msg(old, new), false, None)?.into();
+                let mut msg: Vec<u8> = new.clone().into();
+                let mut record = (msg.len() as u64).encode_into();
+                record.append(&mut msg);
+                record.extend_from_slice(&sig);
+                Some(record)
             }
+            None => None,
+        };
+
+        // the locks are taken in the order of the encoded ids so rotations in opposite directions
+        // can't deadlock
+        let (new_eid, _, _, _) = self.get_paths(new)?;
+        let (_first, _second) = if eid.to_string() < new_eid.to_string() {
+            (self.lock(old)?, self.lock(new)?)
         } else {
-            fs::create_dir_all(&subfolder)?;
-            debug!("fsmultikey_map: Created subfolder at: {}", subfolder.display());
+            (self.lock(new)?, self.lock(old)?)
+        };
+        let cid = self.get(old)?;
+        let mapped = if self.exists(new)? { Some(self.get(new)?) } else { None };
+        if mapped.as_ref().is_some_and(|mapped| *mapped != cid) {
+            return Err(Error::CasMismatch(None, mapped));
         }
 
-        // store the Cid in the filesystem
-        debug!("fsmultikey_map: Storing Cid at: {}", file.display());
-
-        // try to get the existing cid value
-        let prev_cid = self.get(id).ok();
-
-        // securely create a temporary file. its name begins with "." so that if something goes
-        // wrong, the temporary file will be cleaned up by a future GC pass
-        let mut temp = tempfile::Builder::new()
-            .suffix(&format!(".{}", eid))
-            .tempfile_in(&subfolder)?;
-
-        // write the contents to the file
-        let data: Vec<u8> = cid.clone().into();
-        temp.write_all(data.as_ref())?;
+        if let Some(record) = record {
+            let dir = self.root.join(FORWARD_DIR);
+            fs::create_dir_all(&dir).io_context("create dir", &dir)?;
+            let mut temp = tempfile::Builder::new()
+                .suffix(&format!(".{}", eid))
+                .tempfile_in(&dir).io_context("create temp file in", &dir)?;
+            temp.write_all(&record).io_context("write", temp.path())?;
+            self.persist(temp, &dir.join(eid.to_string()))?;
+        }
 
-        // atomically rename/move it to the correct location
-        temp.persist(&file)?;
+        // the locks are already held so the locked put and rm are used
+        if mapped.is_none() {
+            self.put_cid_locked(new, &cid)?;
+        }
+        self.rm_cid_locked(old)?;
+        debug!("fsmultikey_map: Rotated mapping from {}", eid);
 
-        Ok(prev_cid)
+        Ok(cid)
     }
 
-    fn rm(&self, id: &Multikey) -> Result<Cid, Self::Error> {
-        // first try to get the value
-        let v = self.get(id)?;
-
-        // get the paths
-        let (_, subfolder, file, lazy_deleted_file) = self.get_paths(id)?;
-
-        // remove the file if it exists
-        if file.try_exists()? && file.is_file() {
-            if self.lazy {
-                // rename the file instead of remove it
-                fs::rename(&file, &lazy_deleted_file)?;
-                debug!("fsmultikey_map: Lazy deleted mapping at: {} to {}", file.display(), lazy_deleted_file.display());
-            } else {
-                // not lazy so delete it
-                fs::remove_file(&file)?;
-                debug!("fsmultikey_map: Removed mapping at: {}", file.display());
-            }
+    /// get the key the old key was rotated to from the forwarding record, after checking that
+    /// the rotation from the old key to it was signed with the old key. this is None if no
+    /// signed rotation of the key was kept.
+    pub fn forwarded(&self, old: &Multikey) -> Result<Option<Multikey>, Error> {
+        let (eid, _, _, _) = self.get_paths(old)?;
+        let file = self.root.join(FORWARD_DIR).join(eid.to_string());
+        if !file.try_exists().io_context("stat", &file)? {
+            return Ok(None);
         }
+        let data = fs::read(&file).io_context("read", &file)?;
 
-        // remove the subfolder if it is emtpy and we're not lazy
-        if subfolder.try_exists()? && subfolder.is_dir() && fs::read_dir(&subfolder)?.count() == 0 && !self.lazy {
-            fs::remove_dir(&subfolder)?;
-            debug!("fsmultikey_map: Removed subdir at: {}", subfolder.display());
+        let invalid = || Error::from(FsStorageError::InvalidValue(eid.to_string()));
+        let (len, ptr) = u64::try_decode_from(data.as_slice())?;
+        let len = usize::try_from(len).map_err(|_| invalid())?;
+        if ptr.len() < len {
+            return Err(invalid());
         }
-
-        Ok(v)
+        let (msg, sig) = ptr.split_at(len);
+        let new = Multikey::try_from(msg)?;
+        old.verify_view()?.verify(&Multisig::try_from(sig)?, Some(&rotation_msg(old, &new)))?;
+        Ok(Some(new))
     }
 }
 
+// the message a rotation is signed over, bound to both keys and to its purpose so the signature
+// can't be passed off as any other
+fn rotation_msg(old: &Multikey, new: &Multikey) -> Vec<u8> {
+    let mut msg = ROTATE_DOMAIN.to_vec();
+    msg.append(&mut old.clone().into());
+    msg.append(&mut new.clone().into());
+    msg
+}
+
+impl MapKey for Multikey {
+    type Id = Multikey;
+}
+
 #[cfg(test)]
 mod tests {
     use rand;
@@ -343,4 +443,87 @@ mod tests {
 
         assert!(fs::remove_dir_all(&pb).is_ok());
     }
+
+    #[test]
+    fn test_fingerprinted() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsmultikeymap8");
+
+        let mut mkm = Builder::new(&pb).with_fingerprints(Codec::Blake3).try_build().unwrap();
+        let mk = get_mk();
+        let cid = get_cid(b"for great justice!");
+        assert_eq!(mkm.put(&mk, &cid).unwrap(), None);
+        assert_eq!(mkm.get(&mk).unwrap(), cid);
+
+        // the entry is found from the fingerprint alone, under a short file name
+        let mh = mk.fingerprint_view().unwrap().fingerprint(Codec::Blake3).unwrap();
+        assert_eq!(mkm.get_by_fingerprint(&mh).unwrap(), cid);
+        let (eid, _, _, _) = mkm.get_paths(&mk).unwrap();
+        assert!(eid.len() < 64);
+        assert!(mkm.ids().is_err());
+
+        // the fingerprint codec is part of the config
+        assert!(Builder::new(&pb).try_build().is_err());
+        assert!(Builder::new(&pb).with_fingerprints(Codec::Sha2256).try_build().is_err());
+
+        assert_eq!(mkm.rm(&mk).unwrap(), cid);
+        assert!(!mkm.exists(&mk).unwrap());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsmultikeymap9");
+
+        let mut mkm = Builder::new(&pb).try_build().unwrap();
+        let mut rng = rand::rngs::OsRng::default();
+        let secret = mk::Builder::new_from_random_bytes(Codec::Ed25519Priv, &mut rng)
+            .unwrap()
+            .try_build()
+            .unwrap();
+        let old = secret.conv_view().unwrap().to_public_key().unwrap();
+        let new = get_mk();
+        let cid = get_cid(b"for great justice!");
+        assert_eq!(mkm.put(&old, &cid).unwrap(), None);
+
+        // a signing key that isn't the old key's leaves the map as it was
+        assert!(mkm.rotate(&old, &new, Some(&get_mk())).is_err());
+        assert_eq!(mkm.get(&old).unwrap(), cid);
+
+        // the mapping moves and the signed record leads from the old key to the new one
+        assert_eq!(mkm.rotate(&old, &new, Some(&secret)).unwrap(), cid);
+        assert!(!mkm.exists(&old).unwrap());
+        assert_eq!(mkm.get(&new).unwrap(), cid);
+        assert_eq!(mkm.forwarded(&old).unwrap(), Some(new.clone()));
+        assert_eq!(mkm.forwarded(&new).unwrap(), None);
+
+        // a record signed over the new key alone isn't a rotation
+        let (eid, _, _, _) = mkm.get_paths(&old).unwrap();
+        let file = pb.join(FORWARD_DIR).join(eid.to_string());
+        let mut msg: Vec<u8> = new.clone().into();
+        let sig: Vec<u8> = secret.sign_view().unwrap().sign(&msg, false, None).unwrap().into();
+        let mut record = (msg.len() as u64).encode_into();
+        record.append(&mut msg);
+        record.extend_from_slice(&sig);
+        let good = fs::read(&file).unwrap();
+        fs::write(&file, &record).unwrap();
+        assert!(mkm.forwarded(&old).is_err());
+        fs::write(&file, &good).unwrap();
+
+        // a rotation interrupted after the new key was mapped is finished by rotating again
+        let next = get_mk();
+        assert_eq!(mkm.put(&next, &cid).unwrap(), None);
+        assert_eq!(mkm.rotate(&new, &next, None).unwrap(), cid);
+        assert!(!mkm.exists(&new).unwrap());
+        assert_eq!(mkm.get(&next).unwrap(), cid);
+        let new = next;
+
+        // a key that is already mapped isn't overwritten
+        let other = get_mk();
+        assert_eq!(mkm.put(&other, &get_cid(b"move every zig!")).unwrap(), None);
+        assert!(matches!(mkm.rotate(&new, &other, None), Err(Error::CasMismatch(None, Some(_)))));
+        assert_eq!(mkm.get(&new).unwrap(), cid);
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
 }
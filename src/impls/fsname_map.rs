@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Error, error::FsStorageError, fsstorage::{self, EntryKey, FsStorage, MapKey}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use multicodec::Codec;
+use multihash::mh;
+use multiutil::EncodingInfo;
+use std::path::{Path, PathBuf};
+
+/// Names longer than this many bytes are stored under the hash of the name so that the encoded
+/// file name stays within filesystem limits
+pub const MAX_NAME_LEN: usize = 128;
+
+/// Marks a key as the hash of a long name, this can never start a valid UTF-8 string
+const HASHED_NAME: u8 = 0xff;
+
+/// The key a name is stored under. Short names are stored as their UTF-8 bytes and long names as
+/// a marker byte followed by the Sha3-256 multihash of the name. The key is always base encoded
+/// so names containing path separators or other unsafe characters map to safe file names.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameKey(Vec<u8>);
+
+impl NameKey {
+    /// create the key for a name
+    pub fn try_from_name(name: &str) -> Result<Self, Error> {
+        if name.is_empty() {
+            return Err(FsStorageError::InvalidId(name.to_string()).into());
+        }
+        if name.len() <= MAX_NAME_LEN {
+            return Ok(NameKey(name.as_bytes().to_vec()));
+        }
+        let hash: Vec<u8> = mh::Builder::new_from_bytes(Codec::Sha3256, name.as_bytes())?
+            .try_build()?
+            .into();
+        let mut v = vec![HASHED_NAME];
+        v.extend_from_slice(&hash);
+        Ok(NameKey(v))
+    }
+
+    /// the name this key was created from, None if the name was too long and was hashed
+    pub fn name(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl EncodingInfo for NameKey {
+    fn preferred_encoding() -> Base {
+        Base::Base32Z
+    }
+
+    fn encoding(&self) -> Base {
+        Self::preferred_encoding()
+    }
+}
+
+impl From<NameKey> for Vec<u8> {
+    fn from(key: NameKey) -> Self {
+        key.0
+    }
+}
+
+impl TryFrom<&[u8]> for NameKey {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        match bytes.first() {
+            None => Err(FsStorageError::InvalidId(String::default()).into()),
+            Some(&HASHED_NAME) => Ok(NameKey(bytes.to_vec())),
+            Some(_) => {
+                let name = std::str::from_utf8(bytes)
+                    .map_err(|_| FsStorageError::InvalidId(String::from_utf8_lossy(bytes).to_string()))?;
+                NameKey::try_from_name(name)
+            }
+        }
+    }
+}
+
+/// The FsNameMap type maps human readable names to Cids
+pub type FsNameMap = FsStorage<NameKey>;
+
+/// Builder for a FsNameMap instance
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+    root: PathBuf,
+    lazy: bool,
+    base_encoding: Option<Base>,
+    history: bool,
+    reverse_index: bool,
+}
+
+impl Builder {
+    /// create a new builder from the root path, this defaults to lazy
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("fsname_map::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            lazy: true,
+            base_encoding: None,
+            history: false,
+            reverse_index: false,
+        }
+    }
+
+    /// set lazy to false
+    pub fn not_lazy(mut self) -> Self {
+        self.lazy = false;
+        self
+    }
+
+    /// set the encoding codec to use for names, it needs at least 32 symbols
+    pub fn with_base_encoding(mut self, base: Base) -> Self {
+        self.base_encoding = Some(base);
+        self
+    }
+
+    /// keep the values replaced by each put in a per-mapping history
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    /// maintain a reverse index from Cids to the names that point at them so referrers() works
+    pub fn with_reverse_index(mut self) -> Self {
+        self.reverse_index = true;
+        self
+    }
+
+    /// build the instance
+    pub fn try_build(&self) -> Result<FsNameMap, Error> {
+        let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
+        FsNameMap::check_map_encoding(&base_encoding)?;
+
+        let mut builder = fsstorage::Builder::<NameKey>::new(&self.root).with_base_encoding(base_encoding);
+        if !self.lazy {
+            builder = builder.not_lazy();
+        }
+        if self.history {
+            builder = builder.with_history();
+        }
+        if self.reverse_index {
+            builder = builder.with_reverse_index();
+        }
+
+        builder.try_build()
+    }
+}
+
+impl FsNameMap {
+    /// get the keys of the names that point at the Cid from the reverse index. CidMap::referrers()
+    /// can't return the unsized names so the keys are returned instead, only the names short
+    /// enough to be stored as they are have a name().
+    pub fn referrers(&self, cid: &Cid) -> Result<Vec<NameKey>, Error> {
+        self.read_referrers(cid)
+    }
+}
+
+impl EntryKey<NameKey> for str {
+    fn entry_key(&self) -> Result<NameKey, Error> {
+        NameKey::try_from_name(self)
+    }
+}
+
+impl MapKey for NameKey {
+    type Id = str;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CidMap;
+    use std::fs;
+
+    // returns a Cid for the passed in data
+    fn get_cid(b: &[u8]) -> Cid {
+        multicid::cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Identity)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b).unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_builder_lazy() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsnamemap1");
+
+        let nm = Builder::new(&pb).try_build().unwrap();
+        assert_eq!(nm.root, pb);
+        assert_eq!(nm.lazy, true);
+        assert_eq!(nm.base_encoding, Base::Base32Z);
+        assert!(pb.is_dir());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_builder_small_base() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsnamemap4");
+
+        assert!(Builder::new(&pb).with_base_encoding(Base::Base16Upper).try_build().is_err());
+        assert!(Builder::new(&pb).with_base_encoding(Base::Base10).try_build().is_err());
+        assert!(!pb.try_exists().unwrap());
+    }
+
+    #[test]
+    fn test_put_get_rm() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsnamemap2");
+
+        let mut nm = Builder::new(&pb).try_build().unwrap();
+
+        let cid1 = get_cid(b"for great justice!");
+        let cid2 = get_cid(b"move every zig!");
+        assert!(nm.put("latest", &cid1).unwrap().is_none());
+        assert!(nm.put("config/prod", &cid2).unwrap().is_none());
+        assert!(nm.exists("latest").unwrap());
+        assert_eq!(nm.get("latest").unwrap(), cid1);
+        assert_eq!(nm.get("config/prod").unwrap(), cid2);
+
+        // the name with a separator must not create nested folders
+        let (_, subfolder, file, _) = nm.get_paths(&NameKey::try_from_name("config/prod").unwrap()).unwrap();
+        assert_eq!(file.parent().unwrap(), subfolder);
+        assert_eq!(subfolder.parent().unwrap(), pb);
+
+        assert_eq!(nm.put("latest", &cid2).unwrap(), Some(cid1));
+        assert_eq!(nm.rm("latest").unwrap(), cid2);
+        assert!(!nm.exists("latest").unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_long_and_empty_names() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsnamemap3");
+
+        let mut nm = Builder::new(&pb).try_build().unwrap();
+
+        // long names are hashed
+        let long = "a".repeat(MAX_NAME_LEN * 4);
+        let key = NameKey::try_from_name(&long).unwrap();
+        assert!(key.name().is_none());
+
+        let cid = get_cid(b"for great justice!");
+        assert!(nm.put(&long, &cid).unwrap().is_none());
+        assert_eq!(nm.get(&long).unwrap(), cid);
+
+        // empty names are rejected
+        assert!(nm.put("", &cid).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_referrers() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsnamemap5");
+
+        let mut nm = Builder::new(&pb).with_reverse_index().try_build().unwrap();
+        let cid1 = get_cid(b"for great justice!");
+        let cid2 = get_cid(b"move every zig!");
+        let long = "a".repeat(MAX_NAME_LEN * 4);
+        nm.put("latest", &cid1).unwrap();
+        nm.put("stable", &cid1).unwrap();
+        nm.put(&long, &cid1).unwrap();
+
+        let referrers = nm.referrers(&cid1).unwrap();
+        assert_eq!(referrers.len(), 3);
+        let mut names: Vec<&str> = referrers.iter().filter_map(|key| key.name()).collect();
+        names.sort();
+        assert_eq!(names, vec!["latest", "stable"]);
+        assert!(referrers.contains(&NameKey::try_from_name(&long).unwrap()));
+
+        // moving a name moves its referrer
+        nm.put("latest", &cid2).unwrap();
+        assert_eq!(nm.referrers(&cid2).unwrap(), vec![NameKey::try_from_name("latest").unwrap()]);
+        nm.rm("stable").unwrap();
+        assert_eq!(nm.referrers(&cid1).unwrap(), vec![NameKey::try_from_name(&long).unwrap()]);
+
+        // a map without the index can't answer
+        let mut plain = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        plain.push(".fsnamemap6");
+        assert!(Builder::new(&plain).try_build().unwrap().referrers(&cid1).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+        assert!(fs::remove_dir_all(&plain).is_ok());
+    }
+}
@@ -1,16 +1,419 @@
 // SPDX-License-Identifier: Apache-2.0
-use crate::{Error, error::FsStorageError};
+use crate::{CidMap, Error, bloom::BloomFilter, error::{FsStorageError, IoContext}, Event, Observer};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload}, ChaCha20Poly1305, Nonce};
 use log::debug;
 use multibase::Base;
+use multicid::Cid;
+use multicodec::Codec;
+use multihash::mh;
+use multikey::{mk, Multikey, Views};
+use multitrait::{EncodeInto, TryDecodeFrom};
 use multiutil::{BaseEncoded, BaseEncoder, DetectedEncoder, EncodingInfo};
 use serde::{Deserialize, Serialize};
-use std::{fs, marker::PhantomData, path::{Path, PathBuf}};
+use tempfile::{NamedTempFile, TempPath};
+use std::{fmt, fs, io::{ErrorKind, Write}, marker::PhantomData, path::{Path, PathBuf}, sync::Arc, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+/// How long to wait for another writer to release the lock on an entry
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the prefix of the entry lock files, the rest is the encoded ID
+const LOCK_PREFIX: &str = ".lock.";
+
+// the length of the Sha3-256 digest prefix sharding names the subfolders after and the number of
+// base32z characters it encodes to
+const SHA3_256_LEN: usize = 32;
+const PREFIX_HASH_CHARS: usize = 52;
+
+// the most subfolders a store that doesn't create them lazily creates up front
+const MAX_EAGER_SHARDS: usize = 1 << 16;
+
+/// The name of the file in the root that persists the store configuration
+pub const CONFIG_FILE: &str = ".cas-config";
+
+/// The version of the on-disk layout written by this library
+pub const FORMAT_VERSION: u64 = 1;
+
+/// A step that migrates the store in the root from one format version to the next
+type Migration = fn(&Path) -> Result<(), Error>;
+
+/// The migration steps, MIGRATIONS[i] migrates a store from version i + 1 to version i + 2
+const MIGRATIONS: &[Migration] = &[];
+
+// every format version but the first needs a migration step
+const _: () = assert!(MIGRATIONS.len() as u64 + 1 == FORMAT_VERSION);
+
+/// The name of the file in the root that persists the total number of bytes and entries stored
+pub const USAGE_FILE: &str = ".usage";
+
+/// The name of the file in the root that is locked while the usage and dedup counters are updated
+pub const COUNTERS_LOCK_FILE: &str = ".counters.lock";
+
+/// The name of the file in the root that holds the bloom filter over the stored IDs
+pub const BLOOM_FILE: &str = ".bloom";
+
+/// The name of the folder in the root that holds the expiry times of expiring entries
+pub const EXPIRY_DIR: &str = ".expiry";
+
+/// The name of the folder in the root that holds the history of each entry
+pub const HISTORY_DIR: &str = ".history";
+
+/// The name of the folder in the root that holds the reverse index from Cids to IDs
+pub const REFERRERS_DIR: &str = ".referrers";
+
+/// The name of the folder in the root that holds the index of blocks by hash and target codec
+pub const CODECS_DIR: &str = ".codecs";
+
+/// The name of the folder in the root that holds the metadata sidecars of blocks
+pub const META_DIR: &str = ".meta";
+
+/// The name of the folder in the root where migrate_encoding() stages the re-encoded entries
+pub const MIGRATE_DIR: &str = ".migrate";
+
+/// The marker in the migrate folder that says the staged layout is complete and replaces the store
+const MIGRATE_READY: &str = ".ready";
+
+/// The marker in the migrate folder that says the old layout is gone and the staged one is moving in
+const MIGRATE_SWAPPING: &str = ".swapping";
+
+/// The name of the folder in the root where check() quarantines bad files
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// The longest file name most filesystems accept, in bytes
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+/// The longest prefix added to an encoded ID to name the files that go with it, the temp files
+/// are ".tmp" followed by six random characters and a "."
+pub(crate) const FILE_NAME_OVERHEAD: usize = 11;
+
+/// The name of the folder in the root that holds the encoded IDs of the entries named after a
+/// hash because the encoded ID is too long or reserved to be a file name
+pub const LONG_NAMES_DIR: &str = ".names";
+
+/// Starts the names of the entries named after a hash, no multibase symbol uses it
+const HASHED_NAME_PREFIX: char = '_';
+
+/// The device names Windows reserves in every folder, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// The name of the folder in the root that holds the point-in-time snapshots
+pub const SNAPSHOTS_DIR: &str = ".snapshots";
+
+/// The name of the folder in the root that holds the intents of in-flight writes
+pub const JOURNAL_DIR: &str = ".journal";
+
+/// The name of the folder in the root that holds the entries staged by two-phase commits
+pub const TXN_DIR: &str = ".txn";
+
+/// The name of the folder in the root that holds the forwarding records left by key rotations
+pub const FORWARD_DIR: &str = ".forward";
+
+/// the folders of per-entry records that are part of an exported snapshot
+#[cfg(feature = "tar")]
+const RECORD_DIRS: [&str; 6] = [EXPIRY_DIR, FORWARD_DIR, HISTORY_DIR, LONG_NAMES_DIR, META_DIR, REFERRERS_DIR];
+
+/// How entries are spread across the subfolders of the root
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Sharding {
+    /// one level of subfolders named after the middle character of the encoded ID
+    #[default]
+    Middle,
+    /// depth levels of subfolders named after the leading characters of the base32z encoded
+    /// Sha3-256 hash of the encoded ID, chars characters per level. the ID is hashed because the
+    /// leading characters of encoded Cids are the same for every block.
+    Prefix {
+        /// the number of characters in each subfolder name
+        chars: usize,
+        /// the number of levels of subfolders
+        depth: usize,
+    },
+    /// depth levels of subfolders named after the trailing characters of the encoded ID, chars
+    /// characters per level. this spreads Cids best because they end with their hash digest.
+    Suffix {
+        /// the number of characters in each subfolder name
+        chars: usize,
+        /// the number of levels of subfolders
+        depth: usize,
+    },
+}
+
+impl Sharding {
+    /// get the names of the nested folders the encoded ID is stored under, outermost first.
+    /// remote backends use this to lay out their keys the same way the filesystem stores do.
+    pub fn folders(&self, s: &str) -> Result<Vec<String>, Error> {
+        match *self {
+            Sharding::Middle => {
+                // get the middle char of the encoded CID
+                let l = s.len();
+                let c = s.chars().nth_back(l >> 1).ok_or(FsStorageError::InvalidId(s.to_string()))?;
+                Ok(vec![c.to_string()])
+            }
+            Sharding::Prefix { chars, depth } | Sharding::Suffix { chars, depth } => {
+                // skip the multibase symbol
+                let symbols: Vec<char> = match self {
+                    Sharding::Prefix { .. } => {
+                        let hash: Vec<u8> = mh::Builder::new_from_bytes(Codec::Sha3256, s.as_bytes())?.try_build()?.into();
+                        multibase::encode(Base::Base32Z, &hash[hash.len() - SHA3_256_LEN..]).chars().skip(1).collect()
+                    }
+                    _ => s.chars().skip(1).collect(),
+                };
+                if symbols.len() < chars * depth {
+                    return Err(FsStorageError::InvalidId(s.to_string()).into());
+                }
+                Ok((0..depth)
+                    .map(|level| {
+                        let start = match self {
+                            Sharding::Suffix { .. } => symbols.len() - (depth - level) * chars,
+                            _ => level * chars,
+                        };
+                        symbols[start..start + chars].iter().collect()
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// The configuration persisted in the root of a store. These are the settings that decide where
+/// files live so opening a store with different ones would mis-locate every entry.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Config {
+    /// The on-disk format version
+    pub version: u64,
+    /// The base encoding of the file names
+    #[serde(with = "serde_base")]
+    pub base_encoding: Base,
+    /// How entries are spread across subfolders
+    pub sharding: Sharding,
+    /// Are deletes lazy?
+    pub lazy: bool,
+    /// How the encryption key is derived from the passphrase, None if the store isn't
+    /// passphrase protected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    /// The multibase encoded encryption of nothing with the encryption key of a store that is
+    /// encrypted with a key instead of a passphrase, a wrong key fails to decrypt it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<String>,
+    /// The hash codec of the fingerprints the entries are named after, None if they are named
+    /// after their encoded IDs
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_codec")]
+    pub fingerprint: Option<Codec>,
+    /// The directory new data is written to before it is moved into place, it is kept so the
+    /// store uses it again when reopened
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staging_dir: Option<PathBuf>,
+    /// Do the entries start with the header naming their compression codec? None for stores
+    /// written before this was persisted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compressed: Option<bool>,
+}
+
+/// The parameters of the Argon2id key derivation that turns the passphrase of a passphrase
+/// protected store into its encryption key. They are persisted in the config so the store can
+/// be opened again with the passphrase alone.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct KdfParams {
+    /// The memory cost in KiB
+    pub m_cost: u32,
+    /// The number of iterations
+    pub t_cost: u32,
+    /// The degree of parallelism
+    pub p_cost: u32,
+    /// The multibase encoded random salt
+    pub salt: String,
+    /// The multibase encoded encryption of nothing with the derived key, a wrong passphrase
+    /// fails to decrypt it
+    pub check: String,
+}
+
+/// A passphrase that is kept out of Debug output
+#[derive(Clone)]
+pub(crate) struct Passphrase(pub(crate) String);
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Passphrase(..)")
+    }
+}
+
+/// An encryption key that is kept out of Debug output
+#[derive(Clone, PartialEq)]
+pub(crate) struct EncryptionKey(pub(crate) Multikey);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// Which lazy deleted files gc() removes
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct GcPolicy {
+    /// lazy deleted files younger than this are kept so they can still be restored, the default
+    /// of zero removes them all
+    pub retain_deleted: Duration,
+}
+
+/// How much work one call to gc_step() may do before it returns. Subfolders are collected
+/// whole so a step can go over the budget by up to one subfolder.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcBudget {
+    /// stop once this many files have been removed
+    pub max_entries: Option<usize>,
+    /// stop once this much time has passed
+    pub max_time: Option<Duration>,
+}
+
+impl GcBudget {
+    /// whether a step that started at the start and removed this many files is out of budget
+    fn spent(&self, removed: usize, start: Instant) -> bool {
+        self.max_entries.is_some_and(|max| removed >= max) || self.max_time.is_some_and(|max| start.elapsed() >= max)
+    }
+}
+
+/// Where an incremental garbage collection left off, pass it to the next call to gc_step() to
+/// resume. The default cursor starts a new pass.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct GcCursor {
+    /// whether the expired entries have been removed in this pass
+    expired: bool,
+    /// the last subfolder collected, None if the pass hasn't started on the subfolders yet
+    after: Option<PathBuf>,
+}
+
+/// How hard writes try to make sure committed entries survive a crash or power loss
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Durability {
+    /// leave it to the OS to write the data back whenever it likes
+    #[default]
+    None,
+    /// fsync the file contents before the rename and the parent directory after it
+    SyncData,
+    /// fsync the file contents and metadata before the rename and the parent directory after it
+    SyncAll,
+}
+
+/// What check() should do with the bad files it finds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Repair {
+    /// only report the problems
+    #[default]
+    None,
+    /// move bad files into the quarantine folder in the root
+    Quarantine,
+    /// delete bad files
+    Delete,
+}
+
+/// A problem found by check()
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// the file contents don't match the ID in the filename
+    CorruptData(PathBuf),
+    /// the filename can't be decoded into an ID
+    UndecodableName(PathBuf),
+    /// the file is in the wrong subfolder for its ID
+    Misplaced(PathBuf),
+    /// a temporary file left behind by a failed write
+    StrayTempFile(PathBuf),
+    /// a non-directory entry where a subfolder is expected
+    NotDir(PathBuf),
+}
+
+/// Why gc() would remove a file listed by pending_gc()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PendingKind {
+    /// a lazy deleted entry older than the retention of the gc policy
+    Deleted,
+    /// a temporary or lock file left behind by a write, or belonging to one in progress
+    Temp,
+    /// an entry whose expiry time has passed
+    Expired,
+}
+
+/// A file that gc() would remove
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingGc {
+    /// The path of the file
+    pub path: PathBuf,
+    /// Why it would be removed
+    pub kind: PendingKind,
+    /// How long ago it was deleted, last written or expired
+    pub age: Duration,
+    /// The size of the file on disk
+    pub bytes: u64,
+}
+
+/// The number of largest entries listed in a UsageReport
+pub const REPORT_LARGEST: usize = 10;
+
+/// The usage of one subfolder in a UsageReport
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SubfolderUsage {
+    /// The path of the subfolder relative to the root
+    pub path: PathBuf,
+    /// The number of entries
+    pub entries: u64,
+    /// The bytes on disk used by the entries
+    pub bytes: u64,
+    /// The number of lazy deleted entries waiting for gc()
+    pub tombstones: u64,
+    /// The bytes on disk used by the lazy deleted entries
+    pub tombstone_bytes: u64,
+    /// The number of temporary and lock files
+    pub temp_files: u64,
+    /// The bytes on disk used by the temporary and lock files
+    pub temp_bytes: u64,
+}
+
+/// The usage of a store reported by report(), the totals are the sums over the subfolders
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct UsageReport {
+    /// The usage of each subfolder
+    pub subfolders: Vec<SubfolderUsage>,
+    /// The total number of entries
+    pub entries: u64,
+    /// The total bytes on disk used by the entries
+    pub bytes: u64,
+    /// The total number of lazy deleted entries
+    pub tombstones: u64,
+    /// The total bytes on disk used by the lazy deleted entries
+    pub tombstone_bytes: u64,
+    /// The total number of temporary and lock files
+    pub temp_files: u64,
+    /// The total bytes on disk used by the temporary and lock files
+    pub temp_bytes: u64,
+    /// The encoded IDs and sizes on disk of the largest entries, largest first
+    pub largest: Vec<(String, u64)>,
+}
+
+/// The results of a check() pass
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheckReport {
+    /// The number of files examined
+    pub checked: usize,
+    /// The problems found
+    pub issues: Vec<Issue>,
+    /// The paths that were quarantined or deleted
+    pub repaired: Vec<PathBuf>,
+}
+
+impl CheckReport {
+    /// true if no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
 
 /// Filesystem block storage handle
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct FsStorage<T>
 where
-    T: EncodingInfo + ?Sized
+    T: EncodingInfo
 {
     /// The root directory
     pub root: PathBuf,
@@ -19,129 +422,3023 @@ where
     /// The base encoding for new CIDs
     #[serde(with = "serde_base")]
     pub base_encoding: Base,
+    /// How entries are spread across subfolders
+    #[serde(default)]
+    pub sharding: Sharding,
+    /// The hash codec of the fingerprints entries are named after instead of their encoded IDs,
+    /// if any
+    #[serde(default, with = "serde_codec")]
+    pub fingerprint: Option<Codec>,
+    /// The compression codec for stored data, if any
+    #[serde(default, with = "serde_codec")]
+    pub compression: Option<Codec>,
+    /// The key used to encrypt data at rest, if any. This is never serialized or printed.
+    #[serde(skip)]
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    /// How the encryption key was derived from a passphrase, if it was
+    #[serde(default)]
+    pub kdf: Option<KdfParams>,
+    /// The encryption of nothing with the encryption key that a wrong key fails to decrypt, if
+    /// the store is encrypted with a key instead of a passphrase
+    #[serde(default)]
+    pub key_check: Option<String>,
+    /// The maximum number of bytes that may be stored, if any
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// The number of bytes of free space on the filesystem that puts must leave alone, if any
+    #[serde(default)]
+    pub reserved_bytes: Option<u64>,
+    /// The size in bytes of the largest block puts accept, if any
+    #[serde(default)]
+    pub max_block_size: Option<u64>,
+    /// The hash codecs the Cids of stored blocks may use, None allows any
+    #[serde(default, with = "serde_codecs")]
+    pub allowed_hashes: Option<Vec<Codec>>,
+    /// The target codec of the Cids put_default() builds, if any
+    #[serde(default, with = "serde_codec")]
+    pub target_codec: Option<Codec>,
+    /// The hash codec of the Cids put_default() builds, if any
+    #[serde(default, with = "serde_codec")]
+    pub hash_codec: Option<Codec>,
+    /// Which lazy deleted files gc() removes
+    #[serde(default)]
+    pub gc_policy: GcPolicy,
+    /// The number of threads of the global rayon pool gc() uses at once with the parallel
+    /// feature, None uses all of them
+    #[serde(default)]
+    pub gc_threads: Option<usize>,
+    /// Are replaced values kept in a per-entry history?
+    #[serde(default)]
+    pub history: bool,
+    /// Are puts and removes recorded in a journal so that they can be recovered after a crash?
+    #[serde(default)]
+    pub journal: bool,
+    /// Is a reverse index from Cids to the IDs that point at them maintained?
+    #[serde(default)]
+    pub reverse_index: bool,
+    /// Is an index of blocks by hash and target codec maintained?
+    #[serde(default)]
+    pub codec_index: bool,
+    /// How hard writes try to survive a crash
+    #[serde(default)]
+    pub durability: Durability,
+    /// The directory new data is written to before it is moved into place, None writes it next
+    /// to where it ends up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staging_dir: Option<PathBuf>,
+    /// The bloom filter that speeds up checking for missing entries, if any
+    #[serde(skip, default)]
+    pub(crate) bloom: Option<Arc<BloomFilter>>,
+    /// The observers subscribed to mutation events
+    #[serde(skip, default)]
+    observers: Observers<T>,
+    /// Calculates the fingerprints entries are named after
+    #[serde(skip, default)]
+    fingerprinter: Fingerprinter<T>,
+
+    // phantoms
+    _t: PhantomData<T>,
+}
+
+/// The observers subscribed to a store
+#[derive(Clone)]
+pub(crate) struct Observers<T>(Vec<Arc<dyn Observer<T>>>);
+
+impl<T> Default for Observers<T> {
+    fn default() -> Self {
+        Observers(Vec::default())
+    }
+}
+
+impl<T> fmt::Debug for Observers<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl<T> PartialEq for Observers<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+/// Calculates the fingerprint of an ID with a hash codec for a store whose entries are named
+/// after fingerprints. A function pointer can't be serialized so a deserialized handle can't
+/// calculate them.
+pub(crate) struct Fingerprinter<T>(Option<fn(&T, Codec) -> Result<Vec<u8>, Error>>);
+
+impl<T> Clone for Fingerprinter<T> {
+    fn clone(&self) -> Self {
+        Fingerprinter(self.0)
+    }
+}
+
+impl<T> Default for Fingerprinter<T> {
+    fn default() -> Self {
+        Fingerprinter(None)
+    }
+}
+
+impl<T> fmt::Debug for Fingerprinter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fingerprinter({})", if self.0.is_some() { "Some" } else { "None" })
+    }
+}
+
+impl<T> PartialEq for Fingerprinter<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.is_some() == other.0.is_some()
+    }
+}
+
+/// An operation recorded in the journal before it touches the store
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Intent {
+    /// putting an entry
+    Put,
+    /// removing an entry
+    Rm,
+}
+
+impl fmt::Display for Intent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Intent::Put => write!(f, "put"),
+            Intent::Rm => write!(f, "rm"),
+        }
+    }
+}
+
+/// An intent recorded in the journal. commit() removes it once the operation completes, an
+/// operation that fails part way leaves its intent to be recovered the next time the store opens.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct JournalEntry(Option<PathBuf>);
+
+impl JournalEntry {
+    /// the operation completed so its intent is no longer needed
+    pub(crate) fn commit(self) -> Result<(), Error> {
+        if let Some(path) = &self.0 {
+            fs::remove_file(path).io_context("remove", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// An exclusive lock on one entry. the lock file is removed when this is dropped, while the
+/// lock is still held, and the lock is released when the file is closed right after.
+#[derive(Debug)]
+pub(crate) struct LockFile(PathBuf, #[allow(dead_code)] fs::File);
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// An exclusive lock on the usage and dedup counters of a store, released when this is dropped.
+/// unlike the entry locks its file is never removed.
+#[derive(Debug)]
+pub(crate) struct CountersLock(#[allow(dead_code)] fs::File);
+
+/// try to take the lock in the lock file at the path without waiting, None if someone else
+/// holds it
+fn try_lock(path: &Path) -> Result<Option<LockFile>, Error> {
+    use fs4::fs_std::FileExt;
+
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .io_context("open", path)?;
+    match file.try_lock_exclusive() {
+        // the holder removes the file before releasing the lock, so the lock only counts if the
+        // file is still the one at the path
+        Ok(true) if is_same_file(&file, path)? => Ok(Some(LockFile(path.to_path_buf(), file))),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e).io_context("lock", path),
+    }
+}
+
+/// read a pair of counters written by FsStorage::write_counters, None if the file is missing or
+/// doesn't parse
+pub(crate) fn read_counters(file: &Path) -> Result<Option<(u64, u64)>, Error> {
+    let counters = match fs::read_to_string(file) {
+        Ok(counters) => counters,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).io_context("read", file),
+    };
+    let mut counters = counters.split_whitespace().map(|c| c.parse::<u64>());
+    match (counters.next(), counters.next(), counters.next()) {
+        (Some(Ok(a)), Some(Ok(b)), None) => Ok(Some((a, b))),
+        _ => Ok(None),
+    }
+}
+
+/// is the open file still the one at the path, it isn't once the path was removed or replaced
+fn is_same_file(file: &fs::File, path: &Path) -> Result<bool, Error> {
+    let at_path = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).io_context("stat", path),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let open = file.metadata().io_context("stat", path)?;
+        Ok(open.dev() == at_path.dev() && open.ino() == at_path.ino())
+    }
+    // windows doesn't reuse the name of a removed file while a handle to it is still open
+    #[cfg(not(unix))]
+    {
+        let _ = (file, at_path);
+        Ok(true)
+    }
+}
+
+/// is the file name that of an entry lock file
+pub(crate) fn is_lock_file(name: &str) -> bool {
+    name.starts_with(LOCK_PREFIX)
+}
+
+impl<T> EncodingInfo for FsStorage<T>
+where
+    T: EncodingInfo
+{
+    fn preferred_encoding() -> Base {
+        DetectedEncoder::preferred_encoding(T::preferred_encoding())
+    }
+
+    fn encoding(&self) -> Base {
+        self.base_encoding
+    }
+}
+
+impl<T> FsStorage<T>
+where
+    T: Clone + EncodingInfo + Into<Vec<u8>>
+{
+    /// subscribe an observer to the events for every mutation of the store
+    pub fn subscribe<O>(&mut self, observer: O)
+    where
+        O: Observer<T> + 'static,
+    {
+        self.observers.0.push(Arc::new(observer));
+    }
+
+    /// send the event to all of the subscribed observers
+    pub(crate) fn notify(&self, event: Event<T>) {
+        for observer in &self.observers.0 {
+            observer.notify(&event);
+        }
+    }
+
+    /// garbage collect the block storage to remove any lazy deleted files and empty subfolders
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(path = tracing::field::Empty)
+    ))]
+    pub fn gc(&mut self) -> Result<(), Error>
+    where
+        T: Expire,
+    {
+        record!("path" = self.root.display());
+        self.gc_expired(&GcBudget::default(), Instant::now())?;
+
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+
+            // one chunk of the subfolders per thread on the global pool, each chunk in order
+            let shards = self.shards()?;
+            let threads = self.gc_threads.filter(|threads| *threads > 0).unwrap_or_else(rayon::current_num_threads);
+            shards
+                .par_chunks(shards.len().div_ceil(threads).max(1))
+                .try_for_each(|chunk| chunk.iter().try_for_each(|subfolder| gc_subfolder(&self.root, subfolder, &self.gc_policy).map(|_| ())))?;
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        for subfolder in &self.shards()? {
+            gc_subfolder(&self.root, subfolder, &self.gc_policy)?;
+        }
+
+        Ok(())
+    }
+
+    /// garbage collect a slice of the store within the budget, starting where the cursor left
+    /// off. this returns the cursor to resume from or None once the pass is complete. expired
+    /// entries are removed at the start of each pass and count against the budget. writes may
+    /// go on between the steps, any garbage they leave behind in a subfolder that was already
+    /// collected waits for the next pass.
+    pub fn gc_step(&mut self, cursor: GcCursor, budget: GcBudget) -> Result<Option<GcCursor>, Error>
+    where
+        T: Expire,
+    {
+        let start = Instant::now();
+        let mut next = cursor;
+        let mut removed = 0;
+        if !next.expired {
+            let (expired, done) = self.gc_expired(&budget, start)?;
+            removed += expired;
+            if !done {
+                return Ok(Some(next));
+            }
+            next.expired = true;
+        }
+
+        // the subfolders are collected in order so the cursor is the last one collected
+        let mut shards = self.shards()?;
+        shards.sort();
+        for subfolder in shards {
+            if next.after.as_ref().is_some_and(|after| subfolder <= *after) {
+                continue;
+            }
+            if budget.spent(removed, start) {
+                return Ok(Some(next));
+            }
+            removed += gc_subfolder(&self.root, &subfolder, &self.gc_policy)?;
+            next.after = Some(subfolder);
+        }
+        debug!("fsstorage: Finished incremental GC pass of {}", self.root.display());
+        Ok(None)
+    }
+
+    /// list the files that gc() would remove right now without removing anything, so they can
+    /// be audited first. in-flight writes own temp files too so a gc() racing them removes them
+    /// as well.
+    pub fn pending_gc(&self) -> Result<Vec<PendingGc>, Error> {
+        let mut pending = Vec::default();
+
+        // the expired entries
+        let dir = self.expiry_dir();
+        if dir.try_exists().io_context("stat", &dir)? {
+            let now = SystemTime::now();
+            for entry in fs::read_dir(&dir).io_context("read dir", &dir)? {
+                let entry = entry.io_context("read dir", &dir)?;
+                let expires = match read_timestamp(&entry.path())? {
+                    Some(expires) if expires <= now => expires,
+                    _ => continue,
+                };
+                let name = entry.file_name().to_string_lossy().to_string();
+                let mut file = self.subfolder_for(&name)?;
+                file.push(&name);
+                if file.try_exists().io_context("stat", &file)? {
+                    let bytes = fs::metadata(&file).io_context("stat", &file)?.len();
+                    pending.push(PendingGc {
+                        path: file,
+                        kind: PendingKind::Expired,
+                        age: now.duration_since(expires).unwrap_or_default(),
+                        bytes,
+                    });
+                }
+            }
+        }
+
+        // the lazy deleted and temp files
+        for subfolder in &self.shards()? {
+            if subfolder.is_dir() {
+                pending.append(&mut pending_in_subfolder(subfolder, &self.gc_policy)?);
+            }
+        }
+        Ok(pending)
+    }
+
+    /// remove the temp and lock files older than min_age, both the ones in the subfolders and the
+    /// puts staged in the root or the staging dir. lock files still held are left alone, as are
+    /// lazy deleted entries for gc(). this returns the number of files removed.
+    pub fn cleanup_temp_files(&self, min_age: Duration) -> Result<usize, Error> {
+        let mut stale: Vec<PathBuf> = Vec::default();
+        for subfolder in &self.shards()? {
+            if subfolder.is_dir() {
+                stale.extend(
+                    pending_in_subfolder(subfolder, &self.gc_policy)?
+                        .into_iter()
+                        .filter(|p| p.kind == PendingKind::Temp && p.age >= min_age)
+                        .map(|p| p.path),
+                );
+            }
+        }
+        let now = SystemTime::now();
+
+        // streamed puts are staged in the root or the staging dir
+        let mut staging = vec![self.root.clone()];
+        staging.extend(self.staging_dir.clone());
+        for dir in staging.iter().filter(|d| d.is_dir()) {
+            for file in fs::read_dir(dir).io_context("read dir", dir)? {
+                let file = file.io_context("read dir", dir)?;
+                if !file.file_name().to_string_lossy().starts_with(".tmp") {
+                    continue;
+                }
+                let metadata = file.metadata().io_context("stat", file.path())?;
+                let age = now.duration_since(metadata.modified().unwrap_or(UNIX_EPOCH)).unwrap_or_default();
+                if metadata.is_file() && age >= min_age {
+                    stale.push(file.path());
+                }
+            }
+        }
+
+        for file in &stale {
+            match fs::remove_file(file) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e).io_context("remove", file),
+                _ => debug!("fsstorage: Removed stale temp file {}", file.display()),
+            }
+        }
+
+        // lock files left by crashed holders are removed by taking the lock, which also leaves
+        // the ones still held alone
+        let mut locks = 0;
+        for subfolder in self.shards()?.iter().filter(|d| d.is_dir()) {
+            for file in fs::read_dir(subfolder).io_context("read dir", subfolder)? {
+                let file = file.io_context("read dir", subfolder)?;
+                if !is_lock_file(&file.file_name().to_string_lossy()) {
+                    continue;
+                }
+                let metadata = file.metadata().io_context("stat", file.path())?;
+                let age = now.duration_since(metadata.modified().unwrap_or(UNIX_EPOCH)).unwrap_or_default();
+                if age >= min_age && try_lock(&file.path())?.is_some() {
+                    debug!("fsstorage: Removed stale lock file {}", file.path().display());
+                    locks += 1;
+                }
+            }
+        }
+        Ok(stale.len() + locks)
+    }
+
+    /// remove the entries whose expiry time has passed until the budget is spent and return how
+    /// many were removed and whether that was all of them
+    fn gc_expired(&self, budget: &GcBudget, start: Instant) -> Result<(usize, bool), Error>
+    where
+        T: Expire,
+    {
+        let dir = self.expiry_dir();
+        if !dir.try_exists().io_context("stat", &dir)? {
+            return Ok((0, true));
+        }
+
+        let now = SystemTime::now();
+        let mut freed = 0;
+        let mut removed = 0;
+        let mut done = true;
+        let mut events = Vec::default();
+        for entry in fs::read_dir(&dir).io_context("read dir", &dir)? {
+            let entry = entry.io_context("read dir", &dir)?;
+            match read_timestamp(&entry.path())? {
+                Some(expires) if expires <= now => {}
+                _ => continue,
+            }
+            if budget.spent(removed as usize, start) {
+                done = false;
+                break;
+            }
+
+            // remove the expired entry and its expiry record
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mut file = self.subfolder_for(&name)?;
+            file.push(&name);
+            if file.try_exists().io_context("stat", &file)? {
+                if let Some(id) = self.decode_id(&name) {
+                    events.extend(T::expire(self, &id, &file)?);
+                }
+                freed += fs::metadata(&file).io_context("stat", &file)?.len();
+                removed += 1;
+                fs::remove_file(&file).io_context("remove", &file)?;
+                debug!("fsstorage: GC'd expired file {}", file.display());
+            }
+            fs::remove_file(entry.path()).io_context("remove", &entry.path())?;
+        }
+
+        if removed > 0 {
+            self.update_usage(|bytes, count| (bytes.saturating_sub(freed), count.saturating_sub(removed)))?;
+        }
+        for event in events {
+            self.notify(event);
+        }
+        Ok((removed as usize, done))
+    }
+
+    /// set the time after which gc() removes the entry
+    pub fn set_expiry(&self, id: &T, expires: SystemTime) -> Result<(), Error> {
+        let dir = self.expiry_dir();
+        fs::create_dir_all(&dir).io_context("create dir", &dir)?;
+        let mut temp = tempfile::Builder::new().tempfile_in(&dir).io_context("create temp file in", &dir)?;
+        let secs = expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        temp.write_all(secs.to_string().as_bytes()).io_context("write", temp.path())?;
+        temp.persist(self.expiry_file(id)?)?;
+        Ok(())
+    }
+
+    /// get the time after which gc() removes the entry, if it has one
+    pub fn expiry(&self, id: &T) -> Result<Option<SystemTime>, Error> {
+        read_timestamp(&self.expiry_file(id)?)
+    }
+
+    /// remove the expiry time of the entry so that it is kept until removed
+    pub fn clear_expiry(&self, id: &T) -> Result<(), Error> {
+        // most stores never set an expiry, without the folder there is nothing to clear
+        let dir = self.expiry_dir();
+        if !dir.try_exists().io_context("stat", &dir)? {
+            return Ok(());
+        }
+        let file = self.expiry_file(id)?;
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e).io_context("remove", &file),
+            _ => Ok(()),
+        }
+    }
+
+    fn expiry_dir(&self) -> PathBuf {
+        let mut pb = self.root.clone();
+        pb.push(EXPIRY_DIR);
+        pb
+    }
+
+    fn expiry_file(&self, id: &T) -> Result<PathBuf, Error> {
+        let mut pb = self.expiry_dir();
+        pb.push(self.file_name(self.encode(id)?)?);
+        Ok(pb)
+    }
+
+    /// append the value replaced by a put to the history of the entry, if history is enabled
+    pub(crate) fn record_history(&self, id: &T, prev: &Cid) -> Result<(), Error> {
+        if !self.history {
+            return Ok(());
+        }
+        let dir = self.history_dir();
+        fs::create_dir_all(&dir).io_context("create dir", &dir)?;
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        // the value is packed like the entry itself so an encrypted map doesn't leak it here
+        let data: Vec<u8> = prev.clone().into();
+        let ecid = multibase::encode(Base::Base32Z, self.pack(&self.aad(id)?, &data)?);
+        let file = self.history_file(id)?;
+        let mut f = fs::OpenOptions::new().create(true).append(true).open(&file).io_context("open", &file)?;
+        writeln!(f, "{} {}", secs, ecid).io_context("write", &file)?;
+        Ok(())
+    }
+
+    /// get the replaced values of the entry, oldest first, with the time each was replaced
+    pub(crate) fn read_history(&self, id: &T) -> Result<Vec<(SystemTime, Cid)>, Error> {
+        let file = self.history_file(id)?;
+        if !file.try_exists().io_context("stat", &file)? {
+            return Ok(Vec::default());
+        }
+        let aad = self.aad(id)?;
+        let mut history = Vec::default();
+        for line in fs::read_to_string(&file).io_context("read", &file)?.lines() {
+            let Some((secs, ecid)) = line.split_once(' ') else {
+                continue;
+            };
+            let (Ok(secs), Ok((_, bytes))) = (secs.parse::<u64>(), multibase::decode(ecid)) else {
+                continue;
+            };
+            history.push((UNIX_EPOCH + Duration::from_secs(secs), Cid::try_from(self.unpack(&aad, bytes)?.as_slice())?));
+        }
+        Ok(history)
+    }
+
+    /// get the value the entry had n versions ago, n = 1 is the value before the current one
+    pub(crate) fn history_version(&self, id: &T, n: usize) -> Result<Cid, Error> {
+        let history = self.read_history(id)?;
+        if n == 0 || n > history.len() {
+            return Err(FsStorageError::NoSuchVersion(self.encode(id)?, n).into());
+        }
+        Ok(history[history.len() - n].1.clone())
+    }
+
+    fn history_dir(&self) -> PathBuf {
+        let mut pb = self.root.clone();
+        pb.push(HISTORY_DIR);
+        pb
+    }
+
+    fn history_file(&self, id: &T) -> Result<PathBuf, Error> {
+        let mut pb = self.history_dir();
+        pb.push(self.file_name(self.encode(id)?)?);
+        Ok(pb)
+    }
+
+    /// move the id in the reverse index from the Cid it pointed at to the Cid it points at now,
+    /// if the reverse index is enabled
+    pub(crate) fn index_referrer(&self, id: &T, prev: Option<&Cid>, cid: Option<&Cid>) -> Result<(), Error> {
+        if !self.reverse_index {
+            return Ok(());
+        }
+        let eid = self.file_name(self.encode(id)?)?;
+        if let Some(prev) = prev {
+            let dir = self.referrers_dir(prev);
+            let file = dir.join(&eid);
+            match fs::remove_file(&file) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e).io_context_id("remove", &eid, &file),
+                _ => {}
+            }
+            // drop the folder once nothing refers to the Cid
+            if dir.is_dir() && fs::read_dir(&dir).io_context_id("read dir", &eid, &dir)?.count() == 0 {
+                fs::remove_dir(&dir).io_context_id("remove dir", &eid, &dir)?;
+            }
+        }
+        if let Some(cid) = cid {
+            let dir = self.referrers_dir(cid);
+            fs::create_dir_all(&dir).io_context_id("create dir", &eid, &dir)?;
+            fs::File::create(dir.join(&eid)).io_context_id("create", &eid, &dir.join(&eid))?;
+        }
+        Ok(())
+    }
+
+    /// get the IDs of the entries that point at the Cid from the reverse index
+    pub(crate) fn read_referrers(&self, cid: &Cid) -> Result<Vec<T>, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        if !self.reverse_index {
+            return Err(Error::Unsupported("referrers without a reverse index".to_string()));
+        }
+        let dir = self.referrers_dir(cid);
+        if !dir.try_exists().io_context("stat", &dir)? {
+            return Ok(Vec::default());
+        }
+        self.read_ids(&[dir], |name| Some(name))
+    }
+
+    fn referrers_dir(&self, cid: &Cid) -> PathBuf {
+        let mut pb = self.root.clone();
+        pb.push(REFERRERS_DIR);
+        pb.push(BaseEncoded::<Cid, DetectedEncoder>::new(Base::Base32Z, cid.clone()).to_string());
+        pb
+    }
+
+    /// get the configuration this handle uses
+    pub fn config(&self) -> Config {
+        Config {
+            version: FORMAT_VERSION,
+            base_encoding: self.base_encoding,
+            sharding: self.sharding,
+            lazy: self.lazy,
+            kdf: self.kdf.clone(),
+            key_check: self.key_check.clone(),
+            fingerprint: self.fingerprint,
+            staging_dir: self.staging_dir.clone(),
+            compressed: Some(self.compression.is_some()),
+        }
+    }
+
+    /// read the configuration persisted in the root, if there is one
+    pub fn read_config(&self) -> Result<Option<Config>, Error> {
+        read_config_file(&self.root)
+    }
+
+    /// persist the configuration of this handle in the root
+    pub(crate) fn write_config(&self) -> Result<(), Error> {
+        write_config_file(&self.root, &self.config())
+    }
+
+    /// open an existing store with the layout persisted in its config. stores written by a newer
+    /// version of this library are refused and stores written by an older one must be migrated
+    /// with migrate() first.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        finish_migration(root.as_ref())?;
+        let config = read_config_file(root.as_ref())?
+            .ok_or_else(|| FsStorageError::InvalidConfig(format!("no config in {}", root.as_ref().display())))?;
+        let mut builder = Builder::new(root)
+            .with_base_encoding(config.base_encoding)
+            .with_sharding(config.sharding);
+        if !config.lazy {
+            builder = builder.not_lazy();
+        }
+        // entries are read back with the codec in their header so new ones may use zstd
+        if config.compressed == Some(true) {
+            builder = builder.with_compression(Codec::Zstd);
+        }
+        builder.try_build()
+    }
+
+    /// migrate the store in the root to the current format version by running each migration
+    /// step in turn and returns the version the store was at. the config is updated after every
+    /// step so an interrupted migration resumes where it stopped.
+    pub fn migrate<P: AsRef<Path>>(root: P) -> Result<u64, Error> {
+        let root = root.as_ref();
+        let mut config = read_config_file(root)?
+            .ok_or_else(|| FsStorageError::InvalidConfig(format!("no config in {}", root.display())))?;
+        let from = config.version;
+        if from == 0 || from > FORMAT_VERSION {
+            return Err(FsStorageError::UnsupportedVersion(from).into());
+        }
+        while config.version < FORMAT_VERSION {
+            MIGRATIONS[(config.version - 1) as usize](root)?;
+            config.version += 1;
+            write_config_file(root, &config)?;
+            debug!("fsstorage: Migrated {} to format version {}", root.display(), config.version);
+        }
+        Ok(from)
+    }
+
+    /// make sure the persisted configuration matches this handle, persisting it if the store
+    /// doesn't have one yet
+    fn check_config(&self) -> Result<(), Error> {
+        let Some(config) = self.read_config()? else {
+            return self.write_config();
+        };
+        if config.version == 0 || config.version > FORMAT_VERSION {
+            return Err(FsStorageError::UnsupportedVersion(config.version).into());
+        }
+        if config.version < FORMAT_VERSION {
+            return Err(FsStorageError::NeedsMigration(config.version).into());
+        }
+        let expected = self.config();
+        check_layout(&config, &expected)?;
+        if config.kdf.is_some() != expected.kdf.is_some() {
+            let protected = if config.kdf.is_some() { "is" } else { "isn't" };
+            return Err(FsStorageError::ConfigMismatch(format!("the store {} passphrase protected", protected)).into());
+        }
+        // the staging dir doesn't decide where entries live so a new one just replaces the old,
+        // stores encrypted before key checks were persisted get one and so do stores written
+        // before compression was persisted
+        if config.staging_dir != expected.staging_dir
+            || config.key_check != expected.key_check
+            || config.compressed.is_none()
+        {
+            return self.write_config();
+        }
+        Ok(())
+    }
+
+    /// check if the entry may be stored. this is always true without a bloom filter, with one a
+    /// false result means the entry is definitely not stored.
+    pub(crate) fn may_contain(&self, id: &T) -> bool {
+        match (&self.bloom, self.key_bytes(id)) {
+            (Some(bloom), Ok(key)) => bloom.contains(&key),
+            _ => true,
+        }
+    }
+
+    /// add an entry to the bloom filter, if any. this is done before the entry is moved into
+    /// place so that the filter never misses an entry that can be seen on disk.
+    pub(crate) fn bloom_insert(&self, id: &T) -> Result<(), Error> {
+        match &self.bloom {
+            Some(bloom) => bloom.insert(&self.key_bytes(id)?),
+            None => Ok(()),
+        }
+    }
+
+    // add every stored entry to the filter and save it
+    fn rebuild_bloom(&self, filter: &BloomFilter) -> Result<(), Error> {
+        for subfolder in &self.shards()? {
+            if !subfolder.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(subfolder).io_context("read dir", subfolder)? {
+                let name = file.io_context("read dir", subfolder)?.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') {
+                    continue;
+                }
+                if let Some(bytes) = self.decode_name(&name) {
+                    filter.add(&bytes);
+                }
+            }
+        }
+        debug!("fsstorage: Rebuilt bloom filter");
+        filter.save()
+    }
+
+    /// get the total number of bytes stored, rebuilding the persisted counter if it is missing
+    pub fn stored_bytes(&self) -> Result<u64, Error> {
+        let _counters = self.lock_counters()?;
+        Ok(self.usage()?.0)
+    }
+
+    /// lock the usage and dedup counters against every other handle and process. the lock is
+    /// held from reading the counters until the new ones are written so that racing updates are
+    /// never lost. it isn't reentrant, the helpers that read and write the counters expect the
+    /// caller to hold it.
+    pub(crate) fn lock_counters(&self) -> Result<CountersLock, Error> {
+        use fs4::fs_std::FileExt;
+
+        let path = self.root.join(COUNTERS_LOCK_FILE);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .io_context("open", &path)?;
+        file.lock_exclusive().io_context("lock", &path)?;
+        Ok(CountersLock(file))
+    }
+
+    /// apply the change to the usage counters, if they have been created, under the counters lock
+    pub(crate) fn update_usage<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(u64, u64) -> (u64, u64),
+    {
+        let _counters = self.lock_counters()?;
+        if self.has_usage()? {
+            let (bytes, count) = self.usage()?;
+            let (bytes, count) = f(bytes, count);
+            self.write_usage(bytes, count)?;
+        }
+        Ok(())
+    }
+
+    /// get the persisted total number of bytes and entries stored, rebuilding the counters if
+    /// they are missing or unreadable. the caller must hold the counters lock.
+    pub(crate) fn usage(&self) -> Result<(u64, u64), Error> {
+        match read_counters(&self.usage_file())? {
+            Some(counters) => Ok(counters),
+            None => self.rebuild_counters(),
+        }
+    }
+
+    /// whether the usage counters have been created. once they exist every operation that adds
+    /// or removes entries must keep them up to date, otherwise they are rebuilt when first read.
+    pub(crate) fn has_usage(&self) -> Result<bool, Error> {
+        let file = self.usage_file();
+        file.try_exists().io_context("stat", &file)
+    }
+
+    /// walk the store and recompute the total number of bytes stored, persisting the result
+    pub fn rebuild_usage(&self) -> Result<u64, Error> {
+        let _counters = self.lock_counters()?;
+        Ok(self.rebuild_counters()?.0)
+    }
+
+    // walk the store and recompute the usage counters, the caller must hold the counters lock
+    fn rebuild_counters(&self) -> Result<(u64, u64), Error> {
+        let mut total = 0;
+        let mut count = 0;
+        for subfolder in &self.shards()? {
+            if !subfolder.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(subfolder).io_context("read dir", &subfolder)? {
+                let file = file.io_context("read dir", subfolder)?;
+                if file.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                total += file.metadata().io_context("stat", file.path())?.len();
+                count += 1;
+            }
+        }
+        debug!("fsstorage: Rebuilt usage counters, {} bytes in {} entries stored", total, count);
+        self.write_usage(total, count)?;
+        Ok((total, count))
+    }
+
+    /// persist the total number of bytes and entries stored, the caller must hold the counters lock
+    pub(crate) fn write_usage(&self, bytes: u64, count: u64) -> Result<(), Error> {
+        self.write_counters(&self.usage_file(), bytes, count)
+    }
+
+    /// persist a pair of counters in the file in the root, the caller must hold the counters lock
+    pub(crate) fn write_counters(&self, file: &Path, a: u64, b: u64) -> Result<(), Error> {
+        // write it atomically so that a crash never leaves a partial counter behind
+        let mut temp = tempfile::Builder::new().tempfile_in(&self.root).io_context("create temp file in", &self.root)?;
+        temp.write_all(format!("{} {}", a, b).as_bytes()).io_context("write", temp.path())?;
+        self.rename_into_place(temp.into_temp_path(), file)
+    }
+
+    fn usage_file(&self) -> PathBuf {
+        let mut pb = self.root.clone();
+        pb.push(USAGE_FILE);
+        pb
+    }
+
+    /// get the IDs of all of the entries in the store in lexicographic order of their encoded
+    /// form, so every replica of a store lists it the same way. lazy deleted entries and files
+    /// with names that can't be decoded are skipped.
+    pub fn ids(&self) -> Result<Vec<T>, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        self.read_ids(&self.shards()?, |name| (!name.starts_with('.')).then_some(name))
+    }
+
+    /// read the IDs from the names of the files in the dirs in lexicographic order of the encoded
+    /// IDs. the select closure returns the encoded ID in a file name or None to skip the file.
+    pub(crate) fn read_ids<F>(&self, dirs: &[PathBuf], select: F) -> Result<Vec<T>, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+        F: Fn(&str) -> Option<&str>,
+    {
+        if self.fingerprint.is_some() {
+            return Err(Error::Unsupported("listing the IDs of entries named after fingerprints".to_string()));
+        }
+        let mut ids = Vec::default();
+        for dir in dirs {
+            if !dir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir).io_context("read dir", dir)? {
+                let name = file.io_context("read dir", dir)?.file_name().to_string_lossy().to_string();
+                if let Some(eid) = select(&name).and_then(|name| self.encoded_id(name)) {
+                    if let Some(id) = self.decode_id(&eid) {
+                        ids.push((eid, id));
+                    }
+                }
+            }
+        }
+        ids.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(ids.into_iter().map(|(_, id)| id).collect())
+    }
+
+    /// get the IDs of the entries whose base encoded ID starts with the prefix, including the
+    /// multibase symbol, in lexicographic order like ids(). this is for looking up short IDs.
+    /// no sharding keeps the IDs with a common prefix together so the whole store is walked.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<T>, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        let ids = self.read_ids(&self.shards()?, |name| (!name.starts_with('.') && name.starts_with(prefix)).then_some(name))?;
+        debug!("fsstorage: Found {} entries starting with {}", ids.len(), prefix);
+        Ok(ids)
+    }
+
+    /// walk the entire store and report how the space is used in each subfolder. this reads
+    /// the metadata of every file so it takes as long as a check() without the validation.
+    pub fn report(&self) -> Result<UsageReport, Error> {
+        let mut report = UsageReport::default();
+        for subfolder in &self.shards()? {
+            if !subfolder.is_dir() {
+                continue;
+            }
+            let mut usage = SubfolderUsage {
+                path: subfolder.strip_prefix(&self.root).unwrap_or(subfolder).to_path_buf(),
+                ..Default::default()
+            };
+            for file in fs::read_dir(subfolder).io_context("read dir", subfolder)? {
+                let file = file.io_context("read dir", subfolder)?;
+                let size = file.metadata().io_context("stat", file.path())?.len();
+                let name = file.file_name().to_string_lossy().to_string();
+                match name.strip_prefix('.') {
+                    None => {
+                        usage.entries += 1;
+                        usage.bytes += size;
+                        report.largest.push((name, size));
+                    }
+                    // lazy deleted files are a "." followed by the encoded ID, temp and lock
+                    // files have more
+                    Some(rest) if !rest.contains('.') => {
+                        usage.tombstones += 1;
+                        usage.tombstone_bytes += size;
+                    }
+                    Some(_) => {
+                        usage.temp_files += 1;
+                        usage.temp_bytes += size;
+                    }
+                }
+            }
+
+            // only keep the largest entries seen so far
+            report.largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            report.largest.truncate(REPORT_LARGEST);
+
+            report.entries += usage.entries;
+            report.bytes += usage.bytes;
+            report.tombstones += usage.tombstones;
+            report.tombstone_bytes += usage.tombstone_bytes;
+            report.temp_files += usage.temp_files;
+            report.temp_bytes += usage.temp_bytes;
+            report.subfolders.push(usage);
+        }
+        Ok(report)
+    }
+
+    /// walk the entire store looking for problems. the validate closure is called with each
+    /// decoded ID and the unpacked file contents and returns false if the contents are bad. for
+    /// block stores pass a closure that calls blocks::verify. bad files are quarantined or
+    /// deleted depending on the repair setting.
+    pub fn check<F>(&self, repair: Repair, validate: F) -> Result<CheckReport, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+        F: Fn(&T, &[u8]) -> bool,
+    {
+        let mut report = CheckReport::default();
+        for subfolder in &self.shards()? {
+            if !subfolder.try_exists().io_context("stat", &subfolder)? {
+                continue;
+            }
+            if !subfolder.is_dir() {
+                debug!("fsstorage: check found non-directory {}", subfolder.display());
+                self.repair(repair, subfolder, &mut report)?;
+                report.issues.push(Issue::NotDir(subfolder.clone()));
+                continue;
+            }
+            for file in fs::read_dir(subfolder).io_context("read dir", &subfolder)? {
+                let path = file.io_context("read dir", subfolder)?.path();
+                if path.is_dir() || is_lock_file(&path.file_name().unwrap_or_default().to_string_lossy()) {
+                    continue;
+                }
+                report.checked += 1;
+                if let Some(issue) = self.check_file(subfolder, &path, &validate)? {
+                    debug!("fsstorage: check found {:?}", issue);
+                    self.repair(repair, &path, &mut report)?;
+                    report.issues.push(issue);
+                }
+            }
+        }
+
+        // repairs remove entries so the usage counters have to be recomputed
+        if !report.repaired.is_empty() && self.has_usage()? {
+            let _counters = self.lock_counters()?;
+            self.rebuild_counters()?;
+        }
+        Ok(report)
+    }
+
+    fn check_file<F>(&self, subfolder: &Path, path: &Path, validate: &F) -> Result<Option<Issue>, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+        F: Fn(&T, &[u8]) -> bool,
+    {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+        // lazy deleted files are a "." followed by the encoded ID, anything else is a temp file
+        if let Some(name) = name.strip_prefix('.') {
+            if self.decode_key(name).is_none() {
+                return Ok(Some(Issue::StrayTempFile(path.to_path_buf())));
+            }
+            return Ok(None);
+        }
+
+        let key = match self.decode_key(&name) {
+            Some(key) => key,
+            None => return Ok(Some(Issue::UndecodableName(path.to_path_buf()))),
+        };
+
+        if self.subfolder_for(&multibase::encode(self.base_encoding, &key))? != subfolder {
+            return Ok(Some(Issue::Misplaced(path.to_path_buf())));
+        }
+
+        let valid = match (self.unpack(&key, fs::read(path).io_context("read", &path)?), self.fingerprint) {
+            (Err(_), _) => false,
+            // fingerprints can't be decoded back into IDs so only the packing is checked
+            (Ok(_), Some(_)) => true,
+            (Ok(data), None) => T::try_from(key.as_slice()).map_or(false, |id| validate(&id, &data)),
+        };
+        if !valid {
+            return Ok(Some(Issue::CorruptData(path.to_path_buf())));
+        }
+
+        Ok(None)
+    }
+
+    /// stream the config and every live entry, along with the expiry, history, reverse index and
+    /// forwarding records, into a tar archive. entries are only ever replaced by atomic renames so every
+    /// archived file is complete even while other writers are busy. lazy deleted entries, temp
+    /// files and the usage counters aren't archived.
+    #[cfg(feature = "tar")]
+    pub fn export_snapshot<W: Write>(&self, writer: W) -> Result<(), Error> {
+        let mut tar = tar::Builder::new(writer);
+        // the config always comes first so the import can check it before writing anything
+        let config = self.root.join(CONFIG_FILE);
+        if config.try_exists().io_context("stat", &config)? {
+            tar.append_path_with_name(&config, CONFIG_FILE).io_context("archive", &config)?;
+        } else {
+            let data = serde_json::to_vec_pretty(&self.config())
+                .map_err(|e| FsStorageError::InvalidConfig(e.to_string()))?;
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, CONFIG_FILE, data.as_slice()).io_context("archive", &config)?;
+        }
+        for subfolder in &self.shards()? {
+            if subfolder.is_dir() {
+                archive_dir(&mut tar, &self.root, subfolder, false)?;
+            }
+        }
+        for dir in RECORD_DIRS {
+            let dir = self.root.join(dir);
+            if dir.is_dir() {
+                archive_dir(&mut tar, &self.root, &dir, true)?;
+            }
+        }
+        tar.into_inner().io_context("write snapshot of", &self.root)?;
+        debug!("fsstorage: Exported snapshot of {}", self.root.display());
+        Ok(())
+    }
+
+    /// import the entries in a tar archive created by export_snapshot() into this store. the
+    /// archive must start with a config with the same layout as this store and, if the store is
+    /// encrypted, a key check this store's key passes, anything else is refused before a file is
+    /// written. only files in the shards and the record folders are imported so the usage
+    /// counters, bloom filter, journal and config of the store are never overwritten. each file
+    /// is written atomically and replaces any existing entry with the same ID.
+    #[cfg(feature = "tar")]
+    pub fn import_snapshot<R: std::io::Read>(&self, reader: R) -> Result<(), Error> {
+        let mut archive = tar::Archive::new(reader);
+        let mut checked = false;
+        for entry in archive.entries().io_context("read snapshot into", &self.root)? {
+            let mut entry = entry.io_context("read snapshot into", &self.root)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let rel = entry.path().io_context("read snapshot into", &self.root)?.to_path_buf();
+
+            // refuse anything that would land outside of the root
+            if !rel.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+                return Err(FsStorageError::InvalidSnapshot(format!("invalid path {}", rel.display())).into());
+            }
+
+            if !checked {
+                if rel.as_path() != Path::new(CONFIG_FILE) {
+                    return Err(FsStorageError::InvalidSnapshot(
+                        format!("the first entry is {} not the config", rel.display())
+                    ).into());
+                }
+                let mut data = Vec::default();
+                std::io::Read::read_to_end(&mut entry, &mut data).io_context("read snapshot into", &self.root)?;
+                let config: Config = serde_json::from_slice(&data)
+                    .map_err(|e| FsStorageError::InvalidSnapshot(e.to_string()))?;
+                if config.version != FORMAT_VERSION {
+                    return Err(FsStorageError::ConfigMismatch(
+                        format!("snapshot format version is {} not {}", config.version, FORMAT_VERSION)
+                    ).into());
+                }
+                check_layout(&config, &self.config())?;
+                self.check_snapshot_key(&config)?;
+                checked = true;
+                continue;
+            }
+            if !snapshot_file(&rel) {
+                return Err(FsStorageError::InvalidSnapshot(format!("unexpected file {}", rel.display())).into());
+            }
+
+            let path = self.root.join(&rel);
+            let dir = path.parent().unwrap_or(&self.root);
+            fs::create_dir_all(dir).io_context("create dir", dir)?;
+            let mut temp = tempfile::Builder::new().tempfile_in(dir).io_context("create temp file in", dir)?;
+            std::io::copy(&mut entry, &mut temp).io_context("write", temp.path())?;
+            temp.persist(&path)?;
+        }
+        if !checked {
+            return Err(FsStorageError::InvalidSnapshot("no config".to_string()).into());
+        }
+
+        // the counters and bloom filter have to cover the imported entries
+        if self.has_usage()? {
+            let _counters = self.lock_counters()?;
+            self.rebuild_counters()?;
+        }
+        if let Some(bloom) = &self.bloom {
+            self.rebuild_bloom(bloom)?;
+        }
+        debug!("fsstorage: Imported snapshot into {}", self.root.display());
+        Ok(())
+    }
+
+    /// check that the entries in a snapshot with the config can be decrypted by this store
+    #[cfg(feature = "tar")]
+    fn check_snapshot_key(&self, config: &Config) -> Result<(), Error> {
+        let check = config.kdf.as_ref().map(|kdf| &kdf.check).or(config.key_check.as_ref());
+        match (&self.encryption_key, check) {
+            (None, None) => Ok(()),
+            (None, Some(_)) => Err(FsStorageError::ConfigMismatch("the snapshot is encrypted".to_string()).into()),
+            (Some(_), None) => Err(FsStorageError::ConfigMismatch("the snapshot isn't encrypted".to_string()).into()),
+            (Some(key), Some(check)) => {
+                let (_, encrypted) = multibase::decode(check)
+                    .map_err(|e| FsStorageError::InvalidSnapshot(e.to_string()))?;
+                decrypt(&key.0, &[], &encrypted).map_err(|_| FsStorageError::InvalidEncryptionKey)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// create a point-in-time snapshot of the live entries by hard linking them into a named
+    /// folder under .snapshots with the same layout. put, rm and gc replace or unlink the files
+    /// in the store but never modify them in place, so nothing done to the store after this
+    /// returns changes the snapshot. the returned handle reads from the snapshot and shouldn't be
+    /// written to. history, reverse index and expiry records aren't part of a snapshot.
+    pub fn snapshot(&self, name: &str) -> Result<FsStorage<T>, Error> {
+        let dir = self.snapshot_dir(name)?;
+        if dir.try_exists().io_context("stat", &dir)? {
+            return Err(FsStorageError::InvalidSnapshot(format!("snapshot {} already exists", name)).into());
+        }
+
+        // build it in a dot folder and rename it into place so a partial snapshot is never seen
+        let staging = self.root.join(SNAPSHOTS_DIR).join(format!(".{}", name));
+        if staging.try_exists().io_context("stat", &staging)? {
+            fs::remove_dir_all(&staging).io_context("remove dir", &staging)?;
+        }
+        for subfolder in &self.shards()? {
+            if !subfolder.is_dir() {
+                continue;
+            }
+            let dest = staging.join(subfolder.strip_prefix(&self.root).unwrap_or(subfolder));
+            fs::create_dir_all(&dest).io_context("create dir", &dest)?;
+            for file in fs::read_dir(subfolder).io_context("read dir", subfolder)? {
+                let file = file.io_context("read dir", subfolder)?;
+                if file.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                match fs::hard_link(file.path(), dest.join(file.file_name())) {
+                    Ok(()) => {}
+                    // removed by another writer since the dir was read
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).io_context("link", file.path()),
+                }
+            }
+        }
+        write_config_file(&staging, &self.config())?;
+        fs::rename(&staging, &dir).io_context("rename", &staging)?;
+        debug!("fsstorage: Created snapshot {}", dir.display());
+
+        self.open_snapshot(name)
+    }
+
+    /// get a read handle on an existing snapshot
+    pub fn open_snapshot(&self, name: &str) -> Result<FsStorage<T>, Error> {
+        let dir = self.snapshot_dir(name)?;
+        if !dir.is_dir() {
+            return Err(FsStorageError::NoSuchData(name.to_string()).into());
+        }
+        let mut snapshot = self.clone();
+        snapshot.root = dir;
+        snapshot.max_bytes = None;
+        snapshot.bloom = None;
+        snapshot.observers = Observers::default();
+        Ok(snapshot)
+    }
+
+    /// get the names of the snapshots, sorted
+    pub fn snapshots(&self) -> Result<Vec<String>, Error> {
+        let dir = self.root.join(SNAPSHOTS_DIR);
+        if !dir.try_exists().io_context("stat", &dir)? {
+            return Ok(Vec::default());
+        }
+        let mut names = Vec::default();
+        for entry in fs::read_dir(&dir).io_context("read dir", &dir)? {
+            let name = entry.io_context("read dir", &dir)?.file_name().to_string_lossy().to_string();
+            if !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// remove a snapshot. the files of entries that have since been removed from the store are
+    /// freed once the snapshot no longer links to them.
+    pub fn remove_snapshot(&self, name: &str) -> Result<(), Error> {
+        let dir = self.snapshot_dir(name)?;
+        if !dir.is_dir() {
+            return Err(FsStorageError::NoSuchData(name.to_string()).into());
+        }
+        fs::remove_dir_all(&dir).io_context("remove dir", &dir)?;
+        debug!("fsstorage: Removed snapshot {}", dir.display());
+        Ok(())
+    }
+
+    fn snapshot_dir(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(FsStorageError::InvalidSnapshot(format!("invalid snapshot name {:?}", name)).into());
+        }
+        Ok(self.root.join(SNAPSHOTS_DIR).join(name))
+    }
+
+    /// get the IDs of the lazy deleted entries that can still be restored, in lexicographic order
+    /// like ids()
+    pub fn list_deleted(&self) -> Result<Vec<T>, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        self.read_ids(&self.shards()?, |name| name.strip_prefix('.'))
+    }
+
+    /// fail with an Error::StoreFull if writing the needed bytes would leave less free space on
+    /// the filesystem than the reserve
+    pub(crate) fn check_headroom(&self, needed: u64) -> Result<(), Error> {
+        let Some(reserved) = self.reserved_bytes else {
+            return Ok(());
+        };
+        let free = fs4::available_space(&self.root).io_context("stat", &self.root)?;
+        if free < reserved.saturating_add(needed) {
+            return Err(Error::StoreFull { free, reserved, needed });
+        }
+        Ok(())
+    }
+
+    /// fail with an Error::BlockTooLarge if the data is bigger than the largest block puts accept
+    pub(crate) fn check_block_size(&self, size: u64) -> Result<(), Error> {
+        match self.max_block_size {
+            Some(max) if size > max => Err(Error::BlockTooLarge { size, limit: max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// restore a lazy deleted entry by renaming it back and adding it to the indexes of the
+    /// store again. if the entry was put again after it was deleted, the deleted copy is dropped
+    /// instead.
+    pub fn restore(&self, id: &T) -> Result<(), Error>
+    where
+        T: Restore,
+    {
+        let (eid, _, file, lazy_deleted_file) = self.get_paths(id)?;
+        if !lazy_deleted_file.try_exists().io_context_id("stat", &eid, &lazy_deleted_file)? {
+            return Err(FsStorageError::NoSuchData(eid.to_string()).into());
+        }
+        if file.try_exists().io_context_id("stat", &eid, &file)? {
+            fs::remove_file(&lazy_deleted_file).io_context_id("remove", &eid, &lazy_deleted_file)?;
+            return Ok(());
+        }
+
+        // the restored entry counts against the quota again
+        let size = fs::metadata(&lazy_deleted_file).io_context_id("stat", &eid, &lazy_deleted_file)?.len();
+        {
+            let _counters = self.lock_counters()?;
+            if let Some(max_bytes) = self.max_bytes {
+                let used = self.usage()?.0;
+                if used + size > max_bytes {
+                    return Err(Error::QuotaExceeded { used, limit: max_bytes, needed: size });
+                }
+            }
+
+            self.bloom_insert(id)?;
+            fs::rename(&lazy_deleted_file, &file).io_context_id("rename", &eid, &lazy_deleted_file)?;
+            debug!("fsstorage: Restored {}", file.display());
+
+            if self.has_usage()? {
+                let (bytes, count) = self.usage()?;
+                self.write_usage(bytes + size, count + 1)?;
+            }
+        }
+
+        T::restore(self, id, &file)?;
+        self.notify(Event::Restored(id.clone()));
+        Ok(())
+    }
+
+    /// re-encode the names of all entries under a new base encoding and move them to the
+    /// subfolders the new encoding puts them in, along with the records named after them. lazy
+    /// deleted entries are carried over and stray temporary files are dropped. the new layout is
+    /// linked together in a folder in the root, leaving the store as it is, and marked ready once
+    /// it is complete. a crash before the mark leaves the old layout and one after it is finished
+    /// the next time the store is built. nothing else may use the store while it is migrating and
+    /// it is refused while an entry lock is held.
+    pub fn migrate_encoding(&mut self, base: Base) -> Result<(), Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        if base == self.base_encoding {
+            return Ok(());
+        }
+        Self::encoding_symbols(&base)?;
+
+        let mut target = self.clone();
+        target.base_encoding = base;
+        target.root = self.root.join(MIGRATE_DIR);
+        if target.root.try_exists().io_context("stat", &target.root)? {
+            fs::remove_dir_all(&target.root).io_context("remove dir", &target.root)?;
+        }
+        fs::create_dir_all(&target.root).io_context("create dir", &target.root)?;
+        if let Err(e) = self.stage_migration(&target) {
+            fs::remove_dir_all(&target.root).io_context("remove dir", &target.root)?;
+            return Err(e);
+        }
+
+        // from the moment the marker exists the staged layout is the store
+        write_config_file(&target.root, &target.config())?;
+        let ready = target.root.join(MIGRATE_READY);
+        fs::File::create(&ready).and_then(|f| f.sync_all()).io_context("create", &ready)?;
+        finish_migration(&self.root)?;
+        debug!("fsstorage: Migrated {} to {:?}", self.root.display(), base);
+
+        self.base_encoding = base;
+        if !self.lazy {
+            for subfolder in &self.all_shards()? {
+                fs::create_dir_all(subfolder).io_context("create dir", &subfolder)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// link every entry, its metadata sidecar and the records named after it into the target
+    /// under the target's encoding
+    fn stage_migration(&self, target: &FsStorage<T>) -> Result<(), Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        let base = target.base_encoding;
+        for subfolder in &self.shards()? {
+            if !subfolder.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(subfolder).io_context("read dir", &subfolder)? {
+                let path = file.io_context("read dir", subfolder)?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+                // a held lock means someone is still using the store, a stale one is dropped
+                if let Some(eid) = name.strip_prefix(LOCK_PREFIX) {
+                    if try_lock(&path)?.is_none() {
+                        return Err(FsStorageError::LockTimeout(eid.to_string()).into());
+                    }
+                    continue;
+                }
+                let (deleted, name) = match name.strip_prefix('.') {
+                    Some(name) => (true, name),
+                    None => (false, name.as_str()),
+                };
+                let Some(key) = self.decode_key(name) else {
+                    debug!("fsstorage: Dropped stray file {}", path.display());
+                    continue;
+                };
+                let encoded = multibase::encode(base, &key);
+                let (eid, new_subfolder, file, lazy_deleted_file) = target.paths_for(encoded.clone())?;
+                target.record_encoded_name(encoded)?;
+                link_into(&path, if deleted { &lazy_deleted_file } else { &file })?;
+
+                // the metadata sidecar follows the entry into its new subfolder
+                let meta = meta_file(&self.root, subfolder, name);
+                if meta.try_exists().io_context("stat", &meta)? {
+                    link_into(&meta, &meta_file(&target.root, &new_subfolder, &eid.to_string()))?;
+                }
+            }
+        }
+
+        // the expiry, history, forwarding, reverse index and codec index records are named after
+        // the encoded ids too
+        let mut records = vec![PathBuf::from(EXPIRY_DIR), PathBuf::from(HISTORY_DIR), PathBuf::from(FORWARD_DIR)];
+        let referrers = self.root.join(REFERRERS_DIR);
+        if referrers.is_dir() {
+            for entry in fs::read_dir(&referrers).io_context("read dir", &referrers)? {
+                records.push(Path::new(REFERRERS_DIR).join(entry.io_context("read dir", &referrers)?.file_name()));
+            }
+        }
+        let codecs = self.root.join(CODECS_DIR);
+        if codecs.is_dir() {
+            for kind in fs::read_dir(&codecs).io_context("read dir", &codecs)? {
+                let kind = kind.io_context("read dir", &codecs)?.path();
+                if kind.is_dir() {
+                    for entry in fs::read_dir(&kind).io_context("read dir", &kind)? {
+                        let entry = entry.io_context("read dir", &kind)?.path();
+                        records.push(entry.strip_prefix(&self.root).unwrap_or(&entry).to_path_buf());
+                    }
+                }
+            }
+        }
+        for rel in &records {
+            let dir = self.root.join(rel);
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir).io_context("read dir", &dir)? {
+                let path = entry.io_context("read dir", &dir)?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                if let Some(key) = self.decode_key(&name) {
+                    link_into(&path, &target.root.join(rel).join(target.file_name(multibase::encode(base, &key))?))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode_id(&self, name: &str) -> Option<T>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        T::try_from(self.decode_name(name)?.as_slice()).ok()
+    }
+
+    /// decode the key bytes an entry file is named after, None if the name isn't an encoded ID
+    /// or, when entries are named after fingerprints, isn't base encoded at all
+    fn decode_key(&self, name: &str) -> Option<Vec<u8>>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+    {
+        let bytes = self.decode_name(name)?;
+        (self.fingerprint.is_some() || T::try_from(bytes.as_slice()).is_ok()).then_some(bytes)
+    }
+
+    fn repair(&self, repair: Repair, path: &Path, report: &mut CheckReport) -> Result<(), Error> {
+        match repair {
+            Repair::None => return Ok(()),
+            Repair::Quarantine => {
+                let mut dest = self.root.clone();
+                dest.push(QUARANTINE_DIR);
+                fs::create_dir_all(&dest).io_context("create dir", &dest)?;
+                // earlier bad copies of the same file are kept, later ones get a counter suffix.
+                // linking fails instead of replacing a quarantined file that is already there
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let mut n = 0;
+                loop {
+                    dest.push(if n == 0 { name.clone() } else { format!("{}.{}", name, n) });
+                    match fs::hard_link(path, &dest) {
+                        Ok(()) => break,
+                        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                            dest.pop();
+                            n += 1;
+                        }
+                        Err(e) => return Err(e).io_context("link", &dest),
+                    }
+                }
+                fs::remove_file(path).io_context("remove", &path)?;
+                debug!("fsstorage: Quarantined {} to {}", path.display(), dest.display());
+            }
+            Repair::Delete => {
+                fs::remove_file(path).io_context("remove", &path)?;
+                debug!("fsstorage: Deleted {}", path.display());
+            }
+        }
+        report.repaired.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// get an iterator over the subfolders given the base encoding
+    pub fn subfolders<P: AsRef<Path>>(base_encoding: Option<Base>, root: P) -> Result<Vec<PathBuf>, Error> {
+        let base_encoding = base_encoding.unwrap_or(FsStorage::<T>::preferred_encoding());
+
+        // create the root directory
+        if !root.as_ref().try_exists().io_context("stat", &root.as_ref())? {
+            debug!("fsstorage: creating root dir at {}", root.as_ref().display());
+            fs::create_dir_all(&root).io_context("create dir", &root)?;
+        }
+        debug!("fsstorage: root dir exists");
+
+        // construct the directory structure using the alphabent of the base encoder
+        let symbols = Self::encoding_symbols(&base_encoding)?;
+        Ok(symbols.chars().map(|c| {
+            let mut p = root.as_ref().to_path_buf();
+            p.push(c.to_string());
+            p
+        }).collect())
+    }
+
+    /// get the subfolders that hold entries. with middle sharding these are all of the possible
+    /// subfolders, otherwise the existing folders are walked down to the configured depth.
+    pub fn shards(&self) -> Result<Vec<PathBuf>, Error> {
+        let depth = match self.sharding {
+            Sharding::Middle => return Self::subfolders(Some(self.encoding()), &self.root),
+            Sharding::Prefix { depth, .. } | Sharding::Suffix { depth, .. } => depth,
+        };
+
+        // skip the dot folders in the root, they aren't shards
+        let mut level = vec![self.root.clone()];
+        for _ in 0..depth {
+            let mut next = Vec::default();
+            for dir in &level {
+                for entry in fs::read_dir(dir).io_context("read dir", &dir)? {
+                    let entry = entry.io_context("read dir", dir)?;
+                    if entry.file_type().io_context("stat", entry.path())?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+                        next.push(entry.path());
+                    }
+                }
+            }
+            level = next;
+        }
+        Ok(level)
+    }
+
+    /// get every subfolder the sharding can produce, there are symbols^(chars * depth) of them.
+    /// the builder refuses to create more than MAX_EAGER_SHARDS of them up front.
+    fn all_shards(&self) -> Result<Vec<PathBuf>, Error> {
+        let (chars, depth) = match self.sharding {
+            Sharding::Middle => return Self::subfolders(Some(self.encoding()), &self.root),
+            Sharding::Prefix { chars, depth } | Sharding::Suffix { chars, depth } => (chars, depth),
+        };
+
+        let symbols = Self::shard_symbols(self.sharding, &self.base_encoding)?;
+        let mut names = vec![String::default()];
+        for _ in 0..chars {
+            names = names.iter().flat_map(|n| symbols.chars().map(move |c| format!("{}{}", n, c))).collect();
+        }
+        let names: Vec<String> = names.into_iter().map(shard_name).collect();
+        let mut shards = vec![self.root.clone()];
+        for _ in 0..depth {
+            shards = shards.iter().flat_map(|p| names.iter().map(move |n| p.join(n))).collect();
+        }
+        Ok(shards)
+    }
+
+    /// get the symbols the subfolder names of the sharding are made of, prefix sharding names
+    /// them after a base32z encoded hash whatever the base encoding of the IDs is
+    fn shard_symbols(sharding: Sharding, base: &Base) -> Result<String, Error> {
+        match sharding {
+            Sharding::Prefix { .. } => Self::encoding_symbols(&Base::Base32Z),
+            _ => Self::encoding_symbols(base),
+        }
+    }
+
+    /// fail unless the base encoding has at least 32 symbols. the maps refuse the smaller ones
+    /// (Base2, Base8, Base10 and Base16) because the ids they encode outgrow the file name limit.
+    pub(crate) fn check_map_encoding(base: &Base) -> Result<(), Error> {
+        if Self::encoding_symbols(base)?.len() < 32 {
+            return Err(FsStorageError::UnsupportedBaseEncoding(*base).into());
+        }
+        Ok(())
+    }
+
+    fn encoding_symbols(base: &Base) -> Result<String, Error> {
+        match base {
+            Base::Base2 => Ok("01".into()),
+            Base::Base8 => Ok("01234567".into()),
+            Base::Base10 => Ok("0123456789".into()),
+            Base::Base16Lower => Ok("0123456789abcdef".into()),
+            Base::Base16Upper => Ok("0123456789ABCDEF".into()),
+            Base::Base32Lower | Base::Base32PadLower => Ok("abcdefghijklmnopqrstuvwxyz234567".into()),
+            Base::Base32Upper | Base::Base32PadUpper => Ok("ABCDEFGHIJKLMNOPQRSTUVWXYZ234567".into()),
+            Base::Base32HexLower | Base::Base32HexPadLower => Ok("0123456789abcdefghijklmnopqrstuv".into()),
+            Base::Base32HexUpper | Base::Base32HexPadUpper => Ok("0123456789ABCDEFGHIJKLMNOPQRSTUV".into()),
+            Base::Base32Z => Ok("ybndrfg8ejkmcpqxot1uwisza345h769".into()),
+            Base::Base36Lower => Ok("0123456789abcdefghijklmnopqrstuvwxyz".into()),
+            Base::Base36Upper => Ok("0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ".into()),
+            Base::Base58Flickr => Ok("123456789abcdefghijkmnopqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ".into()),
+            Base::Base58Btc => Ok("123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz".into()),
+            Base::Base64 |
+            Base::Base64Pad |
+            Base::Base64Url |
+            Base::Base64UrlPad => Ok("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_".into()),
+            _ => Err(FsStorageError::UnsupportedBaseEncoding(*base).into())
+        }
+    }
+
+    /// prepare data for writing to disk. if compression is enabled, the data is compressed and
+    /// prefixed with a varuint header containing the compression codec. if encryption is enabled
+    /// the result is then encrypted with the aad of its entry and prefixed with the random nonce.
+    /// either way the packed data starts with the varuint length of the data in the clear so
+    /// that it can be read without unpacking, encryption authenticates it with the aad.
+    pub(crate) fn pack(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.compression.is_none() && self.encryption_key.is_none() {
+            return Ok(data.to_vec());
+        }
+        let mut header = (data.len() as u64).encode_into();
+        let data = match self.compression {
+            Some(codec) => {
+                let mut v = codec.encode_into();
+                v.append(&mut compress(codec, data)?);
+                v
+            }
+            None => data.to_vec(),
+        };
+        let mut data = match &self.encryption_key {
+            Some(key) => encrypt(&key.0, &[aad, header.as_slice()].concat(), &data)?,
+            None => data,
+        };
+        header.append(&mut data);
+        Ok(header)
+    }
+
+    /// read the length of the data in the packed file without reading or unpacking the rest
+    pub(crate) fn packed_len(&self, file: &Path) -> Result<u64, Error> {
+        let mut header = Vec::default();
+        fs::File::open(file)
+            .and_then(|f| std::io::Read::read_to_end(&mut std::io::Read::take(f, MAX_VARUINT_LEN), &mut header))
+            .io_context("read", file)?;
+        let (len, _) = u64::try_decode_from(header.as_slice())?;
+        Ok(len)
+    }
+
+    /// reverse the pack operation on data read from disk. the data is decompressed with the
+    /// codec in its header, not the one this handle compresses with, so entries written with
+    /// another codec still read back.
+    pub(crate) fn unpack(&self, aad: &[u8], data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if self.compression.is_none() && self.encryption_key.is_none() {
+            return Ok(data);
+        }
+        let (len, packed) = u64::try_decode_from(data.as_slice())?;
+        let header = &data[..data.len() - packed.len()];
+        let data = match &self.encryption_key {
+            Some(key) => decrypt(&key.0, &[aad, header].concat(), packed)?,
+            None => packed.to_vec(),
+        };
+        let data = match self.compression {
+            Some(_) => {
+                let (codec, payload) = Codec::try_decode_from(data.as_slice())?;
+                decompress(codec, payload)?
+            }
+            None => data,
+        };
+        if data.len() as u64 != len {
+            return Err(FsStorageError::InvalidValue(format!("packed data of {} bytes holding {}", len, data.len())).into());
+        }
+        Ok(data)
+    }
+
+    /// the associated data that binds an encrypted value to the entry of the id so that it can't
+    /// be moved to another entry undetected. it is the bytes the entry is named after, which
+    /// don't change when the store is migrated to another base encoding.
+    pub(crate) fn aad(&self, id: &T) -> Result<Vec<u8>, Error> {
+        match self.encryption_key {
+            Some(_) => self.key_bytes(id),
+            None => Ok(Vec::default()),
+        }
+    }
+
+    /// move the temporary file into place at the path, syncing it and the directory entry as
+    /// much as the durability setting asks for
+    pub(crate) fn persist(&self, temp: NamedTempFile, path: &Path) -> Result<(), Error> {
+        self.sync_file(temp.as_file(), temp.path())?;
+        self.rename_into_place(temp.into_temp_path(), path)?;
+        self.sync_dir(path)
+    }
+
+    /// rename the closed and synced temp file to the path, without syncing the directory
+    pub(crate) fn rename_into_place(&self, temp: TempPath, path: &Path) -> Result<(), Error> {
+        match temp.persist(path) {
+            Ok(_) => {}
+            Err(e) if e.error.kind() == ErrorKind::CrossesDevices => {
+                // a rename can't cross filesystems so copy the data to a temp file next to the
+                // path and rename that instead. the original temp file is removed when dropped.
+                let dir = path.parent().unwrap_or(&self.root);
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                let local = tempfile::Builder::new()
+                    .suffix(&format!(".{}", name))
+                    .tempfile_in(dir).io_context("create temp file in", dir)?;
+                fs::copy(&e.path, local.path()).io_context("copy", &e.path)?;
+                self.sync_file(local.as_file(), local.path())?;
+                local.persist(path)?;
+                debug!("fsstorage: Copied {} across filesystems", path.display());
+            }
+            Err(e) => return Err(e.error).io_context("rename", path),
+        }
+        Ok(())
+    }
+
+    /// the directory to create the temp file for a write to the subfolder in
+    pub(crate) fn staging<'a>(&'a self, subfolder: &'a Path) -> &'a Path {
+        self.staging_dir.as_deref().unwrap_or(subfolder)
+    }
+
+    /// sync the written contents of the file as much as the durability setting asks for
+    pub(crate) fn sync_file(&self, f: &fs::File, path: &Path) -> Result<(), Error> {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::SyncData => f.sync_data().io_context("sync", path),
+            Durability::SyncAll => f.sync_all().io_context("sync", path),
+        }
+    }
+
+    /// sync the directory holding the path so that a rename or create in it survives a crash.
+    /// directories can't be opened as files on windows so this only syncs on unix.
+    pub(crate) fn sync_dir(&self, path: &Path) -> Result<(), Error> {
+        #[cfg(unix)]
+        if matches!(self.durability, Durability::SyncData | Durability::SyncAll) {
+            if let Some(dir) = path.parent() {
+                fs::File::open(dir).io_context("open", dir)?.sync_all().io_context("sync", dir)?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+
+    /// record the intent to put or remove the entry in the journal, if it is enabled, before the
+    /// operation touches the store. the intent names the entry file and its lazy deleted file
+    /// relative to the root.
+    pub(crate) fn begin(&self, intent: Intent, id: &T) -> Result<JournalEntry, Error> {
+        if !self.journal {
+            return Ok(JournalEntry(None));
+        }
+        let (eid, _, file, lazy_deleted_file) = self.get_paths(id)?;
+        let dir = self.root.join(JOURNAL_DIR);
+        fs::create_dir_all(&dir).io_context_id("create dir", &eid, &dir)?;
+        let mut temp = tempfile::Builder::new().prefix("intent").tempfile_in(&dir).io_context_id("create temp file in", &eid, &dir)?;
+        let relative = |p: &Path| p.strip_prefix(&self.root).unwrap_or(p).display().to_string();
+        writeln!(temp, "{}\n{}\n{}", intent, relative(&file), relative(&lazy_deleted_file)).io_context_id("write", &eid, temp.path())?;
+        self.sync_file(temp.as_file(), temp.path())?;
+        let (_, path) = temp.keep()?;
+        self.sync_dir(&path)?;
+        Ok(JournalEntry(Some(path)))
+    }
+
+    /// finish or roll back the puts and removes left in the journal by a process that crashed
+    /// part way through them and return how many there were. puts that reached their rename are
+    /// kept, the temporary files of the rest are left to cleanup_temp_files() and gc() so that
+    /// their age decides when they go. removes that reached their rename or delete are finished.
+    /// the usage counters and bloom filter are rebuilt if anything was recovered. this must not
+    /// run while another process is writing to the store.
+    pub fn recover(&self) -> Result<usize, Error> {
+        let dir = self.root.join(JOURNAL_DIR);
+        if !dir.try_exists().io_context("stat", &dir)? {
+            return Ok(0);
+        }
+
+        let mut recovered = 0;
+        for entry in fs::read_dir(&dir).io_context("read dir", &dir)? {
+            let path = entry.io_context("read dir", &dir)?.path();
+            let intent = fs::read_to_string(&path).io_context("read", &path)?;
+            let mut lines = intent.lines();
+            // an intent that wasn't completely written never had its operation started
+            if let (true, Some(op), Some(file), Some(lazy_deleted_file)) = (intent.ends_with('\n'), lines.next(), lines.next(), lines.next()) {
+                self.recover_intent(op, &self.root.join(file), &self.root.join(lazy_deleted_file))?;
+                recovered += 1;
+            }
+            fs::remove_file(&path).io_context("remove", &path)?;
+        }
+
+        if recovered > 0 {
+            if self.has_usage()? {
+                let _counters = self.lock_counters()?;
+                self.rebuild_counters()?;
+            }
+            // the filter is rebuilt when the store is next built with one
+            let bloom_file = self.root.join(BLOOM_FILE);
+            if bloom_file.try_exists().io_context("stat", &bloom_file)? {
+                fs::remove_file(&bloom_file).io_context("remove", &bloom_file)?;
+            }
+            debug!("fsstorage: Recovered {} interrupted operations", recovered);
+        }
+        Ok(recovered)
+    }
+
+    fn recover_intent(&self, op: &str, file: &Path, lazy_deleted_file: &Path) -> Result<(), Error> {
+        let subfolder = file.parent().unwrap_or(&self.root);
+        if file.try_exists().io_context("stat", file)? {
+            // the put reached its rename or the remove never got that far
+            debug!("fsstorage: Kept {} after interrupted {}", file.display(), op);
+            return Ok(());
+        }
+
+        match op {
+            // the put never made it into place, its temp file is only removed once it is old
+            "put" => debug!("fsstorage: Rolled back interrupted put of {}", file.display()),
+            "rm" => {
+                if self.lazy {
+                    if lazy_deleted_file.try_exists().io_context("stat", lazy_deleted_file)? {
+                        mark_deleted(lazy_deleted_file)?;
+                    }
+                } else if subfolder.is_dir() && fs::read_dir(subfolder).io_context("read dir", subfolder)?.count() == 0 {
+                    fs::remove_dir(subfolder).io_context("remove dir", subfolder)?;
+                }
+                debug!("fsstorage: Finished interrupted rm of {}", file.display());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// stage the packed value of the entry for the id as part of the named transaction, the
+    /// first phase of a two-phase commit across stores. the block and map stores check the value
+    /// like a put before staging it. the staged value is synced to disk so a prepared transaction
+    /// can still be committed after a crash, but nothing is visible until it is committed.
+    pub(crate) fn stage(&self, txn: &str, id: &T, packed: &[u8]) -> Result<(), Error> {
+        let dir = self.txn_dir(txn)?;
+        // named after the ID even when entries are named after fingerprints so commit can decode it
+        let eid = self.encode_id(id);
+        self.check_headroom(packed.len() as u64)?;
+        fs::create_dir_all(&dir).io_context_id("create dir", &eid, &dir)?;
+        self.sync_dir(&dir)?;
+        let mut temp = tempfile::Builder::new().tempfile_in(&dir).io_context_id("create temp file in", &eid, &dir)?;
+        temp.write_all(packed).io_context_id("write", &eid, temp.path())?;
+        self.persist(temp, &dir.join(&eid))?;
+        debug!("fsstorage: Prepared {} in transaction {}", eid, txn);
+        Ok(())
+    }
+
+    /// move the entries staged for the transaction into place and return how many there were,
+    /// the second phase of a two-phase commit. each entry is moved under a journal intent and
+    /// keeps the usage counters, bloom filter, history and reverse index up to date, then the
+    /// committed closure does the rest of the bookkeeping of a put for the store. the staged
+    /// entries are only dropped once all of them are in place so a coordinator that crashed part
+    /// way through a commit just commits again.
+    pub(crate) fn commit_staged<F>(&self, txn: &str, mut committed: F) -> Result<usize, Error>
+    where
+        T: for<'a> TryFrom<&'a [u8]>,
+        F: FnMut(&T) -> Result<(), Error>,
+    {
+        let dir = self.txn_dir(txn)?;
+        if !dir.try_exists().io_context("stat", &dir)? {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+        for entry in fs::read_dir(&dir).io_context("read dir", &dir)? {
+            let staged = entry.io_context("read dir", &dir)?.path();
+            let name = staged.file_name().unwrap_or_default().to_string_lossy().to_string();
+            // the temp files of an interrupted prepare were never staged
+            if name.starts_with('.') {
+                continue;
+            }
+            let id = self.decode_id(&name).ok_or_else(|| FsStorageError::InvalidId(name.clone()))?;
+            self.commit_entry(&id, &staged)?;
+            committed(&id)?;
+            count += 1;
+        }
+        fs::remove_dir_all(&dir).io_context("remove dir", &dir)?;
+        debug!("fsstorage: Committed {} entries in transaction {}", count, txn);
+        Ok(count)
+    }
+
+    fn commit_entry(&self, id: &T, staged: &Path) -> Result<(), Error> {
+        let (eid, subfolder, file, _) = self.get_paths(id)?;
+        fs::create_dir_all(&subfolder).io_context_id("create dir", &eid, &subfolder)?;
+        let size = fs::metadata(staged).io_context_id("stat", &eid, staged)?.len();
+
+        // only map stores keep a history or reverse index, their values are Cids
+        let cids = if self.history || self.reverse_index {
+            let aad = self.aad(id)?;
+            let prev = fs::read(&file).ok().and_then(|data| Cid::try_from(self.unpack(&aad, data).ok()?.as_slice()).ok());
+            let cid = Cid::try_from(self.unpack(&aad, fs::read(staged).io_context_id("read", &eid, staged)?)?.as_slice())?;
+            Some((prev, cid))
+        } else {
+            None
+        };
+
+        let intent = self.begin(Intent::Put, id)?;
+        if let Some((Some(prev), _)) = &cids {
+            self.record_history(id, prev)?;
+        }
+        self.record_name(id)?;
+        {
+            // the size of any replaced entry is read under the counters lock so that a racing
+            // commit of the same id can't count it twice
+            let _counters = self.lock_counters()?;
+            let prev_size = match fs::metadata(&file) {
+                Ok(metadata) => Some(metadata.len()),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e).io_context_id("stat", &eid, &file),
+            };
+            self.bloom_insert(id)?;
+            fs::rename(staged, &file).io_context_id("rename", &eid, &file)?;
+            if self.has_usage()? {
+                let (used, count) = self.usage()?;
+                self.write_usage(used.saturating_sub(prev_size.unwrap_or_default()) + size, count + u64::from(prev_size.is_none()))?;
+            }
+        }
+        self.sync_dir(&file)?;
+        if let Some((prev, cid)) = &cids {
+            self.index_referrer(id, prev.as_ref(), Some(cid))?;
+        }
+        intent.commit()
+    }
+
+    /// drop the entries staged for the transaction
+    pub fn abort(&self, txn: &str) -> Result<(), Error> {
+        let dir = self.txn_dir(txn)?;
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => {
+                debug!("fsstorage: Aborted transaction {}", txn);
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).io_context("remove dir", &dir),
+        }
+    }
+
+    /// get the names of the prepared transactions that haven't been committed or aborted yet, in
+    /// lexicographic order, so a coordinator can finish them after a crash
+    pub fn prepared(&self) -> Result<Vec<String>, Error> {
+        let dir = self.root.join(TXN_DIR);
+        let mut txns = Vec::default();
+        match fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    txns.push(entry.io_context("read dir", &dir)?.file_name().to_string_lossy().to_string());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).io_context("read dir", &dir),
+        }
+        txns.sort();
+        Ok(txns)
+    }
+
+    fn txn_dir(&self, txn: &str) -> Result<PathBuf, Error> {
+        if txn.is_empty() || txn.starts_with('.') || txn.contains(['/', '\\']) {
+            return Err(FsStorageError::InvalidTransaction(format!("invalid transaction name {:?}", txn)).into());
+        }
+        Ok(self.root.join(TXN_DIR).join(txn))
+    }
+
+    /// read the Cid value of the entry for the id, the get of every CidMap implementation
+    pub(crate) fn get_cid(&self, id: &T) -> Result<Cid, Error> {
+        self.get_cid_at(self.encode(id)?)
+    }
+
+    /// read the Cid value of the entry with the encoded name
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(id = tracing::field::Empty, path = tracing::field::Empty)
+    ))]
+    pub(crate) fn get_cid_at(&self, eid: String) -> Result<Cid, Error> {
+        let aad = match self.encryption_key {
+            Some(_) => multibase::decode(&eid).map_err(|_| FsStorageError::InvalidId(eid.clone()))?.1,
+            None => Vec::default(),
+        };
+        let (eid, _, file, _) = self.paths_for(eid)?;
+        record!("id" = &eid, "path" = file.display());
+        debug!("fsstorage: Getting Cid from: {}", file.display());
+        self.read_cid(&aad, &file)?.ok_or_else(|| FsStorageError::NoSuchData(eid.to_string()).into())
+    }
+
+    /// read the Cid value in the entry file, None if there is no entry
+    fn read_cid(&self, aad: &[u8], file: &Path) -> Result<Option<Cid>, Error> {
+        let data = match fs::read(file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).io_context("read", file),
+        };
+        Ok(Some(Cid::try_from(self.unpack(aad, data)?.as_slice())?))
+    }
+
+    /// set the Cid value of the entry for the id and return the value it replaced, the put of
+    /// every CidMap implementation. the entry lock is held so racing puts can't interleave.
+    pub(crate) fn put_cid(&self, id: &T, cid: &Cid) -> Result<Option<Cid>, Error> {
+        let _lock = self.lock(id)?;
+        self.put_cid_locked(id, cid)
+    }
+
+    /// put_cid() for a caller that already holds the entry lock
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(id = tracing::field::Empty, path = tracing::field::Empty)
+    ))]
+    pub(crate) fn put_cid_locked(&self, id: &T, cid: &Cid) -> Result<Option<Cid>, Error> {
+        let (eid, subfolder, file, _) = self.get_paths(id)?;
+        record!("id" = &eid, "path" = file.display());
+        fs::create_dir_all(&subfolder).io_context_id("create dir", &eid, &subfolder)?;
+        debug!("fsstorage: Storing Cid at: {}", file.display());
+
+        // a missing entry reads as None, any other failure to read the old value fails the put
+        let aad = self.aad(id)?;
+        let prev = self.read_cid(&aad, &file)?;
+        let intent = self.begin(Intent::Put, id)?;
+
+        let data: Vec<u8> = cid.clone().into();
+        let temp = self.stage_entry(&eid, &subfolder, &self.pack(&aad, &data)?)?;
+
+        // keep the replaced value in the history before it is overwritten so a crash can't lose it
+        if let Some(prev) = &prev {
+            self.record_history(id, prev)?;
+        }
+
+        // atomically rename/move it to the correct location
+        self.sync_file(temp.as_file(), temp.path())?;
+        self.place_entry(id, temp.into_temp_path(), &file)?;
+        self.sync_dir(&file)?;
+        self.index_referrer(id, prev.as_ref(), Some(cid))?;
+
+        intent.commit()?;
+        self.notify(Event::MapUpdated(id.clone(), cid.clone()));
+        Ok(prev)
+    }
+
+    /// rename the closed and synced temp file of the entry into place, without syncing the
+    /// directory. the counters lock is held while the entry is added to the bloom filter, so it
+    /// is covered before it is visible, and counted in the usage counters.
+    fn place_entry(&self, id: &T, temp: TempPath, file: &Path) -> Result<(), Error> {
+        let size = fs::metadata(&temp).io_context("stat", &temp)?.len();
+        self.record_name(id)?;
+        let _counters = self.lock_counters()?;
+        let prev_size = match fs::metadata(file) {
+            Ok(metadata) => Some(metadata.len()),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e).io_context("stat", file),
+        };
+        self.bloom_insert(id)?;
+        self.rename_into_place(temp, file)?;
+        if self.has_usage()? {
+            let (used, count) = self.usage()?;
+            self.write_usage(used.saturating_sub(prev_size.unwrap_or_default()) + size, count + u64::from(prev_size.is_none()))?;
+        }
+        Ok(())
+    }
+
+    /// write the data of an entry to a temp file in the staging dir for the caller to persist,
+    /// after checking that it leaves the free space reserve. the temp file name begins with "."
+    /// so that if something goes wrong it is cleaned up by a future GC pass.
+    pub(crate) fn stage_entry(&self, eid: &str, subfolder: &Path, data: &[u8]) -> Result<NamedTempFile, Error> {
+        self.check_headroom(data.len() as u64)?;
+        let staging = self.staging(subfolder);
+        let mut temp = tempfile::Builder::new()
+            .suffix(&format!(".{}", eid))
+            .tempfile_in(staging).io_context_id("create temp file in", &eid, staging)?;
+        temp.write_all(data).io_context_id("write", &eid, temp.path())?;
+        Ok(temp)
+    }
+
+    /// put the Cid only if the entry currently holds the expected value, None meaning that it
+    /// must not exist. the entry lock serializes racing compare and swaps.
+    pub(crate) fn put_cid_cas(&self, id: &T, expected: Option<&Cid>, cid: &Cid) -> Result<Option<Cid>, Error> {
+        let _lock = self.lock(id)?;
+        let (_, _, file, _) = self.get_paths(id)?;
+        let current = self.read_cid(&self.aad(id)?, &file)?;
+        if current.as_ref() != expected {
+            return Err(Error::CasMismatch(expected.cloned(), current));
+        }
+        self.put_cid_locked(id, cid)
+    }
+
+    /// remove the entry for the id and return its Cid value, the rm of every CidMap
+    /// implementation. the entry lock is held so it can't interleave with a put.
+    pub(crate) fn rm_cid(&self, id: &T) -> Result<Cid, Error> {
+        let _lock = self.lock(id)?;
+        self.rm_cid_locked(id)
+    }
+
+    /// rm_cid() for a caller that already holds the entry lock
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(id = tracing::field::Empty, path = tracing::field::Empty)
+    ))]
+    pub(crate) fn rm_cid_locked(&self, id: &T) -> Result<Cid, Error> {
+        let (eid, _, file, _) = self.get_paths(id)?;
+        record!("id" = &eid, "path" = file.display());
+        let cid = self.read_cid(&self.aad(id)?, &file)?.ok_or_else(|| Error::from(FsStorageError::NoSuchData(eid.to_string())))?;
+        let intent = self.begin(Intent::Rm, id)?;
+        if let Some(size) = self.remove_entry(id)? {
+            self.update_usage(|used, count| (used.saturating_sub(size), count.saturating_sub(1)))?;
+        }
+        self.index_referrer(id, Some(&cid), None)?;
+
+        intent.commit()?;
+        self.notify(Event::MapRemoved(id.clone(), cid.clone()));
+        Ok(cid)
+    }
+
+    /// remove the entry file for the id and return its size, None if there is no entry. lazy
+    /// stores rename it to its lazy deleted name for gc() to remove later, the others remove it
+    /// and then its subfolder once that is empty.
+    pub(crate) fn remove_entry(&self, id: &T) -> Result<Option<u64>, Error> {
+        let (eid, subfolder, file, lazy_deleted_file) = self.get_paths(id)?;
+        let size = match fs::metadata(&file) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).io_context_id("stat", &eid, &file),
+        };
+
+        if self.lazy {
+            // rename the file instead of remove it
+            fs::rename(&file, &lazy_deleted_file).io_context_id("rename", &eid, &file)?;
+            mark_deleted(&lazy_deleted_file)?;
+            debug!("fsstorage: Lazy deleted {} to {}", file.display(), lazy_deleted_file.display());
+        } else {
+            fs::remove_file(&file).io_context_id("remove", &eid, &file)?;
+            debug!("fsstorage: Removed {}", file.display());
+
+            // remove the subfolder and its parents if that leaves them empty
+            remove_empty_parents(&self.root, &subfolder);
+        }
+        Ok(Some(size))
+    }
+
+    /// put many Cid values at once for the CidMap implementations. every value is staged in a
+    /// synced temporary file before any of them is renamed into place and each subfolder is
+    /// synced once at the end instead of after every rename. each entry is moved into place under
+    /// its entry lock with the same bookkeeping as put_cid(). returns the previous value of each
+    /// entry or the error that stopped it, in order. the entries in a subfolder that failed to
+    /// sync all get its error as an Error::Wrapped Arc<Error>.
+    pub(crate) fn put_cid_batch(&self, entries: &[(T, Cid)]) -> Vec<Result<Option<Cid>, Error>> {
+        // stage all of the values, they don't depend on what the entries hold now
+        let staged: Vec<Result<_, Error>> = entries
+            .iter()
+            .map(|(id, cid)| {
+                let (eid, subfolder, _, _) = self.get_paths(id)?;
+                fs::create_dir_all(&subfolder).io_context("create dir", &subfolder)?;
+                let data: Vec<u8> = cid.clone().into();
+                let temp = self.stage_entry(&eid, &subfolder, &self.pack(&self.aad(id)?, &data)?)?;
+                self.sync_file(temp.as_file(), temp.path())?;
+                // close the file so large batches don't run out of file descriptors
+                Ok(temp.into_temp_path())
+            })
+            .collect();
+
+        // move them into place one at a time, the lock is only held for each entry
+        let results: Vec<Result<(Option<Cid>, PathBuf), Error>> = entries
+            .iter()
+            .zip(staged)
+            .map(|((id, cid), temp)| {
+                let temp = temp?;
+                let _lock = self.lock(id)?;
+                let (_, _, file, _) = self.get_paths(id)?;
+                let prev = self.read_cid(&self.aad(id)?, &file)?;
+                let intent = self.begin(Intent::Put, id)?;
+                if let Some(prev) = &prev {
+                    self.record_history(id, prev)?;
+                }
+                self.place_entry(id, temp, &file)?;
+                self.index_referrer(id, prev.as_ref(), Some(cid))?;
+                intent.commit()?;
+                Ok((prev, file))
+            })
+            .collect();
+
+        // sync each subfolder once, a failed sync fails every entry renamed into it with the same
+        // shared error so callers can still downcast to it
+        let mut synced = std::collections::BTreeMap::new();
+        for (_, file) in results.iter().flatten() {
+            if let Some(dir) = file.parent() {
+                if !synced.contains_key(dir) {
+                    synced.insert(dir.to_path_buf(), self.sync_dir(file).map_err(Arc::new));
+                }
+            }
+        }
+
+        debug!("fsstorage: Put a batch of {} entries", entries.len());
+        results
+            .into_iter()
+            .zip(entries)
+            .map(|(result, (id, cid))| {
+                let (prev, file) = result?;
+                if let Some(Err(e)) = file.parent().and_then(|dir| synced.get(dir)) {
+                    return Err(Error::Wrapped(Box::new(Arc::clone(e))));
+                }
+                self.notify(Event::MapUpdated(id.clone(), cid.clone()));
+                Ok(prev)
+            })
+            .collect()
+    }
+
+    /// take an exclusive OS advisory lock on the entry for the id, waiting up to LOCK_TIMEOUT
+    /// for any other holder to release it. the lock goes away with its holder, even a crashed
+    /// one, and the lock file is removed by its holder or by cleanup_temp_files() once stale.
+    pub(crate) fn lock(&self, id: &T) -> Result<LockFile, Error> {
+        let (eid, subfolder, _, _) = self.get_paths(id)?;
+        fs::create_dir_all(&subfolder).io_context_id("create dir", &eid, &subfolder)?;
+        let mut path = subfolder;
+        path.push(format!("{}{}", LOCK_PREFIX, eid));
+
+        let start = Instant::now();
+        loop {
+            if let Some(lock) = try_lock(&path)? {
+                return Ok(lock);
+            }
+            if start.elapsed() > LOCK_TIMEOUT {
+                return Err(FsStorageError::LockTimeout(eid.to_string()).into());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// watch the entry for the id and send its Cid every time it is created or updated, by this
+    /// process or any other, until the watch is dropped.
+    #[cfg(feature = "watch")]
+    pub(crate) fn watch_entry(&self, id: &T) -> Result<crate::Watch<Cid>, Error>
+    where
+        T: Send + 'static,
+    {
+        use notify::{EventKind, RecursiveMode, Watcher};
+
+        let (eid, subfolder, file, _) = self.get_paths(id)?;
+        fs::create_dir_all(&subfolder).io_context_id("create dir", &eid, &subfolder)?;
+
+        // the watcher is owned by the returned watch, dropping it closes the events channel and
+        // ends the forwarding thread even if no change ever arrives
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(events_tx).map_err(|e| Error::Wrapped(Box::new(e)))?;
+        watcher.watch(&subfolder, RecursiveMode::NonRecursive).map_err(|e| Error::Wrapped(Box::new(e)))?;
+        debug!("fsstorage: Watching {}", file.display());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let storage = self.clone();
+        let aad = self.aad(id)?;
+        thread::spawn(move || {
+            let mut last = None;
+            for event in events_rx {
+                let Ok(event) = event else {
+                    continue;
+                };
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) || !event.paths.contains(&file) {
+                    continue;
+                }
+                // the entry may already be gone again, only its current value matters
+                let Some(cid) = fs::read(&file)
+                    .ok()
+                    .and_then(|data| storage.unpack(&aad, data).ok())
+                    .and_then(|data| Cid::try_from(data.as_slice()).ok()) else {
+                    continue;
+                };
+                if last.as_ref() == Some(&cid) {
+                    continue;
+                }
+                if tx.send(cid.clone()).is_err() {
+                    break;
+                }
+                last = Some(cid);
+            }
+        });
+
+        Ok(crate::Watch::new(rx, watcher))
+    }
+
+    pub(crate) fn get_paths(&self, id: &T) -> Result<(String, PathBuf, PathBuf, PathBuf), Error> {
+        self.paths_for(self.encode(id)?)
+    }
+
+    /// get the subfolder, file and lazy deleted file of the entry with the encoded name
+    pub(crate) fn paths_for(&self, eid: String) -> Result<(String, PathBuf, PathBuf, PathBuf), Error> {
+        let subfolder = self.subfolder_for(&eid)?;
+        let eid = self.file_name(eid)?;
+        let file = self.get_file(&subfolder, &eid)?;
+        let lazy_deleted_file = self.get_lazy_deleted_file(&subfolder, &eid)?;
+        Ok((eid, subfolder, file, lazy_deleted_file))
+    }
+
+    /// get the name of the entry for the id, the encoded fingerprint of the id when entries are
+    /// named after fingerprints and the encoded id otherwise
+    fn encode(&self, id: &T) -> Result<String, Error> {
+        match self.fingerprint {
+            Some(_) => Ok(multibase::encode(self.base_encoding, self.key_bytes(id)?)),
+            None => Ok(self.encode_id(id)),
+        }
+    }
+
+    /// get the file name for the encoded ID. one too long to be a file name with the prefixes
+    /// of its lock and temp files, or one Windows reserves, is replaced with the hash of it. the
+    /// subfolder is still chosen by the encoded ID. this only works out the name, the writes
+    /// that create entries keep the encoded ID behind a hashed name with record_name().
+    pub(crate) fn file_name(&self, eid: String) -> Result<String, Error> {
+        if eid.len() + FILE_NAME_OVERHEAD <= MAX_FILE_NAME_LEN && !(cfg!(windows) && is_reserved_name(&eid)) {
+            return Ok(eid);
+        }
+        let hash: Vec<u8> = mh::Builder::new_from_bytes(Codec::Sha3256, eid.as_bytes())?.try_build()?.into();
+        // the multibase symbol is left off, the prefix marks the name
+        Ok(format!("{}{}", HASHED_NAME_PREFIX, &multibase::encode(Base::Base32Z, hash)[1..]))
+    }
+
+    /// keep the encoded ID of the entry for the id in a record named after its file name when
+    /// that is a hash, so the entry can still be listed. only puts call this, reads never write.
+    pub(crate) fn record_name(&self, id: &T) -> Result<(), Error> {
+        self.record_encoded_name(self.encode(id)?)
+    }
+
+    fn record_encoded_name(&self, eid: String) -> Result<(), Error> {
+        let name = self.file_name(eid.clone())?;
+        if name == eid {
+            return Ok(());
+        }
+        let dir = self.root.join(LONG_NAMES_DIR);
+        let record = dir.join(&name);
+        if !record.try_exists().io_context("stat", &record)? {
+            fs::create_dir_all(&dir).io_context("create dir", &dir)?;
+            let mut temp = tempfile::Builder::new().tempfile_in(&dir).io_context("create temp file in", &dir)?;
+            temp.write_all(eid.as_bytes()).io_context("write", temp.path())?;
+            self.persist(temp, &record)?;
+        }
+        Ok(())
+    }
+
+    /// get the encoded ID an entry file is named after, from its record if the name is a hash
+    fn encoded_id(&self, name: &str) -> Option<String> {
+        if !name.starts_with(HASHED_NAME_PREFIX) {
+            return Some(name.to_string());
+        }
+        fs::read_to_string(self.root.join(LONG_NAMES_DIR).join(name)).ok()
+    }
+
+    /// decode the bytes of the ID or fingerprint an entry file is named after
+    fn decode_name(&self, name: &str) -> Option<Vec<u8>> {
+        let (_, bytes) = multibase::decode(self.encoded_id(name)?).ok()?;
+        Some(bytes)
+    }
+
+    fn encode_id(&self, id: &T) -> String {
+        BaseEncoded::<T, DetectedEncoder>::new(self.base_encoding, id.clone()).to_string()
+    }
+
+    /// get the bytes the entry for the id is keyed by, its fingerprint when entries are named
+    /// after fingerprints
+    pub(crate) fn key_bytes(&self, id: &T) -> Result<Vec<u8>, Error> {
+        match (self.fingerprint, self.fingerprinter.0) {
+            (Some(codec), Some(fingerprint)) => fingerprint(id, codec),
+            (Some(_), None) => Err(Error::Unsupported("fingerprints without the function that calculates them".to_string())),
+            (None, _) => Ok(id.clone().into()),
+        }
+    }
+
+    fn subfolder_for(&self, s: &str) -> Result<PathBuf, Error> {
+        let mut pb = self.root.clone();
+        for folder in self.sharding.folders(s)? {
+            pb.push(shard_name(folder));
+        }
+        Ok(pb)
+    }
+
+    fn get_file<P: AsRef<Path>>(&self, subfolder: P, eid: &str) -> Result<PathBuf, Error> {
+        let mut pb = subfolder.as_ref().to_path_buf();
+        pb.push(eid);
+        Ok(pb)
+    }
+
+    fn get_lazy_deleted_file<P: AsRef<Path>>(&self, subfolder: P, eid: &str) -> Result<PathBuf, Error> {
+        let mut pb = subfolder.as_ref().to_path_buf();
+        pb.push(&format!(".{}", eid));
+        Ok(pb)
+    }
+}
+
+/// The ID types that name an entry in a FsStorage<T>, T being the key the entry is stored under
+pub trait EntryKey<T> {
+    /// get the key the entry for the ID is stored under
+    fn entry_key(&self) -> Result<T, Error>;
+
+    /// get the ID back from the key it is stored under, None if the key doesn't keep all of it
+    fn from_entry_key(_key: T) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+impl<T: Clone> EntryKey<T> for T {
+    fn entry_key(&self) -> Result<T, Error> {
+        Ok(self.clone())
+    }
+
+    fn from_entry_key(key: T) -> Option<Self> {
+        Some(key)
+    }
+}
+
+/// The keys of the map stores, a FsStorage<T> is a CidMap from the ID type of its key to Cids
+pub trait MapKey: Clone + EncodingInfo + Into<Vec<u8>> + for<'a> TryFrom<&'a [u8]> + Send + 'static {
+    /// The ID type the map is keyed by
+    type Id: EntryKey<Self> + ?Sized;
+}
+
+/// The entries gc() removes once they expire. Expiring an entry drops it from the indexes of the
+/// store and gives the event the observers are sent for its removal, if there is one.
+pub trait Expire: Clone + EncodingInfo + Into<Vec<u8>> + for<'a> TryFrom<&'a [u8]> {
+    /// called with the id and file of the expired entry before the file is removed
+    fn expire(store: &FsStorage<Self>, id: &Self, file: &Path) -> Result<Option<Event<Self>>, Error>;
+}
+
+impl<T: MapKey> Expire for T {
+    fn expire(store: &FsStorage<Self>, id: &Self, file: &Path) -> Result<Option<Event<Self>>, Error> {
+        Ok(store.read_cid(&store.aad(id)?, file)?.map(|cid| Event::MapRemoved(id.clone(), cid)))
+    }
+}
+
+/// The entries restore() brings back. Restoring an entry adds it to the indexes of the store
+/// again.
+pub trait Restore: Clone + EncodingInfo + Into<Vec<u8>> {
+    /// called with the id and file of the restored entry once it is back in place
+    fn restore(store: &FsStorage<Self>, id: &Self, file: &Path) -> Result<(), Error>;
+}
+
+impl<T: MapKey> Restore for T {
+    fn restore(store: &FsStorage<Self>, id: &Self, file: &Path) -> Result<(), Error> {
+        // only Cid maps keep a reverse index so the restored value is a Cid
+        if store.reverse_index {
+            if let Some(cid) = store.read_cid(&store.aad(id)?, file)? {
+                store.index_referrer(id, None, Some(&cid))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> FsStorage<T>
+where
+    T: MapKey
+{
+    // get the key the entry for the id is stored under
+    fn map_key(id: &T::Id) -> Result<T, Error> {
+        <T::Id as EntryKey<T>>::entry_key(id)
+    }
+
+    /// get the previous values of the mapping, oldest first, with the time each was replaced.
+    /// this is empty unless the map was built with history enabled.
+    pub fn history(&self, id: &T::Id) -> Result<Vec<(SystemTime, Cid)>, Error> {
+        self.read_history(&Self::map_key(id)?)
+    }
+
+    /// stage the mapping from the id to the cid as part of the named transaction, the first phase
+    /// of a two-phase commit across stores. nothing is visible until commit() moves it into
+    /// place, abort() drops it instead.
+    pub fn prepare(&self, txn: &str, id: &T::Id, cid: &Cid) -> Result<(), Error> {
+        let value: Vec<u8> = cid.clone().into();
+        let key = Self::map_key(id)?;
+        self.stage(txn, &key, &self.pack(&self.aad(&key)?, &value)?)
+    }
+
+    /// move the mappings staged by prepare() for the transaction into place and return how many
+    /// there were, the second phase of a two-phase commit. like a put each one keeps the history
+    /// and reverse index up to date and the observers are notified.
+    pub fn commit(&self, txn: &str) -> Result<usize, Error> {
+        self.commit_staged(txn, |key| {
+            let (_, _, file, _) = self.get_paths(key)?;
+            if let Some(cid) = self.read_cid(&self.aad(key)?, &file)? {
+                self.notify(Event::MapUpdated(key.clone(), cid));
+            }
+            Ok(())
+        })
+    }
+
+    /// restore the value the mapping had n versions ago, n = 1 being the previous value. the
+    /// rollback is a put so the value it replaces is appended to the history.
+    pub fn rollback(&mut self, id: &T::Id, n: usize) -> Result<Cid, Error> {
+        let key = Self::map_key(id)?;
+        let cid = self.history_version(&key, n)?;
+        self.put_cid(&key, &cid)?;
+        Ok(cid)
+    }
+}
+
+impl<T> CidMap<T::Id> for FsStorage<T>
+where
+    T: MapKey
+{
+    type Error = Error;
+
+    fn exists(&self, id: &T::Id) -> Result<bool, Self::Error> {
+        let (_, _, file, _) = self.get_paths(&Self::map_key(id)?)?;
+        file.try_exists().io_context("stat", &file)
+    }
+
+    fn get(&self, id: &T::Id) -> Result<Cid, Self::Error> {
+        self.get_cid(&Self::map_key(id)?)
+    }
+
+    fn put(&mut self, id: &T::Id, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
+        self.put_cid(&Self::map_key(id)?, cid)
+    }
+
+    fn put_cas(&mut self, id: &T::Id, expected: Option<&Cid>, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
+        self.put_cid_cas(&Self::map_key(id)?, expected, cid)
+    }
+
+    fn rm(&self, id: &T::Id) -> Result<Cid, Self::Error> {
+        self.rm_cid(&Self::map_key(id)?)
+    }
+
+    fn put_batch(&mut self, entries: &[(T::Id, Cid)]) -> Vec<Result<Option<Cid>, Self::Error>>
+    where
+        T::Id: Sized,
+    {
+        let mut keyed = Vec::with_capacity(entries.len());
+        for (id, cid) in entries {
+            match Self::map_key(id) {
+                Ok(key) => keyed.push((key, cid.clone())),
+                // put them one at a time so only the bad IDs fail
+                Err(_) => return entries.iter().map(|(id, cid)| self.put(id, cid)).collect(),
+            }
+        }
+        self.put_cid_batch(&keyed)
+    }
+
+    fn ids(&self) -> Result<Vec<T::Id>, Self::Error>
+    where
+        T::Id: Sized,
+    {
+        Ok(FsStorage::ids(self)?.into_iter().filter_map(<T::Id as EntryKey<T>>::from_entry_key).collect())
+    }
+
+    #[cfg(feature = "watch")]
+    fn watch(&self, id: &T::Id) -> Result<crate::Watch<Cid>, Self::Error> {
+        self.watch_entry(&Self::map_key(id)?)
+    }
+
+    fn referrers(&self, cid: &Cid) -> Result<Vec<T::Id>, Self::Error>
+    where
+        T::Id: Sized,
+    {
+        Ok(self.read_referrers(cid)?.into_iter().filter_map(<T::Id as EntryKey<T>>::from_entry_key).collect())
+    }
+}
+
+/// whether the name is a device name Windows reserves, ignoring case and any extension
+pub fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().to_lowercase();
+    RESERVED_NAMES.contains(&stem.as_str())
+}
+
+/// the folder name for a shard, on Windows the reserved device names get a "_" appended. the
+/// shard names all have the same length so the escaped names never collide with real ones.
+fn shard_name(name: String) -> String {
+    if cfg!(windows) && is_reserved_name(&name) {
+        format!("{}_", name)
+    } else {
+        name
+    }
+}
+
+/// whether the encoding uses both upper and lower case letters
+fn is_case_sensitive(base: &Base) -> bool {
+    matches!(
+        base,
+        Base::Base58Btc | Base::Base58Flickr | Base::Base64 | Base::Base64Pad | Base::Base64Url | Base::Base64UrlPad
+    )
+}
+
+/// whether the filesystem the dir is on folds the case of file names, found by looking for a new
+/// file under its upper case name
+fn is_case_insensitive(dir: &Path) -> Result<bool, Error> {
+    let probe = tempfile::Builder::new().prefix(".tmpcase").tempfile_in(dir).io_context("create temp file in", dir)?;
+    let name = probe.path().file_name().unwrap_or_default().to_string_lossy().to_uppercase();
+    Ok(dir.join(name).try_exists().io_context("stat", dir)?)
+}
+
+/// read the configuration persisted in the root, if there is one
+fn read_config_file(root: &Path) -> Result<Option<Config>, Error> {
+    let file = root.join(CONFIG_FILE);
+    if !file.try_exists().io_context("stat", &file)? {
+        return Ok(None);
+    }
+    let config = serde_json::from_slice(&fs::read(&file).io_context("read", &file)?)
+        .map_err(|e| FsStorageError::InvalidConfig(e.to_string()))?;
+    Ok(Some(config))
+}
+
+/// add the files in the dir to the archive with paths relative to the root, skipping dot files
+#[cfg(feature = "tar")]
+fn archive_dir<W: Write>(tar: &mut tar::Builder<W>, root: &Path, dir: &Path, recurse: bool) -> Result<(), Error> {
+    for file in fs::read_dir(dir).io_context("read dir", dir)? {
+        let file = file.io_context("read dir", dir)?;
+        if file.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = file.path();
+        if path.is_dir() {
+            if recurse {
+                archive_dir(tar, root, &path, recurse)?;
+            }
+            continue;
+        }
+        let name = path.strip_prefix(root).unwrap_or(&path);
+        match tar.append_path_with_name(&path, name) {
+            Ok(()) => {}
+            // removed by another writer since the dir was read
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).io_context("archive", &path),
+        }
+    }
+    Ok(())
+}
+
+/// check that a file in a snapshot is an entry in a shard or a record in one of the record
+/// folders. shards and entries never start with a dot, everything else in the root does.
+#[cfg(feature = "tar")]
+fn snapshot_file(rel: &Path) -> bool {
+    let names: Vec<_> = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    let [first, .., name] = names.as_slice() else {
+        return false;
+    };
+    !name.starts_with('.') && (!first.starts_with('.') || RECORD_DIRS.iter().any(|dir| *dir == first))
+}
+
+/// check the parts of a persisted config that decide where and how entries are stored against
+/// the expected config
+fn check_layout(config: &Config, expected: &Config) -> Result<(), Error> {
+    if config.base_encoding != expected.base_encoding {
+        return Err(FsStorageError::ConfigMismatch(
+            format!("base encoding is {:?} not {:?}", config.base_encoding, expected.base_encoding)
+        ).into());
+    }
+    if config.sharding != expected.sharding {
+        return Err(FsStorageError::ConfigMismatch(
+            format!("sharding is {:?} not {:?}", config.sharding, expected.sharding)
+        ).into());
+    }
+    if config.lazy != expected.lazy {
+        return Err(FsStorageError::ConfigMismatch(
+            format!("lazy is {} not {}", config.lazy, expected.lazy)
+        ).into());
+    }
+    if config.fingerprint != expected.fingerprint {
+        return Err(FsStorageError::ConfigMismatch(
+            format!("fingerprint is {:?} not {:?}", config.fingerprint, expected.fingerprint)
+        ).into());
+    }
+    // the codec of each entry is read from its header so it may change, but entries without
+    // a header can't be told apart from ones with one
+    if config.compressed.is_some_and(|compressed| Some(compressed) != expected.compressed) {
+        let compressed = if config.compressed == Some(true) { "is" } else { "isn't" };
+        return Err(FsStorageError::ConfigMismatch(format!("the store {} compressed", compressed)).into());
+    }
+    Ok(())
+}
+
+/// the metadata sidecar of the entry with the encoded id in the subfolder. the sidecars are laid
+/// out under .meta like the subfolders so no one folder holds all of them.
+pub(crate) fn meta_file(root: &Path, subfolder: &Path, eid: &str) -> PathBuf {
+    let mut pb = root.join(META_DIR);
+    pb.push(subfolder.strip_prefix(root).unwrap_or(subfolder));
+    pb.push(eid);
+    pb
+}
+
+/// finish a migration left in the root by migrate_encoding() or roll it back if it never got
+/// marked ready. every step can be run again after a crash part way through it.
+fn finish_migration(root: &Path) -> Result<(), Error> {
+    let staging = root.join(MIGRATE_DIR);
+    if !staging.try_exists().io_context("stat", &staging)? {
+        return Ok(());
+    }
+    if !staging.join(MIGRATE_READY).try_exists().io_context("stat", &staging)? {
+        fs::remove_dir_all(&staging).io_context("remove dir", &staging)?;
+        debug!("fsstorage: Rolled back the migration of {}", root.display());
+        return Ok(());
+    }
+
+    // remove the old subfolders and the records the staged ones replace, only once, the moved
+    // in ones look just like them
+    let swapping = staging.join(MIGRATE_SWAPPING);
+    if !swapping.try_exists().io_context("stat", &swapping)? {
+        let temp_dir = read_config_file(&staging)?.and_then(|config| config.staging_dir);
+        for entry in fs::read_dir(root).io_context("read dir", root)? {
+            let entry = entry.io_context("read dir", root)?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let replaced = !name.starts_with('.') || staging.join(&name).is_dir();
+            // a staging dir for temp files configured inside the root isn't part of the layout
+            let is_temp_dir = temp_dir
+                .as_deref()
+                .and_then(|dir| dir.canonicalize().ok())
+                .is_some_and(|dir| entry.path().canonicalize().is_ok_and(|path| path == dir));
+            if entry.file_type().io_context("stat", entry.path())?.is_dir() && replaced && !is_temp_dir {
+                fs::remove_dir_all(entry.path()).io_context("remove dir", &entry.path())?;
+            }
+        }
+        fs::File::create(&swapping).and_then(|f| f.sync_all()).io_context("create", &swapping)?;
+    }
 
-    // phantoms
-    _t: PhantomData<T>,
+    // move the staged layout in, the config last so it only changes once the entries are there
+    for entry in fs::read_dir(&staging).io_context("read dir", &staging)? {
+        let entry = entry.io_context("read dir", &staging)?;
+        let name = entry.file_name();
+        if name != MIGRATE_READY && name != MIGRATE_SWAPPING && name != CONFIG_FILE {
+            fs::rename(entry.path(), root.join(&name)).io_context("rename", &entry.path())?;
+        }
+    }
+    let config = staging.join(CONFIG_FILE);
+    if config.try_exists().io_context("stat", &config)? {
+        fs::rename(&config, root.join(CONFIG_FILE)).io_context("rename", &config)?;
+    }
+    fs::remove_dir_all(&staging).io_context("remove dir", &staging)?;
+    debug!("fsstorage: Finished the migration of {}", root.display());
+    Ok(())
 }
 
-impl<T> EncodingInfo for FsStorage<T>
-where
-    T: EncodingInfo
-{
-    fn preferred_encoding() -> Base {
-        DetectedEncoder::preferred_encoding(T::preferred_encoding())
+/// hard link the file to the path, creating the folders above it, or copy it where the
+/// filesystem can't link
+fn link_into(path: &Path, dest: &Path) -> Result<(), Error> {
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir).io_context("create dir", dir)?;
     }
-
-    fn encoding(&self) -> Base {
-        self.base_encoding
+    if fs::hard_link(path, dest).is_err() {
+        fs::copy(path, dest).io_context("copy", path)?;
     }
+    Ok(())
 }
 
-impl<T> FsStorage<T>
-where
-    T: Clone + EncodingInfo + Into<Vec<u8>>
-{
-    /// garbage collect the block storage to remove any lazy deleted files and empty subfolders
-    pub fn gc(&mut self) -> Result<(), Error> {
-        for subfolder in &Self::subfolders(Some(self.encoding()), &self.root)? {
-            if !subfolder.try_exists()? {
-                continue;
-            }
-            for file in fs::read_dir(subfolder)? {
-                let file = file?;
-                if file.file_name().to_string_lossy().starts_with('.') {
-                    fs::remove_file(&file.path())?;
-                    debug!("fsstorage: GC'd file {}", file.path().display());
+/// persist the configuration in the root, atomically so a crash never leaves a partial config
+fn write_config_file(root: &Path, config: &Config) -> Result<(), Error> {
+    let data = serde_json::to_vec_pretty(config)
+        .map_err(|e| FsStorageError::InvalidConfig(e.to_string()))?;
+    let mut temp = tempfile::Builder::new().tempfile_in(root).io_context("create temp file in", &root)?;
+    temp.write_all(&data).io_context("write", temp.path())?;
+    temp.persist(root.join(CONFIG_FILE))?;
+    Ok(())
+}
+
+/// remove the lazy deleted and temporary files in the subfolder that the policy doesn't retain,
+/// then remove the subfolder and its parents below the root if that leaves them empty
+fn gc_subfolder(root: &Path, subfolder: &Path, policy: &GcPolicy) -> Result<usize, Error> {
+    if !subfolder.try_exists().io_context("stat", subfolder)? {
+        return Ok(0);
+    }
+    let pending = pending_in_subfolder(subfolder, policy)?;
+    for pending in &pending {
+        fs::remove_file(&pending.path).io_context("remove", &pending.path)?;
+        debug!("fsstorage: GC'd file {}", pending.path.display());
+
+        // the metadata sidecar goes with the entry once it is gone for good
+        if pending.kind == PendingKind::Deleted {
+            if let Some(eid) = pending.path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_prefix('.')) {
+                let meta = meta_file(root, subfolder, eid);
+                match fs::remove_file(&meta) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e).io_context("remove", &meta),
+                    _ => {}
                 }
             }
-            if fs::read_dir(subfolder)?.count() == 0 {
-                fs::remove_dir(subfolder)?;
-                debug!("fsstorage: GC'd subfolder {}", subfolder.display());
-            }
         }
-        Ok(())
     }
+    if fs::read_dir(subfolder).io_context("read dir", subfolder)?.count() == 0 {
+        remove_empty_parents(root, subfolder);
+    }
+    Ok(pending.len())
+}
 
-    /// get an iterator over the subfolders given the base encoding
-    pub fn subfolders<P: AsRef<Path>>(base_encoding: Option<Base>, root: P) -> Result<Vec<PathBuf>, Error> {
-        let base_encoding = base_encoding.unwrap_or(FsStorage::<T>::preferred_encoding());
+/// remove the folder and then its parents below the root while they are empty. this stops at
+/// the first one that isn't empty or that another thread already removed.
+fn remove_empty_parents(root: &Path, dir: &Path) {
+    let mut dir = Some(dir);
+    while let Some(d) = dir {
+        if d == root || fs::remove_dir(d).is_err() {
+            break;
+        }
+        debug!("fsstorage: Removed empty subfolder {}", d.display());
+        dir = d.parent();
+    }
+}
 
-        // create the root directory
-        if !root.as_ref().try_exists()? {
-            debug!("fsstorage: creating root dir at {}", root.as_ref().display());
-            fs::create_dir_all(&root)?;
+/// find the files in the subfolder that gc() removes under the policy
+fn pending_in_subfolder(subfolder: &Path, policy: &GcPolicy) -> Result<Vec<PendingGc>, Error> {
+    let now = SystemTime::now();
+    let mut pending = Vec::default();
+    for file in fs::read_dir(subfolder).io_context("read dir", subfolder)? {
+        let file = file.io_context("read dir", subfolder)?;
+        let name = file.file_name().to_string_lossy().to_string();
+        // lock files belong to their holders, cleanup_temp_files() removes the stale ones
+        if is_lock_file(&name) {
+            continue;
         }
-        debug!("fsstorage: root dir exists");
+        let Some(rest) = name.strip_prefix('.') else {
+            continue;
+        };
+        let metadata = file.metadata().io_context("stat", file.path())?;
+        let age = now.duration_since(metadata.modified().unwrap_or(UNIX_EPOCH)).unwrap_or_default();
 
-        // construct the directory structure using the alphabent of the base encoder
-        let symbols = Self::encoding_symbols(&base_encoding)?;
-        Ok(symbols.chars().map(|c| {
-            let mut p = root.as_ref().to_path_buf();
-            p.push(c.to_string());
-            p
-        }).collect())
+        // lazy deleted files are a "." followed by the encoded ID, temp files have more
+        let kind = if rest.contains('.') { PendingKind::Temp } else { PendingKind::Deleted };
+        if kind == PendingKind::Deleted && age < policy.retain_deleted {
+            continue;
+        }
+
+        pending.push(PendingGc {
+            path: file.path(),
+            kind,
+            age,
+            bytes: metadata.len(),
+        });
     }
+    Ok(pending)
+}
 
-    fn encoding_symbols(base: &Base) -> Result<String, Error> {
-        match base {
-            Base::Base2 => Ok("01".into()),
-            Base::Base8 => Ok("01234567".into()),
-            Base::Base10 => Ok("0123456789".into()),
-            Base::Base16Lower => Ok("0123456789abcdef".into()),
-            Base::Base16Upper => Ok("0123456789ABCDEF".into()),
-            Base::Base32Lower | Base::Base32PadLower => Ok("abcdefghijklmnopqrstuvwxyz234567".into()),
-            Base::Base32Upper | Base::Base32PadUpper => Ok("ABCDEFGHIJKLMNOPQRSTUVWXYZ234567".into()),
-            Base::Base32HexLower | Base::Base32HexPadLower => Ok("0123456789abcdefghijklmnopqrstuv".into()),
-            Base::Base32HexUpper | Base::Base32HexPadUpper => Ok("0123456789ABCDEFGHIJKLMNOPQRSTUV".into()),
-            Base::Base32Z => Ok("ybndrfg8ejkmcpqxot1uwisza345h769".into()),
-            Base::Base36Lower => Ok("0123456789abcdefghijklmnopqrstuvwxyz".into()),
-            Base::Base36Upper => Ok("0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ".into()),
-            Base::Base58Flickr => Ok("123456789abcdefghijkmnopqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ".into()),
-            Base::Base58Btc => Ok("123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz".into()),
-            Base::Base64 |
-            Base::Base64Pad |
-            Base::Base64Url |
-            Base::Base64UrlPad => Ok("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_".into()),
-            _ => Err(FsStorageError::UnsupportedBaseEncoding(*base).into())
+/// set the modified time of a lazy deleted file to now so gc() can tell how long ago it was deleted
+pub(crate) fn mark_deleted(path: &Path) -> Result<(), Error> {
+    // only the attributes change so the file isn't opened for writing, windows needs the right
+    // to write attributes (FILE_READ_ATTRIBUTES | FILE_WRITE_ATTRIBUTES) to set the time
+    let mut options = fs::File::options();
+    options.read(true);
+    #[cfg(windows)]
+    std::os::windows::fs::OpenOptionsExt::access_mode(&mut options, 0x0080 | 0x0100);
+    options
+        .open(path)
+        .and_then(|f| f.set_modified(SystemTime::now()))
+        .io_context("touch", path)
+}
+
+/// remove the folder and the folders below it if none of them hold any files
+fn remove_empty_dirs(dir: &Path) -> Result<(), Error> {
+    for entry in fs::read_dir(dir).io_context("read dir", &dir)? {
+        let entry = entry.io_context("read dir", dir)?;
+        if entry.file_type().io_context("stat", entry.path())?.is_dir() {
+            remove_empty_dirs(&entry.path())?;
         }
     }
+    if fs::read_dir(dir).io_context("read dir", &dir)?.count() == 0 {
+        fs::remove_dir(dir).io_context("remove dir", &dir)?;
+    }
+    Ok(())
+}
 
-    pub(crate) fn get_paths(&self, id: &T) -> Result<(BaseEncoded<T, DetectedEncoder>, PathBuf, PathBuf, PathBuf), Error> {
-        let eid = self.encode(id)?;
-        let subfolder = self.get_subfolder(&eid)?;
-        let file = self.get_file(&subfolder, &eid)?;
-        let lazy_deleted_file = self.get_lazy_deleted_file(&subfolder, &eid)?;
-        Ok((eid, subfolder, file, lazy_deleted_file))
+/// read a timestamp file written by set_expiry, returns None if it doesn't exist or is invalid
+fn read_timestamp(path: &Path) -> Result<Option<SystemTime>, Error> {
+    if !path.try_exists().io_context("stat", &path)? {
+        return Ok(None);
+    }
+    Ok(fs::read_to_string(path).io_context("read", &path)?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+fn compress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match codec {
+        Codec::Identity => Ok(data.to_vec()),
+        Codec::Zstd => Ok(zstd::encode_all(data, 0).map_err(|e| FsStorageError::CompressionFailed(e.to_string()))?),
+        _ => Err(FsStorageError::UnsupportedCompression(codec).into()),
     }
+}
 
-    fn encode(&self, id: &T) -> Result<BaseEncoded<T, DetectedEncoder>, Error> {
-        Ok(BaseEncoded::<T, DetectedEncoder>::new(self.base_encoding, id.clone()))
+fn decompress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match codec {
+        Codec::Identity => Ok(data.to_vec()),
+        Codec::Zstd => Ok(zstd::decode_all(data).map_err(|e| FsStorageError::CompressionFailed(e.to_string()))?),
+        _ => Err(FsStorageError::UnsupportedCompression(codec).into()),
     }
+}
 
-    fn get_subfolder(&self, eid: &BaseEncoded<T, DetectedEncoder>) -> Result<PathBuf, Error> {
-        // get the middle char of the encoded CID
-        let s = format!("{eid}");
-        let l = s.len();
-        let c = s.chars().nth_back(l >> 1).ok_or(FsStorageError::InvalidId(eid.to_string()))?;
+const NONCE_LEN: usize = 12;
 
-        // create a pathbuf to the subfolder
-        let mut pb = self.root.clone();
-        pb.push(c.to_string());
+// the most bytes the varuint encoding of a u64 takes
+const MAX_VARUINT_LEN: u64 = 10;
 
-        Ok(pb)
+// the length of the keys derived from passphrases and of their salts
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+// the multikey cipher views wrap the key material of another multikey and have no associated
+// data, so values are sealed with the key bytes of the data view instead
+fn cipher(key: &Multikey) -> Result<ChaCha20Poly1305, Error> {
+    let key_bytes = key.data_view()?.key_bytes()?;
+    ChaCha20Poly1305::new_from_slice(&key_bytes).map_err(|_| FsStorageError::InvalidEncryptionKey.into())
+}
+
+/// derive the encryption key of a passphrase protected store with Argon2id, using the
+/// parameters persisted in the root or new ones with a random salt when there aren't any
+fn passphrase_key(root: &Path, passphrase: &str) -> Result<(Multikey, KdfParams), Error> {
+    let invalid = |e: &dyn fmt::Display| Error::from(FsStorageError::InvalidConfig(e.to_string()));
+    let existing = read_config_file(root)?.and_then(|config| config.kdf);
+    let (m_cost, t_cost, p_cost, salt) = match &existing {
+        Some(kdf) => {
+            let (_, salt) = multibase::decode(&kdf.salt).map_err(|e| invalid(&e))?;
+            (kdf.m_cost, kdf.t_cost, kdf.p_cost, salt)
+        }
+        None => {
+            let mut salt = vec![0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            (Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, salt)
+        }
+    };
+
+    let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_LEN)).map_err(|e| invalid(&e))?;
+    let mut key_bytes = [0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key_bytes)
+        .map_err(|e| invalid(&e))?;
+    let key = mk::Builder::new(Codec::Chacha20Poly1305).with_key_bytes(&key_bytes).try_build()?;
+
+    match existing {
+        Some(kdf) => {
+            let (_, check) = multibase::decode(&kdf.check).map_err(|e| invalid(&e))?;
+            decrypt(&key, &[], &check).map_err(|_| FsStorageError::InvalidEncryptionKey)?;
+            Ok((key, kdf))
+        }
+        None => {
+            let check = multibase::encode(Base::Base32Z, encrypt(&key, &[], &[])?);
+            let salt = multibase::encode(Base::Base32Z, salt);
+            Ok((key, KdfParams { m_cost, t_cost, p_cost, salt, check }))
+        }
     }
+}
 
-    fn get_file<P: AsRef<Path>>(&self, subfolder: P, eid: &BaseEncoded<T, DetectedEncoder>) -> Result<PathBuf, Error> {
-        let mut pb = subfolder.as_ref().to_path_buf();
-        pb.push(&eid.to_string());
-        Ok(pb)
+/// check the encryption key of a store that isn't passphrase protected against the key check
+/// persisted in the root, or make a key check for a store that doesn't have one yet
+fn key_check(existing: Option<&Config>, key: &Multikey) -> Result<String, Error> {
+    let invalid = |e: &dyn fmt::Display| Error::from(FsStorageError::InvalidConfig(e.to_string()));
+    match existing.and_then(|config| config.key_check.as_ref()) {
+        Some(check) => {
+            let (_, encrypted) = multibase::decode(check).map_err(|e| invalid(&e))?;
+            decrypt(key, &[], &encrypted).map_err(|_| FsStorageError::InvalidEncryptionKey)?;
+            Ok(check.clone())
+        }
+        None => Ok(multibase::encode(Base::Base32Z, encrypt(key, &[], &[])?)),
     }
+}
 
-    fn get_lazy_deleted_file<P: AsRef<Path>>(&self, subfolder: P, eid: &BaseEncoded<T, DetectedEncoder>) -> Result<PathBuf, Error> {
-        let mut pb = subfolder.as_ref().to_path_buf();
-        pb.push(&format!(".{}", eid));
-        Ok(pb)
+/// load the encryption key from the platform keychain entry, storing the key passed in in it
+/// when the entry doesn't exist yet. a new random key is only made for a new store, an existing
+/// store without its entry can't be decrypted with a new key.
+#[cfg(feature = "keyring")]
+fn keyring_key(service: &str, user: &str, key: Option<&Multikey>, new_store: bool) -> Result<Multikey, Error> {
+    let entry = keyring::Entry::new(service, user)?;
+    match entry.get_secret() {
+        Ok(secret) => {
+            let stored = Multikey::try_from(secret.as_slice())?;
+            if key.is_some_and(|key| *key != stored) {
+                return Err(FsStorageError::InvalidConfig(
+                    format!("keyring entry {}/{} holds a different key", service, user)
+                ).into());
+            }
+            Ok(stored)
+        }
+        Err(keyring::Error::NoEntry) => {
+            let key = match key {
+                Some(key) => key.clone(),
+                None if new_store => mk::Builder::new_from_random_bytes(Codec::Chacha20Poly1305, &mut OsRng)?.try_build()?,
+                None => {
+                    return Err(FsStorageError::InvalidConfig(
+                        format!("keyring entry {}/{} doesn't exist for an existing store", service, user)
+                    ).into());
+                }
+            };
+            let secret: Vec<u8> = key.clone().into();
+            entry.set_secret(&secret)?;
+            debug!("fsstorage: Stored the encryption key in keyring entry {}/{}", service, user);
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// encrypt the data and authenticate it together with the associated data, which isn't stored
+/// and has to be passed in again to decrypt it
+fn encrypt(key: &Multikey, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut ciphertext = cipher(key)?
+        .encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| FsStorageError::EncryptionFailed)?;
+    let mut v = nonce.to_vec();
+    v.append(&mut ciphertext);
+    Ok(v)
+}
+
+fn decrypt(key: &Multikey, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < NONCE_LEN {
+        return Err(FsStorageError::DecryptionFailed.into());
     }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    cipher(key)?
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| FsStorageError::DecryptionFailed.into())
 }
 
 pub(crate) mod serde_base {
@@ -165,6 +3462,52 @@ pub(crate) mod serde_base {
     }
 }
 
+pub(crate) mod serde_codec {
+    use multicodec::Codec;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S>(v: &Option<Codec>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        v.map(|c| c.code()).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<Codec>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<u64>::deserialize(deserializer)? {
+            Some(code) => Ok(Some(Codec::try_from(code).map_err(serde::de::Error::custom)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) mod serde_codecs {
+    use multicodec::Codec;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S>(v: &Option<Vec<Codec>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        v.as_ref().map(|codecs| codecs.iter().map(|c| c.code()).collect::<Vec<_>>()).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<Codec>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<Vec<u64>>::deserialize(deserializer)? {
+            Some(codes) => Ok(Some(
+                codes.into_iter().map(Codec::try_from).collect::<Result<_, _>>().map_err(serde::de::Error::custom)?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
 /// Builder for a FsStorage instance
 #[derive(Clone, Debug, Default)]
 pub struct Builder<T> 
@@ -174,6 +3517,30 @@ where
     root: PathBuf,
     lazy: bool,
     base_encoding: Option<Base>,
+    sharding: Sharding,
+    compression: Option<Codec>,
+    encryption_key: Option<EncryptionKey>,
+    passphrase: Option<Passphrase>,
+    #[cfg(feature = "keyring")]
+    keyring: Option<(String, String)>,
+    max_bytes: Option<u64>,
+    reserved_bytes: Option<u64>,
+    max_block_size: Option<u64>,
+    allowed_hashes: Option<Vec<Codec>>,
+    cid_config: Option<(Codec, Codec)>,
+    gc_policy: GcPolicy,
+    gc_threads: Option<usize>,
+    history: bool,
+    journal: bool,
+    reverse_index: bool,
+    codec_index: bool,
+    bloom: Option<(u64, f64)>,
+    durability: Durability,
+    staging_dir: Option<PathBuf>,
+    cleanup_on_open: Option<Duration>,
+    recover_on_open: bool,
+    fingerprint: Option<Codec>,
+    fingerprinter: Fingerprinter<T>,
     _t: PhantomData<T>,
 }
 
@@ -188,6 +3555,30 @@ where
             root: root.as_ref().to_path_buf(),
             lazy: true,
             base_encoding: None,
+            sharding: Sharding::default(),
+            compression: None,
+            encryption_key: None,
+            passphrase: None,
+            #[cfg(feature = "keyring")]
+            keyring: None,
+            max_bytes: None,
+            reserved_bytes: None,
+            max_block_size: None,
+            allowed_hashes: None,
+            cid_config: None,
+            gc_policy: GcPolicy::default(),
+            gc_threads: None,
+            history: false,
+            journal: false,
+            reverse_index: false,
+            codec_index: false,
+            bloom: None,
+            durability: Durability::default(),
+            staging_dir: None,
+            cleanup_on_open: None,
+            recover_on_open: false,
+            fingerprint: None,
+            fingerprinter: Fingerprinter::default(),
             _t: PhantomData,
         }
     }
@@ -204,35 +3595,353 @@ where
         self
     }
 
+    /// set how entries are spread across subfolders (e.g. Sharding::Suffix { chars: 2, depth: 2 })
+    pub fn with_sharding(mut self, sharding: Sharding) -> Self {
+        self.sharding = sharding;
+        self
+    }
+
+    /// set the codec used to compress data at rest (e.g. Codec::Zstd)
+    pub fn with_compression(mut self, codec: Codec) -> Self {
+        self.compression = Some(codec);
+        self
+    }
+
+    /// set the symmetric key used to encrypt data at rest (e.g. a Codec::Chacha20Poly1305 key)
+    pub fn with_encryption_key(mut self, key: &Multikey) -> Self {
+        self.encryption_key = Some(EncryptionKey(key.clone()));
+        self
+    }
+
+    /// derive the key used to encrypt data at rest from the passphrase with Argon2id. the salt
+    /// and cost parameters are persisted in the config so the store is opened again with the
+    /// same passphrase alone, and a wrong one is refused.
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(Passphrase(passphrase.to_string()));
+        self
+    }
+
+    /// keep the key used to encrypt data at rest in the platform keychain entry for the service
+    /// and user instead of managing the raw key material. the key is loaded from the entry, and
+    /// when the entry doesn't exist yet the key set with with_encryption_key(), or a new random
+    /// Codec::Chacha20Poly1305 key, is stored in it.
+    #[cfg(feature = "keyring")]
+    pub fn with_keyring(mut self, service: &str, user: &str) -> Self {
+        self.keyring = Some((service.to_string(), user.to_string()));
+        self
+    }
+
+    /// set the maximum number of bytes that may be stored
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// keep at least this many bytes free on the filesystem holding the store. puts that would
+    /// eat into the reserve fail up front with an Error::StoreFull.
+    pub fn with_reserved_bytes(mut self, reserved_bytes: u64) -> Self {
+        self.reserved_bytes = Some(reserved_bytes);
+        self
+    }
+
+    /// set the size in bytes of the largest block puts accept, networks like bitswap cap blocks
+    /// at 1-2 MiB so stores feeding them can refuse bigger ones
+    pub fn with_max_block_size(mut self, max_block_size: u64) -> Self {
+        self.max_block_size = Some(max_block_size);
+        self
+    }
+
+    /// only accept blocks whose Cids use one of these hash codecs
+    pub fn with_allowed_hashes(mut self, hashes: &[Codec]) -> Self {
+        self.allowed_hashes = Some(hashes.to_vec());
+        self
+    }
+
+    /// set the target and hash codecs of the Cids the store builds itself
+    pub fn with_cid_config(mut self, target_codec: Codec, hash_codec: Codec) -> Self {
+        self.cid_config = Some((target_codec, hash_codec));
+        self
+    }
+
+    /// set which lazy deleted files gc() removes
+    pub fn with_gc_policy(mut self, policy: GcPolicy) -> Self {
+        self.gc_policy = policy;
+        self
+    }
+
+    /// limit the number of threads gc() uses to scan and delete across subfolders
+    #[cfg(feature = "parallel")]
+    pub fn with_gc_threads(mut self, threads: usize) -> Self {
+        self.gc_threads = Some(threads);
+        self
+    }
+
+    /// keep the values replaced by each put in a per-entry history
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    /// record each put and remove in a journal in the root before it touches the store so that
+    /// the ones interrupted by a crash can be finished or rolled back by recover()
+    pub fn with_journal(mut self) -> Self {
+        self.journal = true;
+        self
+    }
+
+    /// maintain a reverse index from Cids to the IDs that point at them. this should be enabled
+    /// when the store is created, mappings put before it was enabled aren't indexed.
+    pub fn with_reverse_index(mut self) -> Self {
+        self.reverse_index = true;
+        self
+    }
+
+    /// maintain an index of the stored blocks by hash and target codec so they can be listed by
+    /// either. blocks stored before it was enabled are only indexed by rebuild_codec_index().
+    pub fn with_codec_index(mut self) -> Self {
+        self.codec_index = true;
+        self
+    }
+
+    /// keep a bloom filter over the stored IDs sized for the expected number of entries with the
+    /// given false positive rate (e.g. 0.01). the filter is only kept up to date by this process
+    /// so it must not be used when other processes write to the store at the same time.
+    pub fn with_bloom_filter(mut self, expected_items: u64, false_positive_rate: f64) -> Self {
+        self.bloom = Some((expected_items, false_positive_rate));
+        self
+    }
+
+    /// set how hard writes try to make sure committed entries survive a crash
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// write new data to temp files in the directory and move them into place from there, e.g. a
+    /// fast local disk in front of a store on slower storage. the directory may be on another
+    /// filesystem, the data is then copied next to its final place before the atomic rename.
+    pub fn with_staging_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.staging_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// remove the temp and lock files older than min_age that crashed writers left behind when
+    /// the store is opened instead of waiting for a gc(). the age keeps the files of writes that
+    /// other processes have in flight.
+    pub fn with_cleanup_on_open(mut self, min_age: Duration) -> Self {
+        self.cleanup_on_open = Some(min_age);
+        self
+    }
+
+    /// run recover() when the store is opened to finish or roll back the operations a crashed
+    /// process left in the journal. like recover() this must only be used when no other process
+    /// is writing to the store, e.g. when the single process using it starts up.
+    pub fn with_recover_on_open(mut self) -> Self {
+        self.recover_on_open = true;
+        self
+    }
+
+    /// name the entries after the fingerprints of their IDs calculated with the hash codec
+    /// instead of the encoded IDs. the map stores that support this wrap it in a with_fingerprints
+    /// of their own that passes in how their IDs are fingerprinted.
+    pub(crate) fn with_fingerprints(mut self, codec: Codec, fingerprint: fn(&T, Codec) -> Result<Vec<u8>, Error>) -> Self {
+        self.fingerprint = Some(codec);
+        self.fingerprinter = Fingerprinter(Some(fingerprint));
+        self
+    }
+
     /// build the instance
     pub fn try_build(&self) -> Result<FsStorage<T>, Error> {
+        // a migration interrupted by a crash is settled before anything reads the store
+        finish_migration(&self.root)?;
+
         let lazy = self.lazy;
         let base_encoding = self.base_encoding.unwrap_or(FsStorage::<T>::preferred_encoding());
+        let compression = self.compression;
+
+        // make sure the sharding produces at least one subfolder level, no more than the hash
+        // of prefix sharding can name, and few enough subfolders to create them up front
+        match self.sharding {
+            Sharding::Prefix { chars, depth } | Sharding::Suffix { chars, depth } if chars == 0 || depth == 0 => {
+                return Err(FsStorageError::InvalidSharding(chars, depth).into());
+            }
+            Sharding::Prefix { chars, depth } if chars.saturating_mul(depth) > PREFIX_HASH_CHARS => {
+                return Err(FsStorageError::InvalidSharding(chars, depth).into());
+            }
+            Sharding::Prefix { chars, depth } | Sharding::Suffix { chars, depth } if !lazy => {
+                let symbols = FsStorage::<T>::shard_symbols(self.sharding, &base_encoding)?.len();
+                let shards = u32::try_from(chars.saturating_mul(depth)).ok().and_then(|n| symbols.checked_pow(n));
+                if shards.map_or(true, |shards| shards > MAX_EAGER_SHARDS) {
+                    return Err(FsStorageError::InvalidSharding(chars, depth).into());
+                }
+            }
+            _ => {}
+        }
+
+        // case insensitive filesystems would fold IDs that differ only in case into one file
+        if cfg!(windows) && is_case_sensitive(&base_encoding) {
+            return Err(FsStorageError::UnsupportedBaseEncoding(base_encoding).into());
+        }
+
+        // make sure the compression codec is one we support
+        if let Some(codec) = compression {
+            if !matches!(codec, Codec::Identity | Codec::Zstd) {
+                return Err(FsStorageError::UnsupportedCompression(codec).into());
+            }
+        }
+
+        // derive the encryption key from the passphrase, if there is one
+        let (encryption_key, kdf) = match &self.passphrase {
+            Some(_) if self.encryption_key.is_some() => {
+                return Err(FsStorageError::InvalidConfig("both an encryption key and a passphrase".to_string()).into());
+            }
+            Some(passphrase) => {
+                let (key, kdf) = passphrase_key(&self.root, &passphrase.0)?;
+                (Some(key), Some(kdf))
+            }
+            None => (self.encryption_key.clone().map(|key| key.0), None),
+        };
+        #[cfg(feature = "keyring")]
+        let encryption_key = match &self.keyring {
+            Some(_) if kdf.is_some() => {
+                return Err(FsStorageError::InvalidConfig("both a passphrase and a keyring entry".to_string()).into());
+            }
+            Some((service, user)) => {
+                let new_store = !self.root.join(CONFIG_FILE).try_exists().io_context("stat", &self.root)?;
+                Some(keyring_key(service, user, encryption_key.as_ref(), new_store)?)
+            }
+            None => encryption_key,
+        };
+
+        // refuse a key that isn't the one the store was encrypted with. without a key the
+        // persisted key check is kept for the next handle that has one.
+        let existing = read_config_file(&self.root)?;
+        let key_check = match (&encryption_key, &kdf) {
+            (Some(key), None) => Some(key_check(existing.as_ref(), key)?),
+            (None, _) => existing.and_then(|config| config.key_check),
+            (Some(_), Some(_)) => None,
+        };
+
+        // make sure the encryption key is usable
+        if let Some(key) = &encryption_key {
+            cipher(key)?;
+
+            // the reverse index names its files after the Cids so it would undo the encryption
+            if self.reverse_index {
+                return Err(Error::Unsupported("a reverse index on an encrypted store".to_string()));
+            }
+        }
+
+        // the reverse index lists the IDs from their names, fingerprints can't be decoded
+        if self.fingerprint.is_some() && self.reverse_index {
+            return Err(Error::Unsupported("a reverse index on a store named by fingerprints".to_string()));
+        }
 
         // create the root directory
         let root = self.root.clone();
-        if !root.try_exists()? {
+        if !root.try_exists().io_context("stat", &root)? {
             debug!("fsstorage: Creating root folder at {}", root.display());
-            fs::create_dir_all(&root)?;
+            fs::create_dir_all(&root).io_context("create dir", &root)?;
         }
         debug!("fsstorage: Root dir exists");
 
+        // other platforms mount case insensitive filesystems too, macOS does by default
+        if is_case_sensitive(&base_encoding) && is_case_insensitive(&root)? {
+            return Err(FsStorageError::UnsupportedBaseEncoding(base_encoding).into());
+        }
+
+        // the verbatim \\?\ form of the root lifts the MAX_PATH limit from every path below it
+        #[cfg(windows)]
+        let root = fs::canonicalize(&root).io_context("canonicalize", &root)?;
+
+        let mut storage = FsStorage {
+            root,
+            lazy,
+            base_encoding,
+            sharding: self.sharding,
+            compression,
+            encryption_key: encryption_key.map(EncryptionKey),
+            kdf,
+            key_check,
+            fingerprint: self.fingerprint,
+            max_bytes: self.max_bytes,
+            reserved_bytes: self.reserved_bytes,
+            max_block_size: self.max_block_size,
+            allowed_hashes: self.allowed_hashes.clone(),
+            target_codec: self.cid_config.map(|(target, _)| target),
+            hash_codec: self.cid_config.map(|(_, hash)| hash),
+            gc_policy: self.gc_policy,
+            gc_threads: self.gc_threads,
+            history: self.history,
+            journal: self.journal,
+            reverse_index: self.reverse_index,
+            codec_index: self.codec_index,
+            durability: self.durability,
+            staging_dir: self.staging_dir.clone(),
+            bloom: None,
+            observers: Observers::default(),
+            fingerprinter: self.fingerprinter.clone(),
+            _t: PhantomData,
+        };
+
+        // a staging dir set by an earlier build is used until another one is set
+        if storage.staging_dir.is_none() {
+            storage.staging_dir = storage.read_config()?.and_then(|config| config.staging_dir);
+        }
+
+        // refuse to open a store created with a different layout
+        storage.check_config()?;
+
+        // finish or roll back whatever a crashed process left in the journal, only when asked to
+        // because it would roll back the writes other processes have in flight
+        if self.recover_on_open {
+            storage.recover()?;
+        }
+
+        if let Some(dir) = &storage.staging_dir {
+            fs::create_dir_all(dir).io_context("create dir", dir)?;
+        }
+
+        // this is the only cleanup that removes files on open, the age keeps the temp files of
+        // the writes other processes have in flight
+        if let Some(min_age) = self.cleanup_on_open {
+            storage.cleanup_temp_files(min_age)?;
+        }
+
         if !self.lazy {
             // construct the directory structure using the alphabent of the base encoder
-            for subfolder in &FsStorage::<T>::subfolders(self.base_encoding, &root)? {
-                if !subfolder.try_exists()? {
+            for subfolder in &storage.all_shards()? {
+                if !subfolder.try_exists().io_context("stat", &subfolder)? {
                     debug!("fsstorage: Creating subfolder {}", subfolder.display());
-                    fs::create_dir_all(subfolder)?;
+                    fs::create_dir_all(subfolder).io_context("create dir", &subfolder)?;
                 }
             }
         }
 
-        Ok(FsStorage {
-            root,
-            lazy,
-            base_encoding,
-            _t: PhantomData,
-        })
+        // make sure the usage counter exists so that the first put doesn't pay for the rebuild
+        if storage.max_bytes.is_some() {
+            storage.stored_bytes()?;
+        }
+
+        // load or rebuild the bloom filter. without one, any existing filter file is removed
+        // because the puts made without it would make it stale.
+        let bloom_file = storage.root.join(BLOOM_FILE);
+        match self.bloom {
+            Some((expected_items, false_positive_rate)) => {
+                let (filter, loaded) = BloomFilter::open(&bloom_file, expected_items, false_positive_rate)?;
+                if !loaded {
+                    storage.rebuild_bloom(&filter)?;
+                }
+                storage.bloom = Some(Arc::new(filter));
+            }
+            None => {
+                if bloom_file.try_exists().io_context("stat", &bloom_file)? {
+                    fs::remove_file(&bloom_file).io_context("remove", &bloom_file)?;
+                }
+            }
+        }
+
+        Ok(storage)
     }
 }
 
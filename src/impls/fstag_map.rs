@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, CidMap, Error, error::no_such_data, fsblocks::FsBlocks, fsname_map::{self, FsNameMap}};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::path::{Path, PathBuf};
+
+/// Filesystem backed tags, human readable names that point at content in a FsBlocks store the
+/// way git refs or IPNS names do. Publishing a tag is a single atomic rename so readers always
+/// resolve either the old or the new Cid, never a partial write.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsTagMap {
+    /// The mapping from tag names to Cids
+    pub names: FsNameMap,
+
+    /// The store the tags point into, if any
+    pub blocks: Option<FsBlocks>,
+}
+
+/// Builder for a FsTagMap instance
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+    root: PathBuf,
+    history: bool,
+    blocks: Option<FsBlocks>,
+}
+
+impl Builder {
+    /// create a new builder from the root path
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("fstag_map::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            history: false,
+            blocks: None,
+        }
+    }
+
+    /// keep the Cids each tag pointed at before every publish
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    /// only allow tags to be published for Cids that are in the block store
+    pub fn with_blocks(mut self, blocks: &FsBlocks) -> Self {
+        self.blocks = Some(blocks.clone());
+        self
+    }
+
+    /// build the instance
+    pub fn try_build(&self) -> Result<FsTagMap, Error> {
+        // tags are removed for good, there is nothing to gain from lazy deleting them
+        let mut builder = fsname_map::Builder::new(&self.root)
+            .not_lazy()
+            .with_base_encoding(Base::Base32Z);
+        if self.history {
+            builder = builder.with_history();
+        }
+
+        Ok(FsTagMap {
+            names: builder.try_build()?,
+            blocks: self.blocks.clone(),
+        })
+    }
+}
+
+impl FsTagMap {
+    /// point the tag at the Cid, creating the tag if it doesn't exist. this returns the Cid the
+    /// tag pointed at before, if any.
+    pub fn publish(&mut self, name: &str, cid: &Cid) -> Result<Option<Cid>, Error> {
+        if let Some(blocks) = &self.blocks {
+            if !blocks.exists(cid)? {
+                return Err(no_such_data(cid));
+            }
+        }
+        debug!("fstag_map: Publishing {}", name);
+        self.names.put(name, cid)
+    }
+
+    /// get the Cid the tag points at
+    pub fn resolve(&self, name: &str) -> Result<Cid, Error> {
+        self.names.get(name)
+    }
+
+    /// get the data the tag points at from the block store
+    pub fn resolve_block(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let cid = self.resolve(name)?;
+        match &self.blocks {
+            Some(blocks) => blocks.get(&cid),
+            None => Err(Error::Unsupported("resolving blocks without a block store".to_string())),
+        }
+    }
+
+    /// remove the tag, returning the Cid it pointed at
+    pub fn unpublish(&self, name: &str) -> Result<Cid, Error> {
+        self.names.rm(name)
+    }
+
+    /// get the names of all of the tags in lexicographic order. names too long to be stored as
+    /// they are can't be listed.
+    pub fn tags(&self) -> Result<Vec<String>, Error> {
+        let mut tags: Vec<String> = self
+            .names
+            .ids()?
+            .iter()
+            .filter_map(|key| key.name().map(str::to_string))
+            .collect();
+        tags.sort();
+        Ok(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::fs;
+
+    // returns a Cid for the passed in data
+    fn get_cid(b: &[u8]) -> Cid {
+        cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Identity)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Sha3512, b).unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_publish_resolve() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fstagmap1");
+
+        let mut blocks = fsblocks::Builder::new(pb.join("blocks")).try_build().unwrap();
+        let v1 = blocks.put_with_cid(&get_cid(b"v1"), b"v1", true).unwrap();
+        let v2 = blocks.put_with_cid(&get_cid(b"v2"), b"v2", true).unwrap();
+
+        let mut tags = Builder::new(pb.join("tags")).with_blocks(&blocks).try_build().unwrap();
+        assert!(tags.publish("release/latest", &v1).unwrap().is_none());
+        assert_eq!(tags.publish("release/latest", &v2).unwrap(), Some(v1.clone()));
+        assert!(tags.publish("stable", &v1).unwrap().is_none());
+        assert_eq!(tags.resolve("release/latest").unwrap(), v2);
+        assert_eq!(tags.resolve_block("stable").unwrap(), b"v1".to_vec());
+        assert_eq!(tags.tags().unwrap(), vec!["release/latest".to_string(), "stable".to_string()]);
+
+        // a tag can't point at content that isn't in the store
+        assert!(tags.publish("missing", &get_cid(b"v3")).is_err());
+        assert!(tags.resolve("missing").is_err());
+
+        assert_eq!(tags.unpublish("stable").unwrap(), v1);
+        assert!(tags.resolve("stable").is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -1,9 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
-use crate::{CidMap, Error, error::FsStorageError, fsstorage::{self, FsStorage}};
+use crate::{Error, fsstorage::{self, EncryptionKey, FsStorage, MapKey, Passphrase}};
 use log::debug;
 use multibase::Base;
 use multicid::{Cid, Vlad};
-use std::{fs::{self, File}, io::{Read, Write}, path::{Path, PathBuf}};
+use multikey::Multikey;
+use std::path::{Path, PathBuf};
 
 /// The FsMultikeyMap type uses CID's
 pub type FsVladMap = FsStorage<Vlad>;
@@ -14,6 +15,12 @@ pub struct Builder {
     root: PathBuf,
     lazy: bool,
     base_encoding: Option<Base>,
+    history: bool,
+    reverse_index: bool,
+    encryption_key: Option<EncryptionKey>,
+    passphrase: Option<Passphrase>,
+    #[cfg(feature = "keyring")]
+    keyring: Option<(String, String)>,
 }
 
 impl Builder {
@@ -24,6 +31,12 @@ impl Builder {
             root: root.as_ref().to_path_buf(),
             lazy: true,
             base_encoding: None,
+            history: false,
+            reverse_index: false,
+            encryption_key: None,
+            passphrase: None,
+            #[cfg(feature = "keyring")]
+            keyring: None,
         }
     }
 
@@ -39,6 +52,40 @@ impl Builder {
         self
     }
 
+    /// keep the values replaced by each put in a per-mapping history
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    /// maintain a reverse index from Cids to the IDs that point at them so referrers() works
+    pub fn with_reverse_index(mut self) -> Self {
+        self.reverse_index = true;
+        self
+    }
+
+    /// encrypt the stored Cids with the symmetric key (e.g. a Codec::Chacha20Poly1305 key) so
+    /// the mapping from each ID to its Cid can't be read from the disk without it
+    pub fn with_encryption_key(mut self, key: &Multikey) -> Self {
+        self.encryption_key = Some(EncryptionKey(key.clone()));
+        self
+    }
+
+    /// encrypt the stored Cids with a key derived from the passphrase, see
+    /// fsstorage::Builder::with_passphrase()
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(Passphrase(passphrase.to_string()));
+        self
+    }
+
+    /// keep the encryption key in the platform keychain entry for the service and user, see
+    /// fsstorage::Builder::with_keyring()
+    #[cfg(feature = "keyring")]
+    pub fn with_keyring(mut self, service: &str, user: &str) -> Self {
+        self.keyring = Some((service.to_string(), user.to_string()));
+        self
+    }
+
     /// build the instance
     pub fn try_build(&self) -> Result<FsVladMap, Error> {
         let base_encoding = self.base_encoding.unwrap_or(Base::Base32Z);
@@ -47,118 +94,41 @@ impl Builder {
         if !self.lazy {
             builder = builder.not_lazy();
         }
-
-        builder.try_build()
-    }
-}
-
-impl CidMap<Vlad> for FsVladMap {
-    type Error = Error;
-
-    fn exists(&self, id: &Vlad) -> Result<bool, Self::Error> {
-        // get the paths
-        let (_, _, file, _) = self.get_paths(id)?;
-        Ok(file.try_exists()?)
-    }
-
-    fn get(&self, id: &Vlad) -> Result<Cid, Self::Error> {
-        // get the paths
-        let (eid, subfolder, file, _) = self.get_paths(id)?;
-
-        // check if it exists and is a dir...otherwise create the dir
-        if subfolder.try_exists()? {
-            if !subfolder.is_dir() {
-                return Err(FsStorageError::NotDir(subfolder).into());
-            }
-        } else {
-            return Err(FsStorageError::NoSuchData(eid.to_string()).into());
+        if self.history {
+            builder = builder.with_history();
         }
-
-        // store the mapping in the filesystem
-        debug!("fsmultikey_map: Getting Cid from: {}", file.display());
-        let mut f = File::open(&file)?;
-        let mut data = Vec::default();
-        f.read_to_end(&mut data)?;
-
-        // reconstruct the Cid from the data
-        let cid = Cid::try_from(data.as_slice())?;
-        Ok(cid)
-    }
-
-    fn put(&mut self, id: &Vlad, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
-        // get the paths
-        let (eid, subfolder, file, _) = self.get_paths(id)?;
-
-        // check if it exists and is a dir...otherwise create the dir
-        if subfolder.try_exists()? {
-            if !subfolder.is_dir() {
-                return Err(FsStorageError::NotDir(subfolder).into());
-            }
-        } else {
-            fs::create_dir_all(&subfolder)?;
-            debug!("fsmultikey_map: Created subfolder at: {}", subfolder.display());
+        if self.reverse_index {
+            builder = builder.with_reverse_index();
         }
-
-        // store the Cid in the filesystem
-        debug!("fsmultikey_map: Storing Cid at: {}", file.display());
-
-        // try to get the existing cid value
-        let prev_cid = self.get(id).ok();
-
-        // securely create a temporary file. its name begins with "." so that if something goes
-        // wrong, the temporary file will be cleaned up by a future GC pass
-        let mut temp = tempfile::Builder::new()
-            .suffix(&format!(".{}", eid))
-            .tempfile_in(&subfolder)?;
-
-        // write the contents to the file
-        let data: Vec<u8> = cid.clone().into();
-        temp.write_all(data.as_ref())?;
-
-        // atomically rename/move it to the correct location
-        temp.persist(&file)?;
-
-        Ok(prev_cid)
-    }
-
-    fn rm(&self, id: &Vlad) -> Result<Cid, Self::Error> {
-        // first try to get the value
-        let v = self.get(id)?;
-
-        // get the paths
-        let (_, subfolder, file, lazy_deleted_file) = self.get_paths(id)?;
-
-        // remove the file if it exists
-        if file.try_exists()? && file.is_file() {
-            if self.lazy {
-                // rename the file instead of remove it
-                fs::rename(&file, &lazy_deleted_file)?;
-                debug!("fsmultikey_map: Lazy deleted mapping at: {} to {}", file.display(), lazy_deleted_file.display());
-            } else {
-                // not lazy so delete it
-                fs::remove_file(&file)?;
-                debug!("fsmultikey_map: Removed mapping at: {}", file.display());
-            }
+        if let Some(key) = &self.encryption_key {
+            builder = builder.with_encryption_key(&key.0);
         }
-
-        // remove the subfolder if it is emtpy and we're not lazy
-        if subfolder.try_exists()? && subfolder.is_dir() && fs::read_dir(&subfolder)?.count() == 0 && !self.lazy {
-            fs::remove_dir(&subfolder)?;
-            debug!("fsmultikey_map: Removed subdir at: {}", subfolder.display());
+        if let Some(passphrase) = &self.passphrase {
+            builder = builder.with_passphrase(&passphrase.0);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some((service, user)) = &self.keyring {
+            builder = builder.with_keyring(service, user);
         }
 
-        Ok(v)
+        builder.try_build()
     }
 }
 
+impl MapKey for Vlad {
+    type Id = Vlad;
+}
+
 #[cfg(test)]
 mod tests {
     use rand;
     use super::*;
+    use crate::{Blocks, CidMap, Event, traits::cid_map};
     use multicid::{cid, vlad};
     use multicodec::Codec;
     use multihash::mh;
     use multikey::{mk, Multikey};
+    use std::{fs, sync::{Arc, Mutex}};
 
     // returns a random Ed25519 secret key as a Multikey
     fn get_mk() -> Multikey {
@@ -352,4 +322,239 @@ mod tests {
 
         assert!(fs::remove_dir_all(&pb).is_ok());
     }
+
+    #[test]
+    fn test_subscribe() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap8");
+
+        let mut vm = Builder::new(&pb).try_build().unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::default()));
+        let e = events.clone();
+        vm.subscribe(move |event: &Event<Vlad>| e.lock().unwrap().push(event.clone()));
+
+        let vlad = get_vlad(b"for great justice!");
+        let cid = get_cid(b"move every zig!");
+        let _ = vm.put(&vlad, &cid).unwrap();
+        let _ = vm.rm(&vlad).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![Event::MapUpdated(vlad.clone(), cid.clone()), Event::MapRemoved(vlad, cid)]
+        );
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_cas() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap9");
+
+        let mut vm = Builder::new(&pb).try_build().unwrap();
+
+        let vlad = get_vlad(b"for great justice!");
+        let cid1 = get_cid(b"move every zig!");
+        let cid2 = get_cid(b"someday");
+
+        // a new mapping must expect no value
+        assert!(vm.put_cas(&vlad, Some(&cid2), &cid1).is_err());
+        assert!(vm.put_cas(&vlad, None, &cid1).unwrap().is_none());
+
+        // an update must expect the current value
+        assert!(matches!(
+            vm.put_cas(&vlad, None, &cid2),
+            Err(Error::CasMismatch(None, Some(ref found))) if *found == cid1
+        ));
+        assert_eq!(vm.put_cas(&vlad, Some(&cid1), &cid2).unwrap(), Some(cid1));
+        assert_eq!(vm.get(&vlad).unwrap(), cid2);
+
+        // the lock file is released
+        let (_, subfolder, _, _) = vm.get_paths(&vlad).unwrap();
+        assert_eq!(fs::read_dir(&subfolder).unwrap().count(), 1);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_history() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap10");
+
+        let mut vm = Builder::new(&pb).with_history().try_build().unwrap();
+
+        let vlad = get_vlad(b"for great justice!");
+        let cid1 = get_cid(b"move every zig!");
+        let cid2 = get_cid(b"someday");
+        let cid3 = get_cid(b"you have no chance to survive");
+        let _ = vm.put(&vlad, &cid1).unwrap();
+        let _ = vm.put(&vlad, &cid2).unwrap();
+        let _ = vm.put(&vlad, &cid3).unwrap();
+
+        let history: Vec<Cid> = vm.history(&vlad).unwrap().into_iter().map(|(_, cid)| cid).collect();
+        assert_eq!(history, vec![cid1.clone(), cid2.clone()]);
+
+        // roll back two versions to the first value
+        assert_eq!(vm.rollback(&vlad, 2).unwrap(), cid1);
+        assert_eq!(vm.get(&vlad).unwrap(), cid1);
+        assert_eq!(vm.history(&vlad).unwrap().len(), 3);
+        assert!(vm.rollback(&vlad, 4).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_referrers() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap11");
+
+        let mut vm = Builder::new(&pb).with_reverse_index().try_build().unwrap();
+
+        let vlad1 = get_vlad(b"for great justice!");
+        let vlad2 = get_vlad(b"take off every zig!");
+        let cid1 = get_cid(b"move every zig!");
+        let cid2 = get_cid(b"someday");
+        let _ = vm.put(&vlad1, &cid1).unwrap();
+        let _ = vm.put(&vlad2, &cid1).unwrap();
+
+        let mut referrers = vm.referrers(&cid1).unwrap();
+        assert_eq!(referrers.len(), 2);
+        assert!(referrers.contains(&vlad1));
+        assert!(referrers.contains(&vlad2));
+
+        // moving a mapping moves its referrer entry
+        let _ = vm.put(&vlad1, &cid2).unwrap();
+        referrers = vm.referrers(&cid1).unwrap();
+        assert_eq!(referrers, vec![vlad2.clone()]);
+        assert_eq!(vm.referrers(&cid2).unwrap(), vec![vlad1]);
+
+        // removing a mapping drops it from the index
+        let _ = vm.rm(&vlad2).unwrap();
+        assert!(vm.referrers(&cid1).unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap12");
+
+        let key = mk::Builder::new_from_random_bytes(Codec::Chacha20Poly1305, &mut rand::rngs::OsRng::default())
+            .unwrap()
+            .try_build()
+            .unwrap();
+        let mut vm = Builder::new(&pb).with_history().with_encryption_key(&key).try_build().unwrap();
+
+        let vlad = get_vlad(b"for great justice!");
+        let cid1 = get_cid(b"move every zig!");
+        let cid2 = get_cid(b"take off every zig!");
+        let _ = vm.put(&vlad, &cid1).unwrap();
+        let _ = vm.put(&vlad, &cid2).unwrap();
+        assert_eq!(vm.get(&vlad).unwrap(), cid2);
+        assert_eq!(vm.history(&vlad).unwrap()[0].1, cid1);
+
+        // the Cid isn't on disk in the clear
+        let (_, _, file, _) = vm.get_paths(&vlad).unwrap();
+        let cid_bytes: Vec<u8> = cid2.clone().into();
+        assert_ne!(fs::read(&file).unwrap(), cid_bytes);
+
+        // and a reverse index would leak it
+        assert!(Builder::new(pb.join("index")).with_reverse_index().with_encryption_key(&key).try_build().is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap14");
+
+        let blocks = crate::fsblocks::Builder::new(pb.join("blocks")).try_build().unwrap();
+        let vm = Builder::new(pb.join("vlads")).try_build().unwrap();
+        let data = b"for great justice!";
+        let cid = get_cid(data);
+        let vlad = get_vlad(b"move every zig!");
+
+        // a block that doesn't hash to its Cid is refused
+        assert!(blocks.prepare_block("t1", data, |_| Ok(get_cid(b"all your base")), |_| Ok(())).is_err());
+
+        // stage the block and the pointer to it, neither is visible yet
+        assert_eq!(blocks.prepare_block("t1", data, |d| Ok(get_cid(d)), |_| Ok(())).unwrap(), cid);
+        vm.prepare("t1", &vlad, &cid).unwrap();
+        assert!(!blocks.exists(&cid).unwrap());
+        assert!(!vm.exists(&vlad).unwrap());
+
+        // a coordinator that crashed after deciding to commit finishes the commit on restart
+        drop((blocks, vm));
+        let blocks = crate::fsblocks::Builder::new(pb.join("blocks")).try_build().unwrap();
+        let vm = Builder::new(pb.join("vlads")).try_build().unwrap();
+        assert_eq!(blocks.prepared().unwrap(), vec!["t1".to_string()]);
+        assert_eq!(blocks.commit("t1").unwrap(), 1);
+        assert_eq!(vm.commit("t1").unwrap(), 1);
+        assert_eq!(blocks.get_verified(&cid).unwrap(), data.to_vec());
+        assert_eq!(vm.get(&vlad).unwrap(), cid);
+        assert!(vm.prepared().unwrap().is_empty());
+
+        // an aborted transaction leaves nothing behind
+        let other = get_vlad(b"all your base");
+        vm.prepare("t2", &other, &cid).unwrap();
+        vm.abort("t2").unwrap();
+        assert!(!vm.exists(&other).unwrap());
+        assert!(vm.prepare("../t3", &other, &cid).is_err());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_store_and_point() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap15");
+
+        let mut blocks = crate::fsblocks::Builder::new(pb.join("blocks")).try_build().unwrap();
+        let mut vm = Builder::new(pb.join("vlads")).try_build().unwrap();
+        let get_block_cid = |data: &&[u8]| -> Result<Cid, Error> { Ok(get_cid(data)) };
+        let vlad = get_vlad(b"move every zig!");
+        let cid = cid_map::store_and_point(&mut blocks, &mut vm, &vlad, &&b"for great justice!"[..], get_block_cid).unwrap();
+        assert_eq!(vm.get(&vlad).unwrap(), cid);
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+
+        // a file in the way of the mapping's subfolder makes the put fail
+        let other = get_vlad(b"all your base");
+        let (_, subfolder, _, _) = vm.get_paths(&other).unwrap();
+        if subfolder.is_dir() {
+            fs::remove_dir_all(&subfolder).unwrap();
+        }
+        fs::write(&subfolder, b"in the way").unwrap();
+        let data = &b"are belong to us"[..];
+        assert!(cid_map::store_and_point(&mut blocks, &mut vm, &other, &data, get_block_cid).is_err());
+
+        // so the new block is rolled back
+        assert!(!blocks.exists(&get_cid(data)).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn test_watch() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".fsvladmap13");
+
+        let vm = Builder::new(&pb).try_build().unwrap();
+        let vlad = get_vlad(b"for great justice!");
+        let updates = vm.watch(&vlad).unwrap();
+
+        // a separate handle on the same store stands in for another process
+        let mut other = Builder::new(&pb).try_build().unwrap();
+        let cid1 = get_cid(b"move every zig!");
+        let cid2 = get_cid(b"take off every zig!");
+        let _ = other.put(&vlad, &cid1).unwrap();
+        assert_eq!(updates.recv_timeout(std::time::Duration::from_secs(5)).unwrap(), cid1);
+        let _ = other.put(&vlad, &cid2).unwrap();
+        assert_eq!(updates.recv_timeout(std::time::Duration::from_secs(5)).unwrap(), cid2);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
 }
@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, traits::blocks::BlockStat, error::no_such_data};
+use log::debug;
+use multicid::Cid;
+
+/// Blocks mirrored across several stores, e.g. on different disks. Every put and rm goes to all
+/// of the stores and fails with an Error::TooFewReplicas error unless at least the required
+/// number of them succeed. Reads go to the stores in order and the first one holding the block
+/// answers.
+#[derive(Clone, Debug)]
+pub struct MirroredBlocks<B> {
+    stores: Vec<B>,
+    required: usize,
+}
+
+impl<B> MirroredBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error> + core::fmt::Display,
+{
+    /// mirror the blocks across the stores, by default every store has to succeed. There has to
+    /// be at least one store to mirror to.
+    pub fn new(stores: Vec<B>) -> Result<Self, Error> {
+        if stores.is_empty() {
+            return Err(Error::TooFewReplicas { op: "new", needed: 1, succeeded: 0 });
+        }
+        let required = stores.len();
+        Ok(MirroredBlocks { stores, required })
+    }
+
+    /// only require this many stores to succeed for a put or rm to succeed
+    pub fn with_required(mut self, required: usize) -> Self {
+        self.required = required.clamp(1, self.stores.len());
+        self
+    }
+
+    /// the mirrored stores
+    pub fn stores(&self) -> &[B] {
+        &self.stores
+    }
+
+    fn check(&self, op: &'static str, succeeded: usize) -> Result<(), B::Error> {
+        if succeeded < self.required {
+            return Err(Error::TooFewReplicas { op, needed: self.required, succeeded }.into());
+        }
+        Ok(())
+    }
+}
+
+impl<B> Blocks for MirroredBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error> + core::fmt::Display,
+{
+    type Error = B::Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        for store in &self.stores {
+            if store.exists(cid)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        // the block is only missing if no mirror has it, a mirror that has it but fails to read
+        // it is a real error
+        let mut last = None;
+        for store in &self.stores {
+            match store.get(cid) {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    debug!("mirrored_blocks: Failed to get block from a mirror: {}", e);
+                    if store.exists(cid).unwrap_or(true) {
+                        last = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| no_such_data(cid).into()))
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        match self.stores.first() {
+            Some(store) => store.cids(),
+            None => Ok(Vec::default()),
+        }
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        // the side effects of the pre_commit happen once for the whole mirror
+        let cid = get_cid(data)?;
+        pre_commit(&cid)?;
+
+        let mut succeeded = 0;
+        for store in &mut self.stores {
+            match store.put(data, |_| Ok(cid.clone()), |_| Ok(())) {
+                Ok(_) => succeeded += 1,
+                Err(e) => debug!("mirrored_blocks: Failed to put block to a mirror: {}", e),
+            }
+        }
+        self.check("put", succeeded)?;
+        Ok(cid)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        for store in &self.stores {
+            if store.exists(cid)? {
+                return store.stat(cid);
+            }
+        }
+        Err(no_such_data(cid).into())
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let mut removed = None;
+        let mut succeeded = 0;
+        for store in &self.stores {
+            match store.rm(cid) {
+                Ok(data) => {
+                    succeeded += 1;
+                    removed.get_or_insert(data);
+                }
+                Err(e) => debug!("mirrored_blocks: Failed to remove block from a mirror: {}", e),
+            }
+        }
+        self.check("rm", succeeded)?;
+        Ok(removed.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::Builder;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::{fs, path::PathBuf};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    #[test]
+    fn test_mirrored_blocks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".mirroredblocks1");
+
+        let a = Builder::new(pb.join("a")).not_lazy().try_build().unwrap();
+        let b = Builder::new(pb.join("b")).not_lazy().try_build().unwrap();
+        let mut mirror = MirroredBlocks::new(vec![a, b]).unwrap();
+        let cid = mirror.put(&&b"for great justice!"[..], get_cid, |_| Ok(())).unwrap();
+        assert!(mirror.stores().iter().all(|store| store.exists(&cid).unwrap()));
+
+        // reads fall through to the other mirror
+        let _ = mirror.stores()[0].rm(&cid).unwrap();
+        assert_eq!(mirror.get(&cid).unwrap(), b"for great justice!".to_vec());
+
+        // the block is only left on one mirror so removing it everywhere can't succeed
+        assert!(matches!(mirror.rm(&cid), Err(Error::TooFewReplicas { op: "rm", needed: 2, succeeded: 1 })));
+        assert!(!mirror.exists(&cid).unwrap());
+
+        // with one success required the rm succeeds as long as one mirror still has the block
+        let mut mirror = mirror.with_required(1);
+        let cid = mirror.put(&&b"move every zig!"[..], get_cid, |_| Ok(())).unwrap();
+        let _ = mirror.stores()[0].rm(&cid).unwrap();
+        assert_eq!(mirror.rm(&cid).unwrap(), b"move every zig!".to_vec());
+        assert!(matches!(mirror.rm(&cid), Err(Error::TooFewReplicas { op: "rm", needed: 1, succeeded: 0 })));
+
+        // a mirror that has the block but can't read it reports why, not that the block is missing
+        let cid = mirror.put(&&b"all your base"[..], get_cid, |_| Ok(())).unwrap();
+        let _ = mirror.stores()[1].rm(&cid).unwrap();
+        let (_, _, file, _) = mirror.stores()[0].get_paths(&cid).unwrap();
+        fs::remove_file(&file).unwrap();
+        fs::create_dir(&file).unwrap();
+        assert!(matches!(mirror.get(&cid), Err(Error::Io { .. })));
+        fs::remove_dir(&file).unwrap();
+        assert!(matches!(mirror.get(&cid), Err(Error::FsStorage(crate::error::FsStorageError::NoSuchData(_)))));
+
+        // there has to be something to mirror to
+        assert!(MirroredBlocks::<crate::fsblocks::FsBlocks>::new(Vec::default()).is_err());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
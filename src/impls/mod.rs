@@ -1,21 +1,104 @@
 // SPDX-License-Identifier: Apache-2.0
 
+/// Read-only block storage served out of zip and tar archives
+#[cfg(any(feature = "tar", feature = "zip"))]
+pub mod archive_blocks;
+#[cfg(any(feature = "tar", feature = "zip"))]
+pub use archive_blocks::ArchiveBlocks;
+
+/// On-disk bloom filter over the IDs in a store
+pub mod bloom;
+
+/// In-memory caching of the blocks read from a store
+pub mod cached_blocks;
+pub use cached_blocks::{CachedBlocks, Eviction};
+
+/// Single file block storage for shipping datasets
+pub mod cas_file;
+pub use cas_file::CasFile;
+
+/// Block storage that chunks oversized blocks
+pub mod chunked_blocks;
+pub use chunked_blocks::ChunkedBlocks;
+
 /// Filesystem backed block storage
 pub mod fsblocks;
 pub use fsblocks::FsBlocks;
 
+/// Filesystem backed Cid to Cid mapping storage
+pub mod fscid_map;
+pub use fscid_map::FsCidMap;
+
+/// Filesystem backed mapping from an ID to a set of Cids
+pub mod fscid_multi_map;
+pub use fscid_multi_map::FsCidMultiMap;
+
+/// Filesystem backed DID to document Cid mapping storage
+pub mod fsdid_map;
+pub use fsdid_map::FsDidMap;
+
+/// Filesystem backed mapping from an ID to any value
+pub mod fskv_map;
+pub use fskv_map::FsKvMap;
+
+/// Filesystem backed last-writer-wins mapping for replicated stores
+pub mod fslww_map;
+pub use fslww_map::FsLwwMap;
+
 /// Filesystem backed multikey_map storage
 pub mod fsmultikey_map;
 pub use fsmultikey_map::FsMultikeyMap;
 
+/// Filesystem backed name to Cid mapping storage
+pub mod fsname_map;
+pub use fsname_map::FsNameMap;
+
 /// Generic content addressable storage
 pub mod fsstorage;
 pub use fsstorage::FsStorage;
 
+/// Thread safe handle on a filesystem store
+pub mod shared_fsstorage;
+pub use shared_fsstorage::{SharedFsBlocks, SharedFsStorage};
+
+/// Filesystem backed tags naming content in a block store
+pub mod fstag_map;
+pub use fstag_map::FsTagMap;
+
 /// Filesystem backed multikey_map storage
 pub mod fsvlad_map;
 pub use fsvlad_map::FsVladMap;
 
+/// Block storage mirrored across several stores
+pub mod mirrored_blocks;
+pub use mirrored_blocks::MirroredBlocks;
+
+/// Block storage appended into large pack files
+pub mod pack_blocks;
+pub use pack_blocks::PackBlocks;
+
+/// Provenance log hooks recording the mutations of a store
+#[cfg(feature = "provenance")]
+pub mod provenance;
+#[cfg(feature = "provenance")]
+pub use provenance::ProvenanceHook;
+
+/// Block storage replicated with read and write quorums
+pub mod quorum_blocks;
+pub use quorum_blocks::QuorumBlocks;
+
+/// Block storage that repairs itself from replicas on read
+pub mod read_repair;
+pub use read_repair::ReadRepairBlocks;
+
+/// Resolution of Vlads to the content they currently point at
+pub mod resolver;
+pub use resolver::Resolver;
+
+/// Block storage layered over several stores
+pub mod union_blocks;
+pub use union_blocks::UnionBlocks;
+
 /// Simple way to import all public symbols
 pub mod prelude {
     pub use super::*;
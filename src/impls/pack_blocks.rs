@@ -0,0 +1,496 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, traits::blocks::BlockStat, error::{FsStorageError, IoContext}, fsstorage::Durability};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::{collections::BTreeMap, fs::{self, File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+/// The name of the index file in the root
+pub const INDEX_FILE: &str = "pack.idx";
+
+/// The size a pack file grows to before a new one is started
+pub const DEFAULT_MAX_PACK_SIZE: u64 = 1 << 30;
+
+/// The index record tag for a stored block
+const RECORD_PUT: u8 = 1;
+
+/// The index record tag for a removed block
+const RECORD_RM: u8 = 0;
+
+/// Builder for PackBlocks
+#[derive(Clone, Debug)]
+pub struct Builder {
+    root: PathBuf,
+    max_pack_size: u64,
+    durability: Durability,
+}
+
+impl Builder {
+    /// create a new builder from the root path
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        debug!("pack_blocks::Builder::new({})", root.as_ref().display());
+        Builder {
+            root: root.as_ref().to_path_buf(),
+            max_pack_size: DEFAULT_MAX_PACK_SIZE,
+            durability: Durability::default(),
+        }
+    }
+
+    /// start a new pack file once the current one reaches this size
+    pub fn with_max_pack_size(mut self, max_pack_size: u64) -> Self {
+        self.max_pack_size = max_pack_size;
+        self
+    }
+
+    /// how hard each put tries to make the block survive a crash, with Durability::None the
+    /// writes can be made durable in batches with sync()
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// open the store, replaying the index
+    pub fn try_build(&self) -> Result<PackBlocks, Error> {
+        if !self.root.try_exists().io_context("stat", &self.root)? {
+            fs::create_dir_all(&self.root).io_context("create dir", &self.root)?;
+        }
+        if !self.root.is_dir() {
+            return Err(FsStorageError::NotDir(self.root.clone()).into());
+        }
+
+        let index_path = self.root.join(INDEX_FILE);
+        let mut entries = BTreeMap::default();
+        let mut dropped = BTreeMap::default();
+        let mut current = 0;
+        if index_path.try_exists().io_context("stat", &index_path)? {
+            let data = fs::read(&index_path).io_context("read", &index_path)?;
+            let (records, valid) = parse_index(&data);
+
+            // cut off a record torn by a crash so that new records aren't appended after it
+            if valid < data.len() {
+                let index = OpenOptions::new().write(true).open(&index_path).io_context("open", &index_path)?;
+                index.set_len(valid as u64).io_context("truncate", &index_path)?;
+                index.sync_all().io_context("sync", &index_path)?;
+                debug!("pack_blocks: Truncated {} torn bytes from the index", data.len() - valid);
+            }
+
+            let mut pack_sizes = BTreeMap::default();
+            for (tag, key, location) in records {
+                current = current.max(location.pack);
+                if tag == RECORD_RM {
+                    entries.remove(&key);
+                    dropped.remove(&key);
+                    continue;
+                }
+                // drop the blocks a crash cut off at the end of a pack
+                let size = match pack_sizes.get(&location.pack) {
+                    Some(size) => *size,
+                    None => {
+                        let path = pack_path(&self.root, location.pack);
+                        let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                        pack_sizes.insert(location.pack, size);
+                        size
+                    }
+                };
+                if location.offset + location.len <= size {
+                    dropped.remove(&key);
+                    entries.insert(key, location);
+                } else {
+                    dropped.insert(key, location);
+                }
+            }
+        }
+        debug!("pack_blocks: Opened {} with {} blocks", self.root.display(), entries.len());
+
+        let state = open_pack(&self.root, current)?;
+        let mut index = OpenOptions::new().create(true).append(true).open(&index_path).io_context("open", &index_path)?;
+
+        // the next put fills the pack past the dropped blocks, so they are removed in the index
+        // too or the next replay would find them inside the pack pointing at other data
+        if !dropped.is_empty() {
+            let mut records = Vec::default();
+            for (key, location) in &dropped {
+                encode_record(&mut records, RECORD_RM, key, location);
+            }
+            index.write_all(&records).io_context("write", &index_path)?;
+            index.sync_data().io_context("sync", &index_path)?;
+            debug!("pack_blocks: Removed {} blocks cut off at the end of a pack", dropped.len());
+        }
+        Ok(PackBlocks {
+            root: self.root.clone(),
+            max_pack_size: self.max_pack_size,
+            durability: self.durability,
+            state: Mutex::new(PackState { entries, index, pack: state.0, pack_num: current, pack_len: state.1 }),
+        })
+    }
+}
+
+/// Where a block is in the pack files
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Location {
+    pack: u32,
+    offset: u64,
+    len: u64,
+}
+
+/// The open files and the in memory index
+struct PackState {
+    entries: BTreeMap<Vec<u8>, Location>,
+    index: File,
+    pack: File,
+    pack_num: u32,
+    pack_len: u64,
+}
+
+/// Blocks appended into large pack files with an index of where each one is, like git packs.
+/// This is for stores with tens of millions of tiny blocks where the inode overhead and fsync
+/// cost of a file per block dominate. Removing a block only records it in the index, the space
+/// is reclaimed by compact().
+pub struct PackBlocks {
+    root: PathBuf,
+    max_pack_size: u64,
+    durability: Durability,
+    state: Mutex<PackState>,
+}
+
+impl PackBlocks {
+    /// the number of blocks in the store
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// whether the store has no blocks
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// make every put so far durable, for batching the fsyncs of Durability::None puts
+    pub fn sync(&self) -> Result<(), Error> {
+        let state = self.lock();
+        state.pack.sync_data().io_context("sync", pack_path(&self.root, state.pack_num))?;
+        state.index.sync_data().io_context("sync", self.root.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    /// rewrite the live blocks into new packs and a new index, deleting the old packs. returns
+    /// the number of bytes reclaimed.
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        let root = self.root.clone();
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        let old_packs = state.pack_num;
+        let before: u64 = (0..=old_packs).map(|n| fs::metadata(pack_path(&root, n)).map(|m| m.len()).unwrap_or(0)).sum();
+
+        // the new packs are numbered after the old ones so a crash leaves the old index valid
+        let mut pack_num = old_packs + 1;
+        let (mut pack, mut pack_len) = open_pack(&root, pack_num)?;
+        let mut index = Vec::default();
+        let mut entries = BTreeMap::default();
+        for (key, location) in &state.entries {
+            let data = read_at(&root, location)?;
+            if pack_len > 0 && pack_len + data.len() as u64 > self.max_pack_size {
+                pack.sync_data().io_context("sync", pack_path(&root, pack_num))?;
+                pack_num += 1;
+                (pack, pack_len) = open_pack(&root, pack_num)?;
+            }
+            pack.write_all(&data).io_context("write", pack_path(&root, pack_num))?;
+            let location = Location { pack: pack_num, offset: pack_len, len: data.len() as u64 };
+            pack_len += location.len;
+            encode_record(&mut index, RECORD_PUT, key, &location);
+            entries.insert(key.clone(), location);
+        }
+        pack.sync_data().io_context("sync", pack_path(&root, pack_num))?;
+
+        // swap the index in atomically, then the old packs are garbage
+        let index_path = root.join(INDEX_FILE);
+        let mut temp = tempfile::NamedTempFile::new_in(&root).io_context("create temp file", &root)?;
+        temp.write_all(&index).io_context("write", temp.path())?;
+        temp.as_file().sync_data().io_context("sync", temp.path())?;
+        temp.persist(&index_path)?;
+        for n in 0..=old_packs {
+            let path = pack_path(&root, n);
+            if path.try_exists().io_context("stat", &path)? {
+                fs::remove_file(&path).io_context("remove", &path)?;
+            }
+        }
+
+        let after: u64 = entries.values().map(|l: &Location| l.len).sum();
+        state.entries = entries;
+        state.index = OpenOptions::new().append(true).open(&index_path).io_context("open", &index_path)?;
+        state.pack = pack;
+        state.pack_num = pack_num;
+        state.pack_len = pack_len;
+        debug!("pack_blocks: Compacted {} bytes of packs down to {}", before, after);
+        Ok(before.saturating_sub(after))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PackState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sync_put(&self, state: &PackState) -> Result<(), Error> {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::SyncData => {
+                state.pack.sync_data().io_context("sync", pack_path(&self.root, state.pack_num))?;
+                state.index.sync_data().io_context("sync", self.root.join(INDEX_FILE))
+            }
+            Durability::SyncAll => {
+                state.pack.sync_all().io_context("sync", pack_path(&self.root, state.pack_num))?;
+                state.index.sync_all().io_context("sync", self.root.join(INDEX_FILE))
+            }
+        }
+    }
+}
+
+impl Blocks for PackBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        Ok(self.lock().entries.contains_key(&key))
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        let location = self.lock().entries.get(&key).copied().ok_or_else(|| FsStorageError::NoSuchData(encode_key(&key)))?;
+        read_at(&self.root, &location)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        let mut cids: Vec<(String, Vec<u8>)> = self.lock().entries.keys().map(|key| (encode_key(key), key.clone())).collect();
+        cids.sort();
+        cids.iter().map(|(_, key)| Ok(Cid::try_from(key.as_slice())?)).collect()
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let cid = get_cid(data)?;
+        let key: Vec<u8> = cid.clone().into();
+        pre_commit(&cid)?;
+
+        let root = self.root.clone();
+        let max_pack_size = self.max_pack_size;
+        let mut state = self.lock();
+        if state.entries.contains_key(&key) {
+            return Ok(cid);
+        }
+
+        let data = data.as_ref();
+        if state.pack_len > 0 && state.pack_len + data.len() as u64 > max_pack_size {
+            let pack_num = state.pack_num + 1;
+            state.pack.sync_data().io_context("sync", pack_path(&root, state.pack_num))?;
+            (state.pack, state.pack_len) = open_pack(&root, pack_num)?;
+            state.pack_num = pack_num;
+        }
+
+        // the block goes in the pack before the index points at it
+        let location = Location { pack: state.pack_num, offset: state.pack_len, len: data.len() as u64 };
+        state.pack.write_all(data).io_context("write", pack_path(&root, location.pack))?;
+        state.pack_len += location.len;
+        let mut record = Vec::default();
+        encode_record(&mut record, RECORD_PUT, &key, &location);
+        state.index.write_all(&record).io_context("write", root.join(INDEX_FILE))?;
+        state.entries.insert(key, location);
+        self.sync_put(&state)?;
+        Ok(cid)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        let location = self.lock().entries.get(&key).copied().ok_or_else(|| FsStorageError::NoSuchData(encode_key(&key)))?;
+        Ok(BlockStat {
+            size: location.len,
+            created: None,
+            lazy_deleted: false,
+        })
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let key: Vec<u8> = cid.clone().into();
+        let mut state = self.lock();
+        let location = state.entries.get(&key).copied().ok_or_else(|| FsStorageError::NoSuchData(encode_key(&key)))?;
+        let data = read_at(&self.root, &location)?;
+        let mut record = Vec::default();
+        encode_record(&mut record, RECORD_RM, &key, &location);
+        state.index.write_all(&record).io_context("write", self.root.join(INDEX_FILE))?;
+        state.entries.remove(&key);
+        self.sync_put(&state)?;
+        Ok(data)
+    }
+}
+
+fn pack_path(root: &Path, n: u32) -> PathBuf {
+    root.join(format!("pack-{:08}.pack", n))
+}
+
+// open the pack for appending, returning it and its length
+fn open_pack(root: &Path, n: u32) -> Result<(File, u64), Error> {
+    let path = pack_path(root, n);
+    let pack = OpenOptions::new().create(true).append(true).open(&path).io_context("open", &path)?;
+    let len = pack.metadata().io_context("stat", &path)?.len();
+    Ok((pack, len))
+}
+
+fn read_at(root: &Path, location: &Location) -> Result<Vec<u8>, Error> {
+    let path = pack_path(root, location.pack);
+    let mut pack = File::open(&path).io_context("open", &path)?;
+    pack.seek(SeekFrom::Start(location.offset)).io_context("seek", &path)?;
+    let mut data = vec![0; location.len as usize];
+    pack.read_exact(&mut data).io_context("read", &path)?;
+    Ok(data)
+}
+
+fn encode_key(key: &[u8]) -> String {
+    multibase::encode(Base::Base32Z, key)
+}
+
+/// an index record is the tag, the length of the Cid, the Cid and the location, little endian
+fn encode_record(out: &mut Vec<u8>, tag: u8, key: &[u8], location: &Location) {
+    out.push(tag);
+    out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    out.extend_from_slice(key);
+    out.extend_from_slice(&location.pack.to_le_bytes());
+    out.extend_from_slice(&location.offset.to_le_bytes());
+    out.extend_from_slice(&location.len.to_le_bytes());
+}
+
+/// the records in the index and the length of the data they were read from, stopping at a record
+/// torn by a crash
+fn parse_index(mut data: &[u8]) -> (Vec<(u8, Vec<u8>, Location)>, usize) {
+    let total = data.len();
+    let mut records = Vec::default();
+    while data.len() >= 5 {
+        let tag = data[0];
+        let key_len = u32::from_le_bytes([data[1], data[2], data[3], data[4]]) as usize;
+        let rest = &data[5..];
+        if rest.len() < key_len + 20 {
+            break;
+        }
+        let key = rest[..key_len].to_vec();
+        let fields = &rest[key_len..key_len + 20];
+        let location = Location {
+            pack: u32::from_le_bytes(fields[0..4].try_into().unwrap_or_default()),
+            offset: u64::from_le_bytes(fields[4..12].try_into().unwrap_or_default()),
+            len: u64::from_le_bytes(fields[12..20].try_into().unwrap_or_default()),
+        };
+        records.push((tag, key, location));
+        data = &rest[key_len + 20..];
+    }
+    (records, total - data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    fn put(blocks: &mut PackBlocks, data: &[u8]) -> Cid {
+        blocks
+            .put(
+                &data,
+                |data| {
+                    let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+                    Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+                },
+                |_| Ok(()),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pack_blocks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".packblocks1");
+
+        let mut blocks = Builder::new(&pb).with_max_pack_size(64).try_build().unwrap();
+        let cids: Vec<Cid> = (0..10).map(|i| put(&mut blocks, format!("block number {}", i).as_bytes())).collect();
+        assert_eq!(blocks.len(), 10);
+        assert_eq!(put(&mut blocks, b"block number 3"), cids[3]);
+        assert_eq!(blocks.len(), 10);
+        assert!(pack_path(&pb, 1).exists());
+        assert_eq!(blocks.get(&cids[7]).unwrap(), b"block number 7".to_vec());
+        assert_eq!(blocks.stat(&cids[7]).unwrap().size, 14);
+        assert_eq!(blocks.rm(&cids[2]).unwrap(), b"block number 2".to_vec());
+        assert!(!blocks.exists(&cids[2]).unwrap());
+        blocks.sync().unwrap();
+
+        // reopening replays the index, ignoring a torn record at the end
+        drop(blocks);
+        let mut index = OpenOptions::new().append(true).open(pb.join(INDEX_FILE)).unwrap();
+        index.write_all(&[RECORD_PUT, 40, 0]).unwrap();
+        let mut blocks = Builder::new(&pb).with_max_pack_size(64).try_build().unwrap();
+        assert_eq!(blocks.len(), 9);
+        assert_eq!(blocks.get(&cids[9]).unwrap(), b"block number 9".to_vec());
+        assert!(matches!(blocks.get(&cids[2]), Err(Error::FsStorage(FsStorageError::NoSuchData(_)))));
+        let mut listed = blocks.cids().unwrap();
+        assert_eq!(listed.len(), 9);
+        listed.sort_by_key(|cid| cids.iter().position(|c| c == cid));
+        assert_eq!(listed, cids.iter().filter(|c| **c != cids[2]).cloned().collect::<Vec<Cid>>());
+
+        // the torn record was cut off so blocks put after it survive the next reopen
+        let after = put(&mut blocks, b"put after the crash");
+        blocks.sync().unwrap();
+        drop(blocks);
+        let mut blocks = Builder::new(&pb).with_max_pack_size(64).try_build().unwrap();
+        assert_eq!(blocks.len(), 10);
+        assert_eq!(blocks.get(&after).unwrap(), b"put after the crash".to_vec());
+        assert_eq!(blocks.get(&cids[9]).unwrap(), b"block number 9".to_vec());
+
+        // compacting drops the removed block and the old packs
+        assert_eq!(blocks.compact().unwrap(), 14);
+        assert!(!pack_path(&pb, 0).exists());
+        for (i, cid) in cids.iter().enumerate().filter(|(i, _)| *i != 2) {
+            assert_eq!(blocks.get(cid).unwrap(), format!("block number {}", i).into_bytes());
+        }
+        drop(blocks);
+        let blocks = Builder::new(&pb).try_build().unwrap();
+        assert_eq!(blocks.len(), 10);
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_torn_pack_append() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".packblocks2");
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        let cid = put(&mut blocks, b"for great justice!");
+        blocks.sync().unwrap();
+        drop(blocks);
+
+        // a crash after the index record was written but before the block reached the pack
+        let torn = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Identity)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Blake3, b"move every zig!").unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap();
+        let key: Vec<u8> = torn.clone().into();
+        let mut record = Vec::default();
+        encode_record(&mut record, RECORD_PUT, &key, &Location { pack: 0, offset: 18, len: 15 });
+        let mut index = OpenOptions::new().append(true).open(pb.join(INDEX_FILE)).unwrap();
+        index.write_all(&record).unwrap();
+        drop(index);
+
+        let mut blocks = Builder::new(&pb).try_build().unwrap();
+        assert!(!blocks.exists(&torn).unwrap());
+        let after = put(&mut blocks, b"take off every zig");
+        blocks.sync().unwrap();
+        drop(blocks);
+
+        // the put filled the pack past the torn block, which must stay gone
+        for _ in 0..2 {
+            let blocks = Builder::new(&pb).try_build().unwrap();
+            assert!(!blocks.exists(&torn).unwrap());
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+            assert_eq!(blocks.get(&after).unwrap(), b"take off every zig".to_vec());
+        }
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
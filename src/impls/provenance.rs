@@ -0,0 +1,308 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Error, Event, Observer, error::{FsStorageError, IoContext}};
+use log::warn;
+use multibase::Base;
+use multicid::Cid;
+use multitrait::{EncodeInto, TryDecodeFrom};
+use provenance_log::{Key, Op, Value};
+use std::{fs::{self, OpenOptions}, io::{ErrorKind, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+/// get the provenance-log operations that record the mutation. blocks are kept under
+/// /blocks/<cid> and map entries under /map/<id>, both base32 encoded, with the Cid the block or
+/// mapping was put with as the value so every entry of the log references the Cid.
+pub fn ops(event: &Event<Vec<u8>>) -> Result<Vec<Op>, Error> {
+    let op = match event {
+        Event::BlockPut(cid) | Event::BlockRepaired(cid) => Op::Update(key("blocks", &cid_bytes(cid))?, Value::Data(cid_bytes(cid))),
+        Event::BlockRemoved(cid) => Op::Delete(key("blocks", &cid_bytes(cid))?),
+        Event::MapUpdated(id, cid) => Op::Update(key("map", id)?, Value::Data(cid_bytes(cid))),
+        Event::MapRemoved(id, _) => Op::Delete(key("map", id)?),
+        Event::Restored(id) => Op::Update(key("restored", id)?, Value::Data(id.clone())),
+    };
+    Ok(vec![op])
+}
+
+fn key(branch: &str, id: &[u8]) -> Result<Key, Error> {
+    let key = format!("/{}/{}", branch, multibase::encode(Base::Base32Lower, id));
+    Ok(Key::try_from(key.as_str())?)
+}
+
+fn cid_bytes(cid: &Cid) -> Vec<u8> {
+    cid.clone().into()
+}
+
+// the spool record of a mutation
+fn encode_event(event: &Event<Vec<u8>>) -> Vec<u8> {
+    let (tag, id, cid) = match event.clone() {
+        Event::BlockPut(cid) => (0u8, None, Some(cid)),
+        Event::BlockRemoved(cid) => (1, None, Some(cid)),
+        Event::MapUpdated(id, cid) => (2, Some(id), Some(cid)),
+        Event::MapRemoved(id, cid) => (3, Some(id), Some(cid)),
+        Event::Restored(id) => (4, Some(id), None),
+        Event::BlockRepaired(cid) => (5, None, Some(cid)),
+    };
+    let mut v = vec![tag];
+    if let Some(mut id) = id {
+        v.append(&mut (id.len() as u64).encode_into());
+        v.append(&mut id);
+    }
+    if let Some(cid) = cid {
+        v.append(&mut cid.into());
+    }
+    v
+}
+
+// decode the spool record at the start of the bytes and return the rest
+fn decode_event(bytes: &[u8]) -> Result<(Event<Vec<u8>>, &[u8]), Error> {
+    let invalid = || Error::from(FsStorageError::InvalidValue("provenance spool record".to_string()));
+    let (&tag, mut ptr) = bytes.split_first().ok_or_else(invalid)?;
+    let mut id = None;
+    if matches!(tag, 2..=4) {
+        let (len, p) = u64::try_decode_from(ptr)?;
+        let len = usize::try_from(len).map_err(|_| invalid())?;
+        if p.len() < len {
+            return Err(invalid());
+        }
+        id = Some(p[..len].to_vec());
+        ptr = &p[len..];
+    }
+    let mut cid = None;
+    if tag != 4 {
+        let (c, p) = Cid::try_decode_from(ptr)?;
+        cid = Some(c);
+        ptr = p;
+    }
+    let event = match (tag, id, cid) {
+        (0, None, Some(cid)) => Event::BlockPut(cid),
+        (1, None, Some(cid)) => Event::BlockRemoved(cid),
+        (2, Some(id), Some(cid)) => Event::MapUpdated(id, cid),
+        (3, Some(id), Some(cid)) => Event::MapRemoved(id, cid),
+        (4, Some(id), None) => Event::Restored(id),
+        (5, None, Some(cid)) => Event::BlockRepaired(cid),
+        _ => return Err(invalid()),
+    };
+    Ok((event, ptr))
+}
+
+/// A hook that records every mutation of the store it is subscribed to in a cryptidtech
+/// provenance-log, giving a tamper-evident history of the store. Each mutation is turned into
+/// the operations from ops() and handed to the sink, which holds the log's VLAD and signing key
+/// and builds, signs and appends the log entry. Mutations are written to a spool file before the
+/// sink sees them and only dropped from it once the sink has taken them, so the ones it fails to
+/// take, or that a crash interrupts, are handed to it again, in order, before the next one. After
+/// a crash the sink may be handed the last mutation it took a second time.
+pub struct ProvenanceHook<F> {
+    spool: PathBuf,
+    pending: Mutex<Vec<Event<Vec<u8>>>>,
+    sink: F,
+}
+
+impl<F> core::fmt::Debug for ProvenanceHook<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ProvenanceHook").field("spool", &self.spool).field("pending", &self.pending).finish()
+    }
+}
+
+impl<F> ProvenanceHook<F>
+where
+    F: Fn(&[Op]) -> Result<(), Error> + Send + Sync,
+{
+    /// open the hook with the spool file that keeps the mutations the sink hasn't taken yet. any
+    /// an earlier run left there are handed to the sink before the next mutation.
+    pub fn new<P: AsRef<Path>>(sink: F, spool: P) -> Result<Self, Error> {
+        let spool = spool.as_ref().to_path_buf();
+        let data = match fs::read(&spool) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::default(),
+            Err(e) => return Err(e).io_context("read", &spool),
+        };
+
+        // a crash part way through an append leaves a torn record at the end, it is dropped so
+        // the records appended after it stay readable
+        let mut pending = Vec::default();
+        let mut ptr = data.as_slice();
+        while !ptr.is_empty() {
+            match decode_event(ptr) {
+                Ok((event, rest)) => {
+                    pending.push(event);
+                    ptr = rest;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let hook = ProvenanceHook { spool, pending: Mutex::new(Vec::default()), sink };
+        if !ptr.is_empty() {
+            hook.write_spool(&pending)?;
+        }
+        *hook.pending.lock().unwrap_or_else(|e| e.into_inner()) = pending;
+        Ok(hook)
+    }
+
+    /// the number of mutations waiting for the sink to take them
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// hand the mutations the sink failed to take to it again and return how many are left
+    pub fn flush(&self) -> Result<usize, Error> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        self.drain(&mut pending)?;
+        Ok(pending.len())
+    }
+
+    // hand the pending mutations to the sink in order, stopping at the first one it fails to
+    // take, and drop the ones it took from the spool
+    fn drain(&self, pending: &mut Vec<Event<Vec<u8>>>) -> Result<(), Error> {
+        let before = pending.len();
+        let mut result = Ok(());
+        while let Some(event) = pending.first() {
+            if let Err(e) = ops(event).and_then(|ops| (self.sink)(&ops)) {
+                result = Err(e);
+                break;
+            }
+            pending.remove(0);
+        }
+        if pending.len() != before {
+            self.write_spool(pending)?;
+        }
+        result
+    }
+
+    fn append(&self, event: Event<Vec<u8>>) -> Result<(), Error> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.spool).io_context("open", &self.spool)?;
+        f.write_all(&encode_event(&event)).io_context("write", &self.spool)?;
+        f.sync_data().io_context("sync", &self.spool)?;
+        pending.push(event);
+        self.drain(&mut pending)
+    }
+
+    // replace the spool with the mutations still pending
+    fn write_spool(&self, pending: &[Event<Vec<u8>>]) -> Result<(), Error> {
+        let dir = match self.spool.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut temp = tempfile::Builder::new().tempfile_in(dir).io_context("create temp file in", dir)?;
+        for event in pending {
+            temp.write_all(&encode_event(event)).io_context("write", temp.path())?;
+        }
+        temp.as_file().sync_data().io_context("sync", temp.path())?;
+        temp.persist(&self.spool)?;
+        Ok(())
+    }
+}
+
+impl<ID, F> Observer<ID> for ProvenanceHook<F>
+where
+    ID: Clone + Into<Vec<u8>>,
+    F: Fn(&[Op]) -> Result<(), Error> + Send + Sync,
+{
+    fn notify(&self, event: &Event<ID>) {
+        let event = match event.clone() {
+            Event::BlockPut(cid) => Event::BlockPut(cid),
+            Event::BlockRemoved(cid) => Event::BlockRemoved(cid),
+            Event::MapUpdated(id, cid) => Event::MapUpdated(id.into(), cid),
+            Event::MapRemoved(id, cid) => Event::MapRemoved(id.into(), cid),
+            Event::Restored(id) => Event::Restored(id.into()),
+            Event::BlockRepaired(cid) => Event::BlockRepaired(cid),
+        };
+        if let Err(e) = self.append(event) {
+            warn!("provenance: Failed to append to the log, {} mutations are pending: {}", self.pending(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Blocks, fsblocks::Builder};
+    use multicodec::Codec;
+    use multihash::mh;
+    use multicid::cid;
+    use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, time::SystemTime};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    #[test]
+    fn test_provenance_hook() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".provenance1");
+        fs::create_dir_all(&pb).unwrap();
+        let spool = pb.join("spool");
+
+        let log = Arc::new(Mutex::new(Vec::default()));
+        let offline = Arc::new(AtomicBool::new(false));
+        let sink = |log: Arc<Mutex<Vec<Vec<Op>>>>, down: Arc<AtomicBool>| {
+            move |ops: &[Op]| {
+                if down.load(Ordering::Relaxed) {
+                    return Err(Error::Custom("log offline".to_string()));
+                }
+                log.lock().unwrap().push(ops.to_vec());
+                Ok(())
+            }
+        };
+        let hook = Arc::new(ProvenanceHook::new(sink(log.clone(), offline.clone()), &spool).unwrap());
+
+        let mut blocks = Builder::new(pb.join("blocks")).with_cid_config(Codec::Identity, Codec::Blake3).try_build().unwrap();
+        let observer = hook.clone();
+        blocks.subscribe(move |event: &Event<Cid>| observer.notify(event));
+        let a = blocks.put_default(b"for great justice!").unwrap();
+
+        // mutations the sink can't take are kept in the spool, even across a restart
+        offline.store(true, Ordering::Relaxed);
+        let b = blocks.put_default(b"move every zig!").unwrap();
+        assert_eq!(log.lock().unwrap().len(), 1);
+        assert_eq!(hook.pending(), 1);
+        drop(blocks);
+        drop(hook);
+        offline.store(false, Ordering::Relaxed);
+        let hook = Arc::new(ProvenanceHook::new(sink(log.clone(), offline.clone()), &spool).unwrap());
+        assert_eq!(hook.pending(), 1);
+
+        // they are handed over in order before the next one
+        let mut blocks = Builder::new(pb.join("blocks")).try_build().unwrap();
+        let observer = hook.clone();
+        blocks.subscribe(move |event: &Event<Cid>| observer.notify(event));
+        let _ = blocks.rm(&a).unwrap();
+
+        // expired blocks are recorded too
+        blocks.set_expiry(&b, SystemTime::UNIX_EPOCH).unwrap();
+        blocks.gc().unwrap();
+
+        let expected: Vec<_> = [Event::BlockPut(a.clone()), Event::BlockPut(b.clone()), Event::BlockRemoved(a), Event::BlockRemoved(b)]
+            .iter()
+            .map(|event| ops(event).unwrap())
+            .collect();
+        assert_eq!(*log.lock().unwrap(), expected);
+        assert_eq!(hook.flush().unwrap(), 0);
+        assert_eq!(fs::read(&spool).unwrap().len(), 0);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_torn_spool() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".provenance2");
+        fs::create_dir_all(&pb).unwrap();
+        let spool = pb.join("spool");
+
+        // a torn record at the end of the spool is dropped, the ones before it are kept
+        let a = get_cid(&&b"for great justice!"[..]).unwrap();
+        let mut data = encode_event(&Event::BlockPut(a.clone()));
+        let mut torn = encode_event(&Event::MapUpdated(b"id".to_vec(), a.clone()));
+        torn.truncate(torn.len() - 3);
+        data.append(&mut torn);
+        fs::write(&spool, &data).unwrap();
+
+        let hook = ProvenanceHook::new(|_: &[Op]| -> Result<(), Error> { Err(Error::Custom("log offline".to_string())) }, &spool).unwrap();
+        assert_eq!(hook.pending(), 1);
+        assert_eq!(fs::read(&spool).unwrap(), encode_event(&Event::BlockPut(a)));
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
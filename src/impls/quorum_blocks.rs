@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, traits::blocks::BlockStat, error::no_such_data};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::collections::BTreeMap;
+
+/// Blocks replicated across N stores with read and write quorums, for the storage core of a
+/// small replicated service without a consensus protocol. Puts and rms succeed when at least the
+/// write quorum of stores carry them out. Reads ask the stores in order and a block exists when
+/// at least the read quorum of stores hold a copy that verifies, and is missing when at least
+/// the read quorum don't have it. Copies that fail verification count as failed stores, so
+/// blocks have to use hash codecs that can be verified. When neither side reaches the quorum an
+/// Error::TooFewReplicas error is returned. Choosing quorums where read + write > N means every
+/// read overlaps the stores of the last successful write.
+#[derive(Clone, Debug)]
+pub struct QuorumBlocks<B> {
+    stores: Vec<B>,
+    read_quorum: usize,
+    write_quorum: usize,
+}
+
+/// How the stores answered whether they hold a block
+struct Votes {
+    present: usize,
+    absent: usize,
+    data: Option<Vec<u8>>,
+}
+
+impl<B> QuorumBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error> + core::fmt::Display,
+{
+    /// replicate the blocks across the stores, the quorums default to a majority of them
+    pub fn new(stores: Vec<B>) -> Self {
+        let majority = stores.len() / 2 + 1;
+        QuorumBlocks {
+            stores,
+            read_quorum: majority,
+            write_quorum: majority,
+        }
+    }
+
+    /// set the number of stores that have to agree on a read
+    pub fn with_read_quorum(mut self, quorum: usize) -> Self {
+        self.read_quorum = quorum.clamp(1, self.stores.len().max(1));
+        self
+    }
+
+    /// set the number of stores that have to carry out a put or rm
+    pub fn with_write_quorum(mut self, quorum: usize) -> Self {
+        self.write_quorum = quorum.clamp(1, self.stores.len().max(1));
+        self
+    }
+
+    /// the replicated stores
+    pub fn stores(&self) -> &[B] {
+        &self.stores
+    }
+
+    // ask the stores in order until one side reaches the read quorum
+    fn vote(&self, cid: &Cid, read: bool) -> Votes {
+        let mut votes = Votes { present: 0, absent: 0, data: None };
+        for store in &self.stores {
+            match store.exists(cid) {
+                Ok(false) => votes.absent += 1,
+                Ok(true) if !read => votes.present += 1,
+                Ok(true) => match store.get_verified(cid) {
+                    Ok(data) => {
+                        votes.present += 1;
+                        votes.data.get_or_insert(data);
+                    }
+                    Err(e) => debug!("quorum_blocks: Store has a bad copy: {}", e),
+                },
+                Err(e) => debug!("quorum_blocks: Store failed to answer: {}", e),
+            }
+            if votes.present >= self.read_quorum || votes.absent >= self.read_quorum {
+                break;
+            }
+        }
+        votes
+    }
+
+    // whether the block is there once a side reaches the read quorum
+    fn decide(&self, op: &'static str, votes: &Votes) -> Result<bool, B::Error> {
+        if votes.present >= self.read_quorum {
+            Ok(true)
+        } else if votes.absent >= self.read_quorum {
+            Ok(false)
+        } else {
+            Err(Error::TooFewReplicas { op, needed: self.read_quorum, succeeded: votes.present.max(votes.absent) }.into())
+        }
+    }
+}
+
+impl<B> Blocks for QuorumBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error> + core::fmt::Display,
+{
+    type Error = B::Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        let votes = self.vote(cid, false);
+        self.decide("exists", &votes)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let votes = self.vote(cid, true);
+        match (self.decide("get", &votes)?, votes.data) {
+            (true, Some(data)) => Ok(data),
+            _ => Err(no_such_data(cid).into()),
+        }
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        // the blocks listed by at least the read quorum of stores
+        let mut counts: BTreeMap<String, (usize, Cid)> = BTreeMap::default();
+        let mut answered = 0;
+        for store in &self.stores {
+            let cids = match store.cids() {
+                Ok(cids) => cids,
+                Err(e) => {
+                    debug!("quorum_blocks: Store failed to list blocks: {}", e);
+                    continue;
+                }
+            };
+            answered += 1;
+            for cid in cids {
+                let data: Vec<u8> = cid.clone().into();
+                counts.entry(multibase::encode(Base::Base32Z, data)).or_insert((0, cid)).0 += 1;
+            }
+        }
+        if answered < self.read_quorum {
+            return Err(Error::TooFewReplicas { op: "cids", needed: self.read_quorum, succeeded: answered }.into());
+        }
+        Ok(counts.into_values().filter(|(n, _)| *n >= self.read_quorum).map(|(_, cid)| cid).collect())
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let cid = get_cid(data)?;
+        pre_commit(&cid)?;
+
+        let mut succeeded = 0;
+        for store in &mut self.stores {
+            match store.put(data, |_| Ok(cid.clone()), |_| Ok(())) {
+                Ok(_) => succeeded += 1,
+                Err(e) => debug!("quorum_blocks: Failed to put block to a store: {}", e),
+            }
+        }
+        if succeeded < self.write_quorum {
+            return Err(Error::TooFewReplicas { op: "put", needed: self.write_quorum, succeeded }.into());
+        }
+        Ok(cid)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        if !self.exists(cid)? {
+            return Err(no_such_data(cid).into());
+        }
+        for store in &self.stores {
+            if let Ok(true) = store.exists(cid) {
+                return store.stat(cid);
+            }
+        }
+        Err(no_such_data(cid).into())
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        // a store that doesn't have the block has carried out the rm too
+        let mut removed = None;
+        let mut succeeded = 0;
+        for store in &self.stores {
+            match store.rm(cid) {
+                Ok(data) => {
+                    succeeded += 1;
+                    removed.get_or_insert(data);
+                }
+                Err(e) => match store.exists(cid) {
+                    Ok(false) => succeeded += 1,
+                    _ => debug!("quorum_blocks: Failed to remove block from a store: {}", e),
+                },
+            }
+        }
+        if succeeded < self.write_quorum {
+            return Err(Error::TooFewReplicas { op: "rm", needed: self.write_quorum, succeeded }.into());
+        }
+        removed.ok_or_else(|| no_such_data(cid).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::FsStorageError, fsblocks::Builder};
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::{fs, path::PathBuf};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    #[test]
+    fn test_quorum_blocks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".quorumblocks1");
+
+        let stores = ["a", "b", "c"].iter().map(|n| Builder::new(pb.join(n)).not_lazy().try_build().unwrap()).collect();
+        let mut quorum = QuorumBlocks::new(stores);
+        let cid = quorum.put(&&b"for great justice!"[..], get_cid, |_| Ok(())).unwrap();
+        assert_eq!(quorum.get(&cid).unwrap(), b"for great justice!".to_vec());
+
+        // a majority still holds the block when one store loses it
+        let _ = quorum.stores()[0].rm(&cid).unwrap();
+        assert!(quorum.exists(&cid).unwrap());
+        assert_eq!(quorum.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(quorum.cids().unwrap(), vec![cid.clone()]);
+
+        // a corrupt copy doesn't count towards the quorum
+        let (_, _, file, _) = quorum.stores()[1].get_paths(&cid).unwrap();
+        fs::write(&file, b"for great justness").unwrap();
+        assert!(matches!(quorum.get(&cid), Err(Error::TooFewReplicas { op: "get", needed: 2, succeeded: 1 })));
+
+        // a majority without the block means it is missing
+        let _ = quorum.stores()[1].rm(&cid).unwrap();
+        assert!(!quorum.exists(&cid).unwrap());
+        assert!(matches!(quorum.get(&cid), Err(Error::FsStorage(FsStorageError::NoSuchData(_)))));
+
+        // the stores without the block count towards the rm quorum
+        assert_eq!(quorum.rm(&cid).unwrap(), b"for great justice!".to_vec());
+        assert!(quorum.rm(&cid).is_err());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, Event, Observer, traits::blocks::BlockStat};
+use log::debug;
+use multicid::Cid;
+use std::{fmt, sync::Arc};
+
+/// Blocks read from a primary store that repairs itself from its replicas. When a get finds the
+/// block missing on the primary, or the block there fails verification, the block is fetched
+/// from the first replica with a good copy and put back into the primary. Each repair is sent
+/// to the subscribed observers as an Event::BlockRepaired. Writes and removes only go to the
+/// primary. Handles to the primary are cloned to put repaired blocks from get, the way
+/// SharedFsStorage does.
+#[derive(Clone)]
+pub struct ReadRepairBlocks<B> {
+    primary: B,
+    replicas: Vec<B>,
+    observers: Vec<Arc<dyn Observer<Cid>>>,
+}
+
+impl<B: fmt::Debug> fmt::Debug for ReadRepairBlocks<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadRepairBlocks")
+            .field("primary", &self.primary)
+            .field("replicas", &self.replicas)
+            .field("observers", &self.observers.len())
+            .finish()
+    }
+}
+
+impl<B> ReadRepairBlocks<B>
+where
+    B: Blocks + Clone,
+    B::Error: From<Error> + fmt::Display,
+{
+    /// repair the primary from the replicas, which are tried in order
+    pub fn new(primary: B, replicas: Vec<B>) -> Self {
+        ReadRepairBlocks {
+            primary,
+            replicas,
+            observers: Vec::default(),
+        }
+    }
+
+    /// subscribe an observer to the repair events
+    pub fn subscribe<O>(&mut self, observer: O)
+    where
+        O: Observer<Cid> + 'static,
+    {
+        self.observers.push(Arc::new(observer));
+    }
+
+    /// the primary store
+    pub fn primary(&self) -> &B {
+        &self.primary
+    }
+
+    // fetch a good copy from the replicas and put it back into the primary
+    fn repair(&self, cid: &Cid, failure: B::Error) -> Result<Vec<u8>, B::Error> {
+        for replica in &self.replicas {
+            let data = match replica.get_verified(cid) {
+                Ok(data) => data,
+                Err(e) => {
+                    debug!("read_repair: Replica has no good copy: {}", e);
+                    continue;
+                }
+            };
+
+            // a corrupt copy has to go first or the put would keep it
+            let mut primary = self.primary.clone();
+            if primary.exists(cid)? {
+                let _ = primary.rm(cid);
+            }
+            primary.put_with_cid(cid, &data, false)?;
+            debug!("read_repair: Repaired block after: {}", failure);
+            let event = Event::BlockRepaired(cid.clone());
+            for observer in &self.observers {
+                observer.notify(&event);
+            }
+            return Ok(data);
+        }
+        Err(failure)
+    }
+}
+
+impl<B> Blocks for ReadRepairBlocks<B>
+where
+    B: Blocks + Clone,
+    B::Error: From<Error> + fmt::Display,
+{
+    type Error = B::Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        if self.primary.exists(cid)? {
+            return Ok(true);
+        }
+        for replica in &self.replicas {
+            if replica.exists(cid)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        match self.primary.get_verified(cid) {
+            Ok(data) => Ok(data),
+            Err(e) => self.repair(cid, e),
+        }
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        self.primary.cids()
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        self.primary.put(data, get_cid, pre_commit)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        self.primary.stat(cid)
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        self.primary.rm(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::Builder;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::{fs, path::PathBuf, sync::Mutex};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    #[test]
+    fn test_read_repair() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".readrepair1");
+
+        let mut primary = Builder::new(pb.join("primary")).not_lazy().try_build().unwrap();
+        let mut replica = Builder::new(pb.join("replica")).not_lazy().try_build().unwrap();
+        let a = replica.put(&&b"for great justice!"[..], get_cid, |_| Ok(())).unwrap();
+        let b = replica.put(&&b"move every zig!"[..], get_cid, |_| Ok(())).unwrap();
+        primary.put(&&b"move every zig!"[..], get_cid, |_| Ok(())).unwrap();
+
+        let repaired = Arc::new(Mutex::new(Vec::default()));
+        let events = repaired.clone();
+        let mut blocks = ReadRepairBlocks::new(primary, vec![replica]);
+        blocks.subscribe(move |event: &Event<Cid>| events.lock().unwrap().push(event.clone()));
+
+        // a block missing on the primary is fetched from the replica and put back
+        assert!(!blocks.primary().exists(&a).unwrap());
+        assert_eq!(blocks.get(&a).unwrap(), b"for great justice!".to_vec());
+        assert!(blocks.primary().exists(&a).unwrap());
+
+        // so is a block that is corrupt on the primary
+        let (_, _, file, _) = blocks.primary().get_paths(&b).unwrap();
+        fs::write(&file, b"move every zag!").unwrap();
+        assert_eq!(blocks.get(&b).unwrap(), b"move every zig!".to_vec());
+        assert_eq!(blocks.primary().get_verified(&b).unwrap(), b"move every zig!".to_vec());
+        assert_eq!(*repaired.lock().unwrap(), vec![Event::BlockRepaired(a), Event::BlockRepaired(b)]);
+
+        // blocks no replica has still fail
+        let c = get_cid(&&b"all your base"[..]).unwrap();
+        assert!(blocks.get(&c).is_err());
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, CidMap, Error, error::FsStorageError};
+use multibase::Base;
+use multicid::{Cid, Vlad};
+
+/// Resolves Vlads to their current content by following the mapping from the Vlad to a Cid,
+/// e.g. in a FsVladMap, and getting the block with that Cid, e.g. from a FsBlocks store. A Vlad
+/// without a mapping fails with a FsStorageError::NoMapping error and a mapping that points at a
+/// block that isn't stored fails with a FsStorageError::DanglingMapping error.
+#[derive(Clone, Debug)]
+pub struct Resolver<M, B> {
+    map: M,
+    blocks: B,
+}
+
+impl<M, B> Resolver<M, B>
+where
+    M: CidMap<Vlad>,
+    M::Error: From<Error>,
+    B: Blocks<Error = M::Error>,
+{
+    /// resolve Vlads with the mapping and the blocks it points at
+    pub fn new(map: M, blocks: B) -> Self {
+        Resolver { map, blocks }
+    }
+
+    /// the mapping from Vlads to Cids
+    pub fn map(&self) -> &M {
+        &self.map
+    }
+
+    /// the blocks the mapping points at
+    pub fn blocks(&self) -> &B {
+        &self.blocks
+    }
+
+    /// get the Cid the Vlad currently points at and the content of that block
+    pub fn resolve(&self, vlad: &Vlad) -> Result<(Cid, Vec<u8>), M::Error> {
+        if !self.map.exists(vlad)? {
+            return Err(Error::from(FsStorageError::NoMapping(encode(vlad.clone()))).into());
+        }
+        let cid = self.map.get(vlad)?;
+        if !self.blocks.exists(&cid)? {
+            return Err(Error::from(FsStorageError::DanglingMapping(encode(vlad.clone()), encode(cid))).into());
+        }
+        let data = self.blocks.get(&cid)?;
+        Ok((cid, data))
+    }
+}
+
+fn encode<T: Into<Vec<u8>>>(id: T) -> String {
+    multibase::encode(Base::Base32Z, id.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fsblocks, fsvlad_map};
+    use multicid::{cid, vlad};
+    use multicodec::Codec;
+    use multihash::mh;
+    use multikey::mk;
+    use std::{fs, path::PathBuf};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    fn get_vlad(data: &[u8]) -> Vlad {
+        let mut rng = rand::rngs::OsRng;
+        let mk = mk::Builder::new_from_random_bytes(Codec::Ed25519Priv, &mut rng).unwrap().try_build().unwrap();
+        vlad::Builder::default().with_signing_key(&mk).with_cid(&get_cid(&data).unwrap()).try_build().unwrap()
+    }
+
+    #[test]
+    fn test_resolver() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".resolver1");
+
+        let mut blocks = fsblocks::Builder::new(pb.join("blocks")).with_cid_config(Codec::Identity, Codec::Blake3).try_build().unwrap();
+        let mut map = fsvlad_map::Builder::new(pb.join("vlads")).try_build().unwrap();
+        let cid = blocks.put_default(b"for great justice!").unwrap();
+        let vlad = get_vlad(b"move every zig!");
+        let _ = map.put(&vlad, &cid).unwrap();
+
+        let resolver = Resolver::new(map, blocks);
+        assert_eq!(resolver.resolve(&vlad).unwrap(), (cid.clone(), b"for great justice!".to_vec()));
+
+        // a Vlad that was never mapped
+        let other = get_vlad(b"all your base");
+        assert!(matches!(resolver.resolve(&other), Err(Error::FsStorage(FsStorageError::NoMapping(_)))));
+
+        // a mapping to a block that is gone
+        let _ = resolver.blocks().rm(&cid).unwrap();
+        assert!(matches!(resolver.resolve(&vlad), Err(Error::FsStorage(FsStorageError::DanglingMapping(_, _)))));
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
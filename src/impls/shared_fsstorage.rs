@@ -0,0 +1,390 @@
+// SPDX-License-Identifier: Apache-2.0
+pub use crate::fsstorage::EntryKey;
+use crate::{traits::blocks::BlockStat, Blocks, CidMap, Error, fsstorage::FsStorage};
+use log::debug;
+use multicid::Cid;
+use multiutil::EncodingInfo;
+use std::{collections::{BTreeSet, HashSet}, fmt, ops::Deref, path::PathBuf, sync::{Arc, Condvar, Mutex}};
+
+/// The subfolders that have a write in flight. Writes to the same subfolder take turns while
+/// writes to different subfolders run in parallel.
+#[derive(Default)]
+struct ShardLocks {
+    held: Mutex<HashSet<PathBuf>>,
+    freed: Condvar,
+}
+
+impl ShardLocks {
+    // wait until no other thread is writing to the subfolder and claim it
+    fn lock(self: &Arc<Self>, subfolder: PathBuf) -> ShardGuard {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        while held.contains(&subfolder) {
+            held = self.freed.wait(held).unwrap_or_else(|e| e.into_inner());
+        }
+        held.insert(subfolder.clone());
+        ShardGuard { locks: self.clone(), subfolder }
+    }
+}
+
+/// Releases the subfolder when dropped
+struct ShardGuard {
+    locks: Arc<ShardLocks>,
+    subfolder: PathBuf,
+}
+
+impl Drop for ShardGuard {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap_or_else(|e| e.into_inner());
+        held.remove(&self.subfolder);
+        self.locks.freed.notify_all();
+    }
+}
+
+/// A handle on a filesystem store that threads can share and write through at the same time.
+/// Every write to the filesystem backend is an atomic rename so concurrent puts don't need to be
+/// serialized behind a Mutex around the whole store. The put methods here take &self and write
+/// through the shared handle, everything that only reads is available through Deref. Writes
+/// are serialized per subfolder so racing writes to the same key are still safe.
+#[derive(Clone)]
+pub struct SharedFsStorage<T>
+where
+    T: EncodingInfo
+{
+    storage: FsStorage<T>,
+    shards: Arc<ShardLocks>,
+}
+
+impl<T> fmt::Debug for SharedFsStorage<T>
+where
+    T: EncodingInfo + fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedFsStorage").field("storage", &self.storage).finish()
+    }
+}
+
+impl<T> PartialEq for SharedFsStorage<T>
+where
+    T: EncodingInfo + PartialEq
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.storage == other.storage
+    }
+}
+
+/// A shared handle on a block store
+pub type SharedFsBlocks = SharedFsStorage<Cid>;
+
+impl<T> SharedFsStorage<T>
+where
+    T: EncodingInfo
+{
+    /// share the store
+    pub fn new(storage: FsStorage<T>) -> Self {
+        SharedFsStorage {
+            storage,
+            shards: Arc::default(),
+        }
+    }
+
+    /// get the shared store back
+    pub fn into_inner(self) -> FsStorage<T> {
+        self.storage
+    }
+}
+
+impl<T> From<FsStorage<T>> for SharedFsStorage<T>
+where
+    T: EncodingInfo
+{
+    fn from(storage: FsStorage<T>) -> Self {
+        Self::new(storage)
+    }
+}
+
+impl<T> Deref for SharedFsStorage<T>
+where
+    T: EncodingInfo
+{
+    type Target = FsStorage<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.storage
+    }
+}
+
+impl SharedFsBlocks {
+    /// put a block from any thread holding the handle
+    pub fn put_block<D, F1, F2>(&self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Error>,
+        F2: Fn(&Cid) -> Result<(), Error>,
+    {
+        // the Cid decides the subfolder so it is calculated before taking the lock
+        let cid = get_cid(data)?;
+        let _shard = self.lock_shard(&cid)?;
+        self.storage
+            .put_committed(data, |_| Ok(cid.clone()), pre_commit, |_, _| Ok(()))
+            .map(|outcome| outcome.cid().clone())
+    }
+}
+
+impl<T> SharedFsStorage<T>
+where
+    T: Clone + EncodingInfo + Into<Vec<u8>>
+{
+    /// put a mapping from any thread holding the handle
+    pub fn put_mapping<ID>(&self, id: &ID, cid: &Cid) -> Result<Option<Cid>, Error>
+    where
+        FsStorage<T>: CidMap<ID, Error = Error>,
+        ID: EntryKey<T> + ?Sized,
+    {
+        let key = id.entry_key()?;
+        let _shard = self.lock_shard(&key)?;
+        self.storage.put_cid(&key, cid)
+    }
+
+    /// compare and swap a mapping from any thread holding the handle
+    pub fn put_mapping_cas<ID>(&self, id: &ID, expected: Option<&Cid>, cid: &Cid) -> Result<Option<Cid>, Error>
+    where
+        FsStorage<T>: CidMap<ID, Error = Error>,
+        ID: EntryKey<T> + ?Sized,
+    {
+        let key = id.entry_key()?;
+        let _shard = self.lock_shard(&key)?;
+        self.storage.put_cid_cas(&key, expected, cid)
+    }
+
+    // wait for the other writes to the subfolder the key is stored in
+    fn lock_shard(&self, key: &T) -> Result<ShardGuard, Error> {
+        let (_, subfolder, _, _) = self.storage.get_paths(key)?;
+        debug!("shared_fsstorage: Locking {}", subfolder.display());
+        Ok(self.shards.lock(subfolder))
+    }
+
+    // claim the subfolders of all of the ids in order so that racing batches can't deadlock. ids
+    // that don't map to a subfolder are skipped, writing them fails anyway.
+    fn lock_shards<'a, ID>(&self, ids: impl Iterator<Item = &'a ID>) -> Vec<ShardGuard>
+    where
+        ID: EntryKey<T> + 'a,
+    {
+        let subfolders: BTreeSet<PathBuf> = ids
+            .filter_map(|id| self.storage.get_paths(&id.entry_key().ok()?).ok())
+            .map(|(_, subfolder, _, _)| subfolder)
+            .collect();
+        debug!("shared_fsstorage: Locking {} subfolders", subfolders.len());
+        subfolders.into_iter().map(|subfolder| self.shards.lock(subfolder)).collect()
+    }
+}
+
+impl Blocks for SharedFsBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        Blocks::exists(&self.storage, cid)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        Blocks::get(&self.storage, cid)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        Blocks::cids(&self.storage)
+    }
+
+    #[cfg(feature = "bytes")]
+    fn get_bytes(&self, cid: &Cid) -> Result<bytes::Bytes, Self::Error> {
+        self.storage.get_bytes(cid)
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        self.put_block(data, get_cid, pre_commit)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        self.storage.stat(cid)
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let _shard = self.lock_shard(cid)?;
+        Blocks::rm(&self.storage, cid)
+    }
+}
+
+impl<T, ID> CidMap<ID> for SharedFsStorage<T>
+where
+    T: Clone + EncodingInfo + Into<Vec<u8>>,
+    FsStorage<T>: CidMap<ID, Error = Error>,
+    ID: EntryKey<T> + ?Sized,
+{
+    type Error = Error;
+
+    fn exists(&self, id: &ID) -> Result<bool, Self::Error> {
+        CidMap::exists(&self.storage, id)
+    }
+
+    fn get(&self, id: &ID) -> Result<Cid, Self::Error> {
+        CidMap::get(&self.storage, id)
+    }
+
+    fn put(&mut self, id: &ID, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
+        self.put_mapping(id, cid)
+    }
+
+    fn put_cas(&mut self, id: &ID, expected: Option<&Cid>, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
+        self.put_mapping_cas(id, expected, cid)
+    }
+
+    fn rm(&self, id: &ID) -> Result<Cid, Self::Error> {
+        let _shard = self.lock_shard(&id.entry_key()?)?;
+        CidMap::rm(&self.storage, id)
+    }
+
+    fn put_batch(&mut self, entries: &[(ID, Cid)]) -> Vec<Result<Option<Cid>, Self::Error>>
+    where
+        ID: Sized,
+    {
+        let _shards = self.lock_shards(entries.iter().map(|(id, _)| id));
+        CidMap::put_batch(&mut self.storage, entries)
+    }
+
+    fn ids(&self) -> Result<Vec<ID>, Self::Error>
+    where
+        ID: Sized,
+    {
+        CidMap::ids(&self.storage)
+    }
+
+    fn referrers(&self, cid: &Cid) -> Result<Vec<ID>, Self::Error>
+    where
+        ID: Sized,
+    {
+        CidMap::referrers(&self.storage, cid)
+    }
+
+    #[cfg(feature = "watch")]
+    fn watch(&self, id: &ID) -> Result<crate::Watch<Cid>, Self::Error> {
+        CidMap::watch(&self.storage, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fsblocks, fsvlad_map};
+    use multicid::{cid, vlad, Vlad};
+    use multicodec::Codec;
+    use multihash::mh;
+    use multikey::mk;
+    use std::{fs, path::PathBuf, thread};
+
+    // returns a Cid for the passed in data
+    fn get_cid(b: &[u8]) -> Result<Cid, Error> {
+        Ok(cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Identity)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Blake3, b)?.try_build()?)
+            .try_build()?)
+    }
+
+    fn assert_send_sync<S: Send + Sync>() {}
+
+    #[test]
+    fn test_concurrent_puts() {
+        assert_send_sync::<SharedFsBlocks>();
+
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".shared1");
+
+        let blocks = SharedFsStorage::new(fsblocks::Builder::new(pb.join("blocks")).try_build().unwrap());
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let blocks = blocks.clone();
+                thread::spawn(move || {
+                    (0..16u8)
+                        .map(|j| blocks.put_block(&[i, j], |d| get_cid(d), |_| Ok(())).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let cids: Vec<Cid> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        assert_eq!(cids.len(), 128);
+        for cid in &cids {
+            assert!(blocks.exists(cid).unwrap());
+        }
+        assert_eq!(Blocks::cids(&blocks).unwrap().len(), 128);
+
+        // maps put through the shared handle too
+        let map = SharedFsStorage::new(fsvlad_map::Builder::new(pb.join("map")).try_build().unwrap());
+        let mut rng = rand::rngs::OsRng::default();
+        let key = mk::Builder::new_from_random_bytes(Codec::Ed25519Priv, &mut rng).unwrap().try_build().unwrap();
+        let vlad: Vlad = vlad::Builder::default().with_signing_key(&key).with_cid(&cids[0]).try_build().unwrap();
+        assert!(map.put_mapping(&vlad, &cids[1]).unwrap().is_none());
+        assert_eq!(CidMap::get(&map, &vlad).unwrap(), cids[1]);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_same_key_race() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".shared2");
+
+        let map = SharedFsStorage::new(fsvlad_map::Builder::new(&pb).try_build().unwrap());
+        let mut rng = rand::rngs::OsRng::default();
+        let key = mk::Builder::new_from_random_bytes(Codec::Ed25519Priv, &mut rng).unwrap().try_build().unwrap();
+        let first = get_cid(b"first").unwrap();
+        let vlad: Vlad = vlad::Builder::default().with_signing_key(&key).with_cid(&first).try_build().unwrap();
+
+        // every thread tries to create the same mapping, exactly one of them wins
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let map = map.clone();
+                let vlad = vlad.clone();
+                thread::spawn(move || map.put_mapping_cas(&vlad, None, &get_cid(&[i]).unwrap()).is_ok())
+            })
+            .collect();
+        let wins = handles.into_iter().map(|h| h.join().unwrap()).filter(|won| *won).count();
+        assert_eq!(wins, 1);
+        assert!(CidMap::exists(&map, &vlad).unwrap());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_put_batch() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".shared3");
+
+        let map = SharedFsStorage::new(fsvlad_map::Builder::new(&pb).try_build().unwrap());
+        let mut rng = rand::rngs::OsRng::default();
+        let entries: Vec<(Vlad, Cid)> = (0..4u8)
+            .map(|i| {
+                let key = mk::Builder::new_from_random_bytes(Codec::Ed25519Priv, &mut rng).unwrap().try_build().unwrap();
+                let cid = get_cid(&[i]).unwrap();
+                (vlad::Builder::default().with_signing_key(&key).with_cid(&cid).try_build().unwrap(), cid)
+            })
+            .collect();
+
+        // batches go through the override while other threads share the handle
+        let handles: Vec<_> = entries
+            .chunks(2)
+            .map(|chunk| {
+                let mut map = map.clone();
+                let chunk = chunk.to_vec();
+                thread::spawn(move || CidMap::put_batch(&mut map, &chunk).into_iter().all(|r| r.unwrap().is_none()))
+            })
+            .collect();
+        assert!(handles.into_iter().all(|h| h.join().unwrap()));
+        for (vlad, cid) in &entries {
+            assert_eq!(&CidMap::get(&map, vlad).unwrap(), cid);
+        }
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, traits::blocks::BlockStat, error::no_such_data};
+use multibase::Base;
+use multicid::Cid;
+
+/// Blocks layered over a list of stores, like overlayfs. Reads go to the stores in priority
+/// order and the first one holding the block answers. Writes and removes only go to the first
+/// store, so a writable local store can sit on top of read-only base datasets. Removing a block
+/// that is also in a lower store leaves it visible from there.
+#[derive(Clone, Debug, Default)]
+pub struct UnionBlocks<B>(pub Vec<B>);
+
+impl<B> UnionBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    /// the stores in priority order
+    pub fn layers(&self) -> &[B] {
+        &self.0
+    }
+
+    // the first store holding the block
+    fn find(&self, cid: &Cid) -> Result<Option<&B>, B::Error> {
+        for store in &self.0 {
+            if store.exists(cid)? {
+                return Ok(Some(store));
+            }
+        }
+        Ok(None)
+    }
+
+    fn top(&mut self) -> Result<&mut B, B::Error> {
+        self.0.first_mut().ok_or_else(|| Error::Unsupported("write to a union without stores".to_string()).into())
+    }
+}
+
+impl<B> Blocks for UnionBlocks<B>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    type Error = B::Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        Ok(self.find(cid)?.is_some())
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        match self.find(cid)? {
+            Some(store) => store.get(cid),
+            None => Err(no_such_data(cid).into()),
+        }
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        let mut cids = Vec::default();
+        for store in &self.0 {
+            for cid in store.cids()? {
+                let data: Vec<u8> = cid.clone().into();
+                cids.push((multibase::encode(Base::Base32Z, data), cid));
+            }
+        }
+        cids.sort_by(|a, b| a.0.cmp(&b.0));
+        cids.dedup_by(|a, b| a.0 == b.0);
+        Ok(cids.into_iter().map(|(_, cid)| cid).collect())
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        self.top()?.put(data, get_cid, pre_commit)
+    }
+
+    fn stat(&self, cid: &Cid) -> Result<BlockStat, Self::Error> {
+        match self.find(cid)? {
+            Some(store) => store.stat(cid),
+            None => Err(no_such_data(cid).into()),
+        }
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        match self.0.first() {
+            Some(store) => store.rm(cid),
+            None => Err(no_such_data(cid).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::FsStorageError, fsblocks::Builder};
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::{fs, path::PathBuf};
+
+    fn get_cid(data: &&[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Identity).with_hash(&mh).try_build()?)
+    }
+
+    #[test]
+    fn test_union_blocks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".unionblocks1");
+
+        let mut base = Builder::new(pb.join("base")).not_lazy().try_build().unwrap();
+        let a = base.put(&&b"for great justice!"[..], get_cid, |_| Ok(())).unwrap();
+        let b = base.put(&&b"move every zig!"[..], get_cid, |_| Ok(())).unwrap();
+        let local = Builder::new(pb.join("local")).not_lazy().try_build().unwrap();
+
+        let mut union = UnionBlocks(vec![local, base]);
+        let c = union.put(&&b"all your base"[..], get_cid, |_| Ok(())).unwrap();
+        assert!(union.layers()[0].exists(&c).unwrap());
+        assert!(!union.layers()[1].exists(&c).unwrap());
+        assert_eq!(union.get(&a).unwrap(), b"for great justice!".to_vec());
+        assert_eq!(union.get(&c).unwrap(), b"all your base".to_vec());
+        assert_eq!(union.stat(&b).unwrap().size, 15);
+
+        // a block in both layers is listed once
+        assert_eq!(union.put(&&b"move every zig!"[..], get_cid, |_| Ok(())).unwrap(), b);
+        let cids = union.cids().unwrap();
+        assert_eq!(cids.len(), 3);
+        assert!(cids.contains(&a) && cids.contains(&b) && cids.contains(&c));
+
+        // removing only touches the top layer
+        assert!(union.rm(&a).is_err());
+        assert_eq!(union.rm(&b).unwrap(), b"move every zig!".to_vec());
+        assert!(union.exists(&b).unwrap());
+        union.rm(&c).unwrap();
+        assert!(matches!(union.get(&c), Err(Error::FsStorage(FsStorageError::NoSuchData(_)))));
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
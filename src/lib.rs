@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 //! content-addressable
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(missing_docs)]
 #![deny(
     trivial_casts,
@@ -9,17 +10,85 @@
     unused_qualifications,
 )]
 
+extern crate alloc;
+
+/// Record fields on the current tracing span, this is a no-op without the tracing feature
+#[cfg(all(feature = "std", feature = "tracing"))]
+macro_rules! record {
+    ($($name:literal = $value:expr),* $(,)?) => {{
+        let span = tracing::Span::current();
+        $(span.record($name, tracing::field::display($value));)*
+    }};
+}
+#[cfg(all(feature = "std", not(feature = "tracing")))]
+macro_rules! record {
+    ($($name:literal = $value:expr),* $(,)?) => {{
+        $(let _ = &$value;)*
+    }};
+}
+
+/// Blocks stored in Azure Blob Storage containers
+#[cfg(feature = "azure")]
+pub mod azure;
+
+/// Fetching missing blocks from peers and serving local blocks to them over bitswap
+#[cfg(feature = "bitswap")]
+pub mod bitswap;
+
+/// Content defined chunking of large data into blocks
+#[cfg(feature = "std")]
+pub mod chunker;
+
+/// IPLD dag-cbor node decoding and link extraction
+#[cfg(feature = "dag_cbor")]
+pub mod dag;
+
+/// Incremental hashing that shares one pass over the data with writing it
+#[cfg(feature = "digest")]
+pub mod digest;
+
+/// Read-only FUSE filesystem exposing the blocks in a store
+#[cfg(feature = "fuse")]
+pub mod fuse;
+
+/// gRPC server and client for sharing one store between processes
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
 /// Errors produced by this library
 pub mod error;
 pub use error::Error;
 
-/// Implementations of the traits
+/// Filesystem implementations of the traits
+#[cfg(feature = "std")]
 pub mod impls;
+#[cfg(feature = "std")]
 pub use impls::prelude::*;
 
+/// Redis backed stores for caches shared between processes
+#[cfg(feature = "redis")]
+pub mod redis;
+
+/// Read-only HTTP gateway serving blocks from a store
+#[cfg(feature = "serve")]
+pub mod serve;
+
+/// One-way copying of blocks from one store to another
+pub mod sync;
+
+/// Importing files and directories as UnixFS DAGs
+#[cfg(feature = "unixfs")]
+pub mod unixfs;
+
+/// Blocks stored on WebDAV servers
+#[cfg(feature = "webdav")]
+pub mod webdav;
+
 /// Traits from this crate
 pub mod traits;
-pub use traits::{blocks::Blocks, cid_map::CidMap};
+pub use traits::{block_store::BlockStore, blocks::Blocks, cid_map::CidMap, cid_multi_map::CidMultiMap, kv_map::KvMap, observer::{Event, Observer}};
+#[cfg(feature = "watch")]
+pub use traits::observer::Watch;
 
 /// Prelude convenience
 pub mod prelude {
@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, CidMap, Error, error::FsStorageError};
+use ::redis::{Client, Commands, Connection, RedisError};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::{marker::PhantomData, sync::Mutex, time::Duration};
+
+/// The prefix put in front of every key when none is given
+pub const DEFAULT_PREFIX: &str = "cas:";
+
+/// Builder for the Redis backed stores
+#[derive(Clone, Debug)]
+pub struct Builder {
+    url: String,
+    prefix: String,
+    ttl: Option<Duration>,
+}
+
+impl Builder {
+    /// create a new builder from the url of the server (e.g. "redis://127.0.0.1/")
+    pub fn new(url: &str) -> Self {
+        debug!("redis::Builder::new({})", url);
+        Builder {
+            url: url.to_string(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            ttl: None,
+        }
+    }
+
+    /// set the prefix of the keys, stores sharing a server need their own prefixes
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// let Redis expire every entry the given time after it was last put
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// connect and build a block store
+    pub fn try_build_blocks(&self) -> Result<RedisBlocks, Error> {
+        Ok(RedisBlocks { store: self.connect()? })
+    }
+
+    /// connect and build a mapping from IDs to Cids
+    pub fn try_build_map<ID>(&self) -> Result<RedisCidMap<ID>, Error> {
+        Ok(RedisCidMap {
+            store: self.connect()?,
+            _t: PhantomData,
+        })
+    }
+
+    fn connect(&self) -> Result<RedisStore, Error> {
+        let connection = Client::open(self.url.as_str()).and_then(|c| c.get_connection()).map_err(wrap)?;
+        debug!("redis: Connected to {}", self.url);
+        Ok(RedisStore {
+            connection: Mutex::new(connection),
+            prefix: self.prefix.clone(),
+            ttl: self.ttl,
+        })
+    }
+}
+
+/// The connection and key layout shared by the stores
+struct RedisStore {
+    connection: Mutex<Connection>,
+    prefix: String,
+    ttl: Option<Duration>,
+}
+
+impl RedisStore {
+    // run the closure against the connection
+    fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, RedisError>,
+    {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut connection).map_err(wrap)
+    }
+
+    // the key for the id bytes, the same Base32Z encoding the filesystem stores use
+    fn key(&self, id: Vec<u8>) -> String {
+        format!("{}{}", self.prefix, multibase::encode(Base::Base32Z, id))
+    }
+
+    // the id bytes of a key under the prefix
+    fn id(&self, key: &str) -> Option<Vec<u8>> {
+        let (_, bytes) = multibase::decode(key.strip_prefix(&self.prefix)?).ok()?;
+        Some(bytes)
+    }
+
+    // set the value and return the one it replaced
+    fn set(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let mut cmd = ::redis::cmd("SET");
+        cmd.arg(key).arg(value).arg("GET");
+        if let Some(ttl) = self.ttl {
+            cmd.arg("PX").arg(ttl.as_millis() as u64);
+        }
+        self.run(|c| cmd.query(c))
+    }
+
+    // set the value unless the key exists, nothing is sent back. blocks never change so a put of
+    // a stored one only restarts its ttl.
+    fn set_new(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+        let mut cmd = ::redis::cmd("SET");
+        cmd.arg(key).arg(value).arg("NX");
+        if let Some(ttl) = self.ttl {
+            cmd.arg("PX").arg(ttl.as_millis() as u64);
+        }
+        let set: Option<String> = self.run(|c| cmd.query(c))?;
+        if let (None, Some(ttl)) = (set, self.ttl) {
+            let mut cmd = ::redis::cmd("PEXPIRE");
+            cmd.arg(key).arg(ttl.as_millis() as u64);
+            self.run(|c| cmd.query::<()>(c))?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        self.run(|c| c.get::<_, Option<Vec<u8>>>(key))?
+            .ok_or_else(|| FsStorageError::NoSuchData(key.to_string()).into())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Error> {
+        self.run(|c| c.exists(key))
+    }
+
+    fn rm(&self, key: &str) -> Result<Vec<u8>, Error> {
+        self.run(|c| c.get_del::<_, Option<Vec<u8>>>(key))?
+            .ok_or_else(|| FsStorageError::NoSuchData(key.to_string()).into())
+    }
+
+    // the id bytes of every key under the prefix, in lexicographic order of the keys
+    fn ids(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let pattern = format!("{}*", self.prefix);
+        let mut keys: Vec<String> = self.run(|c| Ok(c.scan_match::<_, String>(&pattern)?.collect()))?;
+        keys.sort();
+        Ok(keys.iter().filter_map(|key| self.id(key)).collect())
+    }
+}
+
+/// Blocks kept in Redis, for a hot cache shared by a fleet of stateless services. Blocks expire
+/// with the ttl of the builder, if any.
+pub struct RedisBlocks {
+    store: RedisStore,
+}
+
+impl Blocks for RedisBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        self.store.exists(&self.store.key(cid.clone().into()))
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        self.store.get(&self.store.key(cid.clone().into()))
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        self.store.ids()?.iter().map(|id| Ok(Cid::try_from(id.as_slice())?)).collect()
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let cid = get_cid(data)?;
+        pre_commit(&cid)?;
+        self.store.set_new(&self.store.key(cid.clone().into()), data.as_ref())?;
+        Ok(cid)
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        self.store.rm(&self.store.key(cid.clone().into()))
+    }
+}
+
+/// A mapping from IDs to Cids kept in Redis. Mappings expire with the ttl of the builder, if any.
+pub struct RedisCidMap<ID> {
+    store: RedisStore,
+    _t: PhantomData<ID>,
+}
+
+impl<ID> CidMap<ID> for RedisCidMap<ID>
+where
+    ID: Clone + Into<Vec<u8>> + for<'a> TryFrom<&'a [u8]>,
+{
+    type Error = Error;
+
+    fn exists(&self, id: &ID) -> Result<bool, Self::Error> {
+        self.store.exists(&self.store.key(id.clone().into()))
+    }
+
+    fn get(&self, id: &ID) -> Result<Cid, Self::Error> {
+        let data = self.store.get(&self.store.key(id.clone().into()))?;
+        Ok(Cid::try_from(data.as_slice())?)
+    }
+
+    fn put(&mut self, id: &ID, cid: &Cid) -> Result<Option<Cid>, Self::Error> {
+        let data: Vec<u8> = cid.clone().into();
+        match self.store.set(&self.store.key(id.clone().into()), &data)? {
+            Some(prev) => Ok(Some(Cid::try_from(prev.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn rm(&self, id: &ID) -> Result<Cid, Self::Error> {
+        let data = self.store.rm(&self.store.key(id.clone().into()))?;
+        Ok(Cid::try_from(data.as_slice())?)
+    }
+
+    fn ids(&self) -> Result<Vec<ID>, Self::Error> {
+        Ok(self.store.ids()?.iter().filter_map(|id| ID::try_from(id.as_slice()).ok()).collect())
+    }
+}
+
+fn wrap(e: RedisError) -> Error {
+    Error::Wrapped(Box::new(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fscid_map::CidKey;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    fn get_cid(data: &[u8]) -> Result<Cid, Error> {
+        let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+        Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Raw).with_hash(&mh).try_build()?)
+    }
+
+    // this needs a server so it only runs when REDIS_URL points at one
+    #[test]
+    fn test_redis_stores() {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            return;
+        };
+        let mut blocks = Builder::new(&url).with_prefix("cas-test-blocks:").try_build_blocks().unwrap();
+        let cid = blocks.put(&b"for great justice!", |d| get_cid(*d), |_| Ok(())).unwrap();
+        assert!(blocks.exists(&cid).unwrap());
+        assert_eq!(blocks.get(&cid).unwrap(), b"for great justice!".to_vec());
+        assert!(blocks.cids().unwrap().contains(&cid));
+        assert_eq!(blocks.rm(&cid).unwrap(), b"for great justice!".to_vec());
+        assert!(matches!(blocks.get(&cid), Err(Error::FsStorage(FsStorageError::NoSuchData(_)))));
+
+        let mut map = Builder::new(&url)
+            .with_prefix("cas-test-map:")
+            .with_ttl(Duration::from_secs(60))
+            .try_build_map::<CidKey>()
+            .unwrap();
+        let id = CidKey(get_cid(b"move every zig!").unwrap());
+        assert!(map.put(&id, &cid).unwrap().is_none());
+        assert_eq!(map.put(&id, &cid).unwrap(), Some(cid.clone()));
+        assert_eq!(map.get(&id).unwrap(), cid);
+        assert_eq!(map.ids().unwrap(), vec![id.clone()]);
+        assert_eq!(map.rm(&id).unwrap(), cid);
+        assert!(!map.exists(&id).unwrap());
+    }
+}
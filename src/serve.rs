@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::IoContext};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use log::debug;
+use multicid::Cid;
+use multitrait::EncodeInto;
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::{Arc, Mutex}};
+
+/// The content type of a raw block response
+pub const RAW_CONTENT_TYPE: &str = "application/vnd.ipld.raw";
+
+/// The content type of a CAR response
+pub const CAR_CONTENT_TYPE: &str = "application/vnd.ipld.car";
+
+/// Serve the blocks read-only at `GET /ipfs/{cid}` on the address until the server fails. The
+/// response is the raw block, or a CARv1 holding just the block when `?format=car` is given or
+/// the Accept header asks for one. Clients are expected to verify the blocks they receive.
+pub async fn serve<B>(blocks: B, addr: SocketAddr) -> Result<(), Error>
+where
+    B: Blocks<Error = Error> + Send + 'static,
+{
+    let listener = tokio::net::TcpListener::bind(addr).await.io_context("bind", addr.to_string())?;
+    debug!("serve: Serving blocks on {}", addr);
+    axum::serve(listener, router(blocks)).await.io_context("serve", addr.to_string())
+}
+
+/// Get the router for the gateway so it can be mounted in a larger application
+pub fn router<B>(blocks: B) -> Router
+where
+    B: Blocks<Error = Error> + Send + 'static,
+{
+    Router::new()
+        .route("/ipfs/:cid", get(get_block::<B>))
+        .with_state(Arc::new(Mutex::new(blocks)))
+}
+
+/// Encode a CARv1 with the block as its only root and only block
+pub fn car(cid: &Cid, data: &[u8]) -> Vec<u8> {
+    let cid_bytes: Vec<u8> = cid.clone().into();
+
+    // the dag-cbor header {"roots": [cid], "version": 1}
+    let mut header = vec![0xa2, 0x65];
+    header.extend_from_slice(b"roots");
+    header.extend_from_slice(&[0x81, 0xd8, 0x2a]);
+    cbor_bytes_head(&mut header, cid_bytes.len() as u64 + 1);
+    header.push(0x00);
+    header.extend_from_slice(&cid_bytes);
+    header.push(0x67);
+    header.extend_from_slice(b"version");
+    header.push(0x01);
+
+    let mut v = (header.len() as u64).encode_into();
+    v.append(&mut header);
+    v.append(&mut ((cid_bytes.len() + data.len()) as u64).encode_into());
+    v.extend_from_slice(&cid_bytes);
+    v.extend_from_slice(data);
+    v
+}
+
+// the head of a CBOR byte string of the given length
+fn cbor_bytes_head(v: &mut Vec<u8>, len: u64) {
+    match len {
+        0..=23 => v.push(0x40 | len as u8),
+        24..=0xff => v.extend_from_slice(&[0x58, len as u8]),
+        0x100..=0xffff => {
+            v.push(0x59);
+            v.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            v.push(0x5a);
+            v.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Params {
+    format: Option<String>,
+}
+
+async fn get_block<B>(
+    State(blocks): State<Arc<Mutex<B>>>,
+    Path(cid): Path<String>,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
+) -> Response
+where
+    B: Blocks<Error = Error> + Send + 'static,
+{
+    let Ok((_, bytes)) = multibase::decode(&cid) else {
+        return (StatusCode::BAD_REQUEST, "invalid cid").into_response();
+    };
+    let Ok(cid) = Cid::try_from(bytes.as_slice()) else {
+        return (StatusCode::BAD_REQUEST, "invalid cid").into_response();
+    };
+    let car_requested = match params.format.as_deref() {
+        Some("car") => true,
+        Some("raw") => false,
+        Some(_) => return (StatusCode::BAD_REQUEST, "format must be raw or car").into_response(),
+        None => headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains(CAR_CONTENT_TYPE)),
+    };
+
+    // the store does blocking file I/O so keep it off of the async workers
+    let lookup = cid.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let blocks = blocks.lock().unwrap_or_else(|e| e.into_inner());
+        if !blocks.exists(&lookup)? {
+            return Ok(None);
+        }
+        blocks.get(&lookup).map(Some)
+    }).await;
+
+    match result {
+        Ok(Ok(Some(data))) if car_requested => ([(header::CONTENT_TYPE, CAR_CONTENT_TYPE)], car(&cid, &data)).into_response(),
+        Ok(Ok(Some(data))) => ([(header::CONTENT_TYPE, RAW_CONTENT_TYPE)], data).into_response(),
+        Ok(Ok(None)) => (StatusCode::NOT_FOUND, "block not found").into_response(),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    #[test]
+    fn test_car() {
+        let data = b"for great justice!";
+        let mh = mh::Builder::new_from_bytes(Codec::Sha2256, data).unwrap().try_build().unwrap();
+        let cid = cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Raw).with_hash(&mh).try_build().unwrap();
+        let cid_bytes: Vec<u8> = cid.clone().into();
+
+        let car = car(&cid, data);
+
+        // the header length prefix, then the header with the cid as a tagged byte string
+        let header_len = car[0] as usize;
+        assert_eq!(&car[1..3], &[0xa2, 0x65]);
+        assert_eq!(&car[3..8], b"roots");
+        assert_eq!(&car[8..13], &[0x81, 0xd8, 0x2a, 0x58, cid_bytes.len() as u8 + 1]);
+        assert_eq!(&car[header_len - 7..header_len + 1], b"version\x01");
+
+        // the single block section is the cid followed by the data
+        let section = &car[header_len + 1..];
+        assert_eq!(section[0] as usize, cid_bytes.len() + data.len());
+        assert_eq!(&section[1..1 + cid_bytes.len()], cid_bytes.as_slice());
+        assert_eq!(&section[1 + cid_bytes.len()..], data);
+    }
+}
@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error};
+use alloc::{collections::BTreeSet, vec::Vec};
+use log::debug;
+use multicid::Cid;
+
+/// Statistics about what a sync did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// the number of blocks in the source store
+    pub checked: usize,
+    /// the number of blocks copied to the destination store
+    pub copied: usize,
+    /// the number of blocks the destination store already had
+    pub skipped: usize,
+    /// the number of bytes copied
+    pub bytes: u64,
+}
+
+/// The blocks that are in one store but not the other
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    /// the Cids of the blocks only in the first store, in sorted order
+    pub only_in_a: Vec<Cid>,
+    /// the Cids of the blocks only in the second store, in sorted order
+    pub only_in_b: Vec<Cid>,
+}
+
+impl StoreDiff {
+    /// check if both stores hold the same blocks
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty()
+    }
+}
+
+/// Compare the Cids of the blocks in two stores. Only the Cids are compared, the contents of the
+/// blocks are not read. only_in_a is the minimal set of blocks a sync from a to b copies.
+pub fn diff<A, B>(a: &A, b: &B) -> Result<StoreDiff, A::Error>
+where
+    A: Blocks,
+    A::Error: From<Error> + From<B::Error>,
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let in_a: BTreeSet<Cid> = a.cids()?.into_iter().collect();
+    let in_b: BTreeSet<Cid> = b.cids()?.into_iter().collect();
+    let diff = StoreDiff {
+        only_in_a: in_a.difference(&in_b).cloned().collect(),
+        only_in_b: in_b.difference(&in_a).cloned().collect(),
+    };
+    debug!("sync: {} blocks only in a, {} only in b", diff.only_in_a.len(), diff.only_in_b.len());
+    Ok(diff)
+}
+
+/// Copy every block in the source store that is missing from the destination store. Each block
+/// is verified against its Cid before it is stored so that corruption in the source isn't
+/// replicated. Blocks are never removed from the destination.
+pub fn sync<S, D>(src: &S, dst: &mut D) -> Result<SyncStats, D::Error>
+where
+    S: Blocks,
+    S::Error: From<Error>,
+    D: Blocks,
+    D::Error: From<Error> + From<S::Error>,
+{
+    let mut stats = SyncStats::default();
+    for cid in src.cids()? {
+        stats.checked += 1;
+        if dst.exists(&cid)? {
+            stats.skipped += 1;
+            continue;
+        }
+        let data = src.get(&cid)?;
+        dst.put_with_cid(&cid, &data, true)?;
+        stats.copied += 1;
+        stats.bytes += data.len() as u64;
+    }
+    debug!("sync: Copied {} of {} blocks, {} bytes", stats.copied, stats.checked, stats.bytes);
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::{Builder, FsBlocks};
+    use multicid::{cid, Cid};
+    use multicodec::Codec;
+    use multihash::mh;
+    use std::{fs, path::PathBuf};
+
+    fn put(blocks: &mut FsBlocks, data: &[u8]) -> Cid {
+        blocks.put(&data, |data| -> Result<Cid, Error> {
+            let mh = mh::Builder::new_from_bytes(Codec::Blake3, data)?.try_build()?;
+            Ok(cid::Builder::new(Codec::Cidv1).with_target_codec(Codec::Raw).with_hash(&mh).try_build()?)
+        }, |_| Ok(())).unwrap()
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".sync1");
+        let mut src = Builder::new(pb.join("src")).try_build().unwrap();
+        let mut dst = Builder::new(pb.join("dst")).try_build().unwrap();
+
+        let cid1 = put(&mut src, b"for great justice!");
+        let cid2 = put(&mut src, b"move every zig!");
+        let _ = put(&mut dst, b"for great justice!");
+
+        let stats = sync(&src, &mut dst).unwrap();
+        assert_eq!(stats, SyncStats { checked: 2, copied: 1, skipped: 1, bytes: 15 });
+        assert!(dst.exists(&cid1).unwrap());
+        assert_eq!(dst.get(&cid2).unwrap(), b"move every zig!".to_vec());
+
+        // a second sync has nothing to do
+        let stats = sync(&src, &mut dst).unwrap();
+        assert_eq!(stats.copied, 0);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".sync2");
+        let mut a = Builder::new(pb.join("a")).try_build().unwrap();
+        let mut b = Builder::new(pb.join("b")).try_build().unwrap();
+
+        let cid1 = put(&mut a, b"for great justice!");
+        let _ = put(&mut a, b"move every zig!");
+        let _ = put(&mut b, b"move every zig!");
+        let cid3 = put(&mut b, b"all your base are belong to us");
+
+        let d = diff(&a, &b).unwrap();
+        assert_eq!(d.only_in_a, vec![cid1]);
+        assert_eq!(d.only_in_b, vec![cid3]);
+
+        // after syncing both ways the stores match
+        let _ = sync(&a, &mut b).unwrap();
+        let _ = sync(&b, &mut a).unwrap();
+        assert!(diff(&a, &b).unwrap().is_empty());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
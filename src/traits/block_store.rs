@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error};
+use alloc::vec::Vec;
+use multicid::Cid;
+
+/// Object safe block storage so that backends can be chosen at runtime and held as a
+/// `Box<dyn BlockStore>`. Every `Blocks` implementation that uses this crate's `Error` is a
+/// `BlockStore`. The method names differ from `Blocks` so both traits can be in scope at once.
+pub trait BlockStore {
+    /// Try to confirm a block exists
+    fn block_exists(&self, cid: &Cid) -> Result<bool, Error>;
+
+    /// Try to get a block from its content address
+    fn get_block(&self, cid: &Cid) -> Result<Vec<u8>, Error>;
+
+    /// Try to put a block into storage, this has the same semantics as `Blocks::put` with the
+    /// closures passed as trait objects
+    fn put_with(
+        &mut self,
+        data: &[u8],
+        get_cid: &dyn Fn(&[u8]) -> Result<Cid, Error>,
+        pre_commit: &dyn Fn(&Cid) -> Result<(), Error>,
+    ) -> Result<Cid, Error>;
+
+    /// Try to remove a block from storage
+    fn rm_block(&self, cid: &Cid) -> Result<Vec<u8>, Error>;
+}
+
+impl<B> BlockStore for B
+where
+    B: Blocks<Error = Error>,
+{
+    fn block_exists(&self, cid: &Cid) -> Result<bool, Error> {
+        self.exists(cid)
+    }
+
+    fn get_block(&self, cid: &Cid) -> Result<Vec<u8>, Error> {
+        self.get(cid)
+    }
+
+    fn put_with(
+        &mut self,
+        data: &[u8],
+        get_cid: &dyn Fn(&[u8]) -> Result<Cid, Error>,
+        pre_commit: &dyn Fn(&Cid) -> Result<(), Error>,
+    ) -> Result<Cid, Error> {
+        self.put(&data, |data| get_cid(*data), |cid| pre_commit(cid))
+    }
+
+    fn rm_block(&self, cid: &Cid) -> Result<Vec<u8>, Error> {
+        self.rm(cid)
+    }
+}
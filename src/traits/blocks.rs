@@ -1,5 +1,21 @@
 // SPDX-License-Identifier: Apache-2.0
+use crate::{Error, error::FsStorageError};
+use alloc::{string::ToString, vec::Vec};
 use multicid::Cid;
+use multihash::mh;
+use multiutil::{BaseEncoded, DetectedEncoder, EncodingInfo};
+
+/// Size and metadata of a stored block
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockStat {
+    /// the size of the block data in bytes
+    pub size: u64,
+    /// when the block was stored, if the backend knows it
+    pub created: Option<std::time::SystemTime>,
+    /// whether the block has been lazy deleted but not yet garbage collected
+    pub lazy_deleted: bool,
+}
 
 /// Abstract block storage trait for getting and putting content addressed data
 pub trait Blocks {
@@ -12,6 +28,57 @@ pub trait Blocks {
     /// Try to get a block from its content address 
     fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error>;
 
+    /// Try to get the Cids of all of the stored blocks. Backends that can enumerate their blocks
+    /// return them in lexicographic order of their encoded form so that replicas of the same
+    /// blocks list them identically. Backends that can't return an Error::Unsupported error.
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error>
+    where
+        Self::Error: From<Error>,
+    {
+        Err(Error::Unsupported("cids".to_string()).into())
+    }
+
+    /// Try to get a block as a cheaply cloneable buffer. Backends that cache blocks or map them
+    /// into memory override this to avoid copying, by default it takes ownership of the Vec from
+    /// get without copying it.
+    #[cfg(feature = "bytes")]
+    fn get_bytes(&self, cid: &Cid) -> Result<bytes::Bytes, Self::Error> {
+        Ok(bytes::Bytes::from(self.get(cid)?))
+    }
+
+    /// Try to get a block from its content address and verify that the data hashes to the
+    /// multihash in the Cid. If it doesn't, a FsStorageError::CorruptBlock error is returned.
+    fn get_verified(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error>
+    where
+        Self::Error: From<Error>,
+    {
+        let data = self.get(cid)?;
+        verify(cid, &data)?;
+        Ok(data)
+    }
+
+    /// Try to get many blocks at once, reading them concurrently on up to the given number of
+    /// threads. This warms caches before serving a DAG and keeps remote backends busy. The
+    /// result of getting each block is in the returned map under its Cid.
+    #[cfg(feature = "std")]
+    fn get_many(&self, cids: &[Cid], threads: usize) -> std::collections::BTreeMap<Cid, Result<Vec<u8>, Self::Error>>
+    where
+        Self: Sync,
+        Self::Error: Send,
+    {
+        for_each_par(cids, threads, |cid| self.get(cid)).into_iter().map(|(cid, result)| (cid.clone(), result)).collect()
+    }
+
+    /// Try to read many blocks concurrently without keeping them, so that caching backends hold
+    /// them before they are needed. Returns the number of blocks that could be read.
+    #[cfg(feature = "std")]
+    fn prefetch(&self, cids: &[Cid], threads: usize) -> usize
+    where
+        Self: Sync,
+    {
+        for_each_par(cids, threads, |cid| self.get(cid).is_ok()).into_iter().filter(|(_, read)| *read).count()
+    }
+
     /// Try to put a block into storage. This calls the get_cid closure to calculate the Cid over
     /// the data passed in. It also calls the pre_commit closure after the put transaction has been
     /// set up successfully but before it is committed. This allows for other side effects to
@@ -23,6 +90,77 @@ pub trait Blocks {
         F1: Fn(&D) -> Result<Cid, Self::Error>,
         F2: Fn(&Cid) -> Result<(), Self::Error>;
 
+    /// Try to put a block whose Cid is already known, such as one received over the network.
+    /// When verify is true the data is hashed and checked against the multihash in the Cid first
+    /// and a FsStorageError::CorruptBlock error is returned if it doesn't match.
+    fn put_with_cid(&mut self, cid: &Cid, data: &[u8], verify: bool) -> Result<Cid, Self::Error>
+    where
+        Self::Error: From<Error>,
+    {
+        if verify {
+            self::verify(cid, data)?;
+        }
+        self.put(&data, |_| Ok(cid.clone()), |_| Ok(()))
+    }
+
+    /// Try to get the size and metadata of a block without reading its contents. Backends that
+    /// can't do this return an Error::Unsupported error.
+    #[cfg(feature = "std")]
+    fn stat(&self, _cid: &Cid) -> Result<BlockStat, Self::Error>
+    where
+        Self::Error: From<Error>,
+    {
+        Err(Error::Unsupported("stat".to_string()).into())
+    }
+
     /// Try to remove a block from storage
     fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error>;
+
+    /// Try to remove a block from storage, returning its contents as a cheaply cloneable buffer
+    #[cfg(feature = "bytes")]
+    fn rm_bytes(&self, cid: &Cid) -> Result<bytes::Bytes, Self::Error> {
+        Ok(bytes::Bytes::from(self.rm(cid)?))
+    }
+}
+
+/// Run the closure on every Cid on up to the given number of scoped threads and return the
+/// results in no particular order. The threads take the next Cid from a shared counter until
+/// they run out.
+#[cfg(feature = "std")]
+fn for_each_par<'a, R, F>(cids: &'a [Cid], threads: usize, f: F) -> Vec<(&'a Cid, R)>
+where
+    R: Send,
+    F: Fn(&Cid) -> R + Sync,
+{
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let next = AtomicUsize::new(0);
+    let threads = threads.clamp(1, cids.len().max(1));
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::default();
+                    while let Some(cid) = cids.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        results.push((cid, f(cid)));
+                    }
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// Recompute the multihash over the data and compare it to the one in the Cid
+pub fn verify(cid: &Cid, data: &[u8]) -> Result<(), Error> {
+    let mh = mh::Builder::new_from_bytes(cid.hash().codec(), data)?.try_build()?;
+    if mh != *cid.hash() {
+        let ecid = BaseEncoded::<Cid, DetectedEncoder>::new(Cid::preferred_encoding(), cid.clone());
+        return Err(FsStorageError::CorruptBlock(ecid.to_string()).into());
+    }
+    Ok(())
 }
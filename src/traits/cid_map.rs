@@ -1,8 +1,29 @@
 // SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error};
+use alloc::{string::ToString, vec::Vec};
 use multicid::Cid;
+#[cfg(feature = "std")]
+use crate::error::FsStorageError;
+#[cfg(feature = "std")]
+use alloc::{boxed::Box, string::String};
+#[cfg(feature = "std")]
+use multibase::Base;
+#[cfg(feature = "std")]
+use std::{collections::BTreeMap, io::{Read, Write}};
+
+/// The portable formats that CidMap contents can be exported to
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// a JSON object
+    #[default]
+    Json,
+    /// a CBOR map
+    Cbor,
+}
 
 /// Abstract storage trait for managing Multikey to Cid mappings
-pub trait CidMap<ID> {
+pub trait CidMap<ID: ?Sized> {
     /// The error type returned
     type Error;
 
@@ -16,6 +37,184 @@ pub trait CidMap<ID> {
     /// value if there was one. If the mapping is new, Ok(None) is returned.
     fn put(&mut self, id: &ID, cid: &Cid) -> Result<Option<Cid>, Self::Error>;
 
+    /// Try to update the mapping from the ID to the Cid only if the current value matches
+    /// expected. An expected value of None means the mapping must not exist yet. If the current
+    /// value doesn't match, an Error::CasMismatch is returned and the mapping is left unchanged.
+    /// The default implementation isn't atomic, implementations should override it to guard the
+    /// comparison and the update against concurrent writers.
+    fn put_cas(&mut self, id: &ID, expected: Option<&Cid>, cid: &Cid) -> Result<Option<Cid>, Self::Error>
+    where
+        Self::Error: From<Error>,
+    {
+        let current = if self.exists(id)? { Some(self.get(id)?) } else { None };
+        if current.as_ref() != expected {
+            return Err(Error::CasMismatch(expected.cloned(), current).into());
+        }
+        self.put(id, cid)
+    }
+
     /// Try to remove the current mapping
     fn rm(&self, id: &ID) -> Result<Cid, Self::Error>;
+
+    /// Try to update many mappings at once, returning the result of each put in order. The
+    /// default implementation puts them one at a time, implementations should override it to
+    /// share the expensive parts of a put across the whole batch.
+    fn put_batch(&mut self, entries: &[(ID, Cid)]) -> Vec<Result<Option<Cid>, Self::Error>>
+    where
+        ID: Sized,
+    {
+        entries.iter().map(|(id, cid)| self.put(id, cid)).collect()
+    }
+
+    /// Try to remove many mappings at once, returning the result of each remove in order
+    fn rm_batch(&self, ids: &[ID]) -> Vec<Result<Cid, Self::Error>>
+    where
+        ID: Sized,
+    {
+        ids.iter().map(|id| self.rm(id)).collect()
+    }
+
+    /// Try to get the IDs of every mapping. Implementations that can't enumerate their mappings
+    /// return an Error::Unsupported.
+    fn ids(&self) -> Result<Vec<ID>, Self::Error>
+    where
+        ID: Sized,
+        Self::Error: From<Error>,
+    {
+        Err(Error::Unsupported("ids".to_string()).into())
+    }
+
+    /// Try to write every mapping to the writer as one map from the multibase encoded ID to the
+    /// multibase encoded Cid, sorted by ID so the same mappings always dump the same way. This
+    /// returns the number of mappings written.
+    #[cfg(feature = "std")]
+    fn export<W: Write>(&self, writer: W, format: Format) -> Result<usize, Self::Error>
+    where
+        ID: Sized + Clone + Into<Vec<u8>>,
+        Self::Error: From<Error>,
+    {
+        let mut dump = BTreeMap::new();
+        for id in self.ids()? {
+            let cid: Vec<u8> = self.get(&id)?.into();
+            dump.insert(multibase::encode(Base::Base32Z, id.into()), multibase::encode(Base::Base32Z, cid));
+        }
+        match format {
+            Format::Json => serde_json::to_writer_pretty(writer, &dump).map_err(|e| Error::Wrapped(Box::new(e)))?,
+            Format::Cbor => serde_cbor::to_writer(writer, &dump).map_err(|e| Error::Wrapped(Box::new(e)))?,
+        }
+        Ok(dump.len())
+    }
+
+    /// Try to put every mapping in a dump written by export(), the format is detected from the
+    /// data. This returns the number of mappings put.
+    #[cfg(feature = "std")]
+    fn import<R: Read>(&mut self, mut reader: R) -> Result<usize, Self::Error>
+    where
+        ID: Sized + for<'a> TryFrom<&'a [u8]>,
+        Self::Error: From<Error>,
+    {
+        let mut data = Vec::default();
+        reader.read_to_end(&mut data).map_err(|e| Error::Wrapped(Box::new(e)))?;
+        let dump: BTreeMap<String, String> = match data.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => serde_json::from_slice(&data).map_err(|e| Error::Wrapped(Box::new(e)))?,
+            _ => serde_cbor::from_slice(&data).map_err(|e| Error::Wrapped(Box::new(e)))?,
+        };
+        for (eid, ecid) in &dump {
+            let (_, bytes) = multibase::decode(eid).map_err(|_| Error::from(FsStorageError::InvalidId(eid.clone())))?;
+            let id = ID::try_from(bytes.as_slice()).map_err(|_| Error::from(FsStorageError::InvalidId(eid.clone())))?;
+            let (_, bytes) = multibase::decode(ecid).map_err(|_| Error::from(FsStorageError::InvalidValue(eid.clone())))?;
+            let cid = Cid::try_from(bytes.as_slice()).map_err(Error::from)?;
+            self.put(&id, &cid)?;
+        }
+        Ok(dump.len())
+    }
+
+    /// Try to merge every mapping in other into this map, e.g. after offline edits to two copies
+    /// of the same store. Mappings only in other are copied over and mappings in both that
+    /// disagree are settled by the resolver, which is passed the ID, the existing Cid and the
+    /// incoming Cid and returns the Cid to keep. This returns the number of mappings changed.
+    fn merge_from<O, F>(&mut self, other: &O, resolver: F) -> Result<usize, Self::Error>
+    where
+        ID: Sized,
+        O: CidMap<ID>,
+        F: Fn(&ID, &Cid, &Cid) -> Cid,
+        Self::Error: From<Error> + From<O::Error>,
+    {
+        let mut changed = 0;
+        for id in other.ids()? {
+            let theirs = other.get(&id)?;
+            let cid = if self.exists(&id)? {
+                let ours = self.get(&id)?;
+                if ours == theirs {
+                    continue;
+                }
+                let cid = resolver(&id, &ours, &theirs);
+                if cid == ours {
+                    continue;
+                }
+                cid
+            } else {
+                theirs
+            };
+            self.put(&id, &cid)?;
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    /// Try to watch the mapping from the ID, the watch receives the Cid every time the mapping
+    /// is created or updated, including by other processes, until it is dropped.
+    /// Implementations that can't watch their mappings return an Error::Unsupported.
+    #[cfg(feature = "watch")]
+    fn watch(&self, _id: &ID) -> Result<crate::Watch<Cid>, Self::Error>
+    where
+        Self::Error: From<Error>,
+    {
+        Err(Error::Unsupported("watch".to_string()).into())
+    }
+
+    /// Try to get the IDs of every mapping that currently points at the Cid. Implementations
+    /// that don't maintain a reverse index return an Error::Unsupported.
+    fn referrers(&self, _cid: &Cid) -> Result<Vec<ID>, Self::Error>
+    where
+        ID: Sized,
+        Self::Error: From<Error>,
+    {
+        Err(Error::Unsupported("referrers".to_string()).into())
+    }
+}
+
+/// Merge resolver that settles every conflict in favor of the incoming mapping, treating the map
+/// being merged from as the last writer
+pub fn last_writer_wins<ID: ?Sized>(_id: &ID, _existing: &Cid, incoming: &Cid) -> Cid {
+    incoming.clone()
+}
+
+/// Merge resolver that settles every conflict in favor of the existing mapping so that a merge
+/// only ever adds mappings
+pub fn keep_existing<ID: ?Sized>(_id: &ID, existing: &Cid, _incoming: &Cid) -> Cid {
+    existing.clone()
+}
+
+/// Store the block and point the mapping from the ID at it. If updating the mapping fails the
+/// block is removed again, unless it was already stored before, and the error is returned.
+/// Returns the Cid of the block.
+pub fn store_and_point<B, M, ID, D, F>(blocks: &mut B, map: &mut M, id: &ID, data: &D, get_cid: F) -> Result<Cid, B::Error>
+where
+    B: Blocks,
+    M: CidMap<ID, Error = B::Error>,
+    ID: ?Sized,
+    D: AsRef<[u8]>,
+    F: Fn(&D) -> Result<Cid, B::Error>,
+{
+    let cid = get_cid(data)?;
+    let existed = blocks.exists(&cid)?;
+    blocks.put(data, |_| Ok(cid.clone()), |_| Ok(()))?;
+    if let Err(e) = map.put(id, &cid) {
+        if !existed {
+            let _ = blocks.rm(&cid);
+        }
+        return Err(e);
+    }
+    Ok(cid)
 }
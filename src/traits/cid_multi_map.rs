@@ -0,0 +1,26 @@
+// SPDX-License-Identifier: Apache-2.0
+use alloc::vec::Vec;
+use multicid::Cid;
+
+/// Abstract storage trait for managing mappings from an ID to a set of Cids
+pub trait CidMultiMap<ID: ?Sized> {
+    /// The error type returned
+    type Error;
+
+    /// Try to add the Cid to the set the ID maps to. This returns false if the Cid was already
+    /// in the set.
+    fn add(&mut self, id: &ID, cid: &Cid) -> Result<bool, Self::Error>;
+
+    /// Try to remove the Cid from the set the ID maps to. This returns false if the Cid wasn't in
+    /// the set. Removing the last Cid removes the mapping.
+    fn remove(&mut self, id: &ID, cid: &Cid) -> Result<bool, Self::Error>;
+
+    /// Try to get all of the Cids the ID maps to in the order they were added. An ID without a
+    /// mapping maps to the empty set.
+    fn get_all(&self, id: &ID) -> Result<Vec<Cid>, Self::Error>;
+
+    /// Try to confirm the ID maps to the Cid
+    fn contains(&self, id: &ID, cid: &Cid) -> Result<bool, Self::Error> {
+        Ok(self.get_all(id)?.contains(cid))
+    }
+}
@@ -0,0 +1,20 @@
+// SPDX-License-Identifier: Apache-2.0
+
+/// Abstract storage trait for managing mappings from an ID to an arbitrary value
+pub trait KvMap<ID, V> {
+    /// The error type returned
+    type Error;
+
+    /// Try to confirm a mapping exists
+    fn exists(&self, id: &ID) -> Result<bool, Self::Error>;
+
+    /// Try to get the current mapping value
+    fn get(&self, id: &ID) -> Result<V, Self::Error>;
+
+    /// Try to update the current mapping from the ID to the value. This returns the current
+    /// value if there was one. If the mapping is new, Ok(None) is returned.
+    fn put(&mut self, id: &ID, value: &V) -> Result<Option<V>, Self::Error>;
+
+    /// Try to remove the current mapping
+    fn rm(&self, id: &ID) -> Result<V, Self::Error>;
+}
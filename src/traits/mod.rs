@@ -1,5 +1,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
+/// Object safe block storage interface
+pub mod block_store;
+pub use block_store::BlockStore;
+
 /// Abstract block storage interface
 pub mod blocks;
 pub use blocks::Blocks;
@@ -7,3 +11,17 @@ pub use blocks::Blocks;
 /// Abstract mapping of ID to Cid
 pub mod cid_map;
 pub use cid_map::CidMap;
+
+/// Abstract mapping of ID to a set of Cids
+pub mod cid_multi_map;
+pub use cid_multi_map::CidMultiMap;
+
+/// Abstract mapping of ID to any value
+pub mod kv_map;
+pub use kv_map::KvMap;
+
+/// Observers of store mutations
+pub mod observer;
+pub use observer::{Event, Observer};
+#[cfg(feature = "watch")]
+pub use observer::Watch;
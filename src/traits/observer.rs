@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: Apache-2.0
+use multicid::Cid;
+
+/// Events describing the mutations of a store
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Event<ID> {
+    /// A block was put into a block store
+    BlockPut(Cid),
+    /// A block was removed from a block store
+    BlockRemoved(Cid),
+    /// The mapping from the ID was created or updated to point at the Cid
+    MapUpdated(ID, Cid),
+    /// The mapping from the ID to the Cid was removed
+    MapRemoved(ID, Cid),
+    /// The lazy deleted entry with the ID was restored
+    Restored(ID),
+    /// A block that was missing or corrupt was fetched from a replica and put back
+    BlockRepaired(Cid),
+}
+
+/// Abstract observer that receives an event for every mutation of a store it is subscribed to
+pub trait Observer<ID>: Send + Sync {
+    /// Called after the mutation has been committed
+    fn notify(&self, event: &Event<ID>);
+}
+
+impl<ID, F> Observer<ID> for F
+where
+    F: Fn(&Event<ID>) + Send + Sync,
+{
+    fn notify(&self, event: &Event<ID>) {
+        self(event)
+    }
+}
+
+/// A watch on a store that receives its changes through the receiver it derefs to. Dropping the
+/// watch drops the source of the changes, which ends the thread forwarding them.
+#[cfg(feature = "watch")]
+pub struct Watch<T> {
+    rx: std::sync::mpsc::Receiver<T>,
+    _source: alloc::boxed::Box<dyn core::any::Any + Send>,
+}
+
+#[cfg(feature = "watch")]
+impl<T> Watch<T> {
+    /// Create a watch from the receiver of the changes and the source that sends them
+    pub fn new<S: core::any::Any + Send>(rx: std::sync::mpsc::Receiver<T>, source: S) -> Self {
+        Self {
+            rx,
+            _source: alloc::boxed::Box::new(source),
+        }
+    }
+}
+
+#[cfg(feature = "watch")]
+impl<T> core::ops::Deref for Watch<T> {
+    type Target = std::sync::mpsc::Receiver<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+#[cfg(feature = "watch")]
+impl<T> core::fmt::Debug for Watch<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Watch").field("rx", &self.rx).finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,592 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::{DagError, IoContext}};
+use log::debug;
+use multicid::{cid, Cid};
+use multicodec::Codec;
+use multihash::mh;
+use multitrait::{EncodeInto, TryDecodeFrom};
+use std::{collections::HashSet, fmt::Display, fs::{self, File}, io::{self, ErrorKind, Read, Write}, path::Path};
+
+/// The size of the fixed size chunks files are split into, the same as `ipfs add`
+pub const CHUNK_SIZE: usize = 262_144;
+
+/// The maximum number of links in a file node before the tree gets another level
+pub const MAX_LINKS: usize = 174;
+
+/// The UnixFS data types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum DataType {
+    /// Raw data
+    Raw = 0,
+    /// A directory of named links
+    Directory = 1,
+    /// A file made of the data in its links
+    File = 2,
+    /// Metadata
+    Metadata = 3,
+    /// A symbolic link
+    Symlink = 4,
+    /// A sharded directory
+    HamtShard = 5,
+}
+
+impl TryFrom<u64> for DataType {
+    type Error = Error;
+
+    fn try_from(kind: u64) -> Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(DataType::Raw),
+            1 => Ok(DataType::Directory),
+            2 => Ok(DataType::File),
+            3 => Ok(DataType::Metadata),
+            4 => Ok(DataType::Symlink),
+            5 => Ok(DataType::HamtShard),
+            _ => Err(DagError::InvalidNode(format!("unknown UnixFS type {}", kind)).into()),
+        }
+    }
+}
+
+/// A named link in a dag-pb node
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    /// the Cid of the linked block
+    pub cid: Cid,
+    /// the name of the link, file names in directories and empty in files
+    pub name: String,
+    /// the total size of the encoded blocks in the linked DAG
+    pub tsize: u64,
+}
+
+/// A decoded dag-pb node with its UnixFS data
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    /// the links to the children, in order
+    pub links: Vec<Link>,
+    /// the kind of node
+    pub kind: DataType,
+    /// the inline data, file contents for files and the target for symlinks
+    pub data: Vec<u8>,
+}
+
+impl TryFrom<&[u8]> for Node {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut links = Vec::default();
+        let mut unixfs = None;
+        for (field, value) in fields(bytes)? {
+            match (field, value) {
+                (1, Value::Bytes(data)) => unixfs = Some(data),
+                (2, Value::Bytes(link)) => {
+                    let (mut cid, mut name, mut tsize) = (None, String::default(), 0);
+                    for (field, value) in fields(link)? {
+                        match (field, value) {
+                            (1, Value::Bytes(c)) => cid = Some(Cid::try_from(c)?),
+                            (2, Value::Bytes(n)) => name = String::from_utf8_lossy(n).to_string(),
+                            (3, Value::Varint(t)) => tsize = t,
+                            _ => {}
+                        }
+                    }
+                    let cid = cid.ok_or(DagError::InvalidLink)?;
+                    links.push(Link { cid, name, tsize });
+                }
+                _ => {}
+            }
+        }
+
+        let unixfs = unixfs.ok_or_else(|| DagError::InvalidNode("missing UnixFS data".to_string()))?;
+        let mut kind = None;
+        let mut data = Vec::default();
+        for (field, value) in fields(unixfs)? {
+            match (field, value) {
+                (1, Value::Varint(k)) => kind = Some(DataType::try_from(k)?),
+                (2, Value::Bytes(d)) => data = d.to_vec(),
+                _ => {}
+            }
+        }
+        let kind = kind.ok_or_else(|| DagError::InvalidNode("missing UnixFS type".to_string()))?;
+        Ok(Node { links, kind, data })
+    }
+}
+
+/// A stored file, directory or symlink
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    cid: Cid,
+    // the total size of the encoded blocks in the DAG
+    tsize: u64,
+    // the size of the file contents
+    filesize: u64,
+}
+
+/// Store the file or directory at the path as a UnixFS DAG and return the root Cid. This matches
+/// `ipfs add --cid-version=1`: files are split into fixed size chunks stored as raw leaves under a
+/// balanced tree of dag-pb nodes, every block is hashed with Sha2-256, files that fit in one
+/// chunk are a single raw block and hidden files are skipped.
+pub fn add_path<B, P>(blocks: &mut B, path: P) -> Result<Cid, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+    P: AsRef<Path>,
+{
+    let entry = add_entry(blocks, path.as_ref())?;
+    debug!("unixfs: Added {} bytes from {}", entry.filesize, path.as_ref().display());
+    Ok(entry.cid)
+}
+
+/// Store the contents of the reader as a UnixFS file and return the root Cid
+pub fn add_reader<B, R>(blocks: &mut B, reader: R) -> Result<Cid, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+    R: Read,
+{
+    Ok(add_file(blocks, reader, "-")?.cid)
+}
+
+fn add_entry<B>(blocks: &mut B, path: &Path) -> Result<Entry, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let file_type = fs::symlink_metadata(path).io_context("stat", path)?.file_type();
+    if file_type.is_symlink() {
+        let target = fs::read_link(path).io_context("read link", path)?;
+        let data = encode_data(DataType::Symlink, Some(target.to_string_lossy().as_bytes()), None, &[]);
+        put_node(blocks, &[], &data, 0)
+    } else if file_type.is_dir() {
+        add_dir(blocks, path)
+    } else {
+        add_file(blocks, File::open(path).io_context("open", path)?, path)
+    }
+}
+
+fn add_dir<B>(blocks: &mut B, path: &Path) -> Result<Entry, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let mut names = Vec::default();
+    for entry in fs::read_dir(path).io_context("read dir", path)? {
+        let name = entry.io_context("read dir", path)?.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    names.sort();
+
+    let mut links = Vec::default();
+    for name in names {
+        let entry = add_entry(blocks, &path.join(&name))?;
+        links.push((name, entry));
+    }
+    let data = encode_data(DataType::Directory, None, None, &[]);
+    put_node(blocks, &links, &data, 0)
+}
+
+fn add_file<B, R, P>(blocks: &mut B, mut reader: R, path: P) -> Result<Entry, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+    R: Read,
+    P: AsRef<Path>,
+{
+    // store the chunks as raw leaves, an empty file is one empty leaf
+    let mut layer = Vec::default();
+    loop {
+        let chunk = read_chunk(&mut reader, path.as_ref())?;
+        if chunk.is_empty() && !layer.is_empty() {
+            break;
+        }
+        let cid = put_block(blocks, Codec::Raw, &chunk)?;
+        let len = chunk.len() as u64;
+        layer.push(Entry { cid, tsize: len, filesize: len });
+        if chunk.len() < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    // build the balanced tree of file nodes bottom up
+    while layer.len() > 1 {
+        let mut next = Vec::default();
+        for children in layer.chunks(MAX_LINKS) {
+            let links: Vec<(String, Entry)> = children.iter().map(|c| (String::default(), c.clone())).collect();
+            let filesize = children.iter().map(|c| c.filesize).sum();
+            let blocksizes: Vec<u64> = children.iter().map(|c| c.filesize).collect();
+            let data = encode_data(DataType::File, None, Some(filesize), &blocksizes);
+            next.push(put_node(blocks, &links, &data, filesize)?);
+        }
+        layer = next;
+    }
+    Ok(layer.remove(0))
+}
+
+/// Get a reader that streams the contents of the UnixFS file with the root Cid, fetching blocks
+/// from the store as they are needed
+pub fn cat<'a, B>(blocks: &'a B, root: &Cid) -> Cat<'a, B>
+where
+    B: Blocks,
+{
+    Cat {
+        blocks,
+        pending: vec![root.clone()],
+        buf: Vec::default(),
+        pos: 0,
+    }
+}
+
+/// Reader over the contents of a UnixFS file
+pub struct Cat<'a, B>
+where
+    B: Blocks,
+{
+    blocks: &'a B,
+    // the blocks still to visit, the next one is last
+    pending: Vec<Cid>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<B> Cat<'_, B>
+where
+    B: Blocks,
+    B::Error: Display,
+{
+    // visit the next block, leaves fill the buffer and file nodes queue their children
+    fn next_block(&mut self, cid: &Cid) -> Result<(), Error> {
+        let data = self.blocks.get(cid).map_err(|e| Error::Custom(e.to_string()))?;
+        if cid.target_codec() == Codec::Raw {
+            self.buf = data;
+            self.pos = 0;
+            return Ok(());
+        }
+        if cid.target_codec() != Codec::DagPb {
+            return Err(DagError::UnsupportedCodec(cid.target_codec()).into());
+        }
+        let node = Node::try_from(data.as_slice())?;
+        if !matches!(node.kind, DataType::File | DataType::Raw) {
+            return Err(DagError::InvalidNode(format!("{:?} is not a file", node.kind)).into());
+        }
+        self.pending.extend(node.links.into_iter().rev().map(|l| l.cid));
+        self.buf = node.data;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<B> Read for Cat<'_, B>
+where
+    B: Blocks,
+    B::Error: Display,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // walk the DAG depth first until there is data to return
+        while self.pos == self.buf.len() {
+            match self.pending.pop() {
+                Some(cid) => self.next_block(&cid).map_err(|e| io::Error::other(e.to_string()))?,
+                None => return Ok(0),
+            }
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Write the UnixFS file, directory or symlink with the root Cid to the destination path,
+/// recreating directories as needed
+pub fn export_to_path<B, P>(blocks: &B, root: &Cid, dest: P) -> Result<(), B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error> + Display,
+    P: AsRef<Path>,
+{
+    let dest = dest.as_ref();
+    check_not_symlink(dest)?;
+    let node = match root.target_codec() {
+        Codec::Raw => None,
+        Codec::DagPb => Some(Node::try_from(blocks.get(root)?.as_slice())?),
+        codec => return Err(Error::from(DagError::UnsupportedCodec(codec)).into()),
+    };
+
+    match node {
+        Some(node) if node.kind == DataType::Directory => {
+            fs::create_dir_all(dest).io_context("create dir", dest)?;
+            let mut names = HashSet::new();
+            for link in node.links {
+                // never let a name escape the destination
+                if link.name.is_empty() || link.name == "." || link.name == ".." || link.name.contains(['/', '\\']) {
+                    return Err(Error::from(DagError::InvalidNode(format!("invalid name {:?}", link.name))).into());
+                }
+                // a repeated name could write through a symlink made by an earlier entry
+                if !names.insert(link.name.clone()) {
+                    return Err(Error::from(DagError::InvalidNode(format!("duplicate name {:?}", link.name))).into());
+                }
+                export_to_path(blocks, &link.cid, dest.join(&link.name))?;
+            }
+        }
+        Some(node) if node.kind == DataType::Symlink => {
+            let target = String::from_utf8_lossy(&node.data).to_string();
+            symlink(&target, dest)?;
+        }
+        Some(node) if node.kind == DataType::HamtShard => {
+            return Err(Error::Unsupported("sharded UnixFS directories".to_string()).into());
+        }
+        _ => {
+            let mut f = File::create(dest).io_context("create", dest)?;
+            io::copy(&mut cat(blocks, root), &mut f).io_context("write", dest)?;
+            f.flush().io_context("write", dest)?;
+        }
+    }
+    Ok(())
+}
+
+// refuse to follow or replace a symlink already at the path, it could point outside the destination
+fn check_not_symlink(path: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_symlink() => {
+            Err(io::Error::new(ErrorKind::AlreadyExists, "refusing to export through a symlink")).io_context("export", path)
+        }
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e).io_context("stat", path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, dest: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(target, dest).io_context("create symlink", dest)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, _dest: &Path) -> Result<(), Error> {
+    Err(Error::Unsupported("UnixFS symlinks on this platform".to_string()))
+}
+
+// a decoded protobuf field value
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+// decode the fields of a protobuf message, only varint and length delimited fields are used
+fn fields(mut bytes: &[u8]) -> Result<Vec<(u64, Value<'_>)>, Error> {
+    let mut fields = Vec::default();
+    while !bytes.is_empty() {
+        let (key, rest) = u64::try_decode_from(bytes)?;
+        let value = match key & 7 {
+            0 => {
+                let (value, rest) = u64::try_decode_from(rest)?;
+                bytes = rest;
+                Value::Varint(value)
+            }
+            2 => {
+                let (len, rest) = u64::try_decode_from(rest)?;
+                let len = usize::try_from(len).unwrap_or(usize::MAX);
+                if len > rest.len() {
+                    return Err(DagError::InvalidNode("truncated field".to_string()).into());
+                }
+                bytes = &rest[len..];
+                Value::Bytes(&rest[..len])
+            }
+            wire => return Err(DagError::InvalidNode(format!("unsupported wire type {}", wire)).into()),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+// read up to CHUNK_SIZE bytes, only returning less at the end of the data
+fn read_chunk<R: Read>(reader: &mut R, path: &Path) -> Result<Vec<u8>, Error> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut len = 0;
+    while len < CHUNK_SIZE {
+        match reader.read(&mut chunk[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).io_context("read", path),
+        }
+    }
+    chunk.truncate(len);
+    Ok(chunk)
+}
+
+// encode a dag-pb node with the named links and the UnixFS data and store it
+fn put_node<B>(blocks: &mut B, links: &[(String, Entry)], data: &[u8], filesize: u64) -> Result<Entry, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let node = encode_node(links, data);
+    let cid = put_block(blocks, Codec::DagPb, &node)?;
+    let tsize = node.len() as u64 + links.iter().map(|(_, e)| e.tsize).sum::<u64>();
+    Ok(Entry { cid, tsize, filesize })
+}
+
+fn put_block<B>(blocks: &mut B, codec: Codec, data: &[u8]) -> Result<Cid, B::Error>
+where
+    B: Blocks,
+    B::Error: From<Error>,
+{
+    let mh = mh::Builder::new_from_bytes(Codec::Sha2256, data).map_err(Error::from)?.try_build().map_err(Error::from)?;
+    let cid = cid::Builder::new(Codec::Cidv1)
+        .with_target_codec(codec)
+        .with_hash(&mh)
+        .try_build()
+        .map_err(Error::from)?;
+    blocks.put_with_cid(&cid, data, false)
+}
+
+// a dag-pb PBNode, the links come before the data in the canonical encoding
+fn encode_node(links: &[(String, Entry)], data: &[u8]) -> Vec<u8> {
+    let mut v = Vec::default();
+    for (name, entry) in links {
+        let mut link = Vec::default();
+        put_bytes(&mut link, 1, &Into::<Vec<u8>>::into(entry.cid.clone()));
+        put_bytes(&mut link, 2, name.as_bytes());
+        put_varint(&mut link, 3, entry.tsize);
+        put_bytes(&mut v, 2, &link);
+    }
+    put_bytes(&mut v, 1, data);
+    v
+}
+
+// a UnixFS Data message
+fn encode_data(kind: DataType, data: Option<&[u8]>, filesize: Option<u64>, blocksizes: &[u64]) -> Vec<u8> {
+    let mut v = Vec::default();
+    put_varint(&mut v, 1, kind as u64);
+    if let Some(data) = data {
+        put_bytes(&mut v, 2, data);
+    }
+    if let Some(filesize) = filesize {
+        put_varint(&mut v, 3, filesize);
+    }
+    for blocksize in blocksizes {
+        put_varint(&mut v, 4, *blocksize);
+    }
+    v
+}
+
+// a protobuf varint field
+fn put_varint(v: &mut Vec<u8>, field: u64, value: u64) {
+    v.append(&mut (field << 3).encode_into());
+    v.append(&mut value.encode_into());
+}
+
+// a protobuf length delimited field
+fn put_bytes(v: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    v.append(&mut (field << 3 | 2).encode_into());
+    v.append(&mut (bytes.len() as u64).encode_into());
+    v.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsblocks::Builder;
+    use std::path::PathBuf;
+
+    fn decode_cid(s: &str) -> Cid {
+        let (_, bytes) = multibase::decode(s).unwrap();
+        Cid::try_from(bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_add_path() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".unixfs1");
+        let mut blocks = Builder::new(pb.join("blocks")).try_build().unwrap();
+
+        // small files are a single raw block
+        let file = pb.join("hello.txt");
+        fs::write(&file, b"hello world").unwrap();
+        let cid = add_path(&mut blocks, &file).unwrap();
+        assert_eq!(cid, decode_cid("bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e"));
+
+        // the empty directory has the well known Cid
+        let empty = pb.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        let cid = add_path(&mut blocks, &empty).unwrap();
+        assert_eq!(cid, decode_cid("bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354"));
+
+        // larger files are a dag-pb node over the chunks
+        let big = pb.join("empty").join("big.bin");
+        fs::write(&big, vec![7u8; CHUNK_SIZE + 1]).unwrap();
+        let cid = add_path(&mut blocks, &big).unwrap();
+        assert_eq!(cid.target_codec(), Codec::DagPb);
+        assert_eq!(blocks.len().unwrap(), 5);
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_export_and_cat() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".unixfs2");
+        let mut blocks = Builder::new(pb.join("blocks")).try_build().unwrap();
+
+        let src = pb.join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        fs::write(src.join("big.bin"), &big).unwrap();
+        fs::write(src.join("sub").join("hello.txt"), b"hello world").unwrap();
+        let root = add_path(&mut blocks, &src).unwrap();
+
+        // the directory node lists its entries by name
+        let node = Node::try_from(blocks.get(&root).unwrap().as_slice()).unwrap();
+        assert_eq!(node.kind, DataType::Directory);
+        let names: Vec<_> = node.links.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["big.bin", "sub"]);
+
+        // files stream back out
+        let mut data = Vec::default();
+        cat(&blocks, &node.links[0].cid).read_to_end(&mut data).unwrap();
+        assert_eq!(data, big);
+
+        // directories can't be read as files
+        assert!(cat(&blocks, &root).read_to_end(&mut Vec::default()).is_err());
+
+        let dest = pb.join("dest");
+        export_to_path(&blocks, &root, &dest).unwrap();
+        assert_eq!(fs::read(dest.join("big.bin")).unwrap(), big);
+        assert_eq!(fs::read(dest.join("sub").join("hello.txt")).unwrap(), b"hello world".to_vec());
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+
+    #[test]
+    fn test_export_refuses_symlinks() {
+        let mut pb = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        pb.push(".unixfs3");
+        let mut blocks = Builder::new(pb.join("blocks")).try_build().unwrap();
+
+        // a directory naming the same entry twice is refused
+        let src = pb.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a"), b"hello world").unwrap();
+        let file = add_path(&mut blocks, &src.join("a")).unwrap();
+        let entry = Entry { cid: file, tsize: 11, filesize: 11 };
+        let data = encode_data(DataType::Directory, None, None, &[]);
+        let links = vec![("a".to_string(), entry.clone()), ("a".to_string(), entry)];
+        let dup = put_node(&mut blocks, &links, &data, 0).unwrap();
+        assert!(export_to_path(&blocks, &dup.cid, pb.join("dup")).is_err());
+
+        // an existing symlink at the destination is never written through
+        #[cfg(unix)]
+        {
+            let outside = pb.join("outside");
+            fs::create_dir_all(&outside).unwrap();
+            let root = add_path(&mut blocks, &src).unwrap();
+            let dest = pb.join("dest");
+            fs::create_dir_all(&dest).unwrap();
+            std::os::unix::fs::symlink(&outside, dest.join("a")).unwrap();
+            assert!(export_to_path(&blocks, &root, &dest).is_err());
+            assert!(!outside.join("a").exists());
+        }
+
+        assert!(fs::remove_dir_all(&pb).is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: Apache-2.0
+use crate::{Blocks, Error, error::{FsStorageError, IoContext}, fsstorage::Sharding};
+use log::debug;
+use multibase::Base;
+use multicid::Cid;
+use std::io::Read;
+
+/// Blocks stored on a WebDAV server, e.g. a NAS or `rclone serve webdav`. The collections under
+/// the base url use the same sharded layout as the filesystem stores so the root of an
+/// uncompressed, unencrypted FsBlocks store can be shared over WebDAV and read or written by
+/// either side. SFTP servers can be reached through a WebDAV gateway such as rclone.
+pub struct WebDavBlocks {
+    agent: ureq::Agent,
+    base: String,
+    authorization: Option<String>,
+    sharding: Sharding,
+}
+
+impl WebDavBlocks {
+    /// use the collection at the base url as the root of the store
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            return Err(FsStorageError::InvalidConfig(base_url.to_string()).into());
+        }
+        debug!("webdav: Using {}", base_url);
+        Ok(WebDavBlocks {
+            agent: ureq::Agent::new(),
+            base: base_url.trim_end_matches('/').to_string(),
+            authorization: None,
+            sharding: Sharding::default(),
+        })
+    }
+
+    /// authenticate every request with basic auth
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+        let encoded = multibase::encode(Base::Base64Pad, format!("{}:{}", user, password));
+        // strip the multibase symbol
+        self.authorization = Some(format!("Basic {}", &encoded[1..]));
+        self
+    }
+
+    /// lay the collections out with the sharding instead of the default, it has to match the
+    /// sharding of any FsBlocks store sharing the same root
+    pub fn with_sharding(mut self, sharding: Sharding) -> Self {
+        self.sharding = sharding;
+        self
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}/{}", self.base, path));
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    // create the collections leading to the block, servers answer 405 for existing ones
+    fn mkcols(&self, folders: &[String]) -> Result<(), Error> {
+        for i in 1..=folders.len() {
+            match self.request("MKCOL", &format!("{}/", folders[..i].join("/"))).call() {
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(e) => return Err(wrap(e)),
+            }
+        }
+        Ok(())
+    }
+
+    // the paths of the members of the collection, collections end with a '/'
+    fn members(&self, path: &str) -> Result<Vec<String>, Error> {
+        let listing = self
+            .request("PROPFIND", path)
+            .set("Depth", "1")
+            .call()
+            .map_err(wrap)?
+            .into_string()
+            .io_context("read", &self.base)?;
+        Ok(parse_hrefs(&listing)
+            .into_iter()
+            .filter_map(|href| member_path(&self.base, &href))
+            .filter(|member| member.trim_end_matches('/') != path.trim_end_matches('/'))
+            .collect())
+    }
+}
+
+impl Blocks for WebDavBlocks {
+    type Error = Error;
+
+    fn exists(&self, cid: &Cid) -> Result<bool, Self::Error> {
+        let (folders, name) = block_path(&self.sharding, cid)?;
+        match self.request("HEAD", &join(&folders, &name)).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(wrap(e)),
+        }
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let (folders, name) = block_path(&self.sharding, cid)?;
+        let response = match self.request("GET", &join(&folders, &name)).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Err(FsStorageError::NoSuchData(name).into()),
+            Err(e) => return Err(wrap(e)),
+        };
+        let mut data = Vec::default();
+        response.into_reader().read_to_end(&mut data).io_context("read", &self.base)?;
+        Ok(data)
+    }
+
+    fn cids(&self) -> Result<Vec<Cid>, Self::Error> {
+        // walk the collections, skipping the dot folders and files the stores keep at the root
+        let mut cids = Vec::default();
+        let mut pending = vec![String::default()];
+        while let Some(path) = pending.pop() {
+            for member in self.members(&path)? {
+                let name = member.trim_end_matches('/').rsplit('/').next().unwrap_or_default().to_string();
+                if name.is_empty() || name.starts_with('.') {
+                    continue;
+                }
+                if member.ends_with('/') {
+                    pending.push(member);
+                } else if let Ok((_, bytes)) = multibase::decode(&name) {
+                    if let Ok(cid) = Cid::try_from(bytes.as_slice()) {
+                        cids.push((name, cid));
+                    }
+                }
+            }
+        }
+        cids.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(cids.into_iter().map(|(_, cid)| cid).collect())
+    }
+
+    fn put<D, F1, F2>(&mut self, data: &D, get_cid: F1, pre_commit: F2) -> Result<Cid, Self::Error>
+    where
+        D: AsRef<[u8]>,
+        F1: Fn(&D) -> Result<Cid, Self::Error>,
+        F2: Fn(&Cid) -> Result<(), Self::Error>,
+    {
+        let cid = get_cid(data)?;
+        pre_commit(&cid)?;
+
+        // upload to a temp file and move it into place so readers never see a partial block
+        let (folders, name) = block_path(&self.sharding, &cid)?;
+        self.mkcols(&folders)?;
+        let temp = join(&folders, &format!(".tmp.{}", name));
+        self.request("PUT", &temp).send_bytes(data.as_ref()).map_err(wrap)?;
+        self.request("MOVE", &temp)
+            .set("Destination", &format!("{}/{}", self.base, join(&folders, &name)))
+            .set("Overwrite", "T")
+            .call()
+            .map_err(wrap)?;
+        debug!("webdav: Stored block {}", name);
+        Ok(cid)
+    }
+
+    fn rm(&self, cid: &Cid) -> Result<Vec<u8>, Self::Error> {
+        let data = self.get(cid)?;
+        let (folders, name) = block_path(&self.sharding, cid)?;
+        match self.request("DELETE", &join(&folders, &name)).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(data),
+            Err(e) => Err(wrap(e)),
+        }
+    }
+}
+
+/// the collections and file name of the block
+fn block_path(sharding: &Sharding, cid: &Cid) -> Result<(Vec<String>, String), Error> {
+    let data: Vec<u8> = cid.clone().into();
+    let encoded = multibase::encode(Base::Base32Z, data);
+    Ok((sharding.folders(&encoded)?, encoded))
+}
+
+fn join(folders: &[String], name: &str) -> String {
+    let mut path = folders.join("/");
+    path.push('/');
+    path.push_str(name);
+    path
+}
+
+/// the contents of every href element in a multistatus response, whatever the namespace prefix
+fn parse_hrefs(xml: &str) -> Vec<String> {
+    xml.split('<')
+        .filter_map(|s| s.split_once('>'))
+        .filter(|(tag, _)| {
+            let tag = tag.split_whitespace().next().unwrap_or_default();
+            tag == "href" || tag.ends_with(":href")
+        })
+        .map(|(_, href)| href.trim().to_string())
+        .collect()
+}
+
+/// the path of an href relative to the base url, None if it is outside of it
+fn member_path(base: &str, href: &str) -> Option<String> {
+    // hrefs are either absolute urls or absolute paths
+    let base_path = match base.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or(""),
+        None => base,
+    };
+    let href_path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+        None => href,
+    };
+    let relative = href_path.strip_prefix(base_path)?;
+    Some(relative.trim_start_matches('/').to_string())
+}
+
+fn wrap(e: ureq::Error) -> Error {
+    Error::Wrapped(Box::new(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multicid::cid;
+    use multicodec::Codec;
+    use multihash::mh;
+
+    #[test]
+    fn test_layout() {
+        let cid = cid::Builder::new(Codec::Cidv1)
+            .with_target_codec(Codec::Raw)
+            .with_hash(&mh::Builder::new_from_bytes(Codec::Blake3, b"for great justice!").unwrap().try_build().unwrap())
+            .try_build()
+            .unwrap();
+        let (folders, name) = block_path(&Sharding::Suffix { chars: 2, depth: 2 }, &cid).unwrap();
+        assert_eq!(folders.len(), 2);
+        assert!(name.ends_with(&folders.concat()));
+        assert_eq!(join(&folders, &name), format!("{}/{}/{}", folders[0], folders[1], name));
+
+        let xml = r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">
+            <D:response><D:href>/dav/store/</D:href></D:response>
+            <D:response><D:href>https://nas.local/dav/store/ab/</D:href></D:response>
+            <d:response><d:href>/dav/store/ab/ybndrfg</d:href></d:response>
+            <response><href xmlns="DAV:">/elsewhere/x</href></response></D:multistatus>"#;
+        let members: Vec<String> = parse_hrefs(xml).iter().filter_map(|h| member_path("https://nas.local/dav/store", h)).collect();
+        assert_eq!(members, vec!["".to_string(), "ab/".to_string(), "ab/ybndrfg".to_string()]);
+
+        let blocks = WebDavBlocks::new("https://nas.local/dav/store/").unwrap().with_credentials("user", "pass");
+        assert_eq!(blocks.authorization, Some("Basic dXNlcjpwYXNz".to_string()));
+        assert!(WebDavBlocks::new("nas.local/dav").is_err());
+    }
+}